@@ -5,6 +5,11 @@ pub enum YieldType {
   EndOfTheQueue,
   /// The process gives up running for infinite receive or a similar reason.
   InfiniteWait,
+  /// The process waits in a receive with a timeout, its timer is running.
+  TimedWait,
+  /// The process has called a dirty native function, it waits for the
+  /// result from a dirty thread.
+  DirtyCall,
 }
 
 /// Enum is used by VM dispatch handlers for opcodes to indicate whether to
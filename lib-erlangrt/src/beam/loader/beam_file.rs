@@ -161,12 +161,12 @@ impl BeamFile {
   /// Read Attr section: two terms (module attributes and compiler info) encoded
   /// as external term format.
   fn load_attributes(&mut self, r: &mut BinaryReader) -> RtResult<()> {
-    self.mod_attrs = etf::decode(r, &mut self.lit_heap)?;
+    self.mod_attrs = etf::decode(r, &mut self.lit_heap, None)?;
     Ok(())
   }
 
   fn load_compiler_info(&mut self, r: &mut BinaryReader) -> RtResult<()> {
-    self.compiler_info = etf::decode(r, &mut self.lit_heap)?;
+    self.compiler_info = etf::decode(r, &mut self.lit_heap, None)?;
     Ok(())
   }
 
@@ -314,7 +314,7 @@ impl BeamFile {
       let _size = r.read_u32be();
 
       // TODO: Instead of unwrap return error and possibly log/return error location too?
-      let literal = etf::decode(&mut r, &mut self.lit_heap).unwrap();
+      let literal = etf::decode(&mut r, &mut self.lit_heap, None).unwrap();
 
       self.lit_tab.push(literal);
     }
@@ -33,12 +33,15 @@ impl OpcodeBsGetBinary2 {
     _flags: Term,
     dst: Term,
   ) -> RtResult<DispatchResult> {
-    // Allocate a sub-binary and possibly GC if does not fit?
+    // Allocate a sub-binary and possibly GC if does not fit
     let bit_size = BitSize::with_unit(size, unit);
-    let src_bin = (*match_state).get_src_binary();
 
     if !bit_size.is_empty() {
-      proc.get_heap_mut().allocate_intent(BinarySlice::storage_size(), live)?;
+      // The match state can be moved by the GC, so it is passed as a root
+      let mut ms_root = [Term::make_boxed(match_state)];
+      proc.ensure_heap(BinarySlice::storage_size(), live, &mut ms_root)?;
+      let match_state = ms_root[0].get_box_ptr_mut::<BinaryMatchState>();
+      let src_bin = (*match_state).get_src_binary();
 
       // Create slice
       let bit_offset = (*match_state).get_offset();
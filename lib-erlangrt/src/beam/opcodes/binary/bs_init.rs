@@ -27,13 +27,13 @@ define_opcode!(
 impl OpcodeBsInit2 {
   #[inline]
   fn bs_init2(
-    vm: &mut VM,
+    _vm: &VM,
     runtime_ctx: &mut Context,
     proc: &mut Process,
     fail: Term,
     sz: usize,
     words: usize,
-    _regs: usize,
+    regs: usize,
     _flags: usize,
     dst: Term,
   ) -> RtResult<DispatchResult> {
@@ -46,12 +46,13 @@ impl OpcodeBsInit2 {
       return Ok(DispatchResult::Normal);
     }
 
-    // Show intent to allocate memory; TODO: add GC related args, like live/regs
+    // Show intent to allocate memory, this might run the GC
+    runtime_ctx.live = regs;
     boxed::Binary::ensure_memory_for_binary(
-      vm,
-      proc.get_heap_mut(),
+      proc,
       BitSize::with_bytes(sz),
       WordSize::new(words),
+      regs,
     )?;
 
     let binary_size = BitSize::with_bytes(sz);
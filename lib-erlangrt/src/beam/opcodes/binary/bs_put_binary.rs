@@ -38,7 +38,7 @@ impl OpcodeBsPutBinary {
   /// Put Binary opcode with the size
   #[inline]
   fn bs_put_binary(
-    _vm: &mut VM,
+    _vm: &VM,
     ctx: &mut Context,
     _proc: &mut Process,
     fail: Term,
@@ -19,7 +19,7 @@ define_opcode!(
 impl OpcodeBsPutInteger {
   #[inline]
   fn bs_put_integer(
-    _vm: &mut VM,
+    _vm: &VM,
     ctx: &mut Context,
     _proc: &mut Process,
     _fail: Term,
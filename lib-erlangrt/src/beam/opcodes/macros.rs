@@ -42,7 +42,7 @@ macro_rules! define_opcode {
 
       #[inline]
       pub fn __run(
-        $vmarg: &mut crate::emulator::vm::VM,
+        $vmarg: &crate::emulator::vm::VM,
         $ctxarg: &mut Context,
         $procarg: &mut Process
       ) -> RtResult<DispatchResult> {
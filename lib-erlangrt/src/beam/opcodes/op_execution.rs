@@ -2,13 +2,13 @@
 //! returns etc.
 use crate::{
   beam::disp_result::DispatchResult,
-  defs::exc_type::ExceptionType,
+  defs::{exc_type::ExceptionType, Reductions},
   emulator::{
     gen_atoms,
     process::Process,
     runtime_ctx::{
       call_native_fun::{self, find_and_call_native_fun},
-      Context, ReturnResult,
+      hibernate, Context, ReturnResult,
     },
     vm::VM,
   },
@@ -34,6 +34,7 @@ impl OpcodeCall {
   #[inline]
   pub fn call(ctx: &mut Context, arity: usize, dst: Term) -> RtResult<DispatchResult> {
     ctx.live = arity;
+    ctx.consume_reductions(Reductions::CALL_COST);
     debug_assert!(dst.is_boxed(), "Call location must be a box (have {})", dst);
 
     ctx.debug_trace_call("opcode:call", dst, 0, arity);
@@ -62,6 +63,7 @@ impl OpcodeCallOnly {
     dst: Term,
   ) -> RtResult<DispatchResult> {
     ctx.live = arity;
+    ctx.consume_reductions(Reductions::CALL_COST);
     ctx.debug_trace_call("opcode:call_only", dst, 0, arity);
     ctx.jump(dst); // jump will assert if the location is cp
     Ok(DispatchResult::Normal)
@@ -87,6 +89,7 @@ impl OpcodeCallLast {
     dealloc: usize,
   ) -> RtResult<DispatchResult> {
     ctx.live = arity;
+    ctx.consume_reductions(Reductions::CALL_COST);
     let hp = curr_p.get_heap_mut();
     ctx.set_cp(hp.stack_deallocate(dealloc));
     ctx.debug_trace_call("opcode:call_last", dst, 0, arity);
@@ -108,7 +111,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeCallExtOnly {
   #[inline]
   pub fn call_ext_only(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     arity: usize,
@@ -133,7 +136,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeCallExt {
   #[inline]
   pub fn call_ext(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     arity: usize,
@@ -156,7 +159,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeCallExtLast {
   #[inline]
   pub fn call_ext_last(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     arity: usize,
@@ -174,7 +177,7 @@ impl OpcodeCallExtLast {
 /// Arg: dst_import: boxed::Import which will contain MFArity to call.
 #[inline]
 fn generic_call_ext(
-  vm: &mut VM,
+  vm: &VM,
   ctx: &mut Context,
   proc: &mut Process,
   dst_import: Term,
@@ -186,7 +189,11 @@ fn generic_call_ext(
 
   match unsafe { boxed::Import::mut_from_term(dst_import) } {
     Ok(import_ptr) => unsafe {
-      if (*import_ptr).get_is_bif(&vm.code_server) {
+      if hibernate::is_hibernate(&(*import_ptr).mfarity) {
+        return hibernate::hibernate(vm, ctx, proc, args);
+      }
+      let is_bif = (*import_ptr).get_is_bif(&vm.code_server.read().unwrap());
+      if is_bif {
         // Perform a BIF application
         let cb_target = call_native_fun::CallBifTarget::ImportPointer(import_ptr);
         let native_dispatch_result = find_and_call_native_fun(
@@ -206,7 +213,14 @@ fn generic_call_ext(
           // Because tail call might happen on an empty stack, the return with
           // empty stack will end the process life here (no more code).
           match ctx.return_and_clear_cp(proc) {
-            ReturnResult::EmptyStack => return Ok(DispatchResult::Finished),
+            ReturnResult::EmptyStack => {
+              // A dirty call is running, finish when it returns
+              if let Some(dirty_call) = proc.dirty_call.as_mut() {
+                dirty_call.finish = true;
+                return native_dispatch_result;
+              }
+              return Ok(DispatchResult::Finished);
+            }
             ReturnResult::Success => return native_dispatch_result,
           };
         }
@@ -216,8 +230,8 @@ fn generic_call_ext(
         if save_cp {
           ctx.cp = ctx.ip; // Points at the next opcode after this
         }
-        let code_srv = vm.get_code_server_p();
-        let import_dst = (*import_ptr).resolve(&mut (*code_srv))?;
+        ctx.consume_reductions(Reductions::CALL_COST);
+        let import_dst = (*import_ptr).resolve(&vm.code_server)?;
         ctx.jump_ptr(import_dst.get_pointer());
         Ok(DispatchResult::Normal)
       }
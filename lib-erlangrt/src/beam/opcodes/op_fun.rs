@@ -53,7 +53,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeCallFun {
   #[inline]
   pub fn call_fun(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     arity: usize,
@@ -89,15 +89,14 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeApply {
   #[inline]
   pub fn apply(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     arity: usize,
   ) -> RtResult<DispatchResult> {
     let mfa = ModFunArity::new(ctx.get_x(arity), ctx.get_x(arity + 1), arity);
     ctx.live = arity + 2;
-    fixed_apply(vm, ctx, curr_p, &mfa, 0)?;
-    Ok(DispatchResult::Normal)
+    fixed_apply(vm, ctx, curr_p, &mfa, 0)
   }
 }
 
@@ -116,7 +115,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeApplyLast {
   #[inline]
   pub fn apply_last(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     arity: usize,
@@ -131,31 +130,33 @@ impl OpcodeApplyLast {
     ctx.live = arity + 2;
 
     let mfa = ModFunArity::new(module, function, arity);
-    fixed_apply(vm, ctx, curr_p, &mfa, dealloc)?;
-    Ok(DispatchResult::Normal)
+    fixed_apply(vm, ctx, curr_p, &mfa, dealloc)
   }
 }
 
 /// Perform application of module:function/arity to args stored in registers,
 /// with optional deallocation.
 fn fixed_apply(
-  vm: &mut VM,
+  vm: &VM,
   ctx: &mut Context,
   curr_p: &mut Process,
   mfa: &ModFunArity,
   dealloc: usize,
-) -> RtResult<()> {
+) -> RtResult<DispatchResult> {
   if mfa.m == gen_atoms::ERLANG && mfa.f == gen_atoms::APPLY && mfa.arity == 3 {
     panic!("TODO special handling for apply on apply/3");
   }
+  if runtime_ctx::hibernate::is_hibernate(mfa) {
+    let args = ctx.registers_slice(0, mfa.arity);
+    return runtime_ctx::hibernate::hibernate(vm, ctx, curr_p, args);
+  }
 
   println!("call_mfa {}", mfa);
-  let l_result = vm.code_server.lookup_mfa(mfa, true);
+  let l_result = vm.code_server.write().unwrap().lookup_mfa(mfa, true);
   if l_result.is_err() {
     return fail::create::undef();
   }
 
   let args = ctx.registers_slice(0, mfa.arity);
-  ctx.call_mfa(vm, curr_p, &l_result.unwrap(), args, dealloc == 0)?;
-  Ok(())
+  ctx.call_mfa(vm, curr_p, &l_result.unwrap(), args, dealloc == 0)
 }
@@ -14,24 +14,26 @@ fn gen_alloc(
   stack_need: WordSize,
   heap_need: WordSize,
   live: usize,
-  zero: bool,
 ) -> RtResult<()> {
   ctx.live = live;
 
-  let hp = curr_p.get_heap_mut();
-
-  if hp.allocate_intent(heap_need, live).is_err() {
-    return Err(RtErr::HeapIsFull("heap::gen_alloc"));
+  // Heap and stack share the free space, stack also needs one word for CP.
+  // This will run the GC if there is not enough.
+  let need = heap_need + stack_need + WordSize::one();
+  match curr_p.ensure_heap(need, live, &mut []) {
+    Err(RtErr::HeapIsFull(_)) => return Err(RtErr::HeapIsFull("heap::gen_alloc")),
+    other => other?,
   }
 
+  let hp = curr_p.get_heap_mut();
   if hp.stack_check_available(stack_need + WordSize::one()) {
     // Stack has enough words, we can allocate unchecked
     if stack_need.words > 0 {
-      hp.stack_alloc_unchecked(stack_need, zero);
+      hp.stack_alloc_unchecked(stack_need);
     }
     hp.stack_push_lterm_unchecked(ctx.cp.to_cp_term());
   } else {
-    // Stack has not enough, invoke GC and possibly fail
+    // Stack has not enough even after the GC
     return Err(RtErr::HeapIsFull("heap::gen_alloc/stack"));
   }
   Ok(())
@@ -42,7 +44,7 @@ fn gen_alloc(
 define_opcode!(_vm, ctx, curr_p,
   name: OpcodeAllocateZero, arity: 2,
   run: {
-    gen_alloc(ctx, curr_p, WordSize::new(stack_need), WordSize::new(0), live, true)?;
+    gen_alloc(ctx, curr_p, WordSize::new(stack_need), WordSize::new(0), live)?;
     Ok(DispatchResult::Normal)
   },
   args: usize(stack_need), usize(live),
@@ -52,7 +54,7 @@ define_opcode!(_vm, ctx, curr_p,
 define_opcode!(_vm, ctx, curr_p,
   name: OpcodeAllocate, arity: 2,
   run: {
-    gen_alloc(ctx, curr_p, WordSize::new(stack_need), WordSize::new(0), live, false)?;
+    gen_alloc(ctx, curr_p, WordSize::new(stack_need), WordSize::new(0), live)?;
     Ok(DispatchResult::Normal)
   },
   args: usize(stack_need), usize(live),
@@ -64,7 +66,7 @@ define_opcode!(_vm, ctx, curr_p,
 define_opcode!(_vm, ctx, curr_p,
   name: OpcodeAllocateHeapZero, arity: 3,
   run: {
-    gen_alloc(ctx, curr_p, WordSize::new(stack_need), WordSize::new(heap_need), live, true)?;
+    gen_alloc(ctx, curr_p, WordSize::new(stack_need), WordSize::new(heap_need), live)?;
     Ok(DispatchResult::Normal)
   },
   args: usize(stack_need), usize(heap_need), usize(live),
@@ -74,7 +76,7 @@ define_opcode!(_vm, ctx, curr_p,
 define_opcode!(_vm, ctx, curr_p,
   name: OpcodeAllocateHeap, arity: 3,
   run: {
-    gen_alloc(ctx, curr_p, WordSize::new(stack_need), WordSize::new(heap_need), live, false)?;
+    gen_alloc(ctx, curr_p, WordSize::new(stack_need), WordSize::new(heap_need), live)?;
     Ok(DispatchResult::Normal)
   },
   args: usize(stack_need), usize(heap_need), usize(live),
@@ -96,10 +98,11 @@ define_opcode!(_vm, ctx, curr_p,
 // GC using `live` amount of registers as a part of root set.
 // Arg 'live' will be used for gc.
 // Structure: test_heap(heap_need:int, live:int)
-define_opcode!(_vm, _ctx, curr_p,
+define_opcode!(_vm, ctx, curr_p,
   name: OpcodeTestHeap, arity: 2,
   run: {
-    curr_p.get_heap_mut().allocate_intent(WordSize::new(heap_need), live)?;
+    ctx.live = live;
+    curr_p.ensure_heap(WordSize::new(heap_need), live, &mut [])?;
     Ok(DispatchResult::Normal)
   },
   args: usize(heap_need), usize(live),
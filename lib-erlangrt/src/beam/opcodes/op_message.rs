@@ -1,10 +1,12 @@
 use crate::{
   beam::disp_result::DispatchResult,
-  emulator::{process::Process, runtime_ctx::Context, vm::VM},
+  defs::{Reductions, WordSize},
+  emulator::{gen_atoms, process::Process, process_flags, runtime_ctx::Context, vm::VM},
   fail::{self, RtResult},
   term::value::*,
 };
 use crate::beam::disp_result::YieldType;
+use std::time::Duration;
 
 // Sends to x0 value x1, x1 is moved to x0 as result of the operation.
 // If process with pid x0 does not exist, no error is raised.
@@ -18,21 +20,21 @@ define_opcode!(vm, ctx, _curr_p,
 impl OpcodeSend {
   #[inline]
   pub fn send(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
   ) -> RtResult<DispatchResult> {
-    // let sched = vm.get_scheduler_p();
     let x1 = ctx.get_x(1);
     let x0 = ctx.get_x(0);
     if !x0.is_pid() {
       return fail::create::badarg();
     }
-    let p = vm.processes.unsafe_lookup_pid_mut(x0);
-    if !p.is_null() {
-      unsafe {
-        (*p).deliver_message(&mut vm.processes, x1)?;
-      }
+    // The receiver might be running on another scheduler thread
+    let mut size = WordSize::new(0);
+    if let Some(result) = vm.processes.with_process(x0, |p| p.deliver_message(x1)) {
+      size = result?;
     }
+    // Pay for copying the message
+    ctx.consume_reductions(Reductions::SEND_COST + Reductions::for_words(size.words));
 
     ctx.set_x(0, x1);
     Ok(DispatchResult::Normal)
@@ -43,19 +45,24 @@ impl OpcodeSend {
 // If there is no next message, jumps to `fail` label which points to a `wait`
 // or `wait_timeout` instruction.
 // Structure: loop_rec(fail:cp, _source)
-define_opcode!(_vm, ctx, curr_p,
+define_opcode!(vm, ctx, curr_p,
   name: OpcodeLoopRec, arity: 2,
-  run: { Self::loop_rec(ctx, curr_p, fail) },
+  run: { Self::loop_rec(vm, ctx, curr_p, fail) },
   args: cp_or_nil(fail), IGNORE(source),
 );
 
 impl OpcodeLoopRec {
   #[inline]
   pub fn loop_rec(
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     fail: Term,
   ) -> RtResult<DispatchResult> {
+    if !curr_p.mailbox.have_unread_messages() {
+      // Take the messages which have arrived since the process was swapped in
+      curr_p.handle_message_signals(vm);
+    }
     if let Some(msg) = curr_p.mailbox.get_current() {
       ctx.set_x(0, msg);
     } else {
@@ -88,12 +95,27 @@ impl OpcodeLoopRecEnd {
 }
 
 // Removes the current message in the process message list and moves it to `x0`
+// The receive is complete, so its timeout timer is cancelled.
 // Structure: remove_message()
-define_opcode!(_vm, ctx, curr_p,
+define_opcode!(vm, ctx, curr_p,
   name: OpcodeRemoveMessage, arity: 0,
   run: {
     let message = curr_p.mailbox.remove_current();
     ctx.set_x(0, message);
+    vm.timers.cancel_receive_timeout(curr_p);
+    Ok(DispatchResult::Normal)
+  },
+  args:
+);
+
+// Ends a receive after its timeout: resets the receive pointer of the mailbox
+// and clears the timeout flag.
+// Structure: timeout()
+define_opcode!(_vm, _ctx, curr_p,
+  name: OpcodeTimeout, arity: 0,
+  run: {
+    curr_p.mailbox.reset_read_position();
+    curr_p.process_flags.set_value(process_flags::TIMED_OUT, false);
     Ok(DispatchResult::Normal)
   },
   args:
@@ -118,3 +140,114 @@ impl OpcodeWait {
     Ok(DispatchResult::Yield(YieldType::InfiniteWait))
   }
 }
+
+// Suspends the current process waiting for a message, for at most `timeout`
+// milliseconds (or `infinity`). Sets the ip to the label (beginning of the
+// receive loop). When the timer fires, the process continues with the next
+// instruction, which is `timeout`.
+// Structure: wait_timeout(label:cp, timeout:src)
+define_opcode!(vm, ctx, curr_p,
+  name: OpcodeWaitTimeout, arity: 2,
+  run: { Self::wait_timeout(vm, ctx, curr_p, label, timeout) },
+  args: cp_or_nil(label), load(timeout),
+);
+
+impl OpcodeWaitTimeout {
+  #[inline]
+  pub fn wait_timeout(
+    vm: &VM,
+    ctx: &mut Context,
+    curr_p: &mut Process,
+    label: Term,
+    timeout: Term,
+  ) -> RtResult<DispatchResult> {
+    if timeout == gen_atoms::INFINITY {
+      return OpcodeWait::wait(ctx, label);
+    }
+    if !timeout.is_small() || timeout.get_small_signed() < 0 {
+      return fail::create::timeout_value();
+    }
+    if curr_p.process_flags.get(process_flags::TIMED_OUT) {
+      // Timer has fired, step into the timeout branch
+      return Ok(DispatchResult::Normal);
+    }
+    let duration = Duration::from_millis(timeout.get_small_unsigned() as u64);
+    if duration == Duration::from_millis(0) {
+      curr_p.process_flags.set(process_flags::TIMED_OUT);
+      return Ok(DispatchResult::Normal);
+    }
+    // The timer is started once and keeps running while the process wakes up
+    // to check the new messages
+    if curr_p.receive_timer.is_none() {
+      vm.timers.start_receive_timeout(curr_p, duration);
+    }
+    ctx.jump(label);
+    Ok(DispatchResult::Yield(YieldType::TimedWait))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    emulator::{atom, gen_atoms, spawn_options::SpawnOptions, test_util},
+    term::term_builder::TupleBuilder,
+  };
+
+  #[test]
+  fn test_send_pays_for_the_copy() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_send_cost");
+    let opts = SpawnOptions::default();
+    let sender_pid = test_util::spawn(&vm, m, "wait", &opts);
+    let receiver_pid = test_util::spawn(&vm, m, "wait", &opts);
+    let sender = test_util::get_process(&vm, sender_pid);
+
+    let hp = sender.get_heap_mut();
+    let tb = TupleBuilder::with_arity(200, hp).unwrap();
+    for i in 0..200 {
+      unsafe { tb.set_element(i, Term::make_small_unsigned(i)) };
+    }
+    let ctx = unsafe { &mut (*sender.get_context_p()) };
+    ctx.set_x(0, receiver_pid);
+    ctx.set_x(1, tb.make_term());
+    let before = ctx.get_spent_reductions();
+    OpcodeSend::send(&vm, ctx).unwrap();
+    let words = 201;
+    let cost = Reductions::SEND_COST + Reductions::for_words(words);
+    assert_eq!((ctx.get_spent_reductions() - before) as isize, cost);
+
+    // Nothing is copied to a process which does not exist
+    ctx.set_x(0, Term::make_local_pid(1000));
+    let before = ctx.get_spent_reductions();
+    OpcodeSend::send(&vm, ctx).unwrap();
+    let cost = Reductions::SEND_COST;
+    assert_eq!((ctx.get_spent_reductions() - before) as isize, cost);
+  }
+
+  #[test]
+  fn test_receive_does_not_overtake_exit() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_receive_order");
+    let opts = SpawnOptions::default();
+    let sender_pid = test_util::spawn(&vm, m, "wait", &opts);
+    let pid = test_util::spawn(&vm, m, "wait", &opts);
+    let proc = test_util::get_process(&vm, pid);
+
+    let first = atom::from_str("first");
+    proc.deliver_message(first).unwrap();
+    let kill = gen_atoms::KILL;
+    proc.deliver_exit(sender_pid, kill, false).unwrap();
+    proc.deliver_message(atom::from_str("second")).unwrap();
+
+    // The receive takes the message sent before the exit and nothing after it
+    let ctx = unsafe { &mut (*proc.get_context_p()) };
+    OpcodeLoopRec::loop_rec(&vm, ctx, proc, Term::nil()).unwrap();
+    assert_eq!(ctx.get_x(0), first);
+    assert_eq!(proc.mailbox.get_messages().len(), 1);
+    assert!(proc.pending_exit.is_none());
+
+    proc.handle_signals(&vm);
+    assert!(proc.pending_exit.is_some());
+  }
+}
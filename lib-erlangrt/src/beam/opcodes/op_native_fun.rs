@@ -24,7 +24,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeBif0 {
   #[inline]
   fn bif0(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     target: Term,
@@ -47,7 +47,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeBif1 {
   #[inline]
   fn bif1(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     fail: Term,
@@ -70,7 +70,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeBif2 {
   #[inline]
   fn bif2(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     fail: Term,
@@ -93,7 +93,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeGcBif1 {
   #[inline]
   fn gc_bif1(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     fail: Term,
@@ -119,7 +119,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeGcBif2 {
   #[inline]
   fn gc_bif2(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     fail: Term,
@@ -145,7 +145,7 @@ define_opcode!(vm, ctx, curr_p,
 impl OpcodeGcBif3 {
   #[inline]
   fn gc_bif3(
-    vm: &mut VM,
+    vm: &VM,
     ctx: &mut Context,
     curr_p: &mut Process,
     fail: Term,
@@ -4,29 +4,45 @@ use crate::{
     gen_op,
     vm_dispatch::dispatch_op_inline,
   },
-  emulator::{disasm, scheduler::SliceResult, vm::VM},
+  defs::exc_type::ExceptionType,
+  emulator::{
+    disasm, process::Process, runtime_ctx::call_native_fun, scheduler::SliceResult,
+    vm::VM,
+  },
   fail::{RtErr, RtResult},
 };
+use std::time::Instant;
 
 // fn module() -> &'static str { "vm_loop: " }
 
 impl VM {
-  /// Take a process from scheduler.
-  /// Fetch an opcode and execute it.
-  /// Reduce the reduction (instruction) count and once it reaches zero, return.
+  /// Take a process from the scheduler `index`, handle its signals.
+  /// Calls, sends, native functions and GC spend reductions, once the count
+  /// reaches zero, return.
   /// Call dispatch again to schedule another process.
   ///
   /// Returns: `false` if VM found no process to run, `true` if the process has
   /// used its time slice and wants to run another.
-  pub fn dispatch(&mut self) -> RtResult<bool> {
-    // let scheduler = self.get_scheduler_p();
-    let curr_p = match self.scheduler.next_process(&mut self.processes) {
+  pub fn dispatch(&self, index: usize) -> RtResult<bool> {
+    let curr_p = match self.schedulers[index].next_process(self) {
       None => return Ok(false),
       Some(next_pid) => {
         let next_ptr = self.processes.unsafe_lookup_pid_mut(next_pid);
         unsafe { &mut (*next_ptr) }
       }
     };
+    let started = Instant::now();
+    let result = self.run_timeslice(curr_p);
+    self.schedulers[index]
+      .stats
+      .count_timeslice(started.elapsed());
+    result
+  }
+
+  /// Run the process until its time slice ends.
+  fn run_timeslice(&self, curr_p: &mut Process) -> RtResult<bool> {
+    // Messages and other signals sent while the process was not running
+    curr_p.handle_signals(self);
 
     // Ugly borrowing the context from the process, but we guarantee that the
     // borrow will not outlive the owning process or we pay the harsh price
@@ -36,15 +52,38 @@ impl VM {
     ctx.swap_in(); // tell the context, that it is active now
                    // curr_p.heap.print_stack();
 
-    let cs = self.get_code_server_p();
+    // An exit signal has arrived, the process dies without running its code
+    if let Some((reason, _)) = curr_p.pending_exit {
+      curr_p.killed = true;
+      curr_p.set_exception(ExceptionType::Exit, reason);
+      curr_p.timeslice_result = SliceResult::Exception;
+      return Ok(true);
+    }
+
+    // A suspended process has survived the exit signal which woke it up, it
+    // is suspended again when the time slice ends
+    if curr_p.lock_sched_state().is_suspended() {
+      curr_p.timeslice_result = SliceResult::Yield;
+      return Ok(true);
+    }
+
+    // A dirty native function has returned while the process was away, or a
+    // native function has trapped and continues now
+    if let Some(dirty_call) = curr_p.dirty_call.take() {
+      let result = call_native_fun::finish_dirty_call(self, &mut ctx, curr_p, dirty_call);
+      if Self::end_of_timeslice(curr_p, result)? {
+        return Ok(true);
+      }
+    }
 
     // Fetch some opcodes, Execute some opcodes
     //
     loop {
       if cfg!(feature = "trace_opcode_execution") {
         print!("   ↳ ");
+        let code_server = self.code_server.read().unwrap();
         unsafe {
-          disasm::disasm_op(ctx.ip.get_pointer(), &(*cs));
+          disasm::disasm_op(ctx.ip.get_pointer(), &code_server);
         }
         //        curr_p.heap.stack_dump();
       }
@@ -58,38 +97,21 @@ impl VM {
       );
 
       // Handle next opcode
-      let disp_result = match dispatch_op_inline(self, op, &mut ctx, curr_p) {
-        Err(RtErr::Exception(exc_type, exc_reason)) => {
-          println!("vm: Exception type={} reason={}", exc_type, exc_reason);
-          curr_p.set_exception(exc_type, exc_reason);
-          curr_p.timeslice_result = SliceResult::Exception;
-          return Ok(true);
-        }
-        other => other?,
-      };
+      let disp_result = dispatch_op_inline(self, op, &mut ctx, curr_p);
+      if Self::end_of_timeslice(curr_p, disp_result)? {
+        return Ok(true);
+      }
 
-      match disp_result {
-        DispatchResult::Yield(yt) => {
-          curr_p.timeslice_result = match yt {
-            YieldType::EndOfTheQueue => SliceResult::Yield,
-            YieldType::InfiniteWait => SliceResult::InfiniteWait,
-          };
-          return Ok(true);
-        }
-        DispatchResult::Normal => {
-          // curr_p.timeslice_result = SliceResult::None;
-        } // keep looping
-        DispatchResult::Finished => {
-          // Scheduler will terminate the process with EXIT:NORMAL
-          curr_p.timeslice_result = SliceResult::Finished;
-          return Ok(true);
-        }
+      // The VM is halting, leave the process as it is
+      if self.is_stopping() {
+        curr_p.timeslice_result = SliceResult::Yield;
+        return Ok(true);
       }
 
-      // TODO: this seems to not work?
-      if self.processes.count() == 0 {
-        println!("All processes finished, this is the end.");
-        return Ok(false);
+      // A random preemption point in the deterministic mode
+      if self.deterministic.is_enabled() && self.deterministic.should_preempt() {
+        curr_p.timeslice_result = SliceResult::Yield;
+        return Ok(true);
       }
 
       if ctx.reductions <= 0 {
@@ -100,4 +122,42 @@ impl VM {
       }
     } // end loop
   }
+
+  /// Record the result of an opcode in the process.
+  /// Returns: `true` if the time slice of the process has ended.
+  fn end_of_timeslice(
+    curr_p: &mut Process,
+    disp_result: RtResult<DispatchResult>,
+  ) -> RtResult<bool> {
+    let disp_result = match disp_result {
+      Err(RtErr::Exception(exc_type, exc_reason)) => {
+        println!("vm: Exception type={} reason={}", exc_type, exc_reason);
+        curr_p.set_exception(exc_type, exc_reason);
+        curr_p.timeslice_result = SliceResult::Exception;
+        return Ok(true);
+      }
+      other => other?,
+    };
+
+    match disp_result {
+      DispatchResult::Yield(yt) => {
+        curr_p.timeslice_result = match yt {
+          YieldType::EndOfTheQueue => SliceResult::Yield,
+          YieldType::InfiniteWait => SliceResult::InfiniteWait,
+          YieldType::TimedWait => SliceResult::TimedWait,
+          YieldType::DirtyCall => SliceResult::DirtyCall,
+        };
+        Ok(true)
+      }
+      DispatchResult::Normal => {
+        // curr_p.timeslice_result = SliceResult::None;
+        Ok(false) // keep looping
+      }
+      DispatchResult::Finished => {
+        // Scheduler will terminate the process with EXIT:NORMAL
+        curr_p.timeslice_result = SliceResult::Finished;
+        Ok(true)
+      }
+    }
+  }
 }
@@ -7,6 +7,29 @@ use crate::{
   },
 };
 use crate::emulator::heap::Designation;
+use std::thread;
+
+/// Options of the deterministic scheduling mode, see `emulator::deterministic`.
+#[derive(Debug, Default)]
+pub struct DeterministicArgs {
+  /// Random seed for the run queue order (option +Dseed N)
+  pub seed: Option<u64>,
+  /// Cut a time slice on average once in N opcodes (option +Dpreempt N)
+  pub preempt_one_in: Option<u64>,
+  /// Write the scheduling log to a file (option +Drecord File)
+  pub record: Option<String>,
+  /// Replay a scheduling log from a file (option +Dreplay File)
+  pub replay: Option<String>,
+}
+
+impl DeterministicArgs {
+  pub fn is_enabled(&self) -> bool {
+    self.seed.is_some()
+      || self.preempt_one_in.is_some()
+      || self.record.is_some()
+      || self.replay.is_some()
+  }
+}
 
 #[derive(Debug)]
 pub enum NodeName {
@@ -28,6 +51,13 @@ pub struct ErlStartArgs {
   /// Which modules:functions to start (option -s m f arg1,...)
   pub start: Vec<Vec<String>>,
   pub search_path: Vec<String>,
+  /// How many scheduler threads to run (option +S N)
+  pub schedulers: usize,
+  /// How many dirty CPU threads to run (option +SDcpu N)
+  pub dirty_cpu_schedulers: usize,
+  /// How many dirty IO threads to run (option +SDio N)
+  pub dirty_io_schedulers: usize,
+  pub deterministic: DeterministicArgs,
 
   /// Small heap only for storing command line available globally
   arg_heap: Heap,
@@ -37,12 +67,17 @@ pub struct ErlStartArgs {
 
 impl ErlStartArgs {
   pub fn new(raw_command_line: &Vec<String>) -> Self {
+    let schedulers = thread::available_parallelism().map_or(1, |n| n.get());
     Self {
       command_line: raw_command_line.clone(),
       other_args: Vec::new(),
       node: NodeName::Short("nonode@nohost".to_string()),
       start: Vec::new(),
       search_path: vec![],
+      schedulers,
+      dirty_cpu_schedulers: schedulers,
+      dirty_io_schedulers: 10,
+      deterministic: DeterministicArgs::default(),
       arg_heap: Heap::new(Designation::ProgramArgumentsHeap),
       args_term: Term::non_value(),
     }
@@ -61,6 +96,12 @@ impl ErlStartArgs {
   {
     loop {
       if let Some(s) = iter.next() {
+        if Self::takes_value(s) {
+          if let Some(value) = iter.next() {
+            self.add_arg2(s, value);
+            continue;
+          }
+        }
         self.add_arg1(s.as_ref())
       } else {
         break;
@@ -68,6 +109,15 @@ impl ErlStartArgs {
     }
   }
 
+  /// Whether the option is followed by a separate value arg.
+  fn takes_value(a: &str) -> bool {
+    match a {
+      "-sname" | "-name" | "+S" | "+SDcpu" | "+SDio" | "+Dseed" | "+Dpreempt"
+      | "+Drecord" | "+Dreplay" => true,
+      _ => false,
+    }
+  }
+
   /// Parses and adds one argument with no parameter
   pub fn add_arg1(&mut self, a1: &str) {
     self.parse_arg(&[a1]);
@@ -89,10 +139,62 @@ impl ErlStartArgs {
       "-name" => {
         self.node = NodeName::Full(args[1].to_string());
       }
+      "+Dseed" => match args.get(1).map(|v| v.parse::<u64>()) {
+        Some(Ok(seed)) => self.deterministic.seed = Some(seed),
+        _ => println!("Bad deterministic seed: {:?}", args.get(1)),
+      },
+      "+Dpreempt" => match args.get(1).map(|v| v.parse::<u64>()) {
+        Some(Ok(n)) if n > 0 => self.deterministic.preempt_one_in = Some(n),
+        _ => println!("Bad deterministic preempt rate: {:?}", args.get(1)),
+      },
+      "+Drecord" => self.deterministic.record = args.get(1).map(|v| v.to_string()),
+      "+Dreplay" => self.deterministic.replay = args.get(1).map(|v| v.to_string()),
+      // +SDcpu DirtyCPUSchedulers[:DirtyCPUSchedulersOnline]
+      s if s.starts_with("+SDcpu") => {
+        if let Some(n) = Self::parse_thread_count(&s[6..], args) {
+          self.dirty_cpu_schedulers = n
+        }
+      }
+      // +SDio DirtyIOSchedulers
+      s if s.starts_with("+SDio") => {
+        if let Some(n) = Self::parse_thread_count(&s[5..], args) {
+          self.dirty_io_schedulers = n
+        }
+      }
+      // +S Schedulers[:SchedulersOnline], only the first number is used
+      s if s.starts_with("+S") => {
+        if let Some(n) = Self::parse_thread_count(&s[2..], args) {
+          self.schedulers = n
+        }
+      }
       other => self.other_args.push(String::from(other)),
     }
   }
 
+  /// Parse the thread count following an option, either glued to the option
+  /// or in the next arg. Only the number before `:` is used.
+  fn parse_thread_count(glued: &str, args: &[&str]) -> Option<usize> {
+    let value = if !glued.is_empty() {
+      glued
+    } else {
+      args.get(1).map_or("", |v| *v)
+    };
+    let value = value.split(':').next().unwrap_or("");
+    match value.parse::<usize>() {
+      Ok(n) if n > 0 => Some(n),
+      _ => {
+        println!("Bad number of threads: {}", value);
+        None
+      }
+    }
+  }
+
+  pub fn get_node_name(&self) -> &str {
+    match &self.node {
+      NodeName::Full(n) | NodeName::Short(n) => n,
+    }
+  }
+
   /// Set node name without changing node mode
   pub fn set_node_name(&mut self, n: &str) {
     match self.node {
@@ -48,17 +48,52 @@ pub const WORD_BYTES: Word = WORD_BITS / 8;
 pub const MAX_XREGS: Word = 256;
 pub const MAX_FPREGS: Word = 8;
 
+/// Reductions measure the work done by a process. One reduction is roughly one
+/// function call, heavier work is charged in proportion to its size.
 pub struct Reductions {}
 impl Reductions {
-  /// How many function-calls/heavier opcodes we process before the process will
-  /// be scheduled out and give the way to other processes in the queue.
-  pub const DEFAULT: isize = 200;
+  /// How many reductions a process can spend before it will be scheduled out
+  /// and give the way to other processes in the queue (same as ERTS).
+  pub const DEFAULT: isize = 4000;
 
   // Costs are taken for different operations
   //
 
-  /// Fetch is base "tax" for fetching an opcode and dispatching to its handler
-  pub const FETCH_OPCODE_COST: isize = 1;
+  /// A call to Erlang code or to a native function
+  pub const CALL_COST: isize = 1;
+  /// Sending a message, plus the cost of copying it
+  pub const SEND_COST: isize = 1;
+
+  /// List elements visited by a native function per one reduction
+  pub const ELEMENTS_PER_REDUCTION: usize = 10;
+  /// Binary bytes processed by a native function per one reduction
+  pub const BYTES_PER_REDUCTION: usize = 100;
+  /// Words copied by a message send or by the garbage collector per one
+  /// reduction
+  pub const WORDS_PER_REDUCTION: usize = 64;
+
+  #[inline]
+  pub fn for_elements(n: usize) -> isize {
+    (n / Self::ELEMENTS_PER_REDUCTION) as isize
+  }
+
+  /// How many list elements a native function can visit with `left`
+  /// reductions. At least one reduction worth, so that the function always
+  /// makes progress.
+  #[inline]
+  pub fn elements_budget(left: isize) -> usize {
+    left.max(1) as usize * Self::ELEMENTS_PER_REDUCTION
+  }
+
+  #[inline]
+  pub fn for_bytes(n: usize) -> isize {
+    (n / Self::BYTES_PER_REDUCTION) as isize
+  }
+
+  #[inline]
+  pub fn for_words(n: usize) -> isize {
+    (n / Self::WORDS_PER_REDUCTION) as isize
+  }
 }
 
 // / For n bytes calculate how many words are required to store this
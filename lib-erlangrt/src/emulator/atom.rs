@@ -1,6 +1,8 @@
 //! Global Atom storage.
 //! Global is ugly, but necessary for now. If we ever create more than 1 VM,
 //! this will have to be shared somehow.
+//! The scheduler threads share the tables, atoms are only ever added so the
+//! lookups take the read lock.
 
 use crate::{
   defs::Word,
@@ -11,7 +13,7 @@ use crate::{
 use core::ptr;
 use std::{
   collections::BTreeMap,
-  sync::{Mutex, MutexGuard},
+  sync::{RwLock, RwLockWriteGuard},
   u16,
 };
 
@@ -61,8 +63,9 @@ impl Atom {
 /// A quick way to find an atom index by its string.
 type StrLookup = BTreeMap<String, usize>;
 
-/// A quick way to find an atom by its index.
-type IndexLookup = Vec<Atom>;
+/// A quick way to find an atom by its index. Atoms are boxed so that their
+/// addresses do not change when the table grows.
+type IndexLookup = Vec<Box<Atom>>;
 
 /// Lookup table for atom to atom index and back. Declared static for use by
 /// printing and atom loading facilities without having to pass the VM pointer
@@ -73,17 +76,17 @@ struct AtomStorage {
   //  /// NOTE: By design these blocks are not movable, make movable maybe?
   //  str_storage: Vec<*const u8>,
   /// Direct mapping string to atom index
-  atoms_by_str: Mutex<StrLookup>,
+  atoms_by_str: RwLock<StrLookup>,
 
   /// Reverse mapping atom index to string (sorted by index)
-  atoms_by_index: Mutex<IndexLookup>,
+  atoms_by_index: RwLock<IndexLookup>,
 }
 
 /// Stores atom lookup tables.
 impl AtomStorage {
   pub fn add_init_atoms(&mut self) {
-    let mut atoms = self.atoms_by_str.lock().unwrap();
-    let mut atoms_r = self.atoms_by_index.lock().unwrap();
+    let mut atoms = self.atoms_by_str.write().unwrap();
+    let mut atoms_r = self.atoms_by_index.write().unwrap();
 
     for (index, ga) in gen_atoms::ATOM_INIT_NAMES.iter().enumerate() {
       let actual_i = AtomStorage::register_atom(&mut atoms, &mut atoms_r, ga);
@@ -92,13 +95,13 @@ impl AtomStorage {
   }
 
   fn register_atom(
-    atoms_by_str: &mut MutexGuard<StrLookup>,
-    atoms_by_index: &mut MutexGuard<IndexLookup>,
+    atoms_by_str: &mut RwLockWriteGuard<StrLookup>,
+    atoms_by_index: &mut RwLockWriteGuard<IndexLookup>,
     s: &str,
   ) -> Word {
     let index = atoms_by_index.len();
     atoms_by_str.insert(s.to_string(), index);
-    atoms_by_index.push(Atom::new(s));
+    atoms_by_index.push(Box::new(Atom::new(s)));
     index
   }
 }
@@ -106,8 +109,8 @@ impl AtomStorage {
 lazy_static! {
   static ref ATOMS: AtomStorage = {
     let mut storage = AtomStorage {
-      atoms_by_str: Mutex::new(BTreeMap::new()),
-      atoms_by_index: Mutex::new(Vec::new()),
+      atoms_by_str: RwLock::new(BTreeMap::new()),
+      atoms_by_index: RwLock::new(Vec::new()),
     };
     storage.add_init_atoms();
     storage
@@ -117,13 +120,17 @@ lazy_static! {
 // Allocate new atom in the atom table or find existing. Pack the atom index
 // as an immediate2 Term
 pub fn from_str(val: &str) -> Term {
-  let mut atoms = ATOMS.atoms_by_str.lock().unwrap();
+  if let Some(index) = ATOMS.atoms_by_str.read().unwrap().get(val) {
+    return Term::make_atom(*index);
+  }
 
+  // Check again, another thread might have added the atom meanwhile
+  let mut atoms = ATOMS.atoms_by_str.write().unwrap();
   if atoms.contains_key(val) {
     return Term::make_atom(atoms[val]);
   }
 
-  let mut atoms_r = ATOMS.atoms_by_index.lock().unwrap();
+  let mut atoms_r = ATOMS.atoms_by_index.write().unwrap();
 
   let index = AtomStorage::register_atom(&mut atoms, &mut atoms_r, val);
 
@@ -161,10 +168,10 @@ pub fn is_printable_atom(s: &str) -> bool {
 
 pub fn lookup(a: Term) -> *const Atom {
   assert!(a.is_atom());
-  let atoms_r = ATOMS.atoms_by_index.lock().unwrap();
+  let atoms_r = ATOMS.atoms_by_index.read().unwrap();
   let index = a.atom_index();
   if index >= atoms_r.len() {
     return ptr::null();
   }
-  &atoms_r[index] as *const Atom
+  &*atoms_r[index] as *const Atom
 }
@@ -7,6 +7,7 @@ use crate::{
   emulator::{
     atom,
     code::{pointer::VersionedCodePtr, CodePtr},
+    dirty_scheduler::DirtyKind,
     mfa::ModFunArity,
     module::{Module, VersionedModuleName},
   },
@@ -17,6 +18,7 @@ use crate::{
 use std::{
   collections::BTreeMap,
   path::{Path, PathBuf},
+  sync::RwLock,
 };
 
 fn module() -> &'static str {
@@ -35,7 +37,8 @@ struct ModuleGenerations {
 
 pub enum MFALookupResult {
   FoundBeamCode(CodePtr),
-  FoundBif(NativeFn),
+  /// A native function and its dirty kind, `None` runs on the scheduler
+  FoundBif(NativeFn, Option<DirtyKind>),
   /* TODO: also NIF?
    * FoundNif(?), */
 }
@@ -69,8 +72,8 @@ impl CodeServer {
     allow_load: bool,
   ) -> RtResult<MFALookupResult> {
     // It could be a BIF
-    if let Some(bif_fn) = self.native_functions.find_mfa(mfa) {
-      return Ok(MFALookupResult::FoundBif(bif_fn));
+    if let Some(entry) = self.native_functions.find_entry(mfa) {
+      return Ok(MFALookupResult::FoundBif(entry.func, entry.dirty));
     }
     // Try look for a BEAM export somewhere
     if let Ok(code_p) = if allow_load {
@@ -126,17 +129,40 @@ impl CodeServer {
   }
 
   /// Notify the code server about the fact that a new module is ready to be
-  /// added to the codebase.
-  pub fn module_loaded(&mut self, mod_ptr: Box<Module>) {
+  /// added to the codebase. The current version of the module becomes old,
+  /// fails if the old version exists and was not purged.
+  pub fn module_loaded(&mut self, mod_ptr: Box<Module>) -> RtResult<()> {
     let name = mod_ptr.versioned_name.module;
     let v = mod_ptr.versioned_name.version;
+    if let Some(mg) = self.mods.get(&name) {
+      if mg.old_modp.is_some() {
+        let msg = format!("{}Old version of {} must be purged", module(), name);
+        return Err(RtErr::CodeLoadingFailed(msg));
+      }
+    }
+    let (old_modp, old_version) = match self.mods.remove(&name) {
+      Some(mg) => (Some(mg.curr_modp), mg.curr_version),
+      None => (None, 0),
+    };
+    mod_ptr.register_literals();
     let mg = ModuleGenerations {
       curr_modp: mod_ptr,
       curr_version: v,
-      old_modp: None,
-      old_version: 0,
+      old_modp,
+      old_version,
     };
     self.mods.insert(name, mg);
+    Ok(())
+  }
+
+  /// Remove the old version of module `m` and return it, the caller must make
+  /// sure that no process refers to its literals before dropping it.
+  // TODO: Check that no process is running the old code
+  pub fn take_old_module(&mut self, m: Term) -> Option<Box<Module>> {
+    match self.mods.get_mut(&m) {
+      Some(mg) => mg.old_modp.take(),
+      None => None,
+    }
   }
 
   /// Lookup, which will attempt to load a missing module if lookup fails
@@ -146,10 +172,13 @@ impl CodeServer {
     match self.lookup_beam_code(mfarity) {
       Ok(ip) => return Ok(ip),
       Err(_e) => {
-        let mod_name = atom::to_str(mfarity.m)?;
-        let found_mod = self.find_module_file(&mod_name).unwrap();
+        // A loaded module does not have the function, do not load it again
+        if !self.mods.contains_key(&mfarity.m) {
+          let mod_name = atom::to_str(mfarity.m)?;
+          let found_mod = self.find_module_file(&mod_name).unwrap();
 
-        self.try_load_module(&found_mod)?;
+          self.try_load_module(&found_mod)?;
+        }
       }
     };
     // Try lookup again
@@ -174,8 +203,7 @@ impl CodeServer {
   /// refc (Arc) module pointer or an error
   fn try_load_module(&mut self, mod_file_path: &PathBuf) -> RtResult<()> {
     let mod_ptr = loader::load_module(self, mod_file_path)?;
-    self.module_loaded(mod_ptr);
-    Ok(())
+    self.module_loaded(mod_ptr)
   }
 
   /// Given a code address try find a module and function where this belongs.
@@ -212,7 +240,8 @@ fn first_that_exists(search_path: &[String], filename: &str) -> Option<PathBuf>
   None
 }
 
-// External API guarded by mutex
+// External API guarded by a lock, the code server is shared by the scheduler
+// threads.
 //
 
 //#[allow(dead_code)]
@@ -222,12 +251,17 @@ fn first_that_exists(search_path: &[String], filename: &str) -> Option<PathBuf>
 //  cs.lookup(mfarity)
 //}
 
-//#[inline]
-// pub fn lookup_and_load(mfarity: &MFArity) -> Hopefully<CodePtr> {
-//  // TODO: Optimize by write-locking the load part and read-locking the lookup part
-//  let mut cs = CODE_SRV.write().unwrap();
-//  cs.lookup_and_load(mfarity)
-//}
+/// Lookup with the read lock held, the write lock is only taken if the module
+/// has to be loaded.
+pub fn lookup_and_load(
+  code_srv: &RwLock<CodeServer>,
+  mfarity: &ModFunArity,
+) -> RtResult<CodePtr> {
+  if let Ok(ip) = code_srv.read().unwrap().lookup_beam_code(mfarity) {
+    return Ok(ip);
+  }
+  code_srv.write().unwrap().lookup_beam_code_and_load(mfarity)
+}
 
 //#[inline]
 // pub fn code_reverse_lookup(ip: CodePtr) -> Option<MFArity> {
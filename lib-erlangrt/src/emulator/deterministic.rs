@@ -0,0 +1,404 @@
+//! Debug tool to reproduce concurrency bugs. In the deterministic mode the VM
+//! runs one scheduler thread, the order in which the queued processes run is
+//! chosen by a seeded random generator and the time slices can be cut short at
+//! random points. Every scheduling decision, message delivery and timer firing
+//! can be recorded to a log file, one event per line. Replaying the log makes
+//! the same decisions in the same order and fires the timers at the same
+//! points, a divergence from the log is reported with a panic.
+//!
+//! The mode is switched on with the command line options `+Dseed N`,
+//! `+Dpreempt N`, `+Drecord File` or `+Dreplay File`. The state belongs to the
+//! VM, several VMs in one program do not share it.
+use crate::{
+  command_line_args::DeterministicArgs, emulator::timer::TimerId, term::value::Term,
+};
+use std::{
+  collections::VecDeque,
+  fmt,
+  fs::File,
+  io::{self, BufRead, BufReader, BufWriter, Write},
+  sync::Mutex,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+/// One line of the log.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Event {
+  /// A process was taken from the run queues
+  Schedule(Term),
+  /// The time slice of the running process was cut after so many opcodes
+  Preempt(usize),
+  /// A message was put to the mailbox of the process, the message is stored
+  /// as text
+  Message(Term, String),
+  /// A timer has fired
+  Timer(TimerId),
+}
+
+impl fmt::Display for Event {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Event::Schedule(pid) => write!(f, "schedule {}", pid.get_term_val_without_tag()),
+      Event::Preempt(ops) => write!(f, "preempt {}", ops),
+      Event::Message(pid, text) => {
+        write!(f, "message {} {}", pid.get_term_val_without_tag(), text)
+      }
+      Event::Timer(id) => write!(f, "timer {}", id),
+    }
+  }
+}
+
+impl Event {
+  /// Parse a line written by `Display`.
+  pub fn parse(line: &str) -> Option<Self> {
+    let mut parts = line.splitn(3, ' ');
+    let kind = parts.next()?;
+    let value = parts.next()?.parse::<usize>().ok()?;
+    match kind {
+      "schedule" => Some(Event::Schedule(Term::make_local_pid(value))),
+      "preempt" => Some(Event::Preempt(value)),
+      "message" => {
+        let text = parts.next().unwrap_or("").to_string();
+        Some(Event::Message(Term::make_local_pid(value), text))
+      }
+      "timer" => Some(Event::Timer(value as TimerId)),
+      _ => None,
+    }
+  }
+}
+
+struct State {
+  /// Xorshift random generator state, never zero
+  random: u64,
+  /// Cut a time slice on average once in so many opcodes, 0 to never cut
+  preempt_one_in: u64,
+  /// Opcodes run in the current time slice
+  slice_ops: usize,
+  record: Option<BufWriter<File>>,
+  replay: Option<VecDeque<Event>>,
+  /// Replayed preemption point of the current time slice
+  replay_preempt: Option<usize>,
+}
+
+impl State {
+  fn next_random(&mut self) -> u64 {
+    let mut x = self.random;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    self.random = x;
+    x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+  }
+
+  fn record(&mut self, event: Event) {
+    if let Some(log) = self.record.as_mut() {
+      if let Err(e) = writeln!(log, "{}", event) {
+        println!("deterministic: Failed to write the log: {}", e);
+        self.record = None;
+      }
+    }
+  }
+
+  /// Take the next replayed event which must be equal to `event`.
+  fn replay(&mut self, event: &Event) {
+    let replay = match self.replay.as_mut() {
+      Some(replay) => replay,
+      None => return,
+    };
+    match replay.pop_front() {
+      Some(ref expected) if expected == event => {}
+      Some(expected) => {
+        panic!(
+          "deterministic: Replay has diverged, expected '{}' got '{}'",
+          expected, event
+        )
+      }
+      None => {
+        println!("deterministic: Replay log has ended, continue with the seed");
+        self.replay = None;
+      }
+    }
+  }
+
+  fn on_event(&mut self, event: Event) {
+    self.replay(&event);
+    self.record(event);
+  }
+}
+
+/// Deterministic mode of one VM, does nothing unless it was switched on by
+/// the args.
+pub struct Deterministic {
+  state: Option<Mutex<State>>,
+}
+
+impl Deterministic {
+  /// Switch on the deterministic mode if the args ask for it.
+  pub fn new(args: &DeterministicArgs) -> io::Result<Self> {
+    if !args.is_enabled() {
+      return Ok(Self::disabled());
+    }
+    let seed = args.seed.unwrap_or_else(|| {
+      let now = SystemTime::now().duration_since(UNIX_EPOCH);
+      now.map_or(0, |d| d.as_nanos() as u64)
+    });
+    let mut record = match &args.record {
+      Some(path) => Some(BufWriter::new(File::create(path)?)),
+      None => None,
+    };
+    if let Some(log) = record.as_mut() {
+      writeln!(log, "# seed {}", seed)?;
+    }
+    let replay = match &args.replay {
+      Some(path) => Some(read_log(path)?),
+      None => None,
+    };
+    println!("deterministic: Scheduling with seed {}", seed);
+
+    let state = State {
+      random: (seed ^ 0x9e37_79b9_7f4a_7c15) | 1,
+      preempt_one_in: args.preempt_one_in.unwrap_or(0),
+      slice_ops: 0,
+      record,
+      replay,
+      replay_preempt: None,
+    };
+    Ok(Self {
+      state: Some(Mutex::new(state)),
+    })
+  }
+
+  pub fn disabled() -> Self {
+    Self { state: None }
+  }
+
+  #[inline]
+  pub fn is_enabled(&self) -> bool {
+    self.state.is_some()
+  }
+
+  fn with_state<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+    let state = self
+      .state
+      .as_ref()
+      .expect("deterministic mode is not started");
+    f(&mut state.lock().unwrap())
+  }
+
+  /// Choose which of `count` queued processes runs next.
+  pub fn choose_index(&self, count: usize) -> usize {
+    self.with_state(|s| (s.next_random() % count as u64) as usize)
+  }
+
+  /// The process which the replay log runs next, if a replay is in progress.
+  pub fn get_replayed_schedule(&self) -> Option<Term> {
+    self.with_state(|s| match s.replay.as_ref().and_then(|r| r.front()) {
+      Some(Event::Schedule(pid)) => Some(*pid),
+      _ => None,
+    })
+  }
+
+  /// The timer which the replay log fires next, if a replay is in progress.
+  /// Replayed timers fire at their recorded points and not by the clock.
+  pub fn get_replayed_timer(&self) -> Option<TimerId> {
+    self.with_state(|s| match s.replay.as_ref().and_then(|r| r.front()) {
+      Some(Event::Timer(id)) => Some(*id),
+      _ => None,
+    })
+  }
+
+  /// Whether the timers are fired by the replay log instead of the clock.
+  pub fn is_replaying(&self) -> bool {
+    self.with_state(|s| s.replay.is_some())
+  }
+
+  /// A process was taken from the run queues and begins its time slice.
+  pub fn on_schedule(&self, pid: Term) {
+    self.with_state(|s| {
+      s.on_event(Event::Schedule(pid));
+      s.slice_ops = 0;
+      // A replayed preemption belongs to this slice if it comes before the
+      // next schedule
+      s.replay_preempt = s.replay.as_ref().and_then(|r| {
+        r.iter()
+          .take_while(|e| match e {
+            Event::Schedule(_) => false,
+            _ => true,
+          })
+          .find_map(|e| match e {
+            Event::Preempt(ops) => Some(*ops),
+            _ => None,
+          })
+      });
+      if let Some(log) = s.record.as_mut() {
+        // Keep the log complete if the VM crashes
+        let _ = log.flush();
+      }
+    })
+  }
+
+  /// Called after each opcode, returns true if the time slice should end now.
+  pub fn should_preempt(&self) -> bool {
+    self.with_state(|s| {
+      s.slice_ops += 1;
+      let preempt = if s.replay.is_some() {
+        s.replay_preempt == Some(s.slice_ops)
+      } else {
+        s.preempt_one_in > 0 && s.next_random() % s.preempt_one_in == 0
+      };
+      if preempt {
+        let ops = s.slice_ops;
+        s.on_event(Event::Preempt(ops));
+      }
+      preempt
+    })
+  }
+
+  /// A message was put to the mailbox of `pid`.
+  pub fn on_message(&self, pid: Term, message: Term) {
+    // Keep one event per line
+    let text = format!("{}", message).replace('\n', "\\n");
+    self.with_state(|s| s.on_event(Event::Message(pid, text)))
+  }
+
+  /// A timer has fired.
+  pub fn on_timer(&self, id: TimerId) {
+    self.with_state(|s| s.on_event(Event::Timer(id)))
+  }
+
+  /// Write the rest of the recorded log to the file.
+  pub fn flush(&self) {
+    self.with_state(|s| {
+      if let Some(log) = s.record.as_mut() {
+        if let Err(e) = log.flush() {
+          println!("deterministic: Failed to write the log: {}", e);
+        }
+      }
+    })
+  }
+}
+
+/// Read the events of a recorded log, skip the `#` comments.
+fn read_log(path: &str) -> io::Result<VecDeque<Event>> {
+  let mut events = VecDeque::new();
+  for line in BufReader::new(File::open(path)?).lines() {
+    let line = line?;
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    match Event::parse(&line) {
+      Some(event) => events.push_back(event),
+      None => {
+        let msg = format!("bad replay log line: {}", line);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+      }
+    }
+  }
+  Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    command_line_args::ErlStartArgs,
+    emulator::{
+      atom,
+      heap::{Designation, Heap},
+      mfa::ModFunArgs,
+      spawn_options::SpawnOptions,
+      test_util,
+      vm::VM,
+    },
+  };
+  use std::{env, fs};
+
+  fn log_path(name: &str) -> String {
+    let file = format!("erlangrt_{}_{}.log", name, std::process::id());
+    env::temp_dir().join(file).to_string_lossy().into_owned()
+  }
+
+  /// Seeded scheduling with frequent preemption, recorded to `log`.
+  fn det_args(seed: u64, log: &str, replay: Option<String>) -> DeterministicArgs {
+    DeterministicArgs {
+      seed: Some(seed),
+      preempt_one_in: Some(2),
+      record: Some(log_path(log)),
+      replay,
+    }
+  }
+
+  /// Run a few processes in a deterministic VM, returns the logged events.
+  fn run_logged(module: &str, det_args: DeterministicArgs) -> VecDeque<Event> {
+    let record = det_args.record.clone().unwrap();
+    let mut args = ErlStartArgs::new(&Vec::new());
+    args.deterministic = det_args;
+    let vm = VM::new(&mut args);
+    assert!(vm.deterministic.is_enabled());
+    assert_eq!(vm.schedulers.len(), 1);
+
+    let m = test_util::load_test_module(&vm, module);
+    // `done/1` returns its arg
+    let mut hp = Heap::new(Designation::TransientDestructible);
+    let done_args = test_util::make_list(&mut hp, &[Term::nil()]);
+    let mfargs = ModFunArgs::with_args_list(m, atom::from_str("done"), done_args);
+    for _ in 0..6 {
+      vm.create_process(Term::nil(), &mfargs, &SpawnOptions::default())
+        .unwrap();
+    }
+    test_util::run_until_idle(&vm);
+    assert_eq!(vm.processes.count(), 0);
+    // The whole replay log was used
+    let replay_left = vm.deterministic.with_state(|s| s.replay.take());
+    if let Some(replay) = replay_left {
+      assert!(replay.is_empty());
+    }
+    vm.deterministic.flush();
+
+    let events = read_log(&record).unwrap();
+    fs::remove_file(&record).unwrap();
+    events
+  }
+
+  #[test]
+  fn test_event_format_parse() {
+    let events = vec![
+      Event::Schedule(Term::make_local_pid(3)),
+      Event::Preempt(120),
+      Event::Message(Term::make_local_pid(1), "{hello, [1,2]}".to_string()),
+      Event::Timer(7),
+    ];
+    for event in events {
+      assert_eq!(Event::parse(&format!("{}", event)), Some(event));
+    }
+    assert_eq!(Event::parse("unknown 1"), None);
+    assert_eq!(Event::parse("schedule"), None);
+  }
+
+  #[test]
+  fn test_record_then_replay() {
+    let recorded = run_logged("test_det_record", det_args(1, "det_record", None));
+    let schedules = recorded.iter().filter_map(|e| match e {
+      Event::Schedule(pid) => Some(*pid),
+      _ => None,
+    });
+    assert!(schedules.count() >= 6);
+
+    // Another seed makes other choices
+    let other = run_logged("test_det_other", det_args(2, "det_other", None));
+    assert_ne!(other, recorded);
+
+    // With the replay log the same seed makes the recorded choices
+    let replay_path = log_path("det_replay_src");
+    {
+      let mut log = File::create(&replay_path).unwrap();
+      for event in recorded.iter() {
+        writeln!(log, "{}", event).unwrap();
+      }
+    }
+    let args = det_args(2, "det_replay", Some(replay_path.clone()));
+    let replayed = run_logged("test_det_replay", args);
+    fs::remove_file(&replay_path).unwrap();
+    assert_eq!(replayed, recorded);
+  }
+}
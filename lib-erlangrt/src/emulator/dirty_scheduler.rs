@@ -0,0 +1,147 @@
+//! Dirty native functions can run for a long time, so they run on a separate
+//! pool of threads and do not block the schedulers. The calling process leaves
+//! the run queues while the call is running, and is queued again on its
+//! scheduler to take the result when the call has returned.
+use crate::{fail::RtResult, native_fun::NativeFn, term::value::Term};
+use core::slice;
+use std::{
+  collections::VecDeque,
+  sync::{Condvar, Mutex},
+  time::Duration,
+};
+
+/// Which pool of dirty threads runs the native function.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DirtyKind {
+  /// Uses CPU for a long time
+  Cpu,
+  /// Waits for IO
+  Io,
+}
+
+/// A native function call which was handed to the dirty threads, stored in
+/// the calling process until the scheduler takes the result. A call which has
+/// trapped (see `RtErr::Trap`) is stored the same way, until the process runs
+/// again and continues it on the scheduler.
+pub struct DirtyCall {
+  /// Which dirty threads run the call, `None` for a trapped call
+  pub kind: Option<DirtyKind>,
+  pub func: NativeFn,
+  /// Args are loaded from the registers when the call is made, they are GC
+  /// roots while the function runs
+  pub args: [Term; 4],
+  pub n_args: usize,
+  /// Where the result goes, or NIL
+  pub dst: Term,
+  /// Jump here on exception if it is a CP
+  pub fail_label: Term,
+  /// Whether the garbage can be collected if the function runs out of heap
+  pub gc: bool,
+  /// How many times the owner has collected the garbage and sent the call back
+  /// to the dirty thread
+  pub gc_attempts: usize,
+  /// The call was a tail call on an empty stack, the process finishes with
+  /// the result
+  pub finish: bool,
+  /// Set by the dirty thread when the function has returned
+  pub result: Option<RtResult<Term>>,
+}
+
+impl DirtyCall {
+  pub fn new(
+    kind: Option<DirtyKind>,
+    func: NativeFn,
+    args: [Term; 4],
+    n_args: usize,
+    fail_label: Term,
+    dst: Term,
+    gc: bool,
+  ) -> Self {
+    Self {
+      kind,
+      func,
+      args,
+      n_args,
+      dst,
+      fail_label,
+      gc,
+      gc_attempts: 0,
+      finish: false,
+      result: None,
+    }
+  }
+
+  /// The args and the result are on the process heap, they are GC roots while
+  /// the call is stored in the process.
+  pub fn get_roots_mut(&mut self) -> [&mut [Term]; 2] {
+    let result = match self.result.as_mut() {
+      Some(Ok(value)) => slice::from_mut(value),
+      _ => &mut [],
+    };
+    [&mut self.args[0..self.n_args], result]
+  }
+}
+
+/// Pids of the processes waiting for a dirty thread of one kind.
+struct DirtyQueue {
+  pids: Mutex<VecDeque<Term>>,
+  ready: Condvar,
+}
+
+impl DirtyQueue {
+  fn new() -> Self {
+    Self {
+      pids: Mutex::new(VecDeque::new()),
+      ready: Condvar::new(),
+    }
+  }
+}
+
+pub struct DirtySchedulers {
+  /// How many dirty CPU threads to run
+  pub cpu_threads: usize,
+  /// How many dirty IO threads to run
+  pub io_threads: usize,
+  cpu: DirtyQueue,
+  io: DirtyQueue,
+}
+
+impl DirtySchedulers {
+  pub fn new(cpu_threads: usize, io_threads: usize) -> Self {
+    Self {
+      cpu_threads,
+      io_threads,
+      cpu: DirtyQueue::new(),
+      io: DirtyQueue::new(),
+    }
+  }
+
+  fn get_queue(&self, kind: DirtyKind) -> &DirtyQueue {
+    match kind {
+      DirtyKind::Cpu => &self.cpu,
+      DirtyKind::Io => &self.io,
+    }
+  }
+
+  /// Queue a process which has a dirty call stored, and wake up a thread.
+  pub fn submit(&self, kind: DirtyKind, pid: Term) {
+    let queue = self.get_queue(kind);
+    queue.pids.lock().unwrap().push_back(pid);
+    queue.ready.notify_one();
+  }
+
+  /// How many processes wait for a dirty thread of this kind.
+  pub fn get_queued_count(&self, kind: DirtyKind) -> usize {
+    self.get_queue(kind).pids.lock().unwrap().len()
+  }
+
+  /// Take the next process for a dirty thread, wait up to `timeout`.
+  pub fn take(&self, kind: DirtyKind, timeout: Duration) -> Option<Term> {
+    let queue = self.get_queue(kind);
+    let mut pids = queue.pids.lock().unwrap();
+    if pids.is_empty() {
+      pids = queue.ready.wait_timeout(pids, timeout).unwrap().0;
+    }
+    pids.pop_front()
+  }
+}
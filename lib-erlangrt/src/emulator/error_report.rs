@@ -0,0 +1,9 @@
+//! Error reports raised by the VM itself (the `error_logger` reports of BEAM,
+//! for example a process exceeding its `max_heap_size`). There is no logger
+//! process yet, so the reports are written to the standard error.
+use core::fmt;
+
+/// Write an error report, use with `format_args!`.
+pub fn report(args: fmt::Arguments) {
+  eprintln!("=ERROR REPORT==== {}", args);
+}
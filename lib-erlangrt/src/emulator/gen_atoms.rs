@@ -9,69 +9,159 @@ use crate::term::value::*;
 pub const SYM_PLUS: Term = Term::make_atom(0);
 pub const SYM_MINUS: Term = Term::make_atom(1);
 pub const SYM_EQ_EQ: Term = Term::make_atom(2);
-pub const ALL: Term = Term::make_atom(3);
-pub const APPLY: Term = Term::make_atom(4);
-pub const BADARG: Term = Term::make_atom(5);
-pub const BADARITH: Term = Term::make_atom(6);
-pub const BADARITY: Term = Term::make_atom(7);
-pub const BADFUN: Term = Term::make_atom(8);
-pub const BADMATCH: Term = Term::make_atom(9);
-pub const CASE_CLAUSE: Term = Term::make_atom(10);
-pub const ERLANG: Term = Term::make_atom(11);
-pub const ERROR: Term = Term::make_atom(12);
-pub const ERTS_INTERNAL: Term = Term::make_atom(13);
-pub const EXIT: Term = Term::make_atom(14);
-pub const FALSE: Term = Term::make_atom(15);
-pub const FUNCTION_CLAUSE: Term = Term::make_atom(16);
-pub const HIGH: Term = Term::make_atom(17);
-pub const IF_CLAUSE: Term = Term::make_atom(18);
-pub const INIT: Term = Term::make_atom(19);
-pub const KILL: Term = Term::make_atom(20);
-pub const KILLED: Term = Term::make_atom(21);
-pub const LOW: Term = Term::make_atom(22);
-pub const NIF_ERROR: Term = Term::make_atom(23);
-pub const NOCATCH: Term = Term::make_atom(24);
-pub const NORMAL: Term = Term::make_atom(25);
-pub const OK: Term = Term::make_atom(26);
-pub const SYSTEM_LIMIT: Term = Term::make_atom(27);
-pub const THROW: Term = Term::make_atom(28);
-pub const TRAP_EXIT: Term = Term::make_atom(29);
-pub const TRUE: Term = Term::make_atom(30);
-pub const UNDEF: Term = Term::make_atom(31);
-pub const UNDEFINED: Term = Term::make_atom(32);
+pub const SYM_DOWN: Term = Term::make_atom(3);
+pub const SYM_EXIT: Term = Term::make_atom(4);
+pub const ABS: Term = Term::make_atom(5);
+pub const ALL: Term = Term::make_atom(6);
+pub const APPLY: Term = Term::make_atom(7);
+pub const ASYNC: Term = Term::make_atom(8);
+pub const ASYNCHRONOUS: Term = Term::make_atom(9);
+pub const BADARG: Term = Term::make_atom(10);
+pub const BADARITH: Term = Term::make_atom(11);
+pub const BADARITY: Term = Term::make_atom(12);
+pub const BADFUN: Term = Term::make_atom(13);
+pub const BADMATCH: Term = Term::make_atom(14);
+pub const CANCEL_TIMER: Term = Term::make_atom(15);
+pub const CASE_CLAUSE: Term = Term::make_atom(16);
+pub const CONTEXT_SWITCHES: Term = Term::make_atom(17);
+pub const DICTIONARY: Term = Term::make_atom(18);
+pub const ERLANG: Term = Term::make_atom(19);
+pub const ERROR: Term = Term::make_atom(20);
+pub const ERROR_LOGGER: Term = Term::make_atom(21);
+pub const ERTS_INTERNAL: Term = Term::make_atom(22);
+pub const EXIT: Term = Term::make_atom(23);
+pub const FALSE: Term = Term::make_atom(24);
+pub const FLUSH: Term = Term::make_atom(25);
+pub const FULLSWEEP_AFTER: Term = Term::make_atom(26);
+pub const FUNCTION_CLAUSE: Term = Term::make_atom(27);
+pub const GARBAGE_COLLECTION: Term = Term::make_atom(28);
+pub const GC: Term = Term::make_atom(29);
+pub const HIBERNATE: Term = Term::make_atom(30);
+pub const HIGH: Term = Term::make_atom(31);
+pub const IF_CLAUSE: Term = Term::make_atom(32);
+pub const INFINITY: Term = Term::make_atom(33);
+pub const INFO: Term = Term::make_atom(34);
+pub const INIT: Term = Term::make_atom(35);
+pub const KILL: Term = Term::make_atom(36);
+pub const KILLED: Term = Term::make_atom(37);
+pub const LINK: Term = Term::make_atom(38);
+pub const LOW: Term = Term::make_atom(39);
+pub const MAX: Term = Term::make_atom(40);
+pub const MAX_HEAP_SIZE: Term = Term::make_atom(41);
+pub const MESSAGE: Term = Term::make_atom(42);
+pub const MESSAGE_QUEUE_DATA: Term = Term::make_atom(43);
+pub const MIN_BIN_VHEAP_SIZE: Term = Term::make_atom(44);
+pub const MIN_HEAP_SIZE: Term = Term::make_atom(45);
+pub const MONITOR: Term = Term::make_atom(46);
+pub const NIF_ERROR: Term = Term::make_atom(47);
+pub const NOCATCH: Term = Term::make_atom(48);
+pub const NOPROC: Term = Term::make_atom(49);
+pub const NORMAL: Term = Term::make_atom(50);
+pub const NOT_SUSPENDED: Term = Term::make_atom(51);
+pub const OFF_HEAP: Term = Term::make_atom(52);
+pub const OK: Term = Term::make_atom(53);
+pub const ON_HEAP: Term = Term::make_atom(54);
+pub const PRIORITY: Term = Term::make_atom(55);
+pub const PROCESS: Term = Term::make_atom(56);
+pub const READ_TIMER: Term = Term::make_atom(57);
+pub const REDUCTIONS: Term = Term::make_atom(58);
+pub const RUN_QUEUE: Term = Term::make_atom(59);
+pub const RUN_QUEUE_LENGTHS: Term = Term::make_atom(60);
+pub const RUNTIME: Term = Term::make_atom(61);
+pub const SCHEDULER_WALL_TIME: Term = Term::make_atom(62);
+pub const SIZE: Term = Term::make_atom(63);
+pub const SUSPENDED: Term = Term::make_atom(64);
+pub const SYSTEM_LIMIT: Term = Term::make_atom(65);
+pub const THROW: Term = Term::make_atom(66);
+pub const TIMEOUT: Term = Term::make_atom(67);
+pub const TIMEOUT_VALUE: Term = Term::make_atom(68);
+pub const TIMESLICE: Term = Term::make_atom(69);
+pub const TRAP_EXIT: Term = Term::make_atom(70);
+pub const TRUE: Term = Term::make_atom(71);
+pub const UNDEF: Term = Term::make_atom(72);
+pub const UNDEFINED: Term = Term::make_atom(73);
+pub const UNLESS_SUSPENDING: Term = Term::make_atom(74);
+pub const VERIFY_HEAP: Term = Term::make_atom(75);
+pub const WAIT: Term = Term::make_atom(76);
+pub const WALL_CLOCK: Term = Term::make_atom(77);
 
 pub static ATOM_INIT_NAMES: &'static [&'static str] = &[
   "+", // id=0
   "-", // id=1
   "==", // id=2
-  "all", // id=3
-  "apply", // id=4
-  "badarg", // id=5
-  "badarith", // id=6
-  "badarity", // id=7
-  "badfun", // id=8
-  "badmatch", // id=9
-  "case_clause", // id=10
-  "erlang", // id=11
-  "error", // id=12
-  "erts_internal", // id=13
-  "exit", // id=14
-  "false", // id=15
-  "function_clause", // id=16
-  "high", // id=17
-  "if_clause", // id=18
-  "init", // id=19
-  "kill", // id=20
-  "killed", // id=21
-  "low", // id=22
-  "nif_error", // id=23
-  "nocatch", // id=24
-  "normal", // id=25
-  "ok", // id=26
-  "system_limit", // id=27
-  "throw", // id=28
-  "trap_exit", // id=29
-  "true", // id=30
-  "undef", // id=31
-  "undefined", // id=32
+  "DOWN", // id=3
+  "EXIT", // id=4
+  "abs", // id=5
+  "all", // id=6
+  "apply", // id=7
+  "async", // id=8
+  "asynchronous", // id=9
+  "badarg", // id=10
+  "badarith", // id=11
+  "badarity", // id=12
+  "badfun", // id=13
+  "badmatch", // id=14
+  "cancel_timer", // id=15
+  "case_clause", // id=16
+  "context_switches", // id=17
+  "dictionary", // id=18
+  "erlang", // id=19
+  "error", // id=20
+  "error_logger", // id=21
+  "erts_internal", // id=22
+  "exit", // id=23
+  "false", // id=24
+  "flush", // id=25
+  "fullsweep_after", // id=26
+  "function_clause", // id=27
+  "garbage_collection", // id=28
+  "gc", // id=29
+  "hibernate", // id=30
+  "high", // id=31
+  "if_clause", // id=32
+  "infinity", // id=33
+  "info", // id=34
+  "init", // id=35
+  "kill", // id=36
+  "killed", // id=37
+  "link", // id=38
+  "low", // id=39
+  "max", // id=40
+  "max_heap_size", // id=41
+  "message", // id=42
+  "message_queue_data", // id=43
+  "min_bin_vheap_size", // id=44
+  "min_heap_size", // id=45
+  "monitor", // id=46
+  "nif_error", // id=47
+  "nocatch", // id=48
+  "noproc", // id=49
+  "normal", // id=50
+  "not_suspended", // id=51
+  "off_heap", // id=52
+  "ok", // id=53
+  "on_heap", // id=54
+  "priority", // id=55
+  "process", // id=56
+  "read_timer", // id=57
+  "reductions", // id=58
+  "run_queue", // id=59
+  "run_queue_lengths", // id=60
+  "runtime", // id=61
+  "scheduler_wall_time", // id=62
+  "size", // id=63
+  "suspended", // id=64
+  "system_limit", // id=65
+  "throw", // id=66
+  "timeout", // id=67
+  "timeout_value", // id=68
+  "timeslice", // id=69
+  "trap_exit", // id=70
+  "true", // id=71
+  "undef", // id=72
+  "undefined", // id=73
+  "unless_suspending", // id=74
+  "verify_heap", // id=75
+  "wait", // id=76
+  "wall_clock", // id=77
 ];
@@ -1,85 +1,337 @@
 //! The classic BEAM design approach is to copy terms to the new owning heap
 //! when an object changes its owner process.
+//! The size of a term is calculated first, then the memory is allocated once
+//! and the objects are copied into it. Boxed objects are copied as a whole,
+//! then the terms inside them are updated using `TBoxed::inplace_map` (same
+//! as the GC does it).
+//!
+//! A subterm referred multiple times is copied multiple times (like BEAM does).
+//! Terms in the module literal areas are never copied.
 // TODO: Smarter approach with refcounted movable objects or use shared heap or something else
 use crate::{
-  emulator::heap::THeap,
+  defs::{Word, WordSize},
+  emulator::heap::{
+    literal_area::{self, LiteralRanges},
+    THeap,
+  },
   fail::RtResult,
   term::{
-    boxed,
-    term_builder::{ListBuilder, TupleBuilder},
+    boxed::{
+      self,
+      binary::{refc_bin::ReferenceToBinary, BinaryType},
+      BoxHeader,
+    },
     value::{self, PrimaryTag, Term},
   },
 };
+use core::ptr;
 
 /// Copies term to another heap.
 pub fn copy_to(term: Term, hp: &mut THeap) -> RtResult<Term> {
+  copy_with_literals(term, hp, &literal_area::snapshot())
+}
+
+/// Copies term to another heap together with the module literals it refers
+/// to. Use this for the data which can outlive the module version, like a
+/// message of a running timer.
+pub fn copy_all_to(term: Term, hp: &mut THeap) -> RtResult<Term> {
+  copy_with_literals(term, hp, &LiteralRanges::empty())
+}
+
+/// Copies term to another heap, terms in the `literals` areas are shared.
+fn copy_with_literals(
+  term: Term,
+  hp: &mut THeap,
+  literals: &LiteralRanges,
+) -> RtResult<Term> {
+  if !needs_copy(term, literals) {
+    return Ok(term);
+  }
+  let size = count_words(term, literals);
+  let dst = hp.alloc(size, false)?;
+  let mut copier = Copier {
+    literals,
+    dst,
+    top: 0,
+    capacity: size.words,
+    refbins: Vec::new(),
+  };
+  let result = unsafe { copier.copy(term) };
+  debug_assert_eq!(copier.top, size.words, "Copy size differs from estimate");
+
+  // The copied handles to refcounted binaries now also belong to `hp`
+  for refbin in copier.refbins {
+    unsafe { ReferenceToBinary::on_copied(refbin, hp) };
+  }
+  Ok(result)
+}
+
+/// Calculate how many words the copy of a term will take.
+pub fn size_of(term: Term) -> WordSize {
+  count_words(term, &literal_area::snapshot())
+}
+
+fn count_words(term: Term, literals: &LiteralRanges) -> WordSize {
+  let mut counter = SizeCounter { literals, words: 0 };
+  unsafe { counter.count(term) };
+  WordSize::new(counter.words)
+}
+
+/// Whether a term points to some data which must be copied. Literals of the
+/// loaded modules are shared by all processes and are not copied.
+fn needs_copy(term: Term, literals: &LiteralRanges) -> bool {
   match term.get_term_tag() {
-    PrimaryTag::BOX_PTR => unsafe { copy_boxed_to(term, hp) },
+    PrimaryTag::CONS_PTR => !literals.contains(term.get_cons_ptr() as *const Word),
+    // CP values and NON_VALUE are not data
+    PrimaryTag::BOX_PTR => {
+      !term.is_cp()
+        && !term.is_non_value()
+        && !literals.contains(term.get_box_ptr::<Word>())
+    }
     PrimaryTag::HEADER => {
       panic!("Attempt to copy header value");
     }
-    PrimaryTag::CONS_PTR => unsafe { copy_cons_to(term, hp) },
-    PrimaryTag::SMALL_INT
-    | PrimaryTag::ATOM
-    | PrimaryTag::LOCAL_PID
-    | PrimaryTag::LOCAL_PORT => Ok(term),
     PrimaryTag::SPECIAL => match term.get_special_tag() {
-      value::SpecialTag::CONST => Ok(term),
+      value::SpecialTag::CONST => false,
       _ => panic!("Attempt to copy a special value: {}", term),
     },
-    t => panic!("Not sure how to copy term with {:?}", t),
+    _ => false,
   }
 }
 
-/// For each list element copy it to a new element in the destination heap.
-/// Also copy the tail element.
-/// Returns: `RtResult<copied_term>`
-unsafe fn copy_cons_to(lst: Term, hp: &mut THeap) -> RtResult<Term> {
-  let mut lb = ListBuilder::new()?;
-
-  let tail_el_result = value::cons::for_each(lst, |el| {
-    // Recurse into copy, for each list element
-    let copy_of_el = copy_to(el, hp)?;
-    lb.append(copy_of_el, hp)?;
-    Ok(())
-  })?;
-
-  if let Some(tail_el) = tail_el_result {
-    return Ok(lb.make_term_with_tail(tail_el));
+/// Calls `f` for every term stored inside of a boxed object.
+unsafe fn for_each_boxed_element<F>(p: *const Word, mut f: F)
+where
+  F: FnMut(Term),
+{
+  // The map function writes back the same values, the data does not change
+  let header_p = p as *mut BoxHeader;
+  let trait_p = (*header_p).get_trait_ptr_mut();
+  (*trait_p).inplace_map(&mut |_owner, t| {
+    f(t);
+    t
+  });
+}
+
+/// Walks a term and counts words the copy of it will need. Nested terms are
+/// visited from a work stack, so a deep term does not overflow the thread
+/// stack.
+struct SizeCounter<'a> {
+  literals: &'a LiteralRanges,
+  words: usize,
+}
+
+impl<'a> SizeCounter<'a> {
+  unsafe fn count(&mut self, term: Term) {
+    let mut stack = vec![term];
+    while let Some(t) = stack.pop() {
+      if !needs_copy(t, self.literals) {
+        continue;
+      }
+      if t.is_cons() {
+        let p = t.get_cons_ptr() as *const Word;
+        self.words += 2;
+        stack.push(Term::from_raw(ptr::read(p.add(1))));
+        stack.push(Term::from_raw(ptr::read(p)));
+      } else {
+        let p = t.get_box_ptr::<Word>();
+        self.words += BoxHeader::headerword_to_storage_size(ptr::read(p));
+        for_each_boxed_element(p, |elem| stack.push(elem));
+      }
+    }
   }
+}
 
-  Ok(lb.make_term())
+/// Copies the objects into a preallocated memory block. Each object is copied
+/// as is first, then the terms inside of the copy are replaced by their
+/// copies. The copies which still refer to the source wait in a work stack,
+/// so a deep term does not overflow the thread stack.
+struct Copier<'a> {
+  literals: &'a LiteralRanges,
+  dst: *mut Word,
+  top: usize,
+  capacity: usize,
+  /// Handles to refcounted binaries created by the copy.
+  refbins: Vec<*mut ReferenceToBinary>,
 }
 
-unsafe fn copy_boxed_to(term: Term, hp: &mut THeap) -> RtResult<Term> {
-  let header_ptr = term.get_box_ptr::<boxed::BoxHeader>();
-  let trait_ptr = (*header_ptr).get_trait_ptr();
-  let box_type = (*trait_ptr).get_type();
-
-  match box_type {
-    boxed::BOXTYPETAG_TUPLE => {
-      let tuple_p = header_ptr as *const boxed::Tuple;
-      let arity = (*tuple_p).get_arity();
-      let tb = TupleBuilder::with_arity(arity, hp)?;
-      for i in 0..arity {
-        let element = (*tuple_p).get_element(i);
-        let copied = copy_to(element, hp)?;
-        tb.set_element(i, copied);
+impl<'a> Copier<'a> {
+  unsafe fn alloc(&mut self, n_words: usize) -> *mut Word {
+    assert!(self.top + n_words <= self.capacity, "Copy exceeds estimate");
+    let p = self.dst.add(self.top);
+    self.top += n_words;
+    p
+  }
+
+  unsafe fn copy(&mut self, term: Term) -> Term {
+    let mut pending = Vec::new();
+    let result = self.copy_shallow(term, &mut pending);
+    while let Some(t) = pending.pop() {
+      if t.is_cons() {
+        let p = t.get_cons_ptr() as *mut Term;
+        for i in 0..2 {
+          let elem = self.copy_shallow(ptr::read(p.add(i)), &mut pending);
+          ptr::write(p.add(i), elem);
+        }
+      } else {
+        let header_p = t.get_box_ptr_mut::<BoxHeader>();
+        let trait_p = (*header_p).get_trait_ptr_mut();
+        let this = self as *mut Self;
+        let pending_p = &mut pending as *mut Vec<Term>;
+        (*trait_p)
+          .inplace_map(&mut |_owner, elem| (*this).copy_shallow(elem, &mut *pending_p));
+      }
+    }
+    result
+  }
+
+  /// Copy a cons cell or a boxed object without the terms it refers to, and
+  /// put the copy to `pending` to copy them later.
+  unsafe fn copy_shallow(&mut self, term: Term, pending: &mut Vec<Term>) -> Term {
+    if !needs_copy(term, self.literals) {
+      return term;
+    }
+    let new_t = if term.is_cons() {
+      let new_p = self.alloc(2);
+      ptr::copy_nonoverlapping(term.get_cons_ptr() as *const Word, new_p, 2);
+      Term::make_cons(new_p)
+    } else {
+      self.copy_boxed_shallow(term)
+    };
+    pending.push(new_t);
+    new_t
+  }
+
+  unsafe fn copy_boxed_shallow(&mut self, term: Term) -> Term {
+    let p = term.get_box_ptr::<Word>();
+    let n_words = BoxHeader::headerword_to_storage_size(ptr::read(p));
+    let new_p = self.alloc(n_words);
+    ptr::copy_nonoverlapping(p, new_p, n_words);
+
+    let header_p = new_p as *mut BoxHeader;
+    let trait_p = (*header_p).get_trait_ptr_mut();
+    if (*trait_p).get_type() == boxed::BOXTYPETAG_BINARY {
+      let btrait = boxed::Binary::get_trait(new_p as *const boxed::Binary);
+      if let BinaryType::RefToBinaryHeap = (*btrait).get_type() {
+        self.refbins.push(new_p as *mut ReferenceToBinary);
       }
-      return Ok(tb.make_term());
     }
-    boxed::BOXTYPETAG_BIGINTEGER => {}
-    boxed::BOXTYPETAG_EXTERNALPID => {}
-    boxed::BOXTYPETAG_EXTERNALREF => {}
-    boxed::BOXTYPETAG_EXTERNALPORT => {}
-    boxed::BOXTYPETAG_CLOSURE => {}
-    boxed::BOXTYPETAG_FLOAT => {}
-    boxed::BOXTYPETAG_IMPORT => {}
-    boxed::BOXTYPETAG_EXPORT => {}
-    boxed::BOXTYPETAG_MAP => {}
-    boxed::BOXTYPETAG_BINARY => {}
-    _other => {}
+    Term::make_boxed(new_p)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    emulator::{
+      atom,
+      heap::{gc::GcSettings, Designation, Heap},
+      module::VersionedModuleName,
+    },
+    term::{
+      boxed::{bignum::sign::Sign, Bignum, Binary, Float, Map, Tuple},
+      term_builder::{
+        list_builder::{build_erlstr_from_utf8, ListBuilder},
+        tuple_builder::tuple2,
+      },
+    },
+  };
+
+  #[test]
+  fn test_copy_every_boxed_type() {
+    let mut src = Heap::new_process_heap(GcSettings::default());
+    let mut dst = Heap::new(Designation::HeapFragment);
+    let small = Term::make_small_signed;
+    unsafe {
+      let f = Term::make_boxed(Float::create_into(&mut src, 3.5).unwrap());
+      let digits = [1, 2, 3];
+      let big = Bignum::create_into(&mut src, Sign::Positive, &digits).unwrap();
+      let s = build_erlstr_from_utf8("shared", &mut src).unwrap();
+      let m = Map::create_into(&mut src, 2).unwrap();
+      Map::add(m, small(1), s).unwrap();
+      Map::add(m, small(2), f).unwrap();
+      let bin = (*Binary::create_with_data(b"abc", &mut src).unwrap()).make_term();
+      let t1 = tuple2(&mut src, f, Term::make_boxed(big)).unwrap();
+      let t2 = tuple2(&mut src, s, bin).unwrap();
+      let t3 = tuple2(&mut src, t1, t2).unwrap();
+      let t = tuple2(&mut src, t3, Term::make_boxed(m)).unwrap();
+
+      let size = size_of(t);
+      let c = copy_to(t, &mut dst).unwrap();
+      assert_eq!(dst.get_heap_used_words(), size.words);
+
+      // Maps can not be formatted yet, so they are checked separately
+      let element = |t: Term, i| (*t.get_tuple_ptr()).get_element(i);
+      assert_eq!(format!("{}", element(c, 0)), format!("{}", t3));
+      let c_map = element(c, 1).get_box_ptr::<Map>();
+      assert_ne!(c_map, m as *const Map);
+      let c_f = Map::get(c_map, small(2)).unwrap().unwrap();
+      assert_eq!(format!("{}", c_f), "3.5");
+
+      // The string used twice is copied twice
+      let c_s = Map::get(c_map, small(1)).unwrap().unwrap();
+      assert!(dst.belongs_to_heap(c_s.get_cons_ptr() as *const Word));
+      let c_s2 = element(element(element(c, 0), 1), 0);
+      assert!(dst.belongs_to_heap(c_s2.get_cons_ptr() as *const Word));
+      assert_ne!(c_s, c_s2);
+    }
   }
 
-  panic!("Don't know how to copy {}", term);
+  #[test]
+  fn test_literals_are_not_copied() {
+    let mut lit = Heap::new(Designation::ModuleLiterals);
+    let mut dst = Heap::new(Designation::HeapFragment);
+    let name = VersionedModuleName::new(atom::from_str("test_copy_literals"), 1);
+    unsafe {
+      let s = build_erlstr_from_utf8("literal", &mut lit).unwrap();
+      let t = tuple2(&mut lit, s, Term::nil()).unwrap();
+      literal_area::register(&name, &lit.get_memory_ranges());
+      assert_eq!(size_of(t).words, 0);
+      assert_eq!(copy_to(t, &mut dst).unwrap(), t);
+      literal_area::unregister(&name);
+
+      let c = copy_to(t, &mut dst).unwrap();
+      assert_ne!(c, t);
+      assert_eq!(format!("{}", c), format!("{}", t));
+    }
+  }
+
+  #[test]
+  fn test_copy_deep_term() {
+    const DEPTH: usize = 1_000_000;
+    let mut src = Heap::new(Designation::TransientDestructible);
+    let mut dst = Heap::new(Designation::HeapFragment);
+    unsafe {
+      // A long list inside of deeply nested 1-tuples
+      let mut lb = ListBuilder::new().unwrap();
+      for i in 0..DEPTH {
+        lb.append(Term::make_small_unsigned(i), &mut src).unwrap();
+      }
+      let mut t = lb.make_term();
+      for _ in 0..DEPTH {
+        let tuple = Tuple::create_into(&mut src, 1).unwrap();
+        (*tuple).set_element(0, t);
+        t = Term::make_boxed(tuple);
+      }
+
+      let size = size_of(t);
+      let tuple_words = Tuple::storage_size(1).words;
+      assert_eq!(size.words, DEPTH * 2 + DEPTH * tuple_words);
+      let c = copy_to(t, &mut dst).unwrap();
+
+      // Formatting recurses, so the copy is checked in a loop
+      let mut c_t = c;
+      for _ in 0..DEPTH {
+        assert!(dst.belongs_to_heap(c_t.get_box_ptr::<Word>()));
+        c_t = (*c_t.get_tuple_ptr()).get_element(0);
+      }
+      for i in 0..DEPTH {
+        assert!(dst.belongs_to_heap(c_t.get_cons_ptr() as *const Word));
+        assert_eq!((*c_t.get_cons_ptr()).hd(), Term::make_small_unsigned(i));
+        c_t = (*c_t.get_cons_ptr()).tl();
+      }
+      assert_eq!(c_t, Term::nil());
+    }
+  }
 }
@@ -1,22 +1,29 @@
 use crate::{
-  defs::{Word, WordSize},
-  emulator::heap::{catch::NextCatchResult, heap_trait::THeap, iter, Designation},
+  defs::{Word, WordSize, WORD_BYTES},
+  emulator::heap::{
+    catch::NextCatchResult,
+    gc::{self, CopyingCollector, GcSettings},
+    heap_trait::THeap,
+    iter, Designation,
+  },
   fail::{RtErr, RtResult},
-  term::value::Term,
+  term::{boxed::binary::refc_bin::ReferenceToBinary, value::Term},
 };
 use colored::Colorize;
 use core::{fmt, ptr};
 
-/// Default heap size for constants (literals) when loading a module.
+/// Initial heap size for constants (literals) when loading a module.
 const DEFAULT_LIT_HEAP: usize = 8192;
 
-/// Default heap size when spawning a process. (default: 300)
-const DEFAULT_PROC_HEAP: usize = 16384;
-const BINARY_HEAP_CAPACITY: usize = 65536; // 64k*8 = 512kb
+/// Initial size of a heap fragment for a message.
+const DEFAULT_HEAP_FRAGMENT: usize = 64;
 
-/// A heap structure which grows upwards with allocations. Cannot expand
-/// implicitly and will return error when capacity is exceeded. Organize a
-/// garbage collect call to get more memory TODO: gc on heap
+/// A heap structure which grows upwards with allocations. A process heap
+/// cannot expand implicitly and will return error when capacity is exceeded.
+/// Organize a garbage collect call to get more memory (see `garbage_collect`),
+/// the heap grows or shrinks after the collection.
+/// Heaps which are never collected (literals, message fragments) grow by
+/// starting a new memory block, because their data can not be moved.
 pub struct FlatHeap {
   data: Vec<Word>,
   /// Heap top, begins at 0 and grows up towards the `stack_top`.
@@ -25,6 +32,51 @@ pub struct FlatHeap {
   stack_top: usize,
   /// Marks end of the stack and also end of the heap.
   capacity: usize,
+
+  // Generational GC
+  /// Data below this mark has survived a collection and will be promoted to
+  /// the old heap on the next minor collection.
+  high_water_mark: usize,
+  /// Old generation, only collected during a full sweep (major GC).
+  old_heap: Vec<Word>,
+  old_top: usize,
+  /// Count of minor collections since the last full sweep.
+  minor_gcs: usize,
+  settings: GcSettings,
+
+  /// Whether the heap can grow without a GC by starting a new block.
+  grows_in_place: bool,
+  /// Full memory blocks of a heap which grows in place, kept alive because
+  /// other data refers to them.
+  retired_blocks: Vec<Vec<Word>>,
+
+  // Off-heap binaries
+  /// Handles to the refcounted binaries allocated on this heap, the ones not
+  /// surviving the GC are released.
+  off_heap: Vec<*mut ReferenceToBinary>,
+  /// Total size of the binaries referred from `off_heap` (virtual binary
+  /// heap), the GC runs when it grows over the `bin_vheap_size`.
+  bin_vheap_words: usize,
+  bin_vheap_size: usize,
+}
+
+impl Drop for FlatHeap {
+  fn drop(&mut self) {
+    for refbin in self.off_heap.iter() {
+      unsafe { ReferenceToBinary::on_destroy(*refbin) }
+    }
+  }
+}
+
+/// Selects which areas are collected by `FlatHeap::collect_into`.
+#[derive(Debug, Copy, Clone)]
+enum GcMode {
+  /// Collect the young heap, promote data below the high-water mark.
+  Minor,
+  /// Collect both the young and the old heap into the new young heap.
+  Major,
+  /// Move the young heap into a larger or smaller memory block, no promotion.
+  Resize,
 }
 
 impl FlatHeap {}
@@ -45,13 +97,19 @@ impl THeap for FlatHeap {
     let pos = self.heap_top;
     let n_words = n.words;
     // Explicitly forbid expanding without a GC, fail if capacity is exceeded
-    if pos + n_words >= self.stack_top {
-      // return Err(Error::HeapIsFull);
-      panic!(
-        "Heap is full requested={} have={}",
-        n,
-        self.get_heap_available()
-      );
+    if pos + n_words > self.stack_top {
+      if self.grows_in_place {
+        self.grow_in_place(n_words);
+        return self.alloc(n, init_nil);
+      }
+      if cfg!(feature = "trace_gc") {
+        println!(
+          "Heap is full requested={} have={}",
+          n,
+          self.get_heap_available()
+        );
+      }
+      return Err(RtErr::HeapIsFull("heap::alloc"));
     }
 
     // Assume we can grow the data without reallocating
@@ -100,8 +158,19 @@ impl THeap for FlatHeap {
     iter::HeapIterator::new(begin, begin.offset(last))
   }
 
+  fn add_off_heap_binary(&mut self, refbin: *mut ReferenceToBinary) {
+    self.bin_vheap_words += unsafe { (*refbin).size.get_words_rounded_up().words };
+    self.off_heap.push(refbin);
+  }
+
   fn belongs_to_heap(&self, p: *const Word) -> bool {
-    p < self.get_heap_start_ptr() || p >= self.get_heap_top_ptr()
+    let old_begin = self.old_heap.as_ptr();
+    (p >= self.get_heap_start_ptr() && p < self.get_heap_top_ptr())
+      || (p >= old_begin && p < unsafe { old_begin.add(self.old_top) })
+      || self.retired_blocks.iter().any(|block| {
+        let begin = block.as_ptr();
+        p >= begin && p < unsafe { begin.add(block.len()) }
+      })
   }
 
   /// Set stack value (`index`th from stack top) to `val`.
@@ -139,7 +208,8 @@ impl THeap for FlatHeap {
 
   /// Express the intent to allocate `size` words on the heap, which may either
   /// include an attempt to GC, or incur a heap fragment allocation.
-  /// Does not immediately allocate.
+  /// Does not immediately allocate. The heap does not know the roots, on error
+  /// the owning process runs the GC (see `Process::ensure_heap`).
   fn allocate_intent(&mut self, size: WordSize, _live: usize) -> RtResult<()> {
     if self.heap_check_available(size) {
       return Ok(());
@@ -163,7 +233,9 @@ impl THeap for FlatHeap {
 
 
   /// Allocate stack cells without checking. Call `stack_have(n)` beforehand.
-  fn stack_alloc_unchecked(&mut self, need: WordSize, fill_nil: bool) {
+  /// The new cells are always cleared, because the GC scans the whole stack
+  /// and must not find any leftover garbage there.
+  fn stack_alloc_unchecked(&mut self, need: WordSize) {
     if need.words == 0 {
       return;
     }
@@ -173,11 +245,8 @@ impl THeap for FlatHeap {
     let raw_nil = Term::nil().raw();
     unsafe {
       let p = self.get_heap_begin_ptr_mut().add(self.stack_top);
-
-      if fill_nil {
-        for y in 0..need.words {
-          ptr::write(p.add(y), raw_nil)
-        }
+      for y in 0..need.words {
+        ptr::write(p.add(y), raw_nil)
       }
     }
   }
@@ -217,7 +286,7 @@ impl THeap for FlatHeap {
   /// Arg: new_stack_top - offset from the heap end
   fn drop_stack_words(&mut self, n_drop: usize) {
     println!("drop_stack_words {}", n_drop);
-    assert!(self.stack_top + n_drop < self.capacity);
+    assert!(self.stack_top + n_drop <= self.capacity);
     self.stack_top += n_drop;
   }
 
@@ -257,36 +326,105 @@ impl THeap for FlatHeap {
 impl FlatHeap {
   fn get_size_for(d: Designation) -> usize {
     match d {
-      Designation::ProcessHeap => DEFAULT_PROC_HEAP,
       Designation::ModuleLiterals => DEFAULT_LIT_HEAP,
-      Designation::BinaryHeap => BINARY_HEAP_CAPACITY,
       Designation::TransientDestructible => 1,
       Designation::ProgramArgumentsHeap => 512,
+      Designation::HeapFragment => DEFAULT_HEAP_FRAGMENT,
     }
   }
 
+  /// Create a heap which is never garbage collected and grows in place.
   pub fn new(designation: Designation) -> Self {
     let capacity = Self::get_size_for(designation);
+    Self::with_capacity(capacity, true, GcSettings::default())
+  }
+
+  /// Create a process heap with the given GC settings, initial size is the
+  /// `min_heap_size` rounded up to a heap size class.
+  pub fn new_process_heap(settings: GcSettings) -> Self {
+    let capacity = gc::heap_size_for(settings.min_heap_size);
+    Self::with_capacity(capacity, false, settings)
+  }
+
+  fn with_capacity(capacity: usize, grows_in_place: bool, settings: GcSettings) -> Self {
     assert!(capacity > 0);
     let mut h = Self {
       data: Vec::with_capacity(capacity),
       heap_top: 0,
       stack_top: capacity,
       capacity,
+      high_water_mark: 0,
+      old_heap: Vec::new(),
+      old_top: 0,
+      minor_gcs: 0,
+      settings,
+      grows_in_place,
+      retired_blocks: Vec::new(),
+      off_heap: Vec::new(),
+      bin_vheap_words: 0,
+      bin_vheap_size: settings.min_bin_vheap_size,
     };
     unsafe { h.data.set_len(capacity) };
     h
   }
 
+  /// For a heap which is never collected, put the current memory block aside
+  /// and start a new larger block with at least `need` words free.
+  fn grow_in_place(&mut self, need: usize) {
+    debug_assert!(self.stack_depth() == 0, "Can't grow in place with a stack");
+    let new_capacity = gc::heap_size_for(core::cmp::max(self.capacity * 2, need));
+    let mut new_data: Vec<Word> = Vec::with_capacity(new_capacity);
+    unsafe { new_data.set_len(new_capacity) };
+    let old_data = core::mem::replace(&mut self.data, new_data);
+    self.retired_blocks.push(old_data);
+    self.heap_top = 0;
+    self.stack_top = new_capacity;
+    self.capacity = new_capacity;
+  }
+
+  /// Memory blocks used by this heap as `(begin, end)` pointer pairs. Used to
+  /// find the data of a heap fragment when merging it into a process heap.
+  pub fn get_memory_ranges(&self) -> Vec<(*const Word, *const Word)> {
+    let mut ranges: Vec<(*const Word, *const Word)> = self
+      .retired_blocks
+      .iter()
+      .map(|block| (block.as_ptr(), unsafe { block.as_ptr().add(block.len()) }))
+      .collect();
+    ranges.push((self.get_heap_start_ptr(), self.get_heap_top_ptr()));
+    ranges
+  }
+
+  /// Size of all memory blocks of the heap, including the retired ones.
+  pub fn get_total_capacity(&self) -> usize {
+    let retired: usize = self.retired_blocks.iter().map(|block| block.len()).sum();
+    self.capacity + retired
+  }
+
   /// How many words do we have before it will require GC/growth.
   #[inline]
-  fn get_heap_max_capacity(&self) -> usize {
-    self.data.capacity()
+  pub fn get_heap_max_capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Size of the young and the old heap together, the stack included. This is
+  /// compared against the `max_heap_size` limit.
+  pub fn get_total_heap_size(&self) -> usize {
+    self.capacity + self.old_heap.len()
+  }
+
+  #[inline]
+  pub fn get_settings(&self) -> &GcSettings {
+    &self.settings
+  }
+
+  #[inline]
+  pub fn get_settings_mut(&mut self) -> &mut GcSettings {
+    &mut self.settings
   }
 
   /// Heap usage stat.
   #[inline]
-  fn get_heap_used_words(&self) -> usize {
+  pub fn get_heap_used_words(&self) -> usize {
     self.heap_top
   }
 
@@ -328,6 +466,28 @@ impl FlatHeap {
     self.get_heap_start_ptr().add(self.stack_top)
   }
 
+  /// Create an iterator for walking the old generation heap.
+  pub unsafe fn old_heap_iter(&self) -> iter::HeapIterator {
+    let begin = self.old_heap.as_ptr() as *const Term;
+    iter::HeapIterator::new(begin, begin.add(self.old_top))
+  }
+
+  /// Stack contents, from the stack top to the stack start.
+  pub fn get_stack(&self) -> &[Term] {
+    unsafe {
+      core::slice::from_raw_parts(
+        self.get_stack_top_ptr() as *const Term,
+        self.stack_depth(),
+      )
+    }
+  }
+
+  /// Handles to the off-heap binaries owned by this heap.
+  #[inline]
+  pub fn get_off_heap(&self) -> &[*mut ReferenceToBinary] {
+    &self.off_heap
+  }
+
   #[allow(dead_code)]
   pub fn stack_info(&self) {
     println!("Stack (s_top {}, s_end {})", self.stack_top, self.capacity)
@@ -338,4 +498,435 @@ impl FlatHeap {
   pub fn stack_have_y(&self, y: Word) -> bool {
     self.capacity - self.stack_top >= y + 1
   }
+
+  /// Copy live data reachable from the `roots` and the stack into a new memory
+  /// block, which will have at least `need` words free after the collection.
+  /// The roots are updated to point to the new locations.
+  /// Live data from the heap `fragments` is merged into the new heap, the
+  /// fragments are freed after the collection.
+  /// A minor collection is done if possible, a full sweep happens after
+  /// `fullsweep_after` minor collections or when the old heap is full.
+  pub fn garbage_collect(
+    &mut self,
+    need: WordSize,
+    roots: &mut [&mut [Term]],
+    fragments: Vec<FlatHeap>,
+  ) {
+    self.collect(need, roots, fragments, &[])
+  }
+
+  /// Full sweep which also copies all terms reachable from the roots in the
+  /// `literal_areas` into the heap. Used before the module owning these
+  /// literals is purged.
+  pub fn collect_literals(
+    &mut self,
+    literal_areas: &[(*const Word, *const Word)],
+    roots: &mut [&mut [Term]],
+    fragments: Vec<FlatHeap>,
+  ) {
+    self.collect(WordSize::new(0), roots, fragments, literal_areas)
+  }
+
+  /// Full sweep, then move the live data to a memory block which fits it
+  /// exactly. Used by `erlang:hibernate/3` when the stack is already empty.
+  pub fn compact(&mut self, roots: &mut [&mut [Term]], fragments: Vec<FlatHeap>) {
+    debug_assert_eq!(self.stack_depth(), 0, "Compacting a heap with stack");
+    // Force a full sweep, so that the old heap is merged too
+    self.minor_gcs = self.settings.fullsweep_after;
+    self.collect(WordSize::new(0), roots, fragments, &[]);
+    let live_size = self.heap_top;
+    self.collect_into(GcMode::Resize, live_size, roots, &[], &[]);
+  }
+
+  fn collect(
+    &mut self,
+    need: WordSize,
+    roots: &mut [&mut [Term]],
+    mut fragments: Vec<FlatHeap>,
+    literal_areas: &[(*const Word, *const Word)],
+  ) {
+    let frag_ranges: Vec<(*const Word, *const Word)> = fragments
+      .iter()
+      .flat_map(|f| f.get_memory_ranges())
+      .collect();
+    let frag_words: usize = fragments.iter().map(|f| f.get_total_capacity()).sum();
+    // Binary handles in the fragments will be moved to this heap, or released
+    for frag in fragments.iter_mut() {
+      self.off_heap.append(&mut frag.off_heap);
+    }
+
+    // In the worst case the whole literal areas are copied
+    let literal_words: usize = literal_areas
+      .iter()
+      .map(|(begin, end)| (*end as usize - *begin as usize) / WORD_BYTES)
+      .sum();
+
+    if !literal_areas.is_empty()
+      || self.minor_gcs >= self.settings.fullsweep_after
+      || !self.old_heap_can_promote()
+    {
+      self.major_collect(
+        need.words + frag_words + literal_words,
+        roots,
+        &frag_ranges,
+        literal_areas,
+      );
+    } else {
+      // The new young heap must fit everything above the high-water mark
+      let new_capacity = gc::heap_size_for(core::cmp::max(
+        self.capacity,
+        self.heap_top - self.high_water_mark
+          + self.stack_depth()
+          + need.words
+          + frag_words,
+      ));
+      self.collect_into(GcMode::Minor, new_capacity, roots, &frag_ranges, &[]);
+    }
+
+    // If the live data still takes most of the heap, grow it now so that the
+    // collections will not happen too often. If the heap is mostly empty,
+    // shrink it. This moves the data once more.
+    let used = self.heap_top + self.stack_depth() + need.words;
+    if used > self.capacity / 4 * 3 || used < self.capacity / 4 {
+      let new_capacity =
+        gc::heap_size_for(core::cmp::max(used * 2, self.settings.min_heap_size));
+      if new_capacity != self.capacity {
+        self.collect_into(GcMode::Resize, new_capacity, roots, &[], &[]);
+      }
+    }
+
+    // Let the virtual binary heap grow, so that the binaries which are still
+    // in use do not trigger the GC every time
+    self.bin_vheap_size =
+      core::cmp::max(self.settings.min_bin_vheap_size, self.bin_vheap_words * 2);
+    drop(fragments);
+  }
+
+  /// Check whether the binaries referred from this heap are large enough to
+  /// justify a garbage collection.
+  #[inline]
+  pub fn is_bin_vheap_full(&self) -> bool {
+    self.bin_vheap_words > self.bin_vheap_size
+  }
+
+  /// Full sweep: collect both the young and the old heap into a new young
+  /// heap, the old heap is freed.
+  fn major_collect(
+    &mut self,
+    need: usize,
+    roots: &mut [&mut [Term]],
+    frag_ranges: &[(*const Word, *const Word)],
+    literal_areas: &[(*const Word, *const Word)],
+  ) {
+    // In the worst case everything survives, so the new heap must fit all the
+    // old data, the stack and the requested amount.
+    let new_capacity = gc::heap_size_for(core::cmp::max(
+      self.capacity,
+      self.heap_top + self.old_top + self.stack_depth() + need,
+    ));
+    self.collect_into(
+      GcMode::Major,
+      new_capacity,
+      roots,
+      frag_ranges,
+      literal_areas,
+    );
+  }
+
+  /// Check whether the old heap has space for all data below the high-water
+  /// mark, create the old heap if it does not exist yet.
+  fn old_heap_can_promote(&mut self) -> bool {
+    if self.high_water_mark == 0 {
+      return true;
+    }
+    if self.old_heap.is_empty() {
+      let old_capacity =
+        gc::heap_size_for(core::cmp::max(self.capacity, self.high_water_mark));
+      self.old_heap = Vec::with_capacity(old_capacity);
+      unsafe { self.old_heap.set_len(old_capacity) };
+    }
+    self.old_top + self.high_water_mark <= self.old_heap.len()
+  }
+
+  /// Perform the copying collection into a new memory block of
+  /// `new_capacity` words, which then replaces the current young heap.
+  fn collect_into(
+    &mut self,
+    mode: GcMode,
+    new_capacity: usize,
+    roots: &mut [&mut [Term]],
+    frag_ranges: &[(*const Word, *const Word)],
+    literal_areas: &[(*const Word, *const Word)],
+  ) {
+    let stack_depth = self.stack_depth();
+    let mut new_data: Vec<Word> = Vec::with_capacity(new_capacity);
+    unsafe { new_data.set_len(new_capacity) };
+    let new_stack_top = new_capacity - stack_depth;
+
+    let literal_binaries = unsafe {
+      let from_begin = self.get_heap_start_ptr();
+      let to_space = new_data.as_mut_ptr();
+      let mut gc =
+        CopyingCollector::new(from_begin, from_begin.add(self.heap_top), to_space);
+      for (begin, end) in frag_ranges.iter() {
+        gc.add_from_range(*begin, *end);
+      }
+      for (begin, end) in literal_areas.iter() {
+        gc.add_literal_range(*begin, *end);
+      }
+      match mode {
+        GcMode::Minor => gc.set_promotion(
+          from_begin,
+          from_begin.add(self.high_water_mark),
+          self.old_heap.as_mut_ptr(),
+          self.old_top,
+        ),
+        GcMode::Major => {
+          let old_begin = self.old_heap.as_ptr();
+          gc.add_from_range(old_begin, old_begin.add(self.old_top))
+        }
+        GcMode::Resize => {}
+      }
+
+      // Move the stack to the end of the new memory, and update it in place
+      ptr::copy_nonoverlapping(
+        self.get_stack_top_ptr(),
+        to_space.add(new_stack_top),
+        stack_depth,
+      );
+      let new_stack = core::slice::from_raw_parts_mut(
+        to_space.add(new_stack_top) as *mut Term,
+        stack_depth,
+      );
+      gc.evacuate_slice(new_stack);
+
+      for r in roots.iter_mut() {
+        gc.evacuate_slice(r);
+      }
+      gc.scan();
+
+      // Update the moved binary handles, release the dead ones
+      let off_heap = core::mem::replace(&mut self.off_heap, Vec::new());
+      self.bin_vheap_words = 0;
+      for refbin in off_heap {
+        match gc.get_new_location(refbin as *mut Word) {
+          Some(new_p) => {
+            let new_refbin = new_p as *mut ReferenceToBinary;
+            self.bin_vheap_words += (*new_refbin).size.get_words_rounded_up().words;
+            self.off_heap.push(new_refbin);
+          }
+          None => ReferenceToBinary::on_destroy(refbin),
+        }
+      }
+
+      let (heap_top_before, old_top_before) = (self.heap_top, self.old_top);
+      self.heap_top = gc.get_to_top();
+
+      match mode {
+        GcMode::Minor => {
+          self.old_top = gc.get_old_top();
+          self.minor_gcs += 1;
+        }
+        GcMode::Major => {
+          self.old_heap = Vec::new();
+          self.old_top = 0;
+          self.minor_gcs = 0;
+        }
+        GcMode::Resize => {}
+      }
+
+      if cfg!(feature = "trace_gc") {
+        println!(
+          "{} {:?} {} -> {} words, old {} -> {} (capacity {} -> {})",
+          "GC:".yellow(),
+          mode,
+          heap_top_before,
+          self.heap_top,
+          old_top_before,
+          self.old_top,
+          self.capacity,
+          new_capacity
+        );
+      }
+
+      gc.take_literal_binaries()
+    };
+
+    // Everything in the young heap now has survived a collection
+    self.high_water_mark = self.heap_top;
+    self.data = new_data;
+    self.stack_top = new_stack_top;
+    self.capacity = new_capacity;
+
+    // Binary handles copied from the literals now belong to this heap
+    for refbin in literal_binaries {
+      unsafe { ReferenceToBinary::on_copied(refbin, self) };
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    emulator::heap::{allocate_cons, copy_term, verify::AllowedMemory},
+    term::{
+      boxed::Tuple,
+      term_builder::{list_builder::build_erlstr_from_utf8, tuple_builder::tuple2},
+      value::cons,
+    },
+  };
+
+  fn new_test_heap() -> FlatHeap {
+    let mut settings = GcSettings::default();
+    settings.min_heap_size = 16384;
+    FlatHeap::new_process_heap(settings)
+  }
+
+  #[test]
+  fn test_gc_keeps_roots_and_stack() {
+    let mut hp = new_test_heap();
+    let mut keep = Term::nil();
+    for i in 0..200 {
+      unsafe {
+        build_erlstr_from_utf8("garbage", &mut hp).unwrap();
+        let s = build_erlstr_from_utf8("live", &mut hp).unwrap();
+        let t = tuple2(&mut hp, Term::make_small_signed(i), s).unwrap();
+        if i % 10 == 0 {
+          keep = tuple2(&mut hp, t, keep).unwrap();
+        }
+      }
+    }
+    let before = format!("{}", keep);
+    let used_before = hp.get_heap_used_words();
+    // A stack frame with the CP and y0
+    hp.stack_alloc_unchecked(WordSize::new(2));
+    hp.set_y(0, keep).unwrap();
+
+    let mut roots = [keep, keep];
+    hp.garbage_collect(WordSize::new(100), &mut [&mut roots], Vec::new());
+    assert_eq!(before, format!("{}", roots[0]));
+    assert_eq!(roots[0], roots[1]);
+    assert_eq!(roots[0], hp.get_y(0).unwrap());
+    assert!(hp.belongs_to_heap(roots[0].get_tuple_ptr() as *const Word));
+    assert!(hp.get_heap_used_words() < used_before);
+    assert!(hp.heap_check_available(WordSize::new(100)));
+  }
+
+  #[test]
+  fn test_compact_fits_live_data() {
+    let mut hp = new_test_heap();
+    let mut roots = [Term::nil()];
+    for i in 0..50 {
+      unsafe {
+        build_erlstr_from_utf8("garbage", &mut hp).unwrap();
+        roots[0] = tuple2(&mut hp, Term::make_small_signed(i), roots[0]).unwrap();
+      }
+    }
+    let before = format!("{}", roots[0]);
+    // The old heap is merged too
+    hp.garbage_collect(WordSize::new(0), &mut [&mut roots], Vec::new());
+
+    hp.compact(&mut [&mut roots], Vec::new());
+    assert_eq!(before, format!("{}", roots[0]));
+    // 50 tuples of 2 elements with the header and the guard word
+    let live_words = 50 * Tuple::storage_size(2).words;
+    assert_eq!(hp.get_heap_max_capacity(), live_words);
+    assert_eq!(hp.get_heap_used_words(), live_words);
+
+    // The heap grows again when needed
+    hp.garbage_collect(WordSize::new(100), &mut [&mut roots], Vec::new());
+    assert_eq!(before, format!("{}", roots[0]));
+    assert!(hp.heap_check_available(WordSize::new(100)));
+  }
+
+  #[test]
+  fn test_full_heap_collects() {
+    let mut hp = FlatHeap::new_process_heap(GcSettings::default());
+    let mut roots = [Term::nil()];
+    for i in 0..10000 {
+      let cell = match allocate_cons(&mut hp) {
+        Ok(cell) => cell,
+        Err(RtErr::HeapIsFull(_)) => {
+          hp.garbage_collect(WordSize::new(2), &mut [&mut roots], Vec::new());
+          allocate_cons(&mut hp).unwrap()
+        }
+        Err(e) => panic!("{:?}", e),
+      };
+      unsafe {
+        (*cell).set_hd(Term::make_small_signed(i));
+        (*cell).set_tl(if i % 100 == 0 { Term::nil() } else { roots[0] });
+      }
+      roots[0] = Term::make_cons(cell);
+    }
+    assert_eq!(cons::list_length(roots[0]).unwrap(), 100);
+  }
+
+  #[test]
+  fn test_minor_gc_promotes_survivors() {
+    let mut hp = new_test_heap();
+    hp.settings.fullsweep_after = 2;
+    let mut roots = [unsafe { build_erlstr_from_utf8("survivor", &mut hp).unwrap() }];
+    let before = format!("{}", roots[0]);
+
+    // The first collection marks the data, the second promotes it
+    hp.garbage_collect(WordSize::new(0), &mut [&mut roots], Vec::new());
+    assert_eq!((hp.heap_top, hp.old_top), (16, 0));
+    hp.garbage_collect(WordSize::new(0), &mut [&mut roots], Vec::new());
+    assert_eq!((hp.heap_top, hp.old_top), (0, 16));
+    assert!(hp.belongs_to_heap(roots[0].get_cons_ptr() as *const Word));
+    assert_eq!(before, format!("{}", roots[0]));
+
+    // After `fullsweep_after` minor collections the old heap is collected too
+    hp.garbage_collect(WordSize::new(0), &mut [&mut roots], Vec::new());
+    assert_eq!((hp.heap_top, hp.old_top), (16, 0));
+    assert!(hp.old_heap.is_empty());
+    assert_eq!(before, format!("{}", roots[0]));
+  }
+
+  #[test]
+  fn test_heap_grows_and_shrinks() {
+    let mut hp = FlatHeap::new_process_heap(GcSettings::default());
+    let min_size = hp.get_heap_max_capacity();
+    let mut roots = [Term::nil()];
+    for i in 0..20000 {
+      if hp.allocate_intent(WordSize::new(2), 0).is_err() {
+        hp.garbage_collect(WordSize::new(2), &mut [&mut roots], Vec::new());
+      }
+      let cell = allocate_cons(&mut hp).unwrap();
+      unsafe {
+        (*cell).set_hd(Term::make_small_signed(i));
+        (*cell).set_tl(roots[0]);
+      }
+      roots[0] = Term::make_cons(cell);
+    }
+    assert_eq!(cons::list_length(roots[0]).unwrap(), 20000);
+    assert!(hp.get_total_heap_size() >= 40000);
+
+    // All data is garbage now, the heap shrinks back to the minimal size
+    roots[0] = Term::nil();
+    hp.garbage_collect(WordSize::new(0), &mut [&mut roots], Vec::new());
+    hp.garbage_collect(WordSize::new(0), &mut [&mut roots], Vec::new());
+    assert_eq!(hp.get_heap_max_capacity(), min_size);
+  }
+
+  #[test]
+  fn test_gc_merges_fragments() {
+    let mut hp = new_test_heap();
+    let mut sender = new_test_heap();
+    let mut roots = [Term::nil(); 2];
+    unsafe {
+      let msg = build_erlstr_from_utf8("message", &mut sender).unwrap();
+      let msg = tuple2(&mut sender, msg, Term::make_small_signed(1)).unwrap();
+      let mut fragment = FlatHeap::new(Designation::HeapFragment);
+      roots[0] = copy_term::copy_to(msg, &mut fragment).unwrap();
+      roots[1] = build_erlstr_from_utf8("local", &mut hp).unwrap();
+      let before = format!("{} {}", roots[0], roots[1]);
+
+      hp.garbage_collect(WordSize::new(0), &mut [&mut roots], vec![fragment]);
+      assert_eq!(before, format!("{} {}", roots[0], roots[1]));
+      assert!(hp.belongs_to_heap(roots[0].get_tuple_ptr() as *const Word));
+      assert!(hp.verify(&AllowedMemory::new(&[])).is_ok());
+    }
+  }
 }
@@ -0,0 +1,450 @@
+//! Copying garbage collector (Cheney's algorithm) for the process heaps.
+//! Live data is found starting from the root set (X registers, stack and the
+//! mailbox) and is copied into a new memory block, which then replaces the old
+//! heap. Heap fragments with the delivered messages are collected too, this
+//! merges them into the new heap. Objects which were moved have their first
+//! word replaced with a forwarding pointer, so that other references to them
+//! can be updated.
+//!
+//! The heaps are generational: objects which survived one collection in the
+//! young heap (below the high-water mark) are promoted to the old heap by the
+//! next minor collection. The old heap is only collected during a full sweep.
+//! Erlang terms are immutable once built, so old objects never point to the
+//! young ones and the old heap does not need to be scanned in a minor GC.
+//!
+//! Module literals are outside of the collected areas and are never moved.
+//! Before a module version is purged its literal areas are collected too: the
+//! terms found there are copied into the heap, while the literal area itself
+//! stays untouched because other processes might still refer to it.
+use crate::{
+  defs::Word,
+  emulator::{gen_atoms, heap::heap_trait::THeap},
+  fail::{self, RtResult},
+  term::{
+    boxed::{
+      self,
+      binary::{refc_bin::ReferenceToBinary, BinaryType},
+      BoxHeader, Map,
+    },
+    value::{PrimaryTag, Term},
+  },
+};
+use core::ptr;
+use std::collections::HashMap;
+
+/// How many minor collections can happen before a full sweep is forced.
+pub const DEFAULT_FULLSWEEP_AFTER: usize = 65535;
+
+/// Smallest process heap size in words (includes the stack).
+pub const DEFAULT_MIN_HEAP_SIZE: usize = 233;
+
+/// Smallest binary virtual heap size in words.
+pub const DEFAULT_MIN_BIN_VHEAP_SIZE: usize = 46422;
+
+/// Heap sizes grow as a Fibonacci sequence until this size, then by 20%.
+const FIBONACCI_HEAP_SIZE_LIMIT: usize = 1_000_000;
+
+/// Garbage collection settings of a process heap. Can be changed per process
+/// with the spawn options and globally with `erlang:system_flag/2`.
+#[derive(Debug, Clone, Copy)]
+pub struct GcSettings {
+  pub min_heap_size: usize,
+  pub min_bin_vheap_size: usize,
+  pub fullsweep_after: usize,
+  pub max_heap_size: MaxHeapSize,
+}
+
+impl GcSettings {
+  pub fn default() -> Self {
+    Self {
+      min_heap_size: DEFAULT_MIN_HEAP_SIZE,
+      min_bin_vheap_size: DEFAULT_MIN_BIN_VHEAP_SIZE,
+      fullsweep_after: DEFAULT_FULLSWEEP_AFTER,
+      max_heap_size: MaxHeapSize::default(),
+    }
+  }
+}
+
+/// Limit for the process heap size in words (young and old heap, stack and
+/// the message fragments), checked after each GC. Size 0 means no limit.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxHeapSize {
+  pub size: usize,
+  /// Whether the process is killed when the limit is exceeded.
+  pub kill: bool,
+  /// Whether an error report is printed when the limit is exceeded.
+  pub error_logger: bool,
+}
+
+impl MaxHeapSize {
+  pub fn default() -> Self {
+    Self {
+      size: 0,
+      kill: true,
+      error_logger: true,
+    }
+  }
+
+  /// Parse the `max_heap_size` option: either the size in words, or a map
+  /// with keys `size`, `kill` and `error_logger`.
+  pub fn from_term(val: Term) -> RtResult<Self> {
+    let mut result = Self::default();
+    if val.is_small() && val.get_small_signed() >= 0 {
+      result.size = val.get_small_unsigned();
+      return Ok(result);
+    }
+    if !val.is_map() {
+      return fail::create::badarg();
+    }
+    let map_p = val.get_box_ptr::<Map>();
+    if let Some(size) = unsafe { Map::get(map_p, gen_atoms::SIZE)? } {
+      if !size.is_small() || size.get_small_signed() < 0 {
+        return fail::create::badarg();
+      }
+      result.size = size.get_small_unsigned();
+    }
+    if let Some(kill) = unsafe { Map::get(map_p, gen_atoms::KILL)? } {
+      if !kill.is_bool() {
+        return fail::create::badarg();
+      }
+      result.kill = kill.is_true();
+    }
+    if let Some(error_logger) = unsafe { Map::get(map_p, gen_atoms::ERROR_LOGGER)? } {
+      if !error_logger.is_bool() {
+        return fail::create::badarg();
+      }
+      result.error_logger = error_logger.is_true();
+    }
+    Ok(result)
+  }
+
+  /// Create a map `#{size => _, kill => _, error_logger => _}`.
+  pub fn to_term(&self, hp: &mut THeap) -> RtResult<Term> {
+    let map_p = Map::create_into(hp, 3)?;
+    unsafe {
+      Map::add(map_p, gen_atoms::SIZE, Term::make_small_unsigned(self.size))?;
+      Map::add(map_p, gen_atoms::KILL, Term::make_bool(self.kill))?;
+      Map::add(
+        map_p,
+        gen_atoms::ERROR_LOGGER,
+        Term::make_bool(self.error_logger),
+      )?;
+    }
+    Ok(Term::make_boxed(map_p))
+  }
+
+  /// Check whether a heap of `words` exceeds the limit.
+  #[inline]
+  pub fn is_exceeded(&self, words: usize) -> bool {
+    self.size > 0 && words > self.size
+  }
+}
+
+/// Round `want` up to the nearest heap size class. Heap sizes follow the
+/// Fibonacci sequence (12, 38, 51, 90, 142, 233, ...) and after some limit
+/// each next size is 20% larger than the previous.
+pub fn heap_size_for(want: usize) -> usize {
+  let (mut prev, mut size) = (12usize, 38usize);
+  if want <= prev {
+    return prev;
+  }
+  while size < want {
+    let next = if size < FIBONACCI_HEAP_SIZE_LIMIT {
+      prev + size + 1
+    } else {
+      size + size / 5
+    };
+    prev = size;
+    size = next;
+  }
+  size
+}
+
+/// A memory block where the moved objects are placed.
+struct ToSpace {
+  begin: *mut Word,
+  /// Allocation position, also the end of the scanned area.
+  top: usize,
+  /// Scan position, everything below it has been already updated.
+  scan: usize,
+}
+
+impl ToSpace {
+  fn new(begin: *mut Word, top: usize) -> Self {
+    Self {
+      begin,
+      top,
+      scan: top,
+    }
+  }
+
+  /// Copy `n_words` from old location `src` to the top.
+  unsafe fn copy_words(&mut self, src: *const Word, n_words: usize) -> *mut Word {
+    let dst = self.begin.add(self.top);
+    ptr::copy_nonoverlapping(src, dst, n_words);
+    self.top += n_words;
+    dst
+  }
+}
+
+/// Garbage collection state. The collected areas (from-space) are given as
+/// ranges of words, everything outside of them is not touched (literals,
+/// other heaps, the old heap during a minor collection).
+pub struct CopyingCollector {
+  from: Vec<(*const Word, *const Word)>,
+  /// Objects in this range are promoted to the old heap (the young heap data
+  /// below the high-water mark), empty if there is no promotion.
+  promote: (*const Word, *const Word),
+  /// Memory where the live data is placed, must be large enough to hold
+  /// all data from the old heap.
+  young: ToSpace,
+  /// Old heap receiving the promoted objects, if any.
+  old: Option<ToSpace>,
+  /// Literal areas which are about to be freed, their objects are copied into
+  /// the new heap without modifying the area.
+  literals: Vec<(*const Word, *const Word)>,
+  /// Literal objects which were copied and their new location.
+  literal_copies: HashMap<*const Word, Term>,
+  /// Handles to refcounted binaries which were copied from the literals.
+  literal_binaries: Vec<*mut ReferenceToBinary>,
+}
+
+impl CopyingCollector {
+  pub fn new(
+    from_begin: *const Word,
+    from_end: *const Word,
+    to_space: *mut Word,
+  ) -> Self {
+    Self {
+      from: vec![(from_begin, from_end)],
+      promote: (ptr::null(), ptr::null()),
+      young: ToSpace::new(to_space, 0),
+      old: None,
+      literals: Vec::new(),
+      literal_copies: HashMap::new(),
+      literal_binaries: Vec::new(),
+    }
+  }
+
+  /// Also collect another memory range (the old heap during a full sweep, or
+  /// a heap fragment).
+  pub fn add_from_range(&mut self, begin: *const Word, end: *const Word) {
+    self.from.push((begin, end));
+  }
+
+  /// Also copy the live objects from a literal area into the new heap. The
+  /// new heap must be large enough to hold the whole area.
+  pub fn add_literal_range(&mut self, begin: *const Word, end: *const Word) {
+    self.literals.push((begin, end));
+  }
+
+  /// Give away the handles to refcounted binaries copied from the literals,
+  /// the new heap should take ownership of them.
+  pub fn take_literal_binaries(&mut self) -> Vec<*mut ReferenceToBinary> {
+    core::mem::replace(&mut self.literal_binaries, Vec::new())
+  }
+
+  /// Enable promotion: objects between `begin` and `end` will be moved into
+  /// the old heap `old_space` after its current top `old_top`. The old heap
+  /// must be large enough to hold all promoted data.
+  pub fn set_promotion(
+    &mut self,
+    begin: *const Word,
+    end: *const Word,
+    old_space: *mut Word,
+    old_top: usize,
+  ) {
+    self.promote = (begin, end);
+    self.old = Some(ToSpace::new(old_space, old_top));
+  }
+
+  /// Returns how many words were copied into the new heap.
+  #[inline]
+  pub fn get_to_top(&self) -> usize {
+    self.young.top
+  }
+
+  /// Returns the new old heap top after promotion (0 if promotion was off).
+  #[inline]
+  pub fn get_old_top(&self) -> usize {
+    match &self.old {
+      Some(old) => old.top,
+      None => 0,
+    }
+  }
+
+  #[inline]
+  fn in_from_space(&self, p: *const Word) -> bool {
+    self.from.iter().any(|(begin, end)| p >= *begin && p < *end)
+  }
+
+  #[inline]
+  fn in_literals(&self, p: *const Word) -> bool {
+    let in_range = |(begin, end): &(*const Word, *const Word)| p >= *begin && p < *end;
+    self.literals.iter().any(in_range)
+  }
+
+  /// Copy a literal cons cell or boxed object at `p` to the new young heap
+  /// (unless it was already copied). The literal area is not modified.
+  unsafe fn evacuate_literal(&mut self, t: Term, p: *const Word) -> Term {
+    if let Some(copy) = self.literal_copies.get(&p) {
+      return *copy;
+    }
+    let new_t = if t.is_cons() {
+      Term::make_cons(self.young.copy_words(p, 2))
+    } else {
+      let n_words = BoxHeader::headerword_to_storage_size(ptr::read(p));
+      let new_p = self.young.copy_words(p, n_words);
+      self.on_literal_boxed_copied(new_p as *mut BoxHeader);
+      Term::make_boxed(new_p)
+    };
+    self.literal_copies.insert(p, new_t);
+    new_t
+  }
+
+  /// A copied binary handle is another owner of the refcounted binary.
+  unsafe fn on_literal_boxed_copied(&mut self, header_p: *mut BoxHeader) {
+    if (*(*header_p).get_trait_ptr()).get_type() == boxed::BOXTYPETAG_BINARY {
+      let btrait = boxed::Binary::get_trait(header_p as *const boxed::Binary);
+      if let BinaryType::RefToBinaryHeap = (*btrait).get_type() {
+        let refbin = header_p as *mut ReferenceToBinary;
+        self.literal_binaries.push(refbin);
+      }
+    }
+  }
+
+  /// Copy `n_words` from old location `src` either to the old heap (if it
+  /// is being promoted) or to the new young heap.
+  unsafe fn copy_words(&mut self, src: *const Word, n_words: usize) -> *mut Word {
+    if src >= self.promote.0 && src < self.promote.1 {
+      if let Some(old) = &mut self.old {
+        return old.copy_words(src, n_words);
+      }
+    }
+    self.young.copy_words(src, n_words)
+  }
+
+  /// Given a term, if it points to the old heap, move the data it points to
+  /// into the new heap (unless it was already moved) and return the updated
+  /// term. Terms which do not point to the old heap are returned unchanged.
+  pub fn evacuate(&mut self, t: Term) -> Term {
+    match t.get_term_tag() {
+      PrimaryTag::CONS_PTR => unsafe { self.evacuate_cons(t) },
+      PrimaryTag::BOX_PTR => {
+        // CP values on stack and NON_VALUEs in the mailbox are not data
+        if t.is_cp() || t.is_non_value() {
+          return t;
+        }
+        unsafe { self.evacuate_boxed(t) }
+      }
+      _ => t,
+    }
+  }
+
+  /// Evacuate every term in a slice of roots and update them in place.
+  pub fn evacuate_slice(&mut self, roots: &mut [Term]) {
+    for r in roots.iter_mut() {
+      *r = self.evacuate(*r);
+    }
+  }
+
+  unsafe fn evacuate_cons(&mut self, t: Term) -> Term {
+    let p = t.get_cons_ptr() as *mut Word;
+    if !self.in_from_space(p) {
+      if self.in_literals(p) {
+        return self.evacuate_literal(t, p);
+      }
+      return t;
+    }
+    // A moved cons cell has NON_VALUE in its head and new location in its tail
+    if Term::from_raw(ptr::read(p)).is_non_value() {
+      return Term::from_raw(ptr::read(p.add(1)));
+    }
+    let new_p = self.copy_words(p, 2);
+    let new_t = Term::make_cons(new_p);
+    ptr::write(p, Term::non_value().raw());
+    ptr::write(p.add(1), new_t.raw());
+    new_t
+  }
+
+  unsafe fn evacuate_boxed(&mut self, t: Term) -> Term {
+    let p = t.get_box_ptr_unchecked_mut::<Word>();
+    if !self.in_from_space(p) {
+      if self.in_literals(p) {
+        return self.evacuate_literal(t, p);
+      }
+      return t;
+    }
+    // A moved box has its header word replaced with a box pointer to the new
+    // location, a valid header word can never have the BOX_PTR tag.
+    let header_word = ptr::read(p);
+    if Term::from_raw(header_word).is_boxed() {
+      return Term::from_raw(header_word);
+    }
+    let n_words = BoxHeader::headerword_to_storage_size(header_word);
+    let new_p = self.copy_words(p, n_words);
+    let new_t = Term::make_boxed(new_p);
+    ptr::write(p, new_t.raw());
+    new_t
+  }
+
+  /// After the collection, find where the boxed object at `p` was moved.
+  /// Returns `None` if the object was in the collected area and did not
+  /// survive, or `p` if it was not in the collected area.
+  pub unsafe fn get_new_location(&self, p: *mut Word) -> Option<*mut Word> {
+    if !self.in_from_space(p) {
+      return Some(p);
+    }
+    let header_word = Term::from_raw(ptr::read(p));
+    if header_word.is_boxed() {
+      return Some(header_word.get_box_ptr_unchecked_mut::<Word>());
+    }
+    None
+  }
+
+  /// Walk the copied objects updating all terms inside of them. Copying more
+  /// objects extends the scanned areas, the process stops when the scan
+  /// positions catch up with the tops of both the young and the old heap.
+  pub unsafe fn scan(&mut self) {
+    loop {
+      let young_done = self.young.scan == self.young.top;
+      let old_done = match &self.old {
+        Some(old) => old.scan == old.top,
+        None => true,
+      };
+      if young_done && old_done {
+        return;
+      }
+
+      let young = &mut self.young as *mut ToSpace;
+      self.scan_space(young);
+      if let Some(old) = &mut self.old {
+        let old = old as *mut ToSpace;
+        self.scan_space(old);
+      }
+    }
+  }
+
+  /// Scan one of the to-spaces until its scan position reaches its top.
+  unsafe fn scan_space(&mut self, space: *mut ToSpace) {
+    // The map function below needs its own access to self
+    let this = self as *mut Self;
+    while (*space).scan < (*space).top {
+      let p = (*space).begin.add((*space).scan);
+      let val = Term::from_raw(ptr::read(p));
+
+      if val.get_term_tag() == PrimaryTag::HEADER {
+        // A boxed object, step over it after updating its contents
+        let header_p = p as *mut BoxHeader;
+        let n_words = BoxHeader::headerword_to_storage_size(val.raw());
+        let trait_p = (*header_p).get_trait_ptr_mut();
+        (*trait_p).inplace_map(&mut |_owner, t| (*this).evacuate(t));
+        (*space).scan += n_words;
+      } else {
+        // A cons cell word
+        ptr::write(p, self.evacuate(val).raw());
+        (*space).scan += 1;
+      }
+    }
+  }
+}
@@ -3,7 +3,7 @@ use crate::{
   defs::{sizes::WordSize, Word},
   emulator::heap::{catch::NextCatchResult, iter},
   fail::RtResult,
-  term::value::Term,
+  term::{boxed::binary::refc_bin::ReferenceToBinary, value::Term},
 };
 
 /// Trait defines shared API which all heap implementations must expose
@@ -30,7 +30,7 @@ pub trait THeap {
 
   fn heap_check_available(&self, need: WordSize) -> bool;
   fn stack_check_available(&self, need: WordSize) -> bool;
-  fn stack_alloc_unchecked(&mut self, need: WordSize, fill_nil: bool);
+  fn stack_alloc_unchecked(&mut self, need: WordSize);
   fn stack_depth(&self) -> usize;
 
   /// Push a Term to stack without checking. Call `stack_have(1)` beforehand.
@@ -43,6 +43,10 @@ pub trait THeap {
 
   unsafe fn heap_iter(&self) -> iter::HeapIterator;
   fn belongs_to_heap(&self, p: *const Word) -> bool;
+
+  /// Remember a new handle to an off-heap binary, the heap will release it
+  /// when it is not used anymore.
+  fn add_off_heap_binary(&mut self, refbin: *mut ReferenceToBinary);
   //  fn get_heap_start_ptr(&self) -> *const Word;
   //  fn get_heap_top_ptr(&self) -> *const Word;
   //  fn get_heap_begin_ptr_mut(&mut self) -> *mut Word;
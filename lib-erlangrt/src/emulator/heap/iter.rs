@@ -5,8 +5,7 @@ use crate::term::{
 };
 use core::ptr;
 
-// This is used by heap walkers such as "dump.rs"
-#[allow(dead_code)]
+// This is used by heap walkers such as "dump.rs" and "verify.rs"
 pub struct HeapIterator {
   p: *const Term,
   end: *const Term,
@@ -17,7 +16,14 @@ impl HeapIterator {
     HeapIterator { p: begin, end }
   }
 
+  /// Returns the current position and steps forward. Boxed values are
+  /// returned once (the header address) and their contents are skipped.
   pub unsafe fn next(&mut self) -> Option<*const Term> {
+    if self.p >= self.end {
+      return None;
+    }
+    let result = self.p;
+
     // Peek inside *p to see if we're at a header, and if so - step over it
     // using header arity. Otherwise step by 1 cell
     let val = ptr::read(self.p);
@@ -25,14 +31,9 @@ impl HeapIterator {
       PrimaryTag::HEADER => boxed::BoxHeader::headerword_to_storage_size(val.raw()),
       _ => 1usize,
     };
+    // A zero size header would loop forever, step over it as a single word
+    self.p = self.p.add(core::cmp::max(size, 1));
 
-    self.p.add(size);
-
-    let end = self.end;
-    if self.p >= end {
-      return None;
-    }
-
-    Some(self.p)
+    Some(result)
   }
 }
@@ -0,0 +1,143 @@
+//! Module literals live in their own heaps (`Designation::ModuleLiterals`),
+//! memory of these heaps is registered here. Terms pointing to a literal area
+//! are not copied when sent to another process and are not moved by the GC,
+//! they stay valid until the module version is purged. During the purge the
+//! area is still allocated but no longer shared, the terms are copied again.
+use crate::{defs::Word, emulator::module::VersionedModuleName};
+use std::sync::{Arc, RwLock};
+
+#[derive(Clone)]
+struct LiteralArea {
+  owner: VersionedModuleName,
+  begin: usize,
+  end: usize,
+  /// The module version is being purged, new copies must not point here.
+  purged: bool,
+}
+
+lazy_static! {
+  /// Areas sorted by the address. The vector is replaced on every change, so
+  /// a snapshot can be used without holding the lock.
+  static ref LITERAL_AREAS: RwLock<Arc<Vec<LiteralArea>>> =
+    RwLock::new(Arc::new(Vec::new()));
+}
+
+/// Register memory `ranges` of the literal heap of module version `owner`.
+pub fn register(owner: &VersionedModuleName, ranges: &[(*const Word, *const Word)]) {
+  let mut areas = LITERAL_AREAS.write().unwrap();
+  let mut new_areas = Vec::clone(&areas);
+  for (begin, end) in ranges.iter() {
+    new_areas.push(LiteralArea {
+      owner: *owner,
+      begin: *begin as usize,
+      end: *end as usize,
+      purged: false,
+    });
+  }
+  new_areas.sort_by_key(|area| area.begin);
+  *areas = Arc::new(new_areas);
+}
+
+/// Stop sharing the literal areas of module version `owner`, the terms which
+/// are copied from now on get their own copies of the literals. The memory
+/// stays valid until `unregister`.
+pub fn mark_purged(owner: &VersionedModuleName) {
+  let mut areas = LITERAL_AREAS.write().unwrap();
+  let mut new_areas = Vec::clone(&areas);
+  for area in new_areas.iter_mut().filter(|area| area.owner == *owner) {
+    area.purged = true;
+  }
+  *areas = Arc::new(new_areas);
+}
+
+/// Forget the literal areas of module version `owner`, the memory is about to
+/// be freed.
+pub fn unregister(owner: &VersionedModuleName) {
+  let mut areas = LITERAL_AREAS.write().unwrap();
+  let mut new_areas = Vec::clone(&areas);
+  new_areas.retain(|area| area.owner != *owner);
+  *areas = Arc::new(new_areas);
+}
+
+/// Take a snapshot of the literal areas to check many pointers without taking
+/// the lock for each. Use it for one walk over the terms only, the module
+/// owning an area can be purged later.
+pub fn snapshot() -> LiteralRanges {
+  LiteralRanges(LITERAL_AREAS.read().unwrap().clone())
+}
+
+pub struct LiteralRanges(Arc<Vec<LiteralArea>>);
+
+impl LiteralRanges {
+  /// No literal areas, every term pointing to the data is copied.
+  pub fn empty() -> Self {
+    LiteralRanges(Arc::new(Vec::new()))
+  }
+
+  /// Check whether `p` points into a shared literal area of some loaded
+  /// module. Areas of the module versions being purged are not shared.
+  pub fn contains(&self, p: *const Word) -> bool {
+    match self.find(p) {
+      Some(area) => !area.purged,
+      None => false,
+    }
+  }
+
+  /// Check whether `p` points into any literal area, including the ones
+  /// being purged.
+  pub fn contains_any(&self, p: *const Word) -> bool {
+    self.find(p).is_some()
+  }
+
+  fn find(&self, p: *const Word) -> Option<&LiteralArea> {
+    let p = p as usize;
+    // The areas do not overlap, only the last one beginning before `p` can
+    // contain it
+    let i = self.0.partition_point(|area| area.begin <= p);
+    if i > 0 && p < self.0[i - 1].end {
+      Some(&self.0[i - 1])
+    } else {
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulator::atom;
+
+  #[test]
+  fn test_literal_ranges_lookup() {
+    let name = VersionedModuleName::new(atom::from_str("test_literal_ranges"), 1);
+    let mem = [0 as Word; 64];
+    let p = |i: usize| unsafe { mem.as_ptr().add(i) };
+    register(&name, &[(p(40), p(50)), (p(10), p(20))]);
+    let literals = snapshot();
+    unregister(&name);
+
+    assert!(literals.contains(p(10)));
+    assert!(literals.contains(p(19)));
+    assert!(!literals.contains(p(20)));
+    assert!(!literals.contains(p(30)));
+    assert!(literals.contains(p(45)));
+    assert!(!literals.contains(p(50)));
+    assert!(!literals.contains(p(9)));
+    // The areas are gone from the newer snapshots
+    assert!(!snapshot().contains(p(10)));
+  }
+
+  #[test]
+  fn test_purged_area_is_not_shared() {
+    let name = VersionedModuleName::new(atom::from_str("test_literal_purged"), 1);
+    let mem = [0 as Word; 16];
+    let p = |i: usize| unsafe { mem.as_ptr().add(i) };
+    register(&name, &[(p(0), p(16))]);
+    mark_purged(&name);
+    let literals = snapshot();
+    unregister(&name);
+
+    assert!(!literals.contains(p(8)));
+    assert!(literals.contains_any(p(8)));
+  }
+}
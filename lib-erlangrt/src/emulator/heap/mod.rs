@@ -2,8 +2,11 @@ pub mod catch;
 pub mod copy_term;
 pub mod dump;
 pub mod flat_heap;
+pub mod gc;
 pub mod heap_trait;
 pub mod iter;
+pub mod literal_area;
+pub mod verify;
 
 use crate::{
   defs::WordSize,
@@ -14,15 +17,16 @@ use crate::{
 
 pub type Heap = FlatHeap;
 
-/// Specifies the intended use of the heap
+/// Specifies the intended use of the heap. Process heaps are created with
+/// `FlatHeap::new_process_heap`.
 pub enum Designation {
-  ProcessHeap,
   ModuleLiterals,
-  BinaryHeap,
   // Used to store command line args on startup
   ProgramArgumentsHeap,
   // Heap of smallest size to be destroyed after it is swapped with the real one
   TransientDestructible,
+  // Holds a message copied from another process until the receiver's next GC
+  HeapFragment,
 }
 
 /// Allocate 2 cells `[Head | Tail]` of raw cons cell, and return the pointer.
@@ -0,0 +1,298 @@
+//! Debug tool to verify the heap integrity. Heap corruption usually shows up
+//! much later as an unrelated crash, the verifier walks the heap and checks
+//! that every pointer lands inside the heap, a literal area or other allowed
+//! memory (the message fragments) and that the boxed values are well formed.
+//!
+//! The verification is switched on at runtime with `set_verify_points` or
+//! `erlang:system_flag(verify_heap, [timeslice | gc | message])`.
+use crate::{
+  defs::Word,
+  emulator::heap::{
+    heap_trait::THeap,
+    iter::HeapIterator,
+    literal_area::{self, LiteralRanges},
+    Heap,
+  },
+  term::{
+    boxed::BoxHeader,
+    value::{PrimaryTag, Term},
+  },
+};
+use core::{
+  fmt, ptr,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Verify the heap of a process at the end of each timeslice.
+pub const VERIFY_AFTER_TIMESLICE: usize = 1;
+/// Verify the heap of a process after each garbage collection.
+pub const VERIFY_AFTER_GC: usize = 2;
+/// Verify the heap of a process after a message is delivered to it.
+pub const VERIFY_AFTER_MESSAGE: usize = 4;
+
+static VERIFY_POINTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Select when the heaps are verified (a combination of `VERIFY_AFTER_*`
+/// bits), returns the previous selection.
+pub fn set_verify_points(points: usize) -> usize {
+  VERIFY_POINTS.swap(points, Ordering::Relaxed)
+}
+
+#[inline]
+pub fn get_verify_points() -> usize {
+  VERIFY_POINTS.load(Ordering::Relaxed)
+}
+
+#[inline]
+pub fn is_enabled(point: usize) -> bool {
+  VERIFY_POINTS.load(Ordering::Relaxed) & point != 0
+}
+
+/// A word which has failed the verification.
+pub struct BadWord {
+  pub addr: *const Word,
+  pub value: Word,
+  pub reason: &'static str,
+}
+
+impl fmt::Display for BadWord {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "bad word 0x{:x} at {:p}: {}",
+      self.value, self.addr, self.reason
+    )
+  }
+}
+
+type VerifyResult = Result<(), BadWord>;
+
+/// Memory outside of the heap where the terms are allowed to point: the
+/// literal areas and `extra_ranges` (the message fragments).
+pub struct AllowedMemory<'a> {
+  literals: LiteralRanges,
+  extra_ranges: &'a [(*const Word, *const Word)],
+}
+
+impl<'a> AllowedMemory<'a> {
+  pub fn new(extra_ranges: &'a [(*const Word, *const Word)]) -> Self {
+    Self {
+      literals: literal_area::snapshot(),
+      extra_ranges,
+    }
+  }
+
+  fn contains(&self, p: *const Word) -> bool {
+    self.literals.contains_any(p)
+      || self
+        .extra_ranges
+        .iter()
+        .any(|(begin, end)| p >= *begin && p < *end)
+  }
+}
+
+fn bad_word(addr: *const Word, value: Word, reason: &'static str) -> VerifyResult {
+  Err(BadWord {
+    addr,
+    value,
+    reason,
+  })
+}
+
+impl Heap {
+  /// Check the heap data, the stack and the off-heap binary handles. Pointers
+  /// must point to this heap or the `allowed` memory.
+  pub unsafe fn verify(&self, allowed: &AllowedMemory) -> VerifyResult {
+    self.verify_walk(self.heap_iter(), allowed)?;
+    self.verify_walk(self.old_heap_iter(), allowed)?;
+
+    // Stack holds terms and CPs
+    for y in self.get_stack() {
+      let addr = y as *const Term as *const Word;
+      if !y.is_cp() {
+        self.verify_term(addr, *y, allowed)?;
+      }
+    }
+
+    for refbin in self.get_off_heap() {
+      let addr = *refbin as *const Word;
+      if !self.belongs_to_heap(addr) {
+        return bad_word(addr, addr as Word, "off-heap binary handle outside heap");
+      }
+      let bin = (**refbin).pointer;
+      if bin.is_null() || (*bin).get_refcount() == 0 {
+        return bad_word(addr, bin as Word, "off-heap binary already freed");
+      }
+    }
+    Ok(())
+  }
+
+  /// Check the heap data in every memory block of a heap which is not
+  /// collected (a message fragment).
+  pub unsafe fn verify_fragment(&self) -> VerifyResult {
+    for (begin, end) in self.get_memory_ranges() {
+      let walk = HeapIterator::new(begin as *const Term, end as *const Term);
+      self.verify_walk(walk, &AllowedMemory::new(&[]))?;
+    }
+    Ok(())
+  }
+
+  unsafe fn verify_walk(
+    &self,
+    mut walk: HeapIterator,
+    allowed: &AllowedMemory,
+  ) -> VerifyResult {
+    while let Some(term_p) = walk.next() {
+      let addr = term_p as *const Word;
+      let val = ptr::read(term_p);
+      if val.get_term_tag() == PrimaryTag::HEADER {
+        self.verify_boxed(addr, allowed)?;
+      } else {
+        self.verify_term(addr, val, allowed)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Check a box header at `addr` and the terms stored inside the box.
+  unsafe fn verify_boxed(
+    &self,
+    addr: *const Word,
+    allowed: &AllowedMemory,
+  ) -> VerifyResult {
+    let header_word = ptr::read(addr);
+    let size = BoxHeader::headerword_to_storage_size(header_word);
+    if size < BoxHeader::storage_size().words {
+      return bad_word(addr, header_word, "box size is smaller than box header");
+    }
+    if !self.belongs_to_heap(addr.add(size - 1)) {
+      return bad_word(addr, header_word, "box ends outside heap");
+    }
+    #[cfg(debug_assertions)]
+    {
+      let guard = ptr::read(addr.add(1));
+      if guard != crate::term::boxed::box_header::GUARD_WORD_VALUE {
+        return bad_word(addr.add(1), guard, "box guard word is damaged");
+      }
+    }
+
+    // The map function writes back the same values, the data does not change
+    let mut result = Ok(());
+    let header_p = addr as *mut BoxHeader;
+    let trait_p = (*header_p).get_trait_ptr_mut();
+    (*trait_p).inplace_map(&mut |_owner, t| {
+      if result.is_ok() {
+        result = self.verify_term(addr, t, allowed);
+      }
+      t
+    });
+    result
+  }
+
+  /// Check a term which is stored at `addr`, the term must not be a header
+  /// and its pointer must land in the allowed memory.
+  unsafe fn verify_term(
+    &self,
+    addr: *const Word,
+    t: Term,
+    allowed: &AllowedMemory,
+  ) -> VerifyResult {
+    match t.get_term_tag() {
+      PrimaryTag::HEADER => bad_word(addr, t.raw(), "header in place of a term"),
+      PrimaryTag::CONS_PTR => {
+        let p = t.get_cons_ptr() as *const Word;
+        if !self.is_allowed_pointer(p, allowed) {
+          return bad_word(addr, t.raw(), "cons pointer outside heap");
+        }
+        if Term::from_raw(ptr::read(p)).get_term_tag() == PrimaryTag::HEADER {
+          return bad_word(addr, t.raw(), "cons pointer points to a box header");
+        }
+        Ok(())
+      }
+      PrimaryTag::BOX_PTR if !t.is_cp() && !t.is_non_value() => {
+        // Debug build checks the guard word here, do not use `get_box_ptr`
+        let p = t.get_box_ptr_unchecked::<Word>();
+        if !self.is_allowed_pointer(p, allowed) {
+          return bad_word(addr, t.raw(), "box pointer outside heap");
+        }
+        if Term::from_raw(ptr::read(p)).get_term_tag() != PrimaryTag::HEADER {
+          return bad_word(addr, t.raw(), "box pointer does not point to a header");
+        }
+        Ok(())
+      }
+      _ => Ok(()),
+    }
+  }
+
+  fn is_allowed_pointer(&self, p: *const Word, allowed: &AllowedMemory) -> bool {
+    self.belongs_to_heap(p) || allowed.contains(p)
+  }
+}
+
+/// Check a root term (a register or a message) which is not stored on a heap.
+pub unsafe fn verify_root(hp: &Heap, t: &Term, allowed: &AllowedMemory) -> VerifyResult {
+  let addr = t as *const Term as *const Word;
+  hp.verify_term(addr, *t, allowed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    defs::WordSize,
+    emulator::{
+      heap::{gc::GcSettings, Designation},
+      spawn_options::SpawnOptions,
+      test_util,
+    },
+    term::term_builder::{list_builder::build_erlstr_from_utf8, tuple_builder::tuple2},
+  };
+
+  #[test]
+  fn test_verify_finds_bad_pointer() {
+    let mut hp = Heap::new_process_heap(GcSettings::default());
+    let mut keep = Term::nil();
+    for i in 0..10 {
+      let s = unsafe { build_erlstr_from_utf8("abc", &mut hp).unwrap() };
+      keep = tuple2(&mut hp, s, keep).unwrap();
+      keep = tuple2(&mut hp, Term::make_small_signed(i), keep).unwrap();
+    }
+    hp.stack_alloc_unchecked(WordSize::new(2));
+    hp.set_y(0, keep).unwrap();
+    unsafe {
+      assert!(hp.verify(&AllowedMemory::new(&[])).is_ok());
+
+      // A term on some other heap
+      let mut other = Heap::new(Designation::TransientDestructible);
+      let outside = tuple2(&mut other, Term::nil(), Term::nil()).unwrap();
+      hp.set_y(0, outside).unwrap();
+      let bad = hp.verify(&AllowedMemory::new(&[])).err().unwrap();
+      assert_eq!(bad.reason, "box pointer outside heap");
+      // Unless the other memory is allowed
+      let ranges = other.get_memory_ranges();
+      assert!(hp.verify(&AllowedMemory::new(&ranges)).is_ok());
+    }
+  }
+
+  #[test]
+  fn test_stale_registers_are_not_verified() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_verify_stale_regs");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+
+    // A register which is not live anymore points to a freed heap
+    let mut freed = Heap::new(Designation::TransientDestructible);
+    let stale = tuple2(&mut freed, Term::nil(), Term::nil()).unwrap();
+    proc.context.set_x(0, stale);
+    drop(freed);
+    proc.context.live = 1;
+
+    let old = set_verify_points(get_verify_points() | VERIFY_AFTER_MESSAGE);
+    let msg = tuple2(proc.get_heap_mut(), pid, Term::nil()).unwrap();
+    proc.deliver_message(msg).unwrap();
+    proc.handle_signals(&vm);
+    set_verify_points(old);
+    assert_eq!(proc.mailbox.get_messages().len(), 1);
+  }
+}
@@ -1,19 +1,53 @@
-use crate::term::value::*;
+use crate::{
+  emulator::{heap::Heap, spawn_options::MessageQueueLocation},
+  term::value::*,
+};
+use core::mem;
 
 pub struct ProcessMailbox {
   inbox: Vec<Term>,
+  /// For `OffHeap` message queue: the heap fragment of each queued message,
+  /// `None` if the message is immediate or its fragment is already merged.
+  inbox_fragments: Vec<Option<Heap>>,
   // TODO: Some structure on proc heap?
   read_index: usize,
+  /// Heap fragments where the delivered messages were copied. Received
+  /// messages still point there, so the fragments live until the next GC
+  /// which merges them into the process heap.
+  fragments: Vec<Heap>,
+  /// Sum of the fragment sizes, used to decide when to run the GC.
+  fragments_words: usize,
+  location: MessageQueueLocation,
 }
 
 impl ProcessMailbox {
-  pub fn new() -> Self {
+  pub fn new(location: MessageQueueLocation) -> Self {
     Self {
       inbox: Vec::with_capacity(32),
+      inbox_fragments: Vec::with_capacity(32),
       read_index: 0,
+      fragments: Vec::new(),
+      fragments_words: 0,
+      location,
     }
   }
 
+  #[inline]
+  pub fn get_location(&self) -> MessageQueueLocation {
+    self.location
+  }
+
+  /// Change where the queued messages are stored. Switching to `OnHeap`
+  /// schedules the fragments of all queued messages for merging.
+  pub fn set_location(&mut self, location: MessageQueueLocation) {
+    if location == MessageQueueLocation::OnHeap {
+      for i in 0..self.inbox_fragments.len() {
+        self.merge_fragment_of(i);
+      }
+    }
+    self.location = location;
+  }
+
   #[inline]
   pub fn have_unread_messages(&self) -> bool {
     self.read_index < self.inbox.len()
@@ -24,10 +58,83 @@ impl ProcessMailbox {
 //    self.inbox.is_empty()
 //  }
 
-  /// Copy a message and put into process mailbox.
-  /// Assumes: the message is already copied to receiving process heap.
-  pub fn put(&mut self, message: Term) {
+  /// Access all messages for update (received ones are NON_VALUEs), the
+  /// mailbox is a part of the GC root set. Messages which are stored
+  /// off-heap do not point to the heap and are not changed by the GC.
+  pub fn get_messages_mut(&mut self) -> &mut [Term] {
+    &mut self.inbox
+  }
+
+  /// Read access to all messages (received ones are NON_VALUEs).
+  pub fn get_messages(&self) -> &[Term] {
+    &self.inbox
+  }
+
+  /// All heap fragments owned by the mailbox, both the ones waiting to be
+  /// merged and the ones of the messages stored off-heap.
+  pub fn iter_fragments(&self) -> impl Iterator<Item = &Heap> {
+    let queued = self.inbox_fragments.iter().filter_map(|f| f.as_ref());
+    self.fragments.iter().chain(queued)
+  }
+
+  /// Put a message into process mailbox.
+  /// Assumes: the message is already copied to a heap fragment, which is then
+  /// owned by the mailbox (or is an immediate value and needs no fragment).
+  /// With an `OffHeap` queue the fragment stays with the message until it is
+  /// received, otherwise it will be merged into the heap on the next GC.
+  pub fn put(&mut self, message: Term, fragment: Option<Heap>) {
     self.inbox.push(message);
+    self.inbox_fragments.push(fragment);
+    if self.location == MessageQueueLocation::OnHeap {
+      self.merge_fragment_of(self.inbox.len() - 1);
+    }
+  }
+
+  /// Move the fragment of the message at `index` to the list of fragments to
+  /// be merged into the process heap.
+  fn merge_fragment_of(&mut self, index: usize) {
+    if let Some(frag) = self.inbox_fragments[index].take() {
+      self.fragments_words += frag.get_total_capacity();
+      self.fragments.push(frag);
+    }
+  }
+
+  /// How many words are taken by the heap fragments waiting to be merged.
+  #[inline]
+  pub fn get_fragments_words(&self) -> usize {
+    self.fragments_words
+  }
+
+  /// Give away the heap fragments to be merged into the process heap by the
+  /// garbage collector.
+  pub fn take_fragments(&mut self) -> Vec<Heap> {
+    self.fragments_words = 0;
+    mem::replace(&mut self.fragments, Vec::new())
+  }
+
+  /// Give away the heap fragments of all messages, including the ones which
+  /// are stored off-heap, to be merged into the process heap.
+  pub fn take_all_fragments(&mut self) -> Vec<Heap> {
+    for i in 0..self.inbox_fragments.len() {
+      self.merge_fragment_of(i);
+    }
+    self.take_fragments()
+  }
+
+  /// Remove the queued messages for which `f` returns true, as if they were
+  /// received and dropped.
+  pub fn remove_matching<F>(&mut self, f: F)
+  where
+    F: Fn(Term) -> bool,
+  {
+    for i in 0..self.inbox.len() {
+      let val = self.inbox[i];
+      if val.is_value() && f(val) {
+        self.inbox[i] = Term::non_value();
+        self.merge_fragment_of(i);
+      }
+    }
+    self.step_over();
   }
 
   /// Read message at the current receive pointer.
@@ -44,6 +151,12 @@ impl ProcessMailbox {
     Some(val)
   }
 
+  /// Start reading the messages from the oldest one again.
+  pub fn reset_read_position(&mut self) {
+    self.read_index = 0;
+    self.step_over();
+  }
+
   // TODO: This is ugly, do proper mailbox algorithm impl here
   pub fn step_over(&mut self) {
     // Guard
@@ -67,6 +180,7 @@ impl ProcessMailbox {
       if mri == starting_pos {
         // Done a full loop around mailbox and all values were non-values
         self.inbox.clear();
+        self.inbox_fragments.clear();
         self.read_index = 0;
         return;
       }
@@ -80,7 +194,55 @@ impl ProcessMailbox {
     let mri = self.read_index;
     let val = self.inbox[mri];
     self.inbox[mri] = Term::non_value();
+    // The received message will be a part of the process heap after next GC
+    self.merge_fragment_of(mri);
     self.step_over();
     val
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    emulator::heap::{copy_term, Designation},
+    term::term_builder::tuple_builder::tuple2,
+  };
+
+  /// Put `n` messages `{I, []}`, each in its own heap fragment.
+  fn put_messages(mailbox: &mut ProcessMailbox, n: isize) {
+    let mut sender = Heap::new(Designation::TransientDestructible);
+    for i in 0..n {
+      let msg = tuple2(&mut sender, Term::make_small_signed(i), Term::nil()).unwrap();
+      let mut fragment = Heap::new(Designation::HeapFragment);
+      let msg = copy_term::copy_to(msg, &mut fragment).unwrap();
+      mailbox.put(msg, Some(fragment));
+    }
+  }
+
+  #[test]
+  fn test_off_heap_fragments_stay_until_received() {
+    let mut mailbox = ProcessMailbox::new(MessageQueueLocation::OffHeap);
+    put_messages(&mut mailbox, 3);
+    assert_eq!(mailbox.get_fragments_words(), 0);
+
+    // A received message becomes a part of the process heap on the next GC
+    let msg = mailbox.remove_current();
+    assert_eq!(format!("{}", msg), "{0, []}");
+    assert!(mailbox.get_fragments_words() > 0);
+    assert_eq!(mailbox.take_fragments().len(), 1);
+
+    // Switching to on-heap merges the remaining fragments too
+    assert_eq!(format!("{}", mailbox.get_current().unwrap()), "{1, []}");
+    mailbox.set_location(MessageQueueLocation::OnHeap);
+    assert_eq!(mailbox.take_fragments().len(), 2);
+  }
+
+  #[test]
+  fn test_on_heap_fragments_are_merged() {
+    let mut mailbox = ProcessMailbox::new(MessageQueueLocation::OnHeap);
+    put_messages(&mut mailbox, 2);
+    assert_eq!(mailbox.take_fragments().len(), 2);
+    assert_eq!(mailbox.get_messages().len(), 2);
+  }
+}
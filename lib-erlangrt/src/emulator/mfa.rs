@@ -29,6 +29,14 @@ impl<'a> ModFunArgs<'a> {
     }
   }
 
+  pub fn with_args_slice(m: Term, f: Term, args: &'a [Term]) -> ModFunArgs<'a> {
+    ModFunArgs {
+      m,
+      f,
+      args: Args::Slice(args),
+    }
+  }
+
   pub fn get_mfarity(&self) -> RtResult<ModFunArity> {
     Ok(ModFunArity {
       m: self.m,
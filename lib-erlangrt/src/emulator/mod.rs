@@ -2,7 +2,10 @@ pub mod arith;
 pub mod atom;
 pub mod code;
 pub mod code_srv;
+pub mod deterministic;
+pub mod dirty_scheduler;
 pub mod disasm;
+pub mod error_report;
 pub mod export;
 pub mod funarity;
 pub mod function;
@@ -12,9 +15,15 @@ pub mod mailbox;
 pub mod mfa;
 pub mod module;
 pub mod process;
+pub mod process_dict;
 pub mod process_flags;
 pub mod process_registry;
 pub mod runtime_ctx;
 pub mod scheduler;
+pub mod signal;
 pub mod spawn_options;
+pub mod statistics;
+#[cfg(test)]
+pub mod test_util;
+pub mod timer;
 pub mod vm;
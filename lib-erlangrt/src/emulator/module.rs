@@ -7,7 +7,7 @@ use crate::{
     funarity::FunArity,
     function::FunEntry,
     gen_atoms,
-    heap::Heap,
+    heap::{literal_area, Heap},
     mfa::ModFunArity,
   },
   fail::{RtErr, RtResult},
@@ -48,6 +48,13 @@ pub struct Module {
   pub lit_heap: Heap, // set by module loader
 }
 
+impl Drop for Module {
+  fn drop(&mut self) {
+    // The literal heap is freed, no term can point there anymore
+    literal_area::unregister(&self.versioned_name);
+  }
+}
+
 impl Module {
   /// Create an empty module wrapped in atomic refcounted refcell.
   pub fn new(name: &VersionedModuleName) -> Module {
@@ -60,6 +67,13 @@ impl Module {
     }
   }
 
+  /// Mark the literal heap memory as literals, so that the terms pointing
+  /// there will not be copied between the processes. Called when the module
+  /// has been loaded.
+  pub fn register_literals(&self) {
+    literal_area::register(&self.versioned_name, &self.lit_heap.get_memory_ranges());
+  }
+
   /// Get module name field
   pub fn name(&self) -> Term {
     self.versioned_name.module
@@ -108,7 +122,7 @@ impl Module {
 
     let code_begin = &self.code[0] as *const Word;
     let ip_ptr = ip.get_pointer();
-    assert!(ip_ptr > code_begin);
+    assert!(ip_ptr >= code_begin);
     let ip_offset = (ip_ptr as usize - code_begin as usize) / WORD_BYTES;
 
     for (key, export_offset) in &self.funs {
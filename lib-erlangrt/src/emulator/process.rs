@@ -2,23 +2,59 @@
 //! heap, stack, registers, and message queue.
 
 use crate::{
-  defs::exc_type::ExceptionType,
+  defs::{exc_type::ExceptionType, Reductions, Word, WordSize},
   emulator::{
     code_srv::CodeServer,
-    heap::{copy_term, Designation, Heap},
+    dirty_scheduler::DirtyCall,
+    error_report, gen_atoms,
+    heap::{
+      copy_term,
+      gc::GcSettings,
+      verify::{self, BadWord},
+      Designation, Heap,
+    },
     mailbox::ProcessMailbox,
     mfa::{ModFunArgs, ModFunArity},
-    process_flags::ProcessFlags,
-    process_registry::ProcessRegistry,
+    process_dict::ProcessDict,
+    process_flags::{self, FlagSetting, ProcessFlags},
     runtime_ctx,
     scheduler::{self, Scheduler},
+    signal::Signal,
     spawn_options::SpawnOptions,
+    timer::TimerId,
+    vm::VM,
+  },
+  fail::{RtErr, RtResult},
+  term::{
+    boxed,
+    term_builder::tuple_builder::{tuple2, tuple3, TupleBuilder},
+    value::*,
   },
-  fail::RtResult,
-  term::value::*,
 };
-use core::ptr;
+use core::{mem, ptr};
 use crate::emulator::heap::heap_trait::THeap;
+use std::{
+  collections::{HashMap, HashSet},
+  sync::{
+    atomic::{AtomicPtr, AtomicUsize, Ordering},
+    Mutex, MutexGuard,
+  },
+};
+
+fn module() -> &'static str {
+  "process: "
+}
+
+/// What an exit signal does to the receiving process.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ExitAction {
+  /// A normal exit from another process, the receiver does not trap exits
+  Ignore,
+  /// The receiver traps exits, it gets an `{'EXIT', From, Reason}` message
+  Trap,
+  /// The receiver dies with the reason
+  Die(Term),
+}
 
 //#[allow(dead_code)]
 //#[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -41,24 +77,71 @@ use crate::emulator::heap::heap_trait::THeap;
 //  }
 //}
 
-pub struct Process {
-  pub pid: Term,
-
-  // Scheduling and fail state
+/// Scheduling state of a process. Other threads change it too (suspend,
+/// resume, wakeup), always while holding the queues lock of the owning
+/// scheduler, which is taken before this lock.
+pub struct SchedState {
   /// Scheduling priority (selects the runqueue when this process is scheduled)
   pub prio: scheduler::Prio,
-
   /// Current scheduler queue where this process is registered
   pub current_queue: scheduler::Queue,
-  pub owned_by_scheduler: *mut Scheduler,
+  /// Processes which have suspended this process, with their suspend counts.
+  pub suspended_by: Vec<(Term, usize)>,
+}
+
+impl SchedState {
+  /// Whether `erlang:suspend_process` was called more times than
+  /// `erlang:resume_process`.
+  #[inline]
+  pub fn is_suspended(&self) -> bool {
+    !self.suspended_by.is_empty()
+  }
+}
+
+pub struct Process {
+  pub pid: Term,
+
+  // Scheduling and fail state
+  sched_state: Mutex<SchedState>,
+  /// Scheduler which runs the process, changes when the process is stolen by
+  /// another scheduler thread.
+  owned_by_scheduler: AtomicPtr<Scheduler>,
+  /// Signals sent by other processes and timers, handled by the scheduler
+  /// thread which runs the process.
+  signals: Mutex<Vec<Signal>>,
+  /// Timer of the `receive ... after` in progress, it keeps running when the
+  /// process wakes up to check new messages.
+  pub receive_timer: Option<TimerId>,
+  /// Processes linked to this process, changed by the thread which runs it.
+  pub links: HashSet<Term>,
+  /// An exit signal has arrived which kills the process when it is swapped in,
+  /// the reason is stored in its own heap fragment.
+  pub pending_exit: Option<(Term, Heap)>,
+  /// Monitors created by this process, ref id to the monitored pid (or the
+  /// name if no process was registered with it).
+  pub monitors: HashMap<Word, Term>,
+  /// Monitors watching this process, ref id to the watching pid and the pid
+  /// or name which it has given to `erlang:monitor`.
+  pub monitored_by: HashMap<Word, (Term, Term)>,
+  /// Processes suspended by this process, once per suspend. They are resumed
+  /// when this process exits.
+  pub suspending: Vec<Term>,
 
   // Execution Context, etc.
   /// Runtime context with registers, instruction pointer etc
   pub context: runtime_ctx::Context,
+  /// Reductions spent in the finished time slices, read by `process_info`
+  /// from the other threads
+  reductions: AtomicUsize,
+  /// A dirty native function call in progress or its result, or a native
+  /// function call which has trapped
+  pub dirty_call: Option<DirtyCall>,
 
   // Memory
   heap: Heap,
   pub mailbox: ProcessMailbox,
+  /// Process dictionary, the keys and values are on the process heap
+  pub dictionary: ProcessDict,
 
   // Error handling
   /// Record result of last scheduled timeslice for this process
@@ -69,6 +152,12 @@ pub struct Process {
   pub error: Option<(ExceptionType, Term)>,
   /// How many catch frames are there on stack
   pub num_catches: isize,
+  /// Process was killed by the VM (i.e. heap grew over `max_heap_size`), it
+  /// terminates without running the catch handlers.
+  pub killed: bool,
+  /// Set by `reserve_heap`, the native function being called has made its
+  /// side effects and must not be repeated after a garbage collection.
+  heap_reserved: bool,
 
   pub process_flags: ProcessFlags,
 }
@@ -78,13 +167,22 @@ impl Process {
   // in proc registry for this VM
   pub fn new(
     pid: Term,
-    _parent_pid: Term,
+    parent_pid: Term,
     mfarity: &ModFunArity,
     spawn_opts: &SpawnOptions,
+    gc_settings: GcSettings,
     code_server: &mut CodeServer,
   ) -> RtResult<Process> {
     assert!(pid.is_local_pid());
-    assert!(_parent_pid.is_local_pid() || _parent_pid == Term::nil());
+    assert!(parent_pid.is_local_pid() || parent_pid == Term::nil());
+    let mut links = HashSet::new();
+    if spawn_opts.link {
+      links.insert(parent_pid);
+    }
+    let mut monitored_by = HashMap::new();
+    if let Some(ref_id) = spawn_opts.monitor {
+      monitored_by.insert(ref_id, (parent_pid, pid));
+    }
 
     // Process must start with some code location
     match code_server.lookup_beam_code_and_load(mfarity) {
@@ -94,20 +192,35 @@ impl Process {
           process_flags: spawn_opts.process_flags,
 
           // Scheduling
-          prio: spawn_opts.prio,
-          current_queue: scheduler::Queue::None,
+          sched_state: Mutex::new(SchedState {
+            prio: spawn_opts.prio,
+            current_queue: scheduler::Queue::None,
+            suspended_by: Vec::new(),
+          }),
           timeslice_result: scheduler::SliceResult::None,
-          owned_by_scheduler: ptr::null_mut(),
+          owned_by_scheduler: AtomicPtr::new(ptr::null_mut()),
+          signals: Mutex::new(Vec::new()),
+          receive_timer: None,
+          links,
+          pending_exit: None,
+          monitors: HashMap::new(),
+          monitored_by,
+          suspending: Vec::new(),
 
           // Memory
-          heap: Heap::new(Designation::ProcessHeap),
-          mailbox: ProcessMailbox::new(),
+          heap: Heap::new_process_heap(gc_settings),
+          mailbox: ProcessMailbox::new(spawn_opts.msg_queue),
+          dictionary: ProcessDict::new(),
 
           // Execution
           context: runtime_ctx::Context::new(ip),
+          reductions: AtomicUsize::new(0),
+          dirty_call: None,
 
           error: None,
           num_catches: 0,
+          killed: false,
+          heap_reserved: false,
         };
         Ok(p)
         // Ok(sync::Arc::new(sync::RwLock::new(p)))
@@ -119,6 +232,47 @@ impl Process {
   #[inline]
   pub fn get_heap(&self) -> &THeap { &self.heap as &THeap }
 
+  /// Lock the scheduling state. Take the queues lock of the owning scheduler
+  /// first if both are needed.
+  #[inline]
+  pub fn lock_sched_state(&self) -> MutexGuard<'_, SchedState> {
+    self.sched_state.lock().unwrap()
+  }
+
+  /// Charge `n` reductions for the work done by a native function, the
+  /// process is scheduled out when it runs out of reductions.
+  #[inline]
+  pub fn consume_reductions(&mut self, n: isize) {
+    self.context.consume_reductions(n)
+  }
+
+  /// Reductions left in the current time slice, native functions which walk
+  /// long lists stop and trap when they run out.
+  #[inline]
+  pub fn get_reductions_left(&self) -> isize {
+    self.context.reductions
+  }
+
+  /// Total reductions spent by the process, including the current time slice.
+  /// Call this on the thread which runs the process.
+  pub fn get_reductions(&self) -> usize {
+    self.get_finished_reductions() + self.context.get_spent_reductions()
+  }
+
+  /// Reductions spent in the finished time slices, this can be read while the
+  /// process runs on another thread.
+  pub fn get_finished_reductions(&self) -> usize {
+    self.reductions.load(Ordering::Relaxed)
+  }
+
+  /// The time slice has ended, add the reductions spent to the total.
+  /// Returns: reductions spent in the time slice.
+  pub fn count_timeslice_reductions(&mut self) -> usize {
+    let spent = self.context.swap_out();
+    self.reductions.fetch_add(spent, Ordering::Relaxed);
+    spent
+  }
+
   #[inline]
   pub fn get_heap_mut(&mut self) -> &mut THeap {
     // &self.heap as &mut THeap
@@ -126,12 +280,189 @@ impl Process {
     heap_ref as &mut THeap
   }
 
+  /// Check that `need` words are available on the heap, otherwise run the
+  /// garbage collector with `live` X registers as a part of the root set.
+  /// Terms in `extra_roots` are also kept alive and are updated in place.
+  /// The GC also runs if there are many messages waiting in heap fragments,
+  /// or if the process holds many large binaries which might be garbage.
+  pub fn ensure_heap(
+    &mut self,
+    need: WordSize,
+    live: usize,
+    extra_roots: &mut [Term],
+  ) -> RtResult<()> {
+    if self.mailbox.get_fragments_words() <= self.heap.get_heap_max_capacity()
+      && !self.heap.is_bin_vheap_full()
+      && self.heap.allocate_intent(need, live).is_ok()
+    {
+      return Ok(());
+    }
+    self.garbage_collect(need, live, extra_roots)?;
+    self.heap.allocate_intent(need, live)
+  }
+
+  /// Check that `need` words are free on the heap, a native function with
+  /// side effects calls this before making them. Fails with `HeapIsFull`
+  /// while the call can still be repeated after a garbage collection, the
+  /// allocations made after the side effects must fit in `need` words.
+  pub fn reserve_heap(&mut self, need: WordSize) -> RtResult<()> {
+    self.heap.allocate_intent(need, 0)?;
+    self.heap_reserved = true;
+    Ok(())
+  }
+
+  /// Whether the last native function call has reserved the heap, the flag
+  /// is cleared for the next call.
+  pub fn take_heap_reserved(&mut self) -> bool {
+    mem::replace(&mut self.heap_reserved, false)
+  }
+
+  /// Collect the garbage on the process heap. The roots are: `live` X
+  /// registers, the stack, the mailbox, the dictionary, the binary being
+  /// built, a stored native function call and the `extra_roots` (updated in
+  /// place). Message heap fragments
+  /// are merged into the heap and freed.
+  /// Fails with `exit(killed)` if the heap has grown over the `max_heap_size`
+  /// and the process is to be killed.
+  pub fn garbage_collect(
+    &mut self,
+    need: WordSize,
+    live: usize,
+    extra_roots: &mut [Term],
+  ) -> RtResult<()> {
+    let fragments = self.mailbox.take_fragments();
+    self.collect(live, extra_roots, fragments, |hp, roots, fragments| {
+      hp.garbage_collect(need, roots, fragments)
+    });
+    self.check_max_heap_size()
+  }
+
+  /// Prepare the process for `erlang:hibernate/3`: the stack is discarded
+  /// and the heap is compacted to the size of the live data, which is `live`
+  /// X registers and the mailbox.
+  pub fn hibernate(&mut self, live: usize) -> RtResult<()> {
+    let stack_depth = self.heap.stack_depth();
+    self.heap.drop_stack_words(stack_depth);
+    self.num_catches = 0;
+    self.context.current_bin.dst = None;
+    self.mailbox.reset_read_position();
+
+    let fragments = self.mailbox.take_fragments();
+    self.collect(live, &mut [], fragments, |hp, roots, fragments| {
+      hp.compact(roots, fragments)
+    });
+    self.check_max_heap_size()
+  }
+
+  /// Compare the heap size after GC with the `max_heap_size` limit, write an
+  /// error report and mark the process killed, if it is configured so.
+  fn check_max_heap_size(&mut self) -> RtResult<()> {
+    let limit = self.heap.get_settings().max_heap_size;
+    let words = self.heap.get_total_heap_size() + self.mailbox.get_fragments_words();
+    if !limit.is_exceeded(words) {
+      return Ok(());
+    }
+    if limit.error_logger {
+      error_report::report(format_args!(
+        "Process {} exceeded max_heap_size: total heap size {} words, \
+         limit {} words, kill={}",
+        self.pid, words, limit.size, limit.kill
+      ));
+    }
+    if limit.kill {
+      self.killed = true;
+      return Err(RtErr::Exception(ExceptionType::Exit, gen_atoms::KILLED));
+    }
+    Ok(())
+  }
+
+  /// Access the GC settings of the process heap, used by `process_flag/2`.
+  #[inline]
+  pub fn get_gc_settings_mut(&mut self) -> &mut GcSettings {
+    self.heap.get_settings_mut()
+  }
+
+  /// Copy the terms which the process refers to from the `literal_areas` into
+  /// the process heap, the literals are about to be freed. Messages stored
+  /// off-heap are merged into the heap too, as they might refer to literals.
+  pub fn collect_literals(&mut self, literal_areas: &[(*const Word, *const Word)]) {
+    let fragments = self.mailbox.take_all_fragments();
+    let live = self.context.live;
+    self.collect(live, &mut [], fragments, |hp, roots, fragments| {
+      hp.collect_literals(literal_areas, roots, fragments)
+    })
+  }
+
+  /// Build the root set and run the collection `gc_fn` on the heap.
+  fn collect<F>(
+    &mut self,
+    live: usize,
+    extra_roots: &mut [Term],
+    fragments: Vec<Heap>,
+    gc_fn: F,
+  ) where
+    F: FnOnce(&mut Heap, &mut [&mut [Term]], Vec<Heap>),
+  {
+    // Binary being built is referred by a raw pointer, present it as a term
+    let mut bin_root = [match self.context.current_bin.dst {
+      Some(bin_p) => unsafe { (*bin_p).make_term() },
+      None => Term::non_value(),
+    }];
+
+    let used_before = self.heap.get_heap_used_words();
+    let hp = &mut self.heap;
+    let [call_args, call_result] = match self.dirty_call.as_mut() {
+      Some(call) => call.get_roots_mut(),
+      None => [&mut [][..], &mut [][..]],
+    };
+    let mut roots = [
+      self.context.get_live_regs_mut(live),
+      self.mailbox.get_messages_mut(),
+      self.dictionary.get_roots_mut(),
+      extra_roots,
+      &mut bin_root,
+      call_args,
+      call_result,
+    ];
+    gc_fn(hp, &mut roots, fragments);
+    // The collector is paid by the words which it has kept
+    let live_words = self.heap.get_heap_used_words();
+    self.consume_reductions(Reductions::for_words(live_words));
+    let owner = self.get_owner();
+    if !owner.is_null() {
+      let reclaimed = used_before.saturating_sub(live_words);
+      unsafe { (*owner).stats.count_gc(reclaimed) };
+    }
+
+    if bin_root[0].is_value() {
+      self.context.current_bin.dst =
+        Some(unsafe { boxed::Binary::get_trait_mut_from_term(bin_root[0]) });
+    }
+    if verify::is_enabled(verify::VERIFY_AFTER_GC) {
+      self.verify_heap("after GC", live);
+    }
+  }
+
+  /// Returns the heap size in words, used to decide how much to grow it.
+  #[inline]
+  pub fn get_heap_capacity(&self) -> WordSize {
+    WordSize::new(self.heap.get_heap_max_capacity())
+  }
+
   /// Copy args from mfargs-MFA-something into new process heap and set the
   /// registers to the arguments passed to spawn.
   pub fn set_spawn_args(&mut self, mfargs: &ModFunArgs) -> RtResult<()> {
+    let mut need = WordSize::new(0);
+    mfargs.for_each_arg(|arg| -> RtResult<()> {
+      need = need + copy_term::size_of(arg);
+      Ok(())
+    })?;
+    self.ensure_heap(need, 0, &mut [])?;
+
     let mut xindex = 0;
     mfargs.for_each_arg(|arg| -> RtResult<()> {
-      self.context.set_x(xindex, arg);
+      let arg_copy = copy_term::copy_to(arg, &mut self.heap)?;
+      self.context.set_x(xindex, arg_copy);
       xindex += 1;
       Ok(())
     })
@@ -173,23 +504,330 @@ impl Process {
   //    self.error = ProcessError::None;
   //  }
 
-  /// Copy a message and put into process mailbox.
-  pub fn deliver_message(
-    &mut self,
-    proc_reg: &mut ProcessRegistry,
-    message: Term,
-  ) -> RtResult<()> {
-    let m1 = copy_term::copy_to(message, &mut self.heap)?;
-    self.mailbox.put(m1);
+  #[inline]
+  pub fn get_owner(&self) -> *mut Scheduler {
+    self.owned_by_scheduler.load(Ordering::SeqCst)
+  }
+
+  /// Change the owning scheduler, call this while holding the queues lock of
+  /// the previous owner (or before the process is registered).
+  #[inline]
+  pub fn set_owner(&self, sched: *const Scheduler) {
+    self
+      .owned_by_scheduler
+      .store(sched as *mut Scheduler, Ordering::SeqCst)
+  }
 
-    // Notify our current scheduler that a new message has come to possibly wake
-    // up from infinite or timed wait.
+  /// Change a process flag. Returns: the old setting of the flag.
+  pub fn change_flag(&mut self, setting: FlagSetting) -> FlagSetting {
+    match setting {
+      FlagSetting::TrapExit(on) => FlagSetting::TrapExit(
+        self
+          .process_flags
+          .read_and_set(process_flags::TRAP_EXIT, on),
+      ),
+      FlagSetting::MessageQueueData(location) => {
+        let old = self.mailbox.get_location();
+        self.mailbox.set_location(location);
+        FlagSetting::MessageQueueData(old)
+      }
+      FlagSetting::Priority(prio) => {
+        let old = self.lock_sched_state().prio;
+        Scheduler::change_priority(self, prio);
+        FlagSetting::Priority(old)
+      }
+      FlagSetting::MaxHeapSize(limit) => {
+        let gc_settings = self.get_gc_settings_mut();
+        let old = gc_settings.max_heap_size;
+        gc_settings.max_heap_size = limit;
+        FlagSetting::MaxHeapSize(old)
+      }
+    }
+  }
+
+  /// Copy a message into a new heap fragment and send it to the process.
+  /// The receiver's heap is not touched, the fragment is merged into it on
+  /// the next garbage collection.
+  /// This can be called from any scheduler thread while holding the process
+  /// registry lock (see `ProcessRegistry::with_process`).
+  /// Returns: the size of the copy, which the sender pays for.
+  pub fn deliver_message(&self, message: Term) -> RtResult<WordSize> {
+    let (signal, size) = if message.is_cons() || message.is_boxed() {
+      let mut fragment = Heap::new(Designation::HeapFragment);
+      let m1 = copy_term::copy_to(message, &mut fragment)?;
+      let size = WordSize::new(fragment.get_heap_used_words());
+      (Signal::Message(m1, Some(fragment)), size)
+    } else {
+      (Signal::Message(message, None), WordSize::new(0))
+    };
+    self.send_signal(signal);
+    Ok(size)
+  }
+
+  /// Copy the exit reason into a new heap fragment with the `{'EXIT', From,
+  /// Reason}` message and send an exit signal to the process. `linked` is set
+  /// when a linked process has terminated.
+  pub fn deliver_exit(&self, from: Term, reason: Term, linked: bool) -> RtResult<()> {
+    let mut fragment = Heap::new(Designation::HeapFragment);
+    let reason = copy_term::copy_to(reason, &mut fragment)?;
+    let message = tuple3(&mut fragment, gen_atoms::SYM_EXIT, from, reason)?;
+    self.send_signal(Signal::Exit {
+      from,
+      reason,
+      message,
+      fragment,
+      linked,
+    });
+    Ok(())
+  }
+
+  /// Send the `{'DOWN', Ref, process, Object, Reason}` message of the monitor
+  /// `ref_id`. Object is the monitored pid, or `{Name, Node}` if the monitor
+  /// was created with a registered name.
+  pub fn deliver_down(
+    &self,
+    ref_id: Word,
+    target: Term,
+    node: Term,
+    reason: Term,
+  ) -> RtResult<()> {
+    let mut fragment = Heap::new(Designation::HeapFragment);
+    let reason = copy_term::copy_to(reason, &mut fragment)?;
+    let object = if target.is_atom() {
+      tuple2(&mut fragment, target, node)?
+    } else {
+      target
+    };
+    let mref = boxed::LocalRef::create_into(&mut fragment, ref_id)?;
+    let tb = TupleBuilder::with_arity(5, &mut fragment)?;
     unsafe {
-      (*self.owned_by_scheduler).notify_new_incoming_message(proc_reg, self);
+      tb.set_element(0, gen_atoms::SYM_DOWN);
+      tb.set_element(1, mref);
+      tb.set_element(2, gen_atoms::PROCESS);
+      tb.set_element(3, object);
+      tb.set_element(4, reason);
     }
+    self.send_signal(Signal::Down {
+      ref_id,
+      message: tb.make_term(),
+      fragment,
+    });
     Ok(())
   }
 
+  /// Decide what an exit signal from `from` does to this process. Reason
+  /// `kill` sent with `erlang:exit/2` can not be trapped and kills the process
+  /// with reason `killed`, a normal exit only kills the process which sent it
+  /// to itself.
+  pub fn get_exit_action(&self, from: Term, reason: Term, linked: bool) -> ExitAction {
+    if reason == gen_atoms::KILL && !linked {
+      return ExitAction::Die(gen_atoms::KILLED);
+    }
+    if self.process_flags.get(process_flags::TRAP_EXIT) {
+      return ExitAction::Trap;
+    }
+    if reason == gen_atoms::NORMAL && from != self.pid {
+      return ExitAction::Ignore;
+    }
+    ExitAction::Die(reason)
+  }
+
+  /// Queue a signal for the process, and notify its scheduler to possibly
+  /// wake the process up from infinite or timed wait.
+  pub fn send_signal(&self, signal: Signal) {
+    let is_wakeup = signal.is_wakeup();
+    let is_exit = if let Signal::Exit { .. } = signal {
+      true
+    } else {
+      false
+    };
+    self.signals.lock().unwrap().push(signal);
+    if is_wakeup {
+      Scheduler::notify_wakeup(self, is_exit);
+    }
+  }
+
+  /// Whether there are signals which must wake the process up, checked by the
+  /// scheduler before the process is put to wait.
+  pub fn has_wakeup_signals(&self) -> bool {
+    self.signals.lock().unwrap().iter().any(|s| s.is_wakeup())
+  }
+
+  /// Handle all pending signals, called by the scheduler thread which runs
+  /// the process when the process is swapped in.
+  pub fn handle_signals(&mut self, vm: &VM) {
+    let signals = mem::replace(&mut *self.signals.lock().unwrap(), Vec::new());
+    for signal in signals {
+      self.handle_signal(vm, signal);
+    }
+  }
+
+  /// Handle the pending messages and timeouts in the order of arrival, up to
+  /// the first other signal. That one and the signals after it wait for the
+  /// next swap in, so a message never overtakes an exit or a link change
+  /// sent before it. Used by a running `receive` which found no messages.
+  pub fn handle_message_signals(&mut self, vm: &VM) {
+    let messages: Vec<Signal> = {
+      let mut signals = self.signals.lock().unwrap();
+      let count = signals.iter().take_while(|s| s.is_message()).count();
+      signals.drain(..count).collect()
+    };
+    for signal in messages {
+      self.handle_signal(vm, signal);
+    }
+  }
+
+  fn handle_signal(&mut self, vm: &VM, signal: Signal) {
+    match signal {
+      Signal::Message(message, fragment) => self.put_message(vm, message, fragment),
+      Signal::ReceiveTimeout(timer) => {
+        // A timer of an already finished receive might have fired meanwhile
+        if self.receive_timer == Some(timer) {
+          self.receive_timer = None;
+          self.process_flags.set(process_flags::TIMED_OUT);
+        }
+      }
+      Signal::PurgeLiterals(old_mod) => {
+        let literal_areas = old_mod.lit_heap.get_memory_ranges();
+        self.collect_literals(&literal_areas);
+      }
+      Signal::Link(from) => {
+        self.links.insert(from);
+      }
+      Signal::Unlink(from) => {
+        self.links.remove(&from);
+      }
+      Signal::Exit {
+        from,
+        reason,
+        message,
+        fragment,
+        linked,
+      } => {
+        // The link was removed after the signal was sent
+        if linked && !self.links.remove(&from) {
+          return;
+        }
+        match self.get_exit_action(from, reason, linked) {
+          ExitAction::Ignore => {}
+          ExitAction::Trap => self.put_message(vm, message, Some(fragment)),
+          ExitAction::Die(reason) => {
+            if self.pending_exit.is_none() {
+              self.pending_exit = Some((reason, fragment));
+            }
+          }
+        }
+      }
+      Signal::Monitor {
+        watcher,
+        ref_id,
+        target,
+      } => {
+        self.monitored_by.insert(ref_id, (watcher, target));
+      }
+      Signal::Demonitor(ref_id) => {
+        self.monitored_by.remove(&ref_id);
+      }
+      Signal::Down {
+        ref_id,
+        message,
+        fragment,
+      } => {
+        // Dropped if the monitor was removed after the signal was sent
+        if self.monitors.remove(&ref_id).is_some() {
+          self.put_message(vm, message, Some(fragment));
+        }
+      }
+      Signal::ProcessFlag(setting) => {
+        self.change_flag(setting);
+      }
+    }
+  }
+
+  fn put_message(&mut self, vm: &VM, message: Term, fragment: Option<Heap>) {
+    if vm.deterministic.is_enabled() {
+      vm.deterministic.on_message(self.pid, message);
+    }
+    self.mailbox.put(message, fragment);
+    if verify::is_enabled(verify::VERIFY_AFTER_MESSAGE) {
+      self.verify_heap("after message delivery", 0);
+    }
+  }
+
+  /// Take the links and the monitors watching a terminated process, including
+  /// the ones which are still waiting in the signal queue.
+  pub fn take_links_and_monitors(
+    &mut self,
+  ) -> (HashSet<Term>, HashMap<Word, (Term, Term)>) {
+    let signals = mem::replace(&mut *self.signals.lock().unwrap(), Vec::new());
+    for signal in signals {
+      match signal {
+        Signal::Link(from) => {
+          self.links.insert(from);
+        }
+        // A linked process which has exited is not linked any more
+        Signal::Unlink(from)
+        | Signal::Exit {
+          from, linked: true, ..
+        } => {
+          self.links.remove(&from);
+        }
+        Signal::Monitor {
+          watcher,
+          ref_id,
+          target,
+        } => {
+          self.monitored_by.insert(ref_id, (watcher, target));
+        }
+        Signal::Demonitor(ref_id) => {
+          self.monitored_by.remove(&ref_id);
+        }
+        _ => {}
+      }
+    }
+    let links = mem::replace(&mut self.links, HashSet::new());
+    let monitored_by = mem::replace(&mut self.monitored_by, HashMap::new());
+    (links, monitored_by)
+  }
+
+  /// Run the heap verifier on the process heap, the message fragments, the
+  /// `live` X registers, the mailbox and the dictionary. A damaged heap can
+  /// not be used any further, so the first bad word found is reported with a
+  /// panic. The count of live X registers is only known at a GC point, pass
+  /// 0 elsewhere, the other registers may hold stale values.
+  pub fn verify_heap(&self, when: &str, live: usize) {
+    let mut ranges = Vec::new();
+    for frag in self.mailbox.iter_fragments() {
+      if let Err(bad) = unsafe { frag.verify_fragment() } {
+        self.verify_heap_failed(when, "message fragment", bad);
+      }
+      ranges.extend(frag.get_memory_ranges());
+    }
+    let allowed = verify::AllowedMemory::new(&ranges);
+    if let Err(bad) = unsafe { self.heap.verify(&allowed) } {
+      self.verify_heap_failed(when, "heap", bad);
+    }
+    let regs = self.context.get_live_regs(live);
+    let roots = regs.iter().chain(self.mailbox.get_messages());
+    for x in roots.chain(self.dictionary.get_roots()) {
+      if let Err(bad) = unsafe { verify::verify_root(&self.heap, x, &allowed) } {
+        self.verify_heap_failed(when, "registers, mailbox or dictionary", bad);
+      }
+    }
+  }
+
+  fn verify_heap_failed(&self, when: &str, location: &str, bad: BadWord) -> ! {
+    unsafe { self.heap.dump() };
+    panic!(
+      "{}Heap verification {} failed for process {} in {}: {}",
+      module(),
+      when,
+      self.pid,
+      location,
+      bad
+    )
+  }
+
   /// Ugly hack to mut-borrow the context without making borrow checker sad.
   /// We guarantee that this borrow will not outlive the process, or we will pay
   /// the price debugging the SIGSEGV.
@@ -0,0 +1,107 @@
+//! Process dictionary: a key-value store private to a process. Keys and values
+//! are stored on the process heap, the dictionary is a part of the GC root set.
+use crate::term::{compare, value::Term};
+use core::mem;
+use std::collections::HashMap;
+
+pub struct ProcessDict {
+  /// Keys and values one after another, so that the dictionary can be given
+  /// to the GC as a slice of roots.
+  entries: Vec<Term>,
+  /// Positions of the keys in `entries` by the key hash. The hash does not
+  /// depend on the heap address, so the GC moving the terms keeps it valid.
+  index: HashMap<u64, Vec<usize>>,
+}
+
+impl ProcessDict {
+  pub fn new() -> Self {
+    Self {
+      entries: Vec::new(),
+      index: HashMap::new(),
+    }
+  }
+
+  /// Iterate over the key-value pairs, in no particular order.
+  pub fn iter(&self) -> impl Iterator<Item = (Term, Term)> + '_ {
+    self.entries.chunks(2).map(|kv| (kv[0], kv[1]))
+  }
+
+  /// Access the keys and values for update by the GC.
+  pub fn get_roots_mut(&mut self) -> &mut [Term] {
+    &mut self.entries
+  }
+
+  /// Read access to the keys and values for the heap verifier.
+  pub fn get_roots(&self) -> &[Term] {
+    &self.entries
+  }
+
+  /// Index of the key in `entries`, keys are compared exactly like `=:=`.
+  fn find(&self, key: Term) -> Option<usize> {
+    let positions = self.index.get(&compare::hash_term_exact(key))?;
+    let entries = &self.entries;
+    positions
+      .iter()
+      .cloned()
+      .find(|i| compare::eq_terms_exact(entries[*i], key))
+  }
+
+  pub fn get(&self, key: Term) -> Option<Term> {
+    self.find(key).map(|i| self.entries[i + 1])
+  }
+
+  /// Store `value` under `key`. Returns the previous value if there was one.
+  pub fn put(&mut self, key: Term, value: Term) -> Option<Term> {
+    match self.find(key) {
+      Some(i) => Some(mem::replace(&mut self.entries[i + 1], value)),
+      None => {
+        let hash = compare::hash_term_exact(key);
+        self.index.entry(hash).or_default().push(self.entries.len());
+        self.entries.push(key);
+        self.entries.push(value);
+        None
+      }
+    }
+  }
+
+  /// Remove `key`. Returns the value it had if it was present. The last pair
+  /// takes the place of the removed one.
+  pub fn erase(&mut self, key: Term) -> Option<Term> {
+    let i = self.find(key)?;
+    let value = self.entries[i + 1];
+    self.remove_position(compare::hash_term_exact(key), i);
+    let last = self.entries.len() - 2;
+    if i != last {
+      let moved_hash = compare::hash_term_exact(self.entries[last]);
+      self.remove_position(moved_hash, last);
+      self.index.entry(moved_hash).or_default().push(i);
+      self.entries.swap(i, last);
+      self.entries.swap(i + 1, last + 1);
+    }
+    self.entries.truncate(last);
+    Some(value)
+  }
+
+  fn remove_position(&mut self, hash: u64, i: usize) {
+    if let Some(positions) = self.index.get_mut(&hash) {
+      positions.retain(|p| *p != i);
+      if positions.is_empty() {
+        self.index.remove(&hash);
+      }
+    }
+  }
+
+  pub fn clear(&mut self) {
+    self.entries.clear();
+    self.index.clear();
+  }
+
+  /// Keys which have a value exactly equal to `value`.
+  pub fn get_keys_with_value(&self, value: Term) -> Vec<Term> {
+    self
+      .iter()
+      .filter(|(_, v)| compare::eq_terms_exact(*v, value))
+      .map(|(k, _)| k)
+      .collect()
+  }
+}
@@ -1,8 +1,20 @@
+use crate::{
+  emulator::{
+    heap::{gc::MaxHeapSize, heap_trait::THeap},
+    scheduler::Prio,
+    spawn_options::MessageQueueLocation,
+  },
+  fail::{self, RtResult},
+  term::value::Term,
+};
+
 #[derive(Debug, Clone, Copy)]
 pub struct ProcessFlag(usize);
 
 pub const TRAP_EXIT: ProcessFlag = ProcessFlag(1usize << 0);
 pub const SYSTEM_PROCESS: ProcessFlag = ProcessFlag(1usize << 1);
+/// The timer of `receive ... after` has fired, cleared by the `timeout` opcode.
+pub const TIMED_OUT: ProcessFlag = ProcessFlag(1usize << 2);
 
 #[derive(Debug, Clone, Copy)]
 pub struct ProcessFlags(usize);
@@ -13,7 +25,7 @@ impl ProcessFlags {
   }
 
   #[inline]
-  pub fn get(&mut self, flag: ProcessFlag) -> bool {
+  pub fn get(&self, flag: ProcessFlag) -> bool {
     self.0 & flag.0 != 0
   }
 
@@ -47,3 +59,36 @@ impl ProcessFlags {
     self.0 &= !flag.0;
   }
 }
+
+/// Check that a process with `caller_flags` can give the priority `prio` to
+/// itself or to a process it spawns. Max priority is reserved for the system
+/// processes.
+pub fn check_priority_allowed(caller_flags: &ProcessFlags, prio: Prio) -> RtResult<()> {
+  if prio == Prio::Max && !caller_flags.get(SYSTEM_PROCESS) {
+    return fail::create::badarg();
+  }
+  Ok(())
+}
+
+/// A flag set with `erlang:process_flag`, and also the old value returned by
+/// `Process::change_flag`. A change for another process is sent to it as a
+/// signal.
+#[derive(Debug, Clone, Copy)]
+pub enum FlagSetting {
+  TrapExit(bool),
+  MessageQueueData(MessageQueueLocation),
+  Priority(Prio),
+  MaxHeapSize(MaxHeapSize),
+}
+
+impl FlagSetting {
+  /// The value as returned by `erlang:process_flag`.
+  pub fn value_to_term(&self, hp: &mut THeap) -> RtResult<Term> {
+    match self {
+      FlagSetting::TrapExit(on) => Ok(Term::make_bool(*on)),
+      FlagSetting::MessageQueueData(location) => Ok(location.to_atom()),
+      FlagSetting::Priority(prio) => Ok(prio.to_atom()),
+      FlagSetting::MaxHeapSize(limit) => limit.to_term(hp),
+    }
+  }
+}
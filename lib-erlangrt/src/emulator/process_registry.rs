@@ -1,82 +1,122 @@
 use crate::{emulator::process::Process, term::value::Term};
-use core::ptr;
-use std::collections::HashMap;
+use core::{cell::UnsafeCell, ptr};
+use std::{collections::HashMap, sync::RwLock};
 
+/// Process table shared by the scheduler threads. Processes are boxed and do
+/// not move, a process is only removed by the scheduler thread which runs it,
+/// so that scheduler can keep a pointer to it without holding the lock.
 pub struct ProcessRegistry {
-  /// Dict of pids to process boxes
-  pid_to_proc: HashMap<Term, Process>,
-  name_to_pidport: HashMap<Term, Term>,
+  /// Dict of pids to process boxes, the processes are changed through
+  /// pointers, by the thread which runs them and by the thread-safe methods.
+  pid_to_proc: RwLock<HashMap<Term, Box<UnsafeCell<Process>>>>,
+  name_to_pidport: RwLock<HashMap<Term, Term>>,
 }
 
 impl ProcessRegistry {
   pub fn new() -> Self {
     Self {
-      pid_to_proc: HashMap::new(),
-      name_to_pidport: HashMap::new(),
+      pid_to_proc: RwLock::new(HashMap::new()),
+      name_to_pidport: RwLock::new(HashMap::new()),
     }
   }
 
   /// Register a process `proc_` in the process table and also queue it for
   /// execution. This is invoked by vm when a new process is spawned.
   #[inline]
-  pub fn insert(&mut self, pid: Term, proc: Process) {
-    self.pid_to_proc.insert(pid, proc);
+  pub fn insert(&self, pid: Term, proc: Process) {
+    self
+      .pid_to_proc
+      .write()
+      .unwrap()
+      .insert(pid, Box::new(UnsafeCell::new(proc)));
   }
 
+  /// Remove the process from the table, nothing can be sent to it after
+  /// this. The process is returned to be dropped after the lock is released.
   #[inline]
-  pub fn remove(&mut self, pid: Term) {
-    self.pid_to_proc.remove(&pid);
+  pub fn remove(&self, pid: Term) -> Option<Process> {
+    let proc = self.pid_to_proc.write().unwrap().remove(&pid);
+    proc.map(|p| p.into_inner())
   }
 
   #[inline]
   pub fn count(&self) -> usize {
-    self.pid_to_proc.len()
+    self.pid_to_proc.read().unwrap().len()
   }
 
   /// Borrow a read-only process, if it exists. Return `None` if we are sorry.
+  /// The process must not be used after it has exited.
   #[inline]
   pub fn lookup_pid(&self, pid: Term) -> Option<&Process> {
-    assert!(pid.is_local_pid());
-    self.pid_to_proc.get(&pid)
-  }
-
-  /// Borrow a mutable process, if it exists. Return `None` if we are sorry.
-  #[inline]
-  pub fn lookup_pid_mut(&mut self, pid: Term) -> Option<&mut Process> {
-    assert!(pid.is_local_pid());
-    self.pid_to_proc.get_mut(&pid)
+    let p = self.unsafe_lookup_pid(pid);
+    if p.is_null() {
+      None
+    } else {
+      Some(unsafe { &(*p) })
+    }
   }
 
-
   /// Find a process and instead of borrowing return a pointer to it.
   #[inline]
   #[allow(dead_code)]
   pub fn unsafe_lookup_pid(&self, pid: Term) -> *const Process {
     assert!(pid.is_local_pid());
-    match self.pid_to_proc.get(&pid) {
-      Some(p) => p as *const Process,
+    match self.pid_to_proc.read().unwrap().get(&pid) {
+      Some(p) => p.get() as *const Process,
       None => ptr::null(),
     }
   }
 
   /// Find a process and instead of borrowing return a mutable pointer to it.
   #[inline]
-  pub fn unsafe_lookup_pid_mut(&mut self, pid: Term) -> *mut Process {
+  pub fn unsafe_lookup_pid_mut(&self, pid: Term) -> *mut Process {
     assert!(pid.is_local_pid());
-    match self.pid_to_proc.get_mut(&pid) {
-      Some(p) => p as *mut Process,
+    match self.pid_to_proc.read().unwrap().get(&pid) {
+      Some(p) => p.get(),
       None => ptr::null_mut(),
     }
   }
 
+  /// Run `f` on a process while holding the table lock, so that the process
+  /// can not exit meanwhile. Use this to access a process which might be
+  /// running on another scheduler thread. Returns `None` if there is no such
+  /// process.
+  pub fn with_process<F, R>(&self, pid: Term, f: F) -> Option<R>
+  where
+    F: FnOnce(&Process) -> R,
+  {
+    assert!(pid.is_local_pid());
+    let procs = self.pid_to_proc.read().unwrap();
+    match procs.get(&pid) {
+      // The other threads only reach a process through thread-safe fields
+      Some(p) => Some(f(unsafe { &(*p.get()) })),
+      None => None,
+    }
+  }
+
+  /// Run `f` on every process while holding the table lock.
+  pub fn for_each<F>(&self, mut f: F)
+  where
+    F: FnMut(&Process),
+  {
+    let procs = self.pid_to_proc.read().unwrap();
+    for p in procs.values() {
+      f(unsafe { &(*p.get()) })
+    }
+  }
+
   /// Query contents of the name-to-pid/port table
   pub fn find_registered(&self, name: Term) -> Option<Term> {
-    self.name_to_pidport.get(&name).cloned()
+    self.name_to_pidport.read().unwrap().get(&name).cloned()
   }
 
   /// Add contents of the name-to-pid/port table, no check is made for whether
   /// the value is new, will overwrite.
-  pub fn register_name(&mut self, name: Term, pid_or_port: Term) {
-    self.name_to_pidport.insert(name, pid_or_port);
+  pub fn register_name(&self, name: Term, pid_or_port: Term) {
+    self
+      .name_to_pidport
+      .write()
+      .unwrap()
+      .insert(name, pid_or_port);
   }
 }
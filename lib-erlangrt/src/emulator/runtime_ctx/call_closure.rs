@@ -1,7 +1,7 @@
 use super::Context;
 use crate::{
   beam::disp_result::DispatchResult,
-  defs::Arity,
+  defs::{Arity, Reductions},
   emulator::{process::Process, vm::VM},
   fail::{self, RtResult},
   term::{boxed, value::*},
@@ -14,12 +14,13 @@ fn module() -> &'static str {
 /// The `closure` is a callable closure with some frozen variables made with
 /// `fun() -> code end`.
 pub fn apply(
-  vm: &mut VM,
+  vm: &VM,
   ctx: &mut Context,
   _curr_p: &mut Process,
   closure: *mut boxed::Closure,
   args: &[Term],
 ) -> RtResult<DispatchResult> {
+  ctx.consume_reductions(Reductions::CALL_COST);
   let args_len = args.len();
 
   let (closure_arity, closure_nfrozen) =
@@ -59,8 +60,8 @@ pub fn apply(
   ctx.ip = match dst {
     Some(p) => p.ptr,
     None => unsafe {
-      let cs = vm.get_code_server_p();
-      (*closure).update_location(&mut (*cs))?
+      let mut code_server = vm.code_server.write().unwrap();
+      (*closure).update_location(&mut code_server)?
     },
   };
   Ok(DispatchResult::Normal)
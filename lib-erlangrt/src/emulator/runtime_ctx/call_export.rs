@@ -1,10 +1,14 @@
 use super::Context;
 use crate::{
   beam::disp_result::DispatchResult,
-  defs::Arity,
+  defs::{Arity, Reductions},
   emulator::{
+    code_srv,
     process::Process,
-    runtime_ctx::call_native_fun::{self, CallBifTarget},
+    runtime_ctx::{
+      call_native_fun::{self, CallBifTarget},
+      hibernate,
+    },
     vm::VM,
   },
   fail::{self, RtResult},
@@ -18,7 +22,7 @@ fn module() -> &'static str {
 /// The `exp` is an export made with `fun module:name/0` which can point to
 /// either an Erlang function or to a BIF (native built-in function).
 pub fn apply(
-  vm: &mut VM,
+  vm: &VM,
   ctx: &mut Context,
   curr_p: &mut Process,
   export: *const boxed::Export,
@@ -40,7 +44,16 @@ pub fn apply(
     return fail::create::badarity();
   }
 
-  if vm.code_server.native_functions.mfa_exists(&mfa) {
+  if hibernate::is_hibernate(&mfa) {
+    return hibernate::hibernate(vm, ctx, curr_p, args);
+  }
+  let is_native = vm
+    .code_server
+    .read()
+    .unwrap()
+    .native_functions
+    .mfa_exists(&mfa);
+  if is_native {
     return call_native_fun::find_and_call_native_fun(
       vm,
       ctx,
@@ -52,8 +65,8 @@ pub fn apply(
       false,
     );
   } else {
-    let code_server = vm.get_code_server_p();
-    match unsafe { (*code_server).lookup_beam_code_and_load(&mfa) } {
+    ctx.consume_reductions(Reductions::CALL_COST);
+    match code_srv::lookup_and_load(&vm.code_server, &mfa) {
       Ok(ip) => {
         if save_cp {
           ctx.cp = ctx.ip
@@ -1,17 +1,31 @@
 use super::Context;
 use crate::{
-  beam::disp_result::DispatchResult,
-  emulator::{code_srv::CodeServer, mfa::ModFunArity, process::Process, vm::VM},
+  beam::disp_result::{DispatchResult, YieldType},
+  defs::{Reductions, WordSize},
+  emulator::{
+    code_srv::CodeServer,
+    dirty_scheduler::{DirtyCall, DirtyKind},
+    mfa::ModFunArity,
+    process::Process,
+    vm::VM,
+  },
   fail::{self, RtErr, RtResult},
   native_fun::NativeFn,
   term::{boxed::import, value::*},
 };
-use core::slice;
 
-// fn module() -> &'static str { "runtime_ctx.call_native_fun: " }
+fn module() -> &'static str {
+  "runtime_ctx.call_native_fun: "
+}
 
 // Call Bif generic facilities
 //
+// A native function can be called again with the same args: when it runs out
+// of heap and the garbage is collected (`apply_native_fun_with_gc`), and when
+// it returns `RtErr::Trap` to continue in the next time slice. So its side
+// effects go after the last allocation which can fail, or the heap is reserved
+// with `Process::reserve_heap` before making them.
+//
 
 /// A Bif can be referenced by an import `Term`, an `MFArity`...
 #[allow(dead_code)]
@@ -23,8 +37,8 @@ pub enum CallBifTarget {
   ImportPointer(*const import::Import),
   /// An MFA reference which needs to be resolved.
   MFArity(ModFunArity),
-  /// A resolved pointer to a `BifFn`.
-  BifFnPointer(NativeFn),
+  /// A resolved pointer to a `BifFn` and its dirty kind.
+  BifFnPointer(NativeFn, Option<DirtyKind>),
 }
 
 /// Generic bif0,1,2 application. Bif0 cannot have a fail label but bif1 and
@@ -39,41 +53,78 @@ pub enum CallBifTarget {
 ///   `gc` if gc is allowed then `ctx.live` will be used as live.
 #[inline]
 pub fn find_and_call_native_fun(
-  vm: &mut VM,
+  vm: &VM,
   ctx: &mut Context,
   curr_p: &mut Process,
   fail_label: Term,
   target: CallBifTarget,
   args: &[Term],
   dst: Term,
-  _gc: bool,
+  gc: bool,
 ) -> RtResult<DispatchResult> {
+  ctx.consume_reductions(Reductions::CALL_COST);
+
   // Try resolve BIF destination, which can be defined by an import, mfarity
   // a pointer to import, or a pointer to native_fun function.
   // TODO: Maybe make this use codeserver generic lookup_mfa or extend it to support this
-  let maybe_bif_fn = match target {
-    CallBifTarget::ImportTerm(imp) => {
-      callbif_resolve_import(&vm.code_server, imp, args.len())?
-    }
+  // The code server lock is released before the call, the native fun might
+  // need it
+  let maybe_bif_fn = {
+    let code_server = vm.code_server.read().unwrap();
+    match target {
+      CallBifTarget::ImportTerm(imp) => {
+        callbif_resolve_import(&code_server, imp, args.len())?
+      }
 
-    CallBifTarget::MFArity(mfa) => callbif_resolve_mfa(&vm.code_server, &mfa)?,
+      CallBifTarget::MFArity(mfa) => callbif_resolve_mfa(&code_server, &mfa)?,
 
-    CallBifTarget::ImportPointer(imp_ptr) => {
-      if let Some(fn_ptr) = unsafe { (*imp_ptr).get_native_fn_ptr(&vm.code_server) } {
-        BifResolutionResult::FnPointer(fn_ptr)
-      } else {
-        let bif_name = unsafe { format!("{}", (*imp_ptr).mfarity) };
-        return Err(RtErr::BifNotFound(bif_name));
+      CallBifTarget::ImportPointer(imp_ptr) => {
+        if let Some(entry) = unsafe { (*imp_ptr).get_native_fn_entry(&code_server) } {
+          BifResolutionResult::FnPointer(entry.func, entry.dirty)
+        } else {
+          let bif_name = unsafe { format!("{}", (*imp_ptr).mfarity) };
+          return Err(RtErr::BifNotFound(bif_name));
+        }
       }
-    }
 
-    CallBifTarget::BifFnPointer(fn_ptr) => BifResolutionResult::FnPointer(fn_ptr),
+      CallBifTarget::BifFnPointer(fn_ptr, dirty) => {
+        BifResolutionResult::FnPointer(fn_ptr, dirty)
+      }
+    }
   };
 
   // Now having resolved the native_fun function, let's call it
   let bif_result = match maybe_bif_fn {
-    BifResolutionResult::FnPointer(fn_ptr) => {
-      call_native_fun_fn(vm, ctx, curr_p, fn_ptr, args)
+    BifResolutionResult::FnPointer(fn_ptr, Some(kind)) => {
+      // Runs on a dirty thread, the result is taken when the process is
+      // scheduled again
+      let loaded_args = load_native_fun_args(ctx, curr_p, args);
+      curr_p.dirty_call = Some(DirtyCall::new(
+        Some(kind),
+        fn_ptr,
+        loaded_args,
+        args.len(),
+        fail_label,
+        dst,
+        gc,
+      ));
+      return Ok(DispatchResult::Yield(YieldType::DirtyCall));
+    }
+
+    BifResolutionResult::FnPointer(fn_ptr, None) => {
+      let result = if gc {
+        call_native_fun_fn_with_gc(vm, ctx, curr_p, fn_ptr, args)
+      } else {
+        call_native_fun_fn(vm, ctx, curr_p, fn_ptr, args)
+      };
+      // Out of reductions, the call continues in the next time slice
+      if let Err(RtErr::Trap(trap_args)) = result {
+        let call =
+          DirtyCall::new(None, fn_ptr, trap_args, args.len(), fail_label, dst, gc);
+        curr_p.dirty_call = Some(call);
+        return Ok(DispatchResult::Yield(YieldType::EndOfTheQueue));
+      }
+      result
     }
 
     BifResolutionResult::BadfunError(badfun_val) => {
@@ -81,9 +132,90 @@ pub fn find_and_call_native_fun(
     }
   };
 
-  // Now having called the function let's see if there was some good result or
-  // an error occured
+  handle_native_fun_result(ctx, curr_p, fail_label, dst, bif_result)
+}
 
+/// Take the result of a dirty call when the process runs again, store it or
+/// handle the error the same way as for a call made on the scheduler. A
+/// trapped call is continued here, and it may trap again.
+pub fn finish_dirty_call(
+  vm: &VM,
+  ctx: &mut Context,
+  curr_p: &mut Process,
+  mut call: DirtyCall,
+) -> RtResult<DispatchResult> {
+  let bif_result = match call.result.take() {
+    // Out of heap on the dirty thread. The garbage is collected here by the
+    // owner of the process, and the call goes back to the dirty thread
+    Some(Err(RtErr::HeapIsFull(_)))
+      if call.gc && call.gc_attempts < NATIVE_FUN_GC_ATTEMPTS =>
+    {
+      // First retry only collects the garbage, next ones grow the heap
+      let need = if call.gc_attempts == 0 {
+        WordSize::new(0)
+      } else {
+        curr_p.get_heap_capacity()
+      };
+      call.gc_attempts += 1;
+      let n_args = call.n_args;
+      curr_p.garbage_collect(need, ctx.live, &mut call.args[0..n_args])?;
+      curr_p.dirty_call = Some(call);
+      return Ok(DispatchResult::Yield(YieldType::DirtyCall));
+    }
+    Some(result) => result,
+    None => {
+      if call.kind.is_some() {
+        return Err(RtErr::DirtyCallNotReturned);
+      }
+      let n_args = call.n_args;
+      let args = &mut call.args[0..n_args];
+      let result = if call.gc {
+        apply_native_fun_with_gc(vm, curr_p, call.func, ctx.live, args)
+      } else {
+        (call.func)(vm, curr_p, args)
+      };
+      if let Err(RtErr::Trap(trap_args)) = result {
+        call.args = trap_args;
+        curr_p.dirty_call = Some(call);
+        return Ok(DispatchResult::Yield(YieldType::EndOfTheQueue));
+      }
+      result
+    }
+  };
+  match handle_native_fun_result(ctx, curr_p, call.fail_label, call.dst, bif_result) {
+    Ok(_) if call.finish => Ok(DispatchResult::Finished),
+    other => other,
+  }
+}
+
+/// Run the stored dirty call of a process which is not scheduled, on a dirty
+/// thread. The result is stored in the call. The garbage is never collected
+/// here, running out of heap is handled by the owner in `finish_dirty_call`.
+pub fn run_dirty_call(vm: &VM, curr_p: &mut Process) {
+  let mut call = curr_p.dirty_call.take().expect("no dirty call to run");
+  let n_args = call.n_args;
+  curr_p.take_heap_reserved();
+  let result = (call.func)(vm, curr_p, &call.args[0..n_args]);
+  if let Err(RtErr::HeapIsFull(_)) = result {
+    assert!(
+      !curr_p.take_heap_reserved(),
+      "{}native function has used more heap than it reserved",
+      module()
+    );
+  }
+  call.result = Some(result);
+  curr_p.dirty_call = Some(call);
+}
+
+/// Having called the function let's see if there was some good result or
+/// an error occured.
+fn handle_native_fun_result(
+  ctx: &mut Context,
+  curr_p: &mut Process,
+  fail_label: Term,
+  dst: Term,
+  bif_result: RtResult<Term>,
+) -> RtResult<DispatchResult> {
   // On error and if fail label is a CP, perform a goto
   // Assume that error is already written to `reason` in process
   match bif_result {
@@ -121,7 +253,8 @@ pub fn find_and_call_native_fun(
 
 #[allow(dead_code)]
 enum BifResolutionResult {
-  FnPointer(NativeFn),
+  /// A function and its dirty kind, `None` runs on the scheduler
+  FnPointer(NativeFn, Option<DirtyKind>),
   BadfunError(Term),
 }
 
@@ -143,8 +276,8 @@ fn callbif_resolve_import(
   );
 
   // Here HOImport pointer is found, try and resolve it to a Rust function ptr
-  if let Some(fn_ptr) = unsafe { (*imp_p).get_native_fn_ptr(code_srv) } {
-    return Ok(BifResolutionResult::FnPointer(fn_ptr));
+  if let Some(entry) = unsafe { (*imp_p).get_native_fn_entry(code_srv) } {
+    return Ok(BifResolutionResult::FnPointer(entry.func, entry.dirty));
   }
   Err(RtErr::BifNotFound(unsafe {
     format!("{}", (*imp_p).mfarity)
@@ -158,38 +291,232 @@ fn callbif_resolve_mfa(
   code_srv: &CodeServer,
   mfa: &ModFunArity,
 ) -> RtResult<BifResolutionResult> {
-  if let Some(fn_ptr) = code_srv.native_functions.find_mfa(&mfa) {
-    return Ok(BifResolutionResult::FnPointer(fn_ptr));
+  if let Some(entry) = code_srv.native_functions.find_entry(&mfa) {
+    return Ok(BifResolutionResult::FnPointer(entry.func, entry.dirty));
   }
   Err(RtErr::BifNotFound(format!("{}", mfa)))
 }
 
+/// How many times a native function is called again after it has failed with
+/// `HeapIsFull`. First retry only collects the garbage, next ones grow the heap.
+const NATIVE_FUN_GC_ATTEMPTS: usize = 4;
+
+/// Resolve args which can be register/slot values to the values.
+/// Bif arg count can go up to 3.
+fn load_native_fun_args(ctx: &Context, curr_p: &Process, args: &[Term]) -> [Term; 4] {
+  assert!(args.len() < 4);
+  let mut loaded_args = [Term::nil(); 4];
+  let heap = curr_p.get_heap();
+  for i in 0..args.len() {
+    loaded_args[i] = ctx.load(args[i], heap);
+  }
+  loaded_args
+}
+
 /// Given a native_fun function pointer and args with possibly register/slot values
 /// in them, first resolve these args to values, and then call the function
 // #[inline]
 pub fn call_native_fun_fn(
-  vm: &mut VM,
+  vm: &VM,
+  ctx: &mut Context,
+  curr_p: &mut Process,
+  func_pointer: NativeFn,
+  args: &[Term],
+) -> RtResult<Term> {
+  let loaded_args = load_native_fun_args(ctx, curr_p, args);
+
+  // Apply the BIF call and return BifResult
+  (func_pointer)(vm, curr_p, &loaded_args[0..args.len()])
+}
+
+/// Same as `call_native_fun_fn` but when the native function runs out of heap,
+/// the garbage is collected (with `ctx.live` registers and the args as roots)
+/// and the call is repeated.
+pub fn call_native_fun_fn_with_gc(
+  vm: &VM,
   ctx: &mut Context,
   curr_p: &mut Process,
   func_pointer: NativeFn,
   args: &[Term],
 ) -> RtResult<Term> {
   let n_args = args.len();
+  let mut loaded_args = load_native_fun_args(ctx, curr_p, args);
+  apply_native_fun_with_gc(
+    vm,
+    curr_p,
+    func_pointer,
+    ctx.live,
+    &mut loaded_args[0..n_args],
+  )
+}
 
-  // Make a slice from the args. Bif arg count can go up to 3
-  assert!(args.len() < 4);
-  let mut loaded_args = [Term::nil(); 4];
+/// Call the native function, on `HeapIsFull` collect the garbage with `live`
+/// registers and the loaded args as roots and try again. A function which has
+/// reserved the heap has made side effects and is not repeated, running out of
+/// the reserved heap is a bug in that function.
+fn apply_native_fun_with_gc(
+  vm: &VM,
+  curr_p: &mut Process,
+  func_pointer: NativeFn,
+  live: usize,
+  loaded_args: &mut [Term],
+) -> RtResult<Term> {
+  let mut need = WordSize::new(0);
 
-  {
-    let heap = curr_p.get_heap();
-    for i in 0..n_args {
-      loaded_args[i] = ctx.load(args[i], heap);
+  for _attempt in 0..NATIVE_FUN_GC_ATTEMPTS {
+    curr_p.take_heap_reserved();
+    let result = (func_pointer)(vm, curr_p, loaded_args);
+    let reserved = curr_p.take_heap_reserved();
+    match result {
+      Err(RtErr::HeapIsFull(_)) => {
+        assert!(
+          !reserved,
+          "{}native function has used more heap than it reserved",
+          module()
+        );
+        curr_p.garbage_collect(need, live, loaded_args)?;
+        // Did not help? Next time ask for as much as the heap has now
+        need = curr_p.get_heap_capacity();
+      }
+      other => return other,
     }
   }
+  (func_pointer)(vm, curr_p, loaded_args)
+}
 
-  // Take n_args elements from args
-  let loaded_args1 = unsafe { slice::from_raw_parts(&loaded_args[0], n_args) };
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    emulator::{atom, gen_atoms, spawn_options::SpawnOptions, test_util},
+    native_fun::lists::misc::NfListsMember2,
+  };
 
-  // Apply the BIF call and return BifResult
-  (func_pointer)(vm, curr_p, loaded_args1)
+  /// Allocates as many words as the first arg says, returns `ok`.
+  fn alloc_words(_vm: &VM, proc: &mut Process, args: &[Term]) -> RtResult<Term> {
+    let n = WordSize::new(args[0].get_small_unsigned());
+    proc.get_heap_mut().alloc(n, false)?;
+    Ok(gen_atoms::OK)
+  }
+
+  #[test]
+  fn test_dirty_call_from_apply() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_native_fun_dirty");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+    let ctx = unsafe { &mut (*proc.get_context_p()) };
+
+    // The dirty kind comes with the lookup result, the call is not made here
+    let f = atom::from_str("dirty_cpu");
+    let mfa = ModFunArity::new(atom::from_str("erts_debug"), f, 2);
+    let lr = vm.code_server.write().unwrap().lookup_mfa(&mfa, false);
+    let lr = lr.unwrap();
+    let args = [gen_atoms::WAIT, Term::make_small_unsigned(1)];
+    match ctx.call_mfa(&vm, proc, &lr, &args, true) {
+      Ok(DispatchResult::Yield(YieldType::DirtyCall)) => {}
+      _ => panic!("The call must go to a dirty thread"),
+    }
+    let call = proc.dirty_call.as_ref().unwrap();
+    assert_eq!(call.kind, Some(DirtyKind::Cpu));
+    assert!(call.result.is_none());
+
+    run_dirty_call(&vm, proc);
+    let call = proc.dirty_call.take().unwrap();
+    finish_dirty_call(&vm, ctx, proc, call).unwrap();
+    assert_eq!(ctx.get_x(0), gen_atoms::OK);
+  }
+
+  #[test]
+  fn test_dirty_call_not_returned() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_native_fun_dirty_early");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+    let ctx = unsafe { &mut (*proc.get_context_p()) };
+
+    let size = Term::make_small_unsigned(1);
+    let args = [size, Term::nil(), Term::nil(), Term::nil()];
+    let kind = Some(DirtyKind::Io);
+    let dst = Term::make_register_x(0);
+    let call = DirtyCall::new(kind, alloc_words, args, 1, Term::nil(), dst, false);
+    match finish_dirty_call(&vm, ctx, proc, call) {
+      Err(RtErr::DirtyCallNotReturned) => {}
+      _ => panic!("A dirty call which has not run must fail"),
+    }
+  }
+
+  #[test]
+  fn test_dirty_call_collects_on_the_owner() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_native_fun_dirty_gc");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+    let ctx = unsafe { &mut (*proc.get_context_p()) };
+    ctx.live = 0;
+
+    // More than the heap has, the heap must grow
+    let capacity = proc.get_heap_capacity().words;
+    let size = Term::make_small_unsigned(capacity + 10);
+    let args = [size, Term::nil(), Term::nil(), Term::nil()];
+    let kind = Some(DirtyKind::Cpu);
+    let dst = Term::make_register_x(0);
+    let call = DirtyCall::new(kind, alloc_words, args, 1, Term::nil(), dst, true);
+    proc.dirty_call = Some(call);
+    loop {
+      // The dirty thread never collects the garbage, it returns `HeapIsFull`
+      let before = proc.get_heap_capacity().words;
+      run_dirty_call(&vm, proc);
+      assert_eq!(proc.get_heap_capacity().words, before);
+      let call = proc.dirty_call.take().unwrap();
+      match finish_dirty_call(&vm, ctx, proc, call).unwrap() {
+        DispatchResult::Yield(YieldType::DirtyCall) => {
+          assert!(proc.dirty_call.as_ref().unwrap().result.is_none());
+        }
+        DispatchResult::Normal => break,
+        _ => panic!("Unexpected dispatch result"),
+      }
+    }
+    assert!(proc.get_heap_capacity().words > capacity);
+    assert_eq!(ctx.get_x(0), gen_atoms::OK);
+  }
+
+  #[test]
+  fn test_trapped_call_continues() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_native_fun_trap");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+
+    let items: Vec<Term> = (0..100).map(Term::make_small_unsigned).collect();
+    let list = test_util::make_list(proc.get_heap_mut(), &items);
+    let ctx = unsafe { &mut (*proc.get_context_p()) };
+    ctx.set_x(0, Term::make_small_unsigned(99));
+    ctx.set_x(1, list);
+    ctx.live = 2;
+    // Enough for the call and 10 elements
+    ctx.reductions = 2;
+
+    let x = Term::make_register_x;
+    let target = CallBifTarget::BifFnPointer(NfListsMember2::_f, None);
+    let args = [x(0), x(1)];
+    let result =
+      find_and_call_native_fun(&vm, ctx, proc, Term::nil(), target, &args, x(0), true);
+    match result {
+      Ok(DispatchResult::Yield(YieldType::EndOfTheQueue)) => {}
+      _ => panic!("The call must trap"),
+    }
+    assert_eq!(ctx.reductions, 0);
+
+    // The rest of the list is a GC root while the call waits
+    proc.garbage_collect(WordSize::new(0), 2, &mut []).unwrap();
+    let call = proc.dirty_call.take().unwrap();
+    assert!(call.kind.is_none());
+    assert_eq!(cons::list_length(call.args[1]).unwrap(), 90);
+
+    ctx.swap_in();
+    finish_dirty_call(&vm, ctx, proc, call).unwrap();
+    assert!(proc.dirty_call.is_none());
+    assert_eq!(ctx.get_x(0), gen_atoms::TRUE);
+  }
 }
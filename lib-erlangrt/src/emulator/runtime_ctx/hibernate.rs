@@ -0,0 +1,93 @@
+use super::Context;
+use crate::{
+  beam::disp_result::{DispatchResult, YieldType},
+  emulator::{code_srv, gen_atoms, mfa::ModFunArity, process::Process, vm::VM},
+  fail::{self, RtResult},
+  term::value::{cons, Term},
+};
+
+/// Check whether a call goes to `erlang:hibernate/3`, which is not a regular
+/// native function because it replaces the running code and the stack.
+#[inline]
+pub fn is_hibernate(mfa: &ModFunArity) -> bool {
+  mfa.m == gen_atoms::ERLANG && mfa.f == gen_atoms::HIBERNATE && mfa.arity == 3
+}
+
+/// Implements `erlang:hibernate(M, F, Args)`: the stack is discarded, the heap
+/// is compacted and the process waits for a message. When woken up it
+/// continues at `M:F(Args...)`. Returning from that function ends the process.
+pub fn hibernate(
+  vm: &VM,
+  ctx: &mut Context,
+  curr_p: &mut Process,
+  args: &[Term],
+) -> RtResult<DispatchResult> {
+  let (m, f, fn_args) = (args[0], args[1], args[2]);
+  if !m.is_atom() || !f.is_atom() || !fn_args.is_list() {
+    return fail::create::badarg();
+  }
+  let arity = cons::list_length(fn_args)?;
+  let mfarity = ModFunArity::new(m, f, arity);
+  let ip = match code_srv::lookup_and_load(&vm.code_server, &mfarity) {
+    Ok(ip) => ip,
+    Err(_) => return fail::create::undef(),
+  };
+
+  let mut xindex = 0;
+  cons::for_each(fn_args, |arg| {
+    ctx.set_x(xindex, arg);
+    xindex += 1;
+    Ok(())
+  })?;
+  ctx.live = arity;
+  ctx.ip = ip;
+  ctx.clear_cp();
+
+  curr_p.hibernate(arity)?;
+  Ok(DispatchResult::Yield(YieldType::InfiniteWait))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    defs::WordSize,
+    emulator::{atom, spawn_options::SpawnOptions, test_util},
+    term::term_builder::{list_builder::build_erlstr_from_utf8, tuple_builder::tuple2},
+  };
+
+  #[test]
+  fn test_hibernate_drops_stack_and_compacts() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_hibernate");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+    let ctx = unsafe { &mut *proc.get_context_p() };
+
+    let hp = proc.get_heap_mut();
+    let arg = unsafe { build_erlstr_from_utf8("keep", hp).unwrap() };
+    let arg = tuple2(hp, arg, Term::make_small_signed(1)).unwrap();
+    let before = format!("{}", arg);
+    for _ in 0..10 {
+      unsafe { build_erlstr_from_utf8("garbage", hp).unwrap() };
+    }
+    hp.stack_alloc_unchecked(WordSize::new(2));
+    let done = atom::from_str("done");
+    let args = [m, done, test_util::make_list(hp, &[arg])];
+
+    let result = hibernate(&vm, ctx, proc, &args).unwrap();
+    assert!(matches!(
+      result,
+      DispatchResult::Yield(YieldType::InfiniteWait)
+    ));
+    assert_eq!(format!("{}", ctx.get_x(0)), before);
+    assert_eq!(ctx.live, 1);
+    let hp = proc.get_heap();
+    assert_eq!(hp.stack_depth(), 0);
+    // The heap fits only the argument, there is no free space
+    assert!(!hp.heap_check_available(WordSize::new(1)));
+
+    let bad = [m, done, Term::make_small_signed(1)];
+    assert!(hibernate(&vm, ctx, proc, &bad).is_err());
+  }
+}
@@ -5,14 +5,14 @@ use core::{fmt, ptr, slice};
 use colored::Colorize;
 
 use crate::{
-  beam::gen_op,
+  beam::{disp_result::DispatchResult, gen_op},
   defs::{Reductions, Word, MAX_FPREGS, MAX_XREGS},
   emulator::{
     code::{opcode, CodePtr},
     code_srv::MFALookupResult,
     heap::heap_trait::THeap,
     process::Process,
-    runtime_ctx::current_binary::CurrentBinaryState,
+    runtime_ctx::{call_native_fun::CallBifTarget, current_binary::CurrentBinaryState},
     vm::VM,
   },
   fail::RtResult,
@@ -23,6 +23,7 @@ pub mod call_closure;
 pub mod call_export;
 pub mod call_native_fun;
 pub mod current_binary;
+pub mod hibernate;
 
 fn module() -> &'static str {
   "rt_ctx: "
@@ -40,7 +41,9 @@ pub struct Context {
   /// Return location, for one return without using the stack.
   pub cp: CodePtr,
 
-  /// A metric of CPU time spent on running the code, roughly equal to 1 function call
+  /// A metric of CPU time spent on running the code, roughly equal to 1 function
+  /// call. This is what is left for the current time slice, it might go below
+  /// zero after a heavy native function.
   pub reductions: isize,
 
   /// Current state of X registers.
@@ -75,7 +78,7 @@ impl Context {
       ip,
       regs: [Term::non_value(); MAX_XREGS],
       live: 0,
-      reductions: 0,
+      reductions: Reductions::DEFAULT,
       current_bin: CurrentBinaryState::new(),
     }
   }
@@ -127,6 +130,18 @@ impl Context {
     self.regs[index] = val;
   }
 
+  /// Access first `live` X registers.
+  #[inline]
+  pub fn get_live_regs(&self, live: usize) -> &[Term] {
+    &self.regs[0..live]
+  }
+
+  /// Access first `live` X registers for update, these are the GC roots.
+  #[inline]
+  pub fn get_live_regs_mut(&mut self, live: usize) -> &mut [Term] {
+    &mut self.regs[0..live]
+  }
+
   #[inline]
   pub fn swap_in(&mut self) {
     // This amount is RESET every time process is about to be scheduled in, i.e.
@@ -134,9 +149,28 @@ impl Context {
     self.reductions = Reductions::DEFAULT;
   }
 
+  /// Take `n` reductions from the current time slice.
+  #[inline]
+  pub fn consume_reductions(&mut self, n: isize) {
+    self.reductions -= n;
+  }
+
+  /// Reductions spent in the current time slice.
+  #[inline]
+  pub fn get_spent_reductions(&self) -> usize {
+    (Reductions::DEFAULT - self.reductions) as usize
+  }
+
+  /// Called when the time slice has ended, returns reductions spent in it.
+  #[inline]
+  pub fn swap_out(&mut self) -> usize {
+    let spent = self.get_spent_reductions();
+    self.reductions = Reductions::DEFAULT;
+    spent
+  }
+
   #[inline]
   pub fn fetch_opcode(&mut self) -> opcode::RawOpcode {
-    self.reductions -= Reductions::FETCH_OPCODE_COST;
     let op = opcode::from_memory_word(self.ip_read());
     self.args_ptr = unsafe { self.ip.get_pointer().add(1) };
     self.ip_advance(1isize + gen_op::opcode_arity(op) as isize);
@@ -324,25 +358,34 @@ impl Context {
   /// Optional `save_cp` defines whether CP will be saved
   pub fn call_mfa(
     &mut self,
-    vm: &mut VM,
+    vm: &VM,
     curr_p: &mut Process,
     lr: &MFALookupResult,
     args: &[Term],
     save_cp: bool,
-  ) -> RtResult<()> {
+  ) -> RtResult<DispatchResult> {
     match lr {
       MFALookupResult::FoundBeamCode(code_p) => {
+        self.consume_reductions(Reductions::CALL_COST);
         if save_cp {
           self.cp = self.ip;
         }
         self.ip = code_p.clone();
+        Ok(DispatchResult::Normal)
       }
-      MFALookupResult::FoundBif(bif_fn) => {
-        let x0 = call_native_fun::call_native_fun_fn(vm, self, curr_p, *bif_fn, args)?;
-        self.set_x(0, x0);
+      MFALookupResult::FoundBif(bif_fn, dirty) => {
+        call_native_fun::find_and_call_native_fun(
+          vm,
+          self,
+          curr_p,
+          Term::nil(),
+          CallBifTarget::BifFnPointer(*bif_fn, *dirty),
+          args,
+          Term::make_register_x(0),
+          false,
+        )
       }
     }
-    Ok(())
   }
 
   #[allow(dead_code)]
@@ -2,26 +2,65 @@
 use crate::{
   defs::{exc_type::ExceptionType, Word},
   emulator::{
-    gen_atoms, process::Process, process_flags, process_registry::ProcessRegistry,
+    deterministic::Deterministic,
+    error_report, gen_atoms,
+    heap::verify,
+    process::{Process, SchedState},
+    signal::Signal,
+    statistics::SchedulerStats,
+    timer::{TimerAction, TimerId, TimerMessage},
+    vm::VM,
   },
+  fail::{self, RtResult},
   term::value::*,
 };
 use colored::Colorize;
-use std::collections::{HashMap, VecDeque};
+use std::{
+  collections::{HashMap, VecDeque},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Condvar, Mutex, MutexGuard,
+  },
+  time::{Duration, Instant},
+};
 
 fn module() -> &'static str {
   "scheduler: "
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[allow(dead_code)]
 pub enum Prio {
   /// Runs when no more jobs to take or at 8x disadvantage to normal
   Low = 0,
   /// Most of user processes run at this priority
   Normal = 1,
-  /// Takes priority always over everything else
+  /// Takes priority over low and normal
   High = 2,
+  /// Takes priority always over everything else, only for system processes
+  Max = 3,
+}
+
+impl Prio {
+  /// Parse `low`, `normal`, `high` or `max` atom.
+  pub fn from_atom(val: Term) -> RtResult<Self> {
+    match val {
+      gen_atoms::LOW => Ok(Prio::Low),
+      gen_atoms::NORMAL => Ok(Prio::Normal),
+      gen_atoms::HIGH => Ok(Prio::High),
+      gen_atoms::MAX => Ok(Prio::Max),
+      _ => fail::create::badarg(),
+    }
+  }
+
+  pub fn to_atom(self) -> Term {
+    match self {
+      Prio::Low => gen_atoms::LOW,
+      Prio::Normal => gen_atoms::NORMAL,
+      Prio::High => gen_atoms::HIGH,
+      Prio::Max => gen_atoms::MAX,
+    }
+  }
 }
 
 /// Enum identifies current registration of the process
@@ -35,6 +74,10 @@ pub enum Queue {
   Low,
   TimedWait,
   InfiniteWait,
+  /// Waits for a dirty native function to return
+  Dirty,
+  /// Suspended by `erlang:suspend_process`, not scheduled until resumed
+  Suspended,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -46,6 +89,10 @@ pub enum SliceResult {
   Yield,
   /// Process entered infinite wait during the last timeslice
   InfiniteWait,
+  /// Process entered a wait with timeout during the last timeslice
+  TimedWait,
+  /// Process called a dirty native function during the last timeslice
+  DirtyCall,
   /// Process normally finished during the last timeslice
   Finished,
   /// Error, exit or throw occured during the last timeslice, error is stored
@@ -53,30 +100,171 @@ pub enum SliceResult {
   Exception,
 }
 
+/// Longest sleep of an idle scheduler, after it checks whether it can steal
+/// work from the other schedulers.
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+
 /// How many Normal processes can be scheduled before Low gets to run.
 const NORMAL_ADVANTAGE: Word = 8;
 
-/// Maintains run queues for different priorities and allows queuing processes,
-/// suspending processes, work balancing (TODO), etc.
-pub struct Scheduler {
+/// Run queues and wait sets of one scheduler, protected by the scheduler
+/// lock because other threads wake up processes and steal work from here.
+struct RunQueues {
   // This is the naive implementation of run queues.
   // A better approach would be to build an intrusive double linked list through
   // every process in the queue (as done by the original ERTS).
   queue_low: VecDeque<Term>,
   queue_normal: VecDeque<Term>,
   queue_high: VecDeque<Term>,
+  queue_max: VecDeque<Term>,
   /// Wait set for timed suspended processes (waiting for a timer)
   timed_wait: HashMap<Term, ()>,
   /// Wait set for infinitely suspended processes (in endless receive)
   infinite_wait: HashMap<Term, ()>,
 
-  /// A counter used to skip some schedulings for low processes
+  /// How many normal processes have run since the last low process
   advantage_count: Word,
 
   /// Currently selected process
   current: Option<Term>,
 }
 
+impl RunQueues {
+  fn new() -> Self {
+    Self {
+      queue_low: VecDeque::new(),
+      queue_normal: VecDeque::new(),
+      queue_high: VecDeque::new(),
+      queue_max: VecDeque::new(),
+      timed_wait: HashMap::new(),
+      infinite_wait: HashMap::new(),
+      advantage_count: 0,
+      current: None,
+    }
+  }
+
+  /// Whether any process waits in the run queues.
+  fn has_runnable(&self) -> bool {
+    !(self.queue_max.is_empty()
+      && self.queue_high.is_empty()
+      && self.queue_normal.is_empty()
+      && self.queue_low.is_empty())
+  }
+
+  fn get_queue_mut(&mut self, prio: Prio) -> &mut VecDeque<Term> {
+    match prio {
+      Prio::Low => &mut self.queue_low,
+      Prio::Normal => &mut self.queue_normal,
+      Prio::High => &mut self.queue_high,
+      Prio::Max => &mut self.queue_max,
+    }
+  }
+
+  /// Look through the queues and find some queue with highest priority where
+  /// a process is waiting to be selected.
+  /// Advantage counter lets a low process run after every `NORMAL_ADVANTAGE`
+  /// normal processes, so that the low processes do not starve.
+  fn pick(&mut self, det: &Deterministic) -> Option<Term> {
+    if let Some(pid) = Self::take_next(det, &mut self.queue_max) {
+      return Some(pid);
+    }
+    if let Some(pid) = Self::take_next(det, &mut self.queue_high) {
+      return Some(pid);
+    }
+    if self.advantage_count >= NORMAL_ADVANTAGE || self.queue_normal.is_empty() {
+      if let Some(pid) = Self::take_next(det, &mut self.queue_low) {
+        self.advantage_count = 0;
+        return Some(pid);
+      }
+    }
+    let next = Self::take_next(det, &mut self.queue_normal);
+    if next.is_some() && self.advantage_count < NORMAL_ADVANTAGE {
+      self.advantage_count += 1;
+    }
+    next
+  }
+
+  /// Take the first process of the queue, or a random one chosen with the
+  /// seed in the deterministic mode.
+  fn take_next(det: &Deterministic, queue: &mut VecDeque<Term>) -> Option<Term> {
+    if det.is_enabled() && !queue.is_empty() {
+      let index = det.choose_index(queue.len());
+      return queue.remove(index);
+    }
+    queue.pop_front()
+  }
+
+  /// Take the process which the replay log runs next, wherever it is queued.
+  fn pick_replayed(&mut self, pid: Term) -> Option<Term> {
+    let found = [Prio::Max, Prio::High, Prio::Normal, Prio::Low]
+      .iter()
+      .any(|prio| self.remove_queued(*prio, pid));
+    if !found {
+      panic!("deterministic: Replay has diverged, {} is not queued", pid);
+    }
+    Some(pid)
+  }
+
+  /// The process which `pick_for_stealing` would take.
+  fn peek_for_stealing(&self) -> Option<Term> {
+    let queues = [
+      &self.queue_normal,
+      &self.queue_low,
+      &self.queue_high,
+      &self.queue_max,
+    ];
+    queues.iter().find_map(|q| q.back().cloned())
+  }
+
+  /// Take a process from the far end of the queues for another scheduler.
+  fn pick_for_stealing(&mut self) -> Option<Term> {
+    self
+      .queue_normal
+      .pop_back()
+      .or_else(|| self.queue_low.pop_back())
+      .or_else(|| self.queue_high.pop_back())
+      .or_else(|| self.queue_max.pop_back())
+  }
+
+  /// Remove a process from the run queue `prio`, returns false if the
+  /// process was not there.
+  fn is_queued(&self, prio: Prio, pid: Term) -> bool {
+    let queue = match prio {
+      Prio::Low => &self.queue_low,
+      Prio::Normal => &self.queue_normal,
+      Prio::High => &self.queue_high,
+      Prio::Max => &self.queue_max,
+    };
+    queue.contains(&pid)
+  }
+
+  fn remove_queued(&mut self, prio: Prio, pid: Term) -> bool {
+    let queue = self.get_queue_mut(prio);
+    match queue.iter().position(|p| *p == pid) {
+      Some(index) => {
+        queue.remove(index);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+/// Maintains run queues for different priorities and allows queuing processes,
+/// suspending processes, work balancing etc. Every scheduler thread runs its
+/// own scheduler, and steals processes from other schedulers when idle.
+pub struct Scheduler {
+  /// Index of the scheduler in the VM, also the index of its thread
+  pub index: usize,
+  queues: Mutex<RunQueues>,
+  /// How many processes wait in the run queues, read without the lock to
+  /// choose a scheduler for a new process or for stealing.
+  queued: AtomicUsize,
+  /// Signalled when a process is queued while the scheduler is idle
+  work_available: Condvar,
+  pub stats: SchedulerStats,
+}
+
 /// Hint from the logic finalizing timeslice result from a running process.
 /// The logic may continue running same process if the reductions allow, after
 /// having been interrupted by an exception.
@@ -87,188 +275,276 @@ enum ScheduleHint {
 }
 
 impl Scheduler {
-  pub fn new() -> Self {
+  pub fn new(index: usize) -> Self {
     Self {
-      queue_low: VecDeque::new(),
-      queue_normal: VecDeque::new(),
-      queue_high: VecDeque::new(),
-      timed_wait: HashMap::new(),
-      infinite_wait: HashMap::new(),
+      index,
+      queues: Mutex::new(RunQueues::new()),
+      queued: AtomicUsize::new(0),
+      work_available: Condvar::new(),
+      stats: SchedulerStats::default(),
+    }
+  }
 
-      advantage_count: 0,
-      current: None,
+  /// How many processes wait in the run queues of this scheduler.
+  #[inline]
+  pub fn get_queued_count(&self) -> usize {
+    self.queued.load(Ordering::Relaxed)
+  }
+
+  #[inline]
+  fn lock_queues(&self) -> MutexGuard<'_, RunQueues> {
+    self.queues.lock().unwrap()
+  }
+
+  /// Lock the queues of the scheduler which owns the process. The owner is
+  /// only changed under the queues lock of the previous owner, so it is
+  /// checked again after locking. The owner is set before the process is
+  /// registered.
+  fn lock_owner_queues<'a>(proc: &Process) -> (&'a Scheduler, MutexGuard<'a, RunQueues>) {
+    loop {
+      let sched_p = proc.get_owner();
+      assert!(!sched_p.is_null());
+      let sched = unsafe { &(*sched_p) };
+      let queues = sched.lock_queues();
+      if proc.get_owner() == sched_p {
+        return (sched, queues);
+      }
     }
   }
 
-  /// Queue a process by its pid.
-  pub fn enqueue(&mut self, proc_reg: &mut ProcessRegistry, pid: Term) {
-    self.enqueue_opt(proc_reg, pid, false);
+  /// Queue a process.
+  /// Will `panic!` if the process is already queued.
+  pub fn enqueue(&self, proc: &Process) {
+    let mut queues = self.lock_queues();
+    self.enqueue_locked(&mut queues, &mut proc.lock_sched_state(), proc.pid);
   }
 
-  /// Queue a process by its pid.
-  /// Will `panic!` if the process doesn't exist or is already queued.
-  /// Arg: `skip_queue_check` allows skipping the current queue assertion for
-  ///   when you can guarantee that the process is not queued anywhere.
-  pub fn enqueue_opt(
-    &mut self,
-    proc_reg: &mut ProcessRegistry,
-    pid: Term,
-    skip_queue_check: bool,
-  ) {
+  fn enqueue_locked(&self, queues: &mut RunQueues, state: &mut SchedState, pid: Term) {
     assert!(pid.is_local_pid());
+    assert_eq!(
+      state.current_queue,
+      Queue::None,
+      "Process must not be in any queue when queuing, now in {:?}",
+      state.current_queue
+    );
+    // Runs again when resumed
+    if state.is_suspended() {
+      state.current_queue = Queue::Suspended;
+      return;
+    }
+    self.push_run_queue(queues, state.prio, pid);
+  }
 
-    let prio = {
-      // Lookup the pid
-      let p = proc_reg.lookup_pid(pid).unwrap();
-      if !skip_queue_check {
-        assert_eq!(
-          p.current_queue,
-          Queue::None,
-          "Process must not be in any queue when queuing, now in {:?}",
-          p.current_queue
-        );
-      }
-      p.prio
-    };
+  fn push_run_queue(&self, queues: &mut RunQueues, prio: Prio, pid: Term) {
+    queues.get_queue_mut(prio).push_back(pid);
+    self.queued.fetch_add(1, Ordering::Relaxed);
+    self.work_available.notify_one();
+  }
 
-    match prio {
-      Prio::Normal => self.queue_normal.push_back(pid),
-      Prio::Low => self.queue_low.push_back(pid),
-      Prio::High => self.queue_high.push_back(pid),
+  /// Nothing to run, sleep until a process is queued on this scheduler or
+  /// until the next timer deadline.
+  pub fn wait_for_work(&self, vm: &VM) {
+    let mut timeout = IDLE_WAIT;
+    if let Some(deadline) = vm.timers.next_deadline() {
+      let now = Instant::now();
+      if deadline <= now {
+        return;
+      }
+      timeout = timeout.min(deadline - now);
     }
+    let queues = self.lock_queues();
+    if queues.has_runnable() {
+      return;
+    }
+    let _ = self.work_available.wait_timeout(queues, timeout).unwrap();
+  }
+
+  /// Wake up the idle scheduler thread, i.e. to let it see that the VM stops.
+  pub fn wake_up(&self) {
+    let _queues = self.lock_queues();
+    self.work_available.notify_all();
   }
 
-  /// Queue a process by its pid into either timed_wait or infinite_wait queue.
+  /// Queue a process into either timed_wait or infinite_wait queue.
   #[inline]
-  pub fn enqueue_wait(&mut self, infinite: bool, pid: Term) {
+  fn enqueue_wait(
+    queues: &mut RunQueues,
+    infinite: bool,
+    state: &mut SchedState,
+    pid: Term,
+  ) {
     assert!(pid.is_local_pid());
 
-    if infinite {
-      self.infinite_wait.insert(pid, ());
+    // A resumed process checks its mailbox and goes waiting again
+    if state.is_suspended() {
+      state.current_queue = Queue::Suspended;
+    } else if infinite {
+      queues.infinite_wait.insert(pid, ());
+      state.current_queue = Queue::InfiniteWait;
     } else {
-      self.timed_wait.insert(pid, ());
+      queues.timed_wait.insert(pid, ());
+      state.current_queue = Queue::TimedWait;
     }
   }
 
   #[inline]
-  fn log_next_process(maybe_pid: Option<Term>) {
+  fn log_next_process(&self, maybe_pid: Option<Term>) {
     if cfg!(feature = "trace_opcode_execution") {
       if let Some(pid) = maybe_pid {
         println!(
-          "+ {} {} --- --- --- --- --- --- ---",
-          "Scheduler: switching to".yellow().on_blue(),
+          "+ {} {} {} --- --- --- --- --- --- ---",
+          "Scheduler".yellow().on_blue(),
+          self.index,
           pid
         );
       } else {
         println!(
-          "+ {}",
-          "Scheduler: no process to run".yellow().on_bright_black()
+          "+ {} {}",
+          "Scheduler: no process to run".yellow().on_bright_black(),
+          self.index
         );
       }
     }
   }
 
-  /// Get another process from the run queue for this scheduler.
-  /// Returns: `Option(pid)`
-  pub fn next_process(&mut self, proc_reg: &mut ProcessRegistry) -> Option<Term> {
-    if let Some(prev_pid) = self.current {
-      let hint = self.next_process_finalize_previous(proc_reg, prev_pid);
+  /// Get another process from the run queue for this scheduler, or steal one
+  /// from another scheduler.
+  /// Returns: `Option(pid)`, `None` if there is nothing to run.
+  pub fn next_process(&self, vm: &VM) -> Option<Term> {
+    let prev = self.lock_queues().current.take();
+    if let Some(prev_pid) = prev {
+      let hint = self.next_process_finalize_previous(vm, prev_pid);
       if hint == ScheduleHint::ContinueSameProcess {
-        // do not change self.current and just do the same process again
-        return self.current;
+        // just do the same process again
+        self.lock_queues().current = prev;
+        return prev;
       }
     }
 
     // Do necessities before taking another process
-    self.next_process_duties();
-
-    // Now try and find another process to run
-    loop {
-      // See if any are waiting in realtime (high) priority queue
-      if let Some(next_pid) = self.next_process_pick_from_the_queues() {
-        self.current = Some(next_pid);
-        break;
+    self.next_process_duties(vm);
+
+    let next = {
+      let mut queues = self.lock_queues();
+      let det = &vm.deterministic;
+      let next = if !det.is_enabled() {
+        queues.pick(det)
+      } else if let Some(pid) = det.get_replayed_schedule() {
+        queues.pick_replayed(pid)
+      } else {
+        queues.pick(det)
+      };
+      if next.is_some() {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
       }
+      next
+    };
+    let next = next.or_else(|| self.steal(vm));
+    if let (Some(pid), true) = (next, vm.deterministic.is_enabled()) {
+      vm.deterministic.on_schedule(pid);
     }
+    self.lock_queues().current = next;
 
-    Self::log_next_process(self.current);
-    self.current
+    self.log_next_process(next);
+    next
   }
 
-  /// Look through the queues and find some queue with highest priority where
-  /// a process is waiting to be selected.
-  /// Advantage counter allows running lower queues even if a higher is running.
-  fn next_process_pick_from_the_queues(&mut self) -> Option<Term> {
-    if !self.queue_high.is_empty() {
-      return self.queue_high.pop_front();
-    } else if self.advantage_count < NORMAL_ADVANTAGE {
-      if !self.queue_normal.is_empty() {
-        return self.queue_normal.pop_front();
-      } else if !self.queue_low.is_empty() {
-        return self.queue_low.pop_front();
+  /// Take a process from the run queues of another scheduler, and make this
+  /// scheduler its owner. Nothing is stolen from a scheduler which has only
+  /// one process queued.
+  fn steal(&self, vm: &VM) -> Option<Term> {
+    let count = vm.schedulers.len();
+    for i in 1..count {
+      let victim = &vm.schedulers[(self.index + i) % count];
+      if victim.get_queued_count() < 2 {
+        continue;
       }
-      self.advantage_count += 1;
-    } else {
-      if !self.queue_low.is_empty() {
-        return self.queue_low.pop_front();
-      } else if !self.queue_normal.is_empty() {
-        return self.queue_normal.pop_front();
+      let pid = match victim.lock_queues().peek_for_stealing() {
+        Some(pid) => pid,
+        None => continue,
+      };
+      // Looked up before locking the queues, the process registry lock is
+      // never taken while holding a queues lock
+      let proc = match vm.processes.lookup_pid(pid) {
+        Some(proc) => proc,
+        None => continue,
+      };
+      let mut queues = victim.lock_queues();
+      // The victim might have taken the process meanwhile. While it stays
+      // queued, it has not exited
+      if queues.peek_for_stealing() != Some(pid) {
+        continue;
       }
-      self.advantage_count = 0;
-    };
-    return None;
+      queues.pick_for_stealing();
+      victim.queued.fetch_sub(1, Ordering::Relaxed);
+      // The other threads look for the owner under its queues lock
+      proc.set_owner(self);
+      return Some(pid);
+    }
+    None
   }
 
   /// When time has come to select next running process, first we take a look
   /// at the previous process, what happened to it.
   #[inline]
-  fn next_process_finalize_previous(
-    &mut self,
-    proc_reg: &mut ProcessRegistry,
-    curr_pid: Term,
-  ) -> ScheduleHint {
+  fn next_process_finalize_previous(&self, vm: &VM, curr_pid: Term) -> ScheduleHint {
     // Extract the last running process from the process registry
-    let curr_ptr = proc_reg.unsafe_lookup_pid_mut(curr_pid);
+    let curr_ptr = vm.processes.unsafe_lookup_pid_mut(curr_pid);
     assert!(!curr_ptr.is_null());
 
     // Unspeakable horrors are happening as we speak: (bypassing borrow checker)
     let curr_proc = unsafe { &mut (*curr_ptr) };
 
+    if verify::is_enabled(verify::VERIFY_AFTER_TIMESLICE) {
+      curr_proc.verify_heap("after timeslice", 0);
+    }
+    let spent = curr_proc.count_timeslice_reductions();
+    self.stats.count_reductions(spent);
+
     debug_assert_eq!(
-      curr_proc.current_queue,
+      curr_proc.lock_sched_state().current_queue,
       Queue::None,
-      "Finalizing previous process which is not dequeued, now in {:?}",
-      curr_proc.current_queue
+      "Finalizing previous process which is not dequeued",
     );
 
     match curr_proc.timeslice_result {
-      SliceResult::Yield => {
-        self.enqueue(proc_reg, curr_pid);
-        self.current = None
-      }
+      SliceResult::Yield => self.enqueue(curr_proc),
 
-      SliceResult::None => {
-        self.enqueue(proc_reg, curr_pid);
-        self.current = None
-      }
+      SliceResult::None => self.enqueue(curr_proc),
 
       SliceResult::Finished => {
         // Scheduler will terminate the process with EXIT:NORMAL
         let err = (ExceptionType::Exit, gen_atoms::NORMAL);
-        self.terminate_process(proc_reg, curr_pid, err)
+        self.terminate_process(vm, curr_pid, err)
       }
 
       SliceResult::Exception => {
-        return self.handle_process_exception(proc_reg, curr_ptr, curr_pid);
+        return self.handle_process_exception(vm, curr_ptr, curr_pid);
       }
 
-      SliceResult::InfiniteWait => {
+      SliceResult::InfiniteWait | SliceResult::TimedWait => {
         // Check if there is anything that should wake it up right now, like
-        // an incoming message or another signal?
+        // an incoming message or another signal? Signals are checked under
+        // the lock, a sender which comes later will find the process waiting.
         // TODO: Respect already viewed messages in the mailbox
-        if !curr_proc.mailbox.have_unread_messages() {
-          self.enqueue_wait(true, curr_pid);
+        let infinite = curr_proc.timeslice_result == SliceResult::InfiniteWait;
+        let mut queues = self.lock_queues();
+        let mut state = curr_proc.lock_sched_state();
+        if curr_proc.mailbox.have_unread_messages() || curr_proc.has_wakeup_signals() {
+          self.enqueue_locked(&mut queues, &mut state, curr_pid);
+        } else {
+          Self::enqueue_wait(&mut queues, infinite, &mut state, curr_pid);
+        }
+      }
+
+      SliceResult::DirtyCall => {
+        // Out of the run queues until a dirty thread has run the call
+        let kind = curr_proc.dirty_call.as_ref().and_then(|c| c.kind).unwrap();
+        {
+          let _queues = self.lock_queues();
+          curr_proc.lock_sched_state().current_queue = Queue::Dirty;
         }
+        vm.dirty_schedulers.submit(kind, curr_pid);
       }
     }
     ScheduleHint::TakeAnotherProcess
@@ -277,8 +553,8 @@ impl Scheduler {
   /// If exception happened, check whether a process is catching anything at
   /// this moment, otherwise proceed to terminate.
   fn handle_process_exception(
-    &mut self,
-    proc_reg: &mut ProcessRegistry,
+    &self,
+    vm: &VM,
     proc_p: *mut Process,
     proc_pid: Term,
   ) -> ScheduleHint {
@@ -288,10 +564,9 @@ impl Scheduler {
     assert!(proc.is_failed());
     let p_error = proc.error.unwrap();
 
-    if proc.num_catches <= 0 {
-      // time to terminate, no catches
-      self.terminate_process(proc_reg, proc_pid, p_error);
-      self.current = None;
+    if proc.num_catches <= 0 || proc.killed {
+      // time to terminate, no catches or killed by the VM
+      self.terminate_process(vm, proc_pid, p_error);
       return ScheduleHint::TakeAnotherProcess;
     }
 
@@ -316,44 +591,81 @@ impl Scheduler {
 
       None => {
         println!("Catch not found, terminating...");
-        if proc.process_flags.get(process_flags::TRAP_EXIT) {
-          panic!("todo: on terminate implement trap_exit");
-        }
-        self.terminate_process(proc_reg, proc_pid, p_error);
-        self.current = None;
+        self.terminate_process(vm, proc_pid, p_error);
       }
     }
     return ScheduleHint::TakeAnotherProcess;
   }
 
   /// Things to do before scheduling another process for execution.
+  /// Expired timers are fired by whichever scheduler thread comes here first.
   #[inline]
-  fn next_process_duties(&self) {
-    // TODO: monotonic clock
-    // TODO: wait lists
+  fn next_process_duties(&self, vm: &VM) {
+    let now = Instant::now();
+    while let Some((timer, action)) = Self::pop_expired_timer(vm, now) {
+      match action {
+        TimerAction::ReceiveTimeout(pid) => {
+          vm.processes
+            .with_process(pid, |p| p.send_signal(Signal::ReceiveTimeout(timer)));
+        }
+        TimerAction::SendMessage(msg) => Self::on_timer_message(vm, &msg),
+      }
+    }
     // TODO: network checks
   }
 
+  /// Take the next timer which has expired. When a deterministic replay is in
+  /// progress the timers fire at their recorded points instead.
+  fn pop_expired_timer(vm: &VM, now: Instant) -> Option<(TimerId, TimerAction)> {
+    if !vm.deterministic.is_enabled() {
+      return vm.timers.pop_expired(now);
+    }
+    let fired = if vm.deterministic.is_replaying() {
+      vm.deterministic
+        .get_replayed_timer()
+        .and_then(|id| vm.timers.take(id))
+    } else {
+      vm.timers.pop_expired(now)
+    };
+    if let Some((timer, _)) = fired {
+      vm.deterministic.on_timer(timer);
+    }
+    fired
+  }
+
+  /// A message timer has fired, deliver the message if the destination
+  /// process exists.
+  fn on_timer_message(vm: &VM, msg: &TimerMessage) {
+    let pid = if msg.dest.is_atom() {
+      match vm.processes.find_registered(msg.dest) {
+        Some(pid) if pid.is_pid() => pid,
+        _ => return,
+      }
+    } else {
+      msg.dest
+    };
+    let result = vm
+      .processes
+      .with_process(pid, |p| p.deliver_message(msg.message));
+    if let Some(Err(e)) = result {
+      error_report::report(format_args!("Timer message to {} failed: {:?}", pid, e));
+    }
+  }
+
   /// Assuming that the error was not caught, begin process termination routine.
-  pub fn terminate_process(
-    &mut self,
-    proc_reg: &mut ProcessRegistry,
-    pid: Term,
-    e: (ExceptionType, Term),
-  ) {
+  pub fn terminate_process(&self, vm: &VM, pid: Term, e: (ExceptionType, Term)) {
     // assert that process is not in any queue
     {
-      let p = proc_reg.lookup_pid_mut(pid).unwrap();
-      assert_eq!(p.current_queue, Queue::None);
+      let p = vm.processes.lookup_pid(pid).unwrap();
+      assert_eq!(p.lock_sched_state().current_queue, Queue::None);
     }
 
     // root process exits with halt()
     // assert!(p.get_registered_name() != atom::INIT);
 
     // TODO: ets tables
-    // TODO: notify monitors
-    // TODO: cancel known timers who target this process
-    // TODO: notify links
+    // The receive timer is owned by the process too
+    vm.timers.cancel_owned_by(pid);
     // TODO: unregister name if registered
     // TODO: if pending timers - become zombie and sit in pending timers queue
     println!(
@@ -364,33 +676,276 @@ impl Scheduler {
       e.1 //, p.runtime_ctx.regs[0]
     );
 
-    self.timed_wait.remove(&pid);
-    self.infinite_wait.remove(&pid);
-    assert!(!self.queue_normal.contains(&pid));
-    assert!(!self.queue_low.contains(&pid));
-    assert!(!self.queue_high.contains(&pid));
-    proc_reg.remove(pid);
+    {
+      let mut queues = self.lock_queues();
+      queues.timed_wait.remove(&pid);
+      queues.infinite_wait.remove(&pid);
+      assert!(!queues.queue_normal.contains(&pid));
+      assert!(!queues.queue_low.contains(&pid));
+      assert!(!queues.queue_high.contains(&pid));
+      assert!(!queues.queue_max.contains(&pid));
+    }
+    // The links and monitors are taken while the process is registered, then
+    // once more for the signals which have arrived until it was removed. The
+    // later link and monitor requests find no process and get `noproc`.
+    let proc_p = vm.processes.unsafe_lookup_pid_mut(pid);
+    let (mut links, mut monitored_by) = unsafe { (*proc_p).take_links_and_monitors() };
+    let mut proc = vm.processes.remove(pid).unwrap();
+    let (late_links, late_monitors) = proc.take_links_and_monitors();
+    links.extend(late_links);
+    monitored_by.extend(late_monitors);
+    // The links get the exit reason, a normal exit is ignored by the linked
+    // processes which do not trap exits. A target which has exited meanwhile
+    // is skipped.
+    // TODO: Error reasons should include the stacktrace
+    for link in links {
+      let result = vm
+        .processes
+        .with_process(link, |p| p.deliver_exit(pid, e.1, true));
+      if let Some(Err(err)) = result {
+        error_report::report(format_args!("Exit signal to {} failed: {:?}", link, err));
+      }
+    }
+    for (ref_id, (watcher, target)) in monitored_by {
+      let result = vm.processes.with_process(watcher, |p| {
+        p.deliver_down(ref_id, target, vm.node_name, e.1)
+      });
+      if let Some(Err(err)) = result {
+        error_report::report(format_args!(
+          "Down message to {} failed: {:?}",
+          watcher, err
+        ));
+      }
+    }
+    // The processes monitored by this process forget its monitors
+    for (ref_id, target) in proc.monitors.drain() {
+      if target.is_pid() {
+        vm.processes
+          .with_process(target, |p| p.send_signal(Signal::Demonitor(ref_id)));
+      }
+    }
+    // The processes suspended by this process are resumed
+    for suspendee in proc.suspending.drain(..) {
+      vm.processes
+        .with_process(suspendee, |p| Self::resume_process(p, pid));
+    }
+    drop(proc);
+    if vm.processes.count() == 0 {
+      // Idle schedulers can stop now
+      vm.wake_up_schedulers();
+    }
   }
 
-  /// Called by `Process` when a new message is received. Checks whether the
-  /// process was placed in one of waiting sets and wakes it up.
-  #[inline]
-  pub fn notify_new_incoming_message(
-    &mut self,
-    proc_reg: &mut ProcessRegistry,
-    proc: &mut Process,
-  ) {
+  /// Change the priority of a process, a process which waits in a run queue
+  /// is moved to the run queue of the new priority. A running or a waiting
+  /// process will be queued with the new priority next time.
+  pub fn change_priority(proc: &Process, prio: Prio) {
+    let (_sched, mut queues) = Self::lock_owner_queues(proc);
+    let mut state = proc.lock_sched_state();
+    let old_prio = state.prio;
+    state.prio = prio;
+    if old_prio != prio && queues.remove_queued(old_prio, proc.pid) {
+      queues.get_queue_mut(prio).push_back(proc.pid);
+    }
+  }
+
+  /// Called by a dirty thread when the dirty call of the process has returned,
+  /// queue the process to take the result.
+  pub fn end_dirty_call(proc: &Process) {
+    let (sched, mut queues) = Self::lock_owner_queues(proc);
+    let mut state = proc.lock_sched_state();
+    assert_eq!(state.current_queue, Queue::Dirty);
+    state.current_queue = Queue::None;
+    sched.enqueue_locked(&mut queues, &mut state, proc.pid);
+  }
+
+  /// Suspend the process once more on behalf of `suspender`. With
+  /// `unless_suspending` nothing is done if `suspender` has already suspended
+  /// the process. A running process is suspended at the end of its time slice.
+  /// Returns: false if nothing was done.
+  pub fn suspend_process(
+    proc: &Process,
+    suspender: Term,
+    unless_suspending: bool,
+  ) -> bool {
+    let (sched, mut queues) = Self::lock_owner_queues(proc);
+    let mut state = proc.lock_sched_state();
+    match state
+      .suspended_by
+      .iter_mut()
+      .find(|(pid, _)| *pid == suspender)
+    {
+      Some(_) if unless_suspending => return false,
+      Some((_, count)) => *count += 1,
+      None => state.suspended_by.push((suspender, 1)),
+    }
+
+    // Leave the run queues or the wait sets
+    match state.current_queue {
+      Queue::None => {
+        if queues.remove_queued(state.prio, proc.pid) {
+          sched.queued.fetch_sub(1, Ordering::Relaxed);
+          state.current_queue = Queue::Suspended;
+        }
+      }
+      Queue::InfiniteWait => {
+        queues.infinite_wait.remove(&proc.pid);
+        state.current_queue = Queue::Suspended;
+      }
+      Queue::TimedWait => {
+        queues.timed_wait.remove(&proc.pid);
+        state.current_queue = Queue::Suspended;
+      }
+      _other => {}
+    }
+    true
+  }
+
+  /// Undo one suspend made by `suspender`, the process is queued to run when
+  /// no suspends remain.
+  /// Returns: false if `suspender` has not suspended the process.
+  pub fn resume_process(proc: &Process, suspender: Term) -> bool {
+    let (sched, mut queues) = Self::lock_owner_queues(proc);
+    let mut state = proc.lock_sched_state();
+    let index = match state
+      .suspended_by
+      .iter()
+      .position(|(pid, _)| *pid == suspender)
+    {
+      Some(index) => index,
+      None => return false,
+    };
+    state.suspended_by[index].1 -= 1;
+    if state.suspended_by[index].1 == 0 {
+      state.suspended_by.remove(index);
+    }
+
+    if !state.is_suspended() && state.current_queue == Queue::Suspended {
+      state.current_queue = Queue::None;
+      sched.enqueue_locked(&mut queues, &mut state, proc.pid);
+    }
+    true
+  }
+
+  /// Run `f` on a process which does not run now: it waits in a run queue or
+  /// in a wait set, or it is suspended. The owner's queues lock keeps it
+  /// there until `f` returns, so its heap can be read from another thread.
+  /// Returns: `None` if the process is running, or its dirty call is.
+  pub fn with_stopped_process<F, R>(proc: &Process, f: F) -> Option<R>
+  where
+    F: FnOnce(&Process) -> R,
+  {
+    let (_sched, queues) = Self::lock_owner_queues(proc);
+    let state = proc.lock_sched_state();
+    let stopped = match state.current_queue {
+      Queue::InfiniteWait | Queue::TimedWait | Queue::Suspended => true,
+      Queue::None => queues.is_queued(state.prio, proc.pid),
+      _ => false,
+    };
+    if stopped {
+      Some(f(proc))
+    } else {
+      None
+    }
+  }
+
+  /// Called by `Process` when a new message or another wakeup signal is
+  /// sent to it. Checks whether the process was placed in one of waiting sets
+  /// of its scheduler and wakes it up.
+  /// A waiting process is never stolen, so its owner does not change until
+  /// it is woken up. A suspended process only keeps the message until it is
+  /// resumed, except for an exit signal: then it runs once to handle the
+  /// signal and is suspended again if it survives.
+  pub fn notify_wakeup(proc: &Process, exit: bool) {
+    let (sched, mut queues) = Self::lock_owner_queues(proc);
+    let mut state = proc.lock_sched_state();
+
     // Remove from whatever wait set
-    match proc.current_queue {
+    match state.current_queue {
       Queue::InfiniteWait => {
-        self.infinite_wait.remove(&proc.pid);
-        self.enqueue_opt(proc_reg, proc.pid, true);
+        queues.infinite_wait.remove(&proc.pid);
+        state.current_queue = Queue::None;
+        sched.enqueue_locked(&mut queues, &mut state, proc.pid);
       }
       Queue::TimedWait => {
-        self.timed_wait.remove(&proc.pid);
-        self.enqueue_opt(proc_reg, proc.pid, true);
+        queues.timed_wait.remove(&proc.pid);
+        state.current_queue = Queue::None;
+        sched.enqueue_locked(&mut queues, &mut state, proc.pid);
+      }
+      Queue::Suspended if exit => {
+        state.current_queue = Queue::None;
+        sched.push_run_queue(&mut queues, state.prio, proc.pid);
       }
       _other => {}
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn queue_pids(queue: &mut VecDeque<Term>, first: usize, count: usize) {
+    for i in first..first + count {
+      queue.push_back(Term::make_local_pid(i));
+    }
+  }
+
+  fn pick_all(queues: &mut RunQueues) -> Vec<usize> {
+    let mut order = Vec::new();
+    while let Some(pid) = queues.pick(&Deterministic::disabled()) {
+      order.push(pid.get_term_val_without_tag());
+    }
+    order
+  }
+
+  #[test]
+  fn test_pick_empty() {
+    let mut queues = RunQueues::new();
+    assert_eq!(queues.pick(&Deterministic::disabled()), None);
+  }
+
+  #[test]
+  fn test_pick_max_and_high_first() {
+    let mut queues = RunQueues::new();
+    queue_pids(&mut queues.queue_low, 0, 1);
+    queue_pids(&mut queues.queue_normal, 10, 1);
+    queue_pids(&mut queues.queue_high, 20, 2);
+    queue_pids(&mut queues.queue_max, 30, 1);
+    assert_eq!(pick_all(&mut queues), vec![30, 20, 21, 10, 0]);
+  }
+
+  #[test]
+  fn test_low_runs_after_normal_advantage() {
+    let mut queues = RunQueues::new();
+    queue_pids(&mut queues.queue_normal, 100, 2 * NORMAL_ADVANTAGE + 1);
+    queue_pids(&mut queues.queue_low, 0, 3);
+
+    let order = pick_all(&mut queues);
+    let low_positions: Vec<usize> = order
+      .iter()
+      .enumerate()
+      .filter(|(_, pid)| **pid < 100)
+      .map(|(index, _)| index)
+      .collect();
+    let n = NORMAL_ADVANTAGE;
+    assert_eq!(low_positions, vec![n, 2 * n + 1, 2 * n + 3]);
+  }
+
+  #[test]
+  fn test_low_runs_when_no_normal() {
+    let mut queues = RunQueues::new();
+    queue_pids(&mut queues.queue_low, 0, 2);
+    assert_eq!(pick_all(&mut queues), vec![0, 1]);
+    assert_eq!(queues.advantage_count, 0);
+  }
+
+  #[test]
+  fn test_remove_queued() {
+    let mut queues = RunQueues::new();
+    queue_pids(&mut queues.queue_normal, 0, 3);
+    assert!(queues.remove_queued(Prio::Normal, Term::make_local_pid(1)));
+    assert!(!queues.remove_queued(Prio::High, Term::make_local_pid(2)));
+    assert_eq!(pick_all(&mut queues), vec![0, 2]);
+  }
+}
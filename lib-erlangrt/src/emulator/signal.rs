@@ -0,0 +1,80 @@
+//! Signals are sent to a process by other processes and by the timers,
+//! possibly from another scheduler thread. They wait in the signal queue of
+//! the process until the scheduler thread which runs the process handles them.
+use crate::{
+  defs::Word,
+  emulator::{heap::Heap, module::Module, process_flags::FlagSetting, timer::TimerId},
+  term::value::Term,
+};
+use std::sync::Arc;
+
+pub enum Signal {
+  /// A message, copied to its own heap fragment unless it is an immediate.
+  Message(Term, Option<Heap>),
+  /// The `receive ... after` timer has fired.
+  ReceiveTimeout(TimerId),
+  /// The module is purged, copy its literals which the process refers to.
+  /// The module is freed when every process has handled the signal.
+  PurgeLiterals(Arc<Module>),
+  /// Another process has linked to this process.
+  Link(Term),
+  /// Another process has removed its link to this process.
+  Unlink(Term),
+  /// An exit signal from a process, sent by `erlang:exit/2` or by a linked
+  /// process which has terminated.
+  Exit {
+    from: Term,
+    /// Exit reason, stored inside of `message`
+    reason: Term,
+    /// The `{'EXIT', From, Reason}` message for a process which traps exits
+    message: Term,
+    fragment: Heap,
+    /// The signal comes from a link
+    linked: bool,
+  },
+  /// Another process has started to monitor this process. `target` is the
+  /// pid or the registered name given to `erlang:monitor`.
+  Monitor {
+    watcher: Term,
+    ref_id: Word,
+    target: Term,
+  },
+  /// A monitor of this process was removed.
+  Demonitor(Word),
+  /// A monitored process has terminated, the `{'DOWN', ...}` message is
+  /// delivered unless the monitor was removed meanwhile.
+  Down {
+    ref_id: Word,
+    message: Term,
+    fragment: Heap,
+  },
+  /// `erlang:process_flag/3` was called for this process.
+  ProcessFlag(FlagSetting),
+}
+
+impl Signal {
+  /// Whether the signal wakes up a process which waits in a `receive`.
+  pub fn is_wakeup(&self) -> bool {
+    match self {
+      Signal::Message(_, _)
+      | Signal::ReceiveTimeout(_)
+      | Signal::Exit { .. }
+      | Signal::Down { .. } => true,
+      Signal::PurgeLiterals(_)
+      | Signal::Link(_)
+      | Signal::Unlink(_)
+      | Signal::Monitor { .. }
+      | Signal::Demonitor(_)
+      | Signal::ProcessFlag(_) => false,
+    }
+  }
+
+  /// Whether a running `receive` handles the signal, the others wait until
+  /// the process is swapped in.
+  pub fn is_message(&self) -> bool {
+    match self {
+      Signal::Message(_, _) | Signal::ReceiveTimeout(_) | Signal::Down { .. } => true,
+      _ => false,
+    }
+  }
+}
@@ -1,16 +1,63 @@
-use crate::emulator::{process_flags::ProcessFlags, scheduler::Prio};
+use crate::{
+  defs::Word,
+  emulator::{
+    gen_atoms,
+    heap::gc::{GcSettings, MaxHeapSize},
+    process_flags::{self, ProcessFlags},
+    scheduler::Prio,
+  },
+  fail::{self, RtResult},
+  term::value::{cons, Term},
+};
 
-#[allow(dead_code)]
+/// Where the messages are stored until they are received. `OnHeap` messages
+/// are merged into the process heap on the next GC, `OffHeap` messages stay
+/// in their heap fragments until they are received.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum MessageQueueLocation {
   OnHeap,
   OffHeap,
 }
 
+impl MessageQueueLocation {
+  /// Parse `on_heap` or `off_heap` atom.
+  pub fn from_atom(val: Term) -> RtResult<Self> {
+    match val {
+      gen_atoms::ON_HEAP => Ok(MessageQueueLocation::OnHeap),
+      gen_atoms::OFF_HEAP => Ok(MessageQueueLocation::OffHeap),
+      _ => fail::create::badarg(),
+    }
+  }
+
+  pub fn to_atom(self) -> Term {
+    match self {
+      MessageQueueLocation::OnHeap => gen_atoms::ON_HEAP,
+      MessageQueueLocation::OffHeap => gen_atoms::OFF_HEAP,
+    }
+  }
+}
+
 pub struct SpawnOptions {
   pub msg_queue: MessageQueueLocation,
   pub prio: Prio,
   // TODO: Use bit flags?
   pub process_flags: ProcessFlags,
+  // GC settings, `None` to use the VM global setting
+  /// How many minor GCs can happen before a full sweep.
+  pub fullsweep_after: Option<usize>,
+  /// Smallest process heap size in words.
+  pub min_heap_size: Option<usize>,
+  /// Smallest binary virtual heap size in words.
+  pub min_bin_vheap_size: Option<usize>,
+  /// Heap size limit, the process is killed if the heap grows larger.
+  pub max_heap_size: Option<MaxHeapSize>,
+  /// Link the new process to the parent process.
+  pub link: bool,
+  /// Monitor the new process from the parent process, with this ref id.
+  pub monitor: Option<Word>,
+  /// The `monitor` option was given, the caller creates the reference and
+  /// sets `monitor`.
+  pub monitor_requested: bool,
 }
 
 impl SpawnOptions {
@@ -19,6 +66,77 @@ impl SpawnOptions {
       msg_queue: MessageQueueLocation::OnHeap,
       prio: Prio::Normal,
       process_flags: ProcessFlags::default(),
+      fullsweep_after: None,
+      min_heap_size: None,
+      min_bin_vheap_size: None,
+      max_heap_size: None,
+      link: false,
+      monitor: None,
+      monitor_requested: false,
+    }
+  }
+
+  /// Combine the GC settings given in the options with the global defaults.
+  pub fn get_gc_settings(&self, defaults: &GcSettings) -> GcSettings {
+    GcSettings {
+      min_heap_size: self.min_heap_size.unwrap_or(defaults.min_heap_size),
+      min_bin_vheap_size: self
+        .min_bin_vheap_size
+        .unwrap_or(defaults.min_bin_vheap_size),
+      fullsweep_after: self.fullsweep_after.unwrap_or(defaults.fullsweep_after),
+      max_heap_size: self.max_heap_size.unwrap_or(defaults.max_heap_size),
+    }
+  }
+
+  /// Parse the option list given to `erlang:spawn_opt` by a process with
+  /// `caller_flags`. Unknown or malformed options result in `badarg`.
+  pub fn from_list(opts: Term, caller_flags: &ProcessFlags) -> RtResult<Self> {
+    let mut result = Self::default();
+    cons::for_each(opts, |opt| result.parse_option(opt, caller_flags))?;
+    Ok(result)
+  }
+
+  fn parse_option(&mut self, opt: Term, caller_flags: &ProcessFlags) -> RtResult<()> {
+    if opt == gen_atoms::LINK {
+      self.link = true;
+      return Ok(());
+    }
+    if opt == gen_atoms::MONITOR {
+      self.monitor_requested = true;
+      return Ok(());
+    }
+    if !opt.is_tuple() {
+      return fail::create::badarg();
+    }
+    let tuple = opt.get_tuple_ptr();
+    if unsafe { (*tuple).get_arity() } != 2 {
+      return fail::create::badarg();
+    }
+    let (key, val) = unsafe { ((*tuple).get_element(0), (*tuple).get_element(1)) };
+    if key == gen_atoms::MESSAGE_QUEUE_DATA {
+      self.msg_queue = MessageQueueLocation::from_atom(val)?;
+      return Ok(());
+    }
+    if key == gen_atoms::PRIORITY {
+      let prio = Prio::from_atom(val)?;
+      process_flags::check_priority_allowed(caller_flags, prio)?;
+      self.prio = prio;
+      return Ok(());
+    }
+    if key == gen_atoms::MAX_HEAP_SIZE {
+      self.max_heap_size = Some(MaxHeapSize::from_term(val)?);
+      return Ok(());
+    }
+    if !val.is_small() || val.get_small_signed() < 0 {
+      return fail::create::badarg();
+    }
+    let val = Some(val.get_small_unsigned());
+    match key {
+      gen_atoms::FULLSWEEP_AFTER => self.fullsweep_after = val,
+      gen_atoms::MIN_HEAP_SIZE => self.min_heap_size = val,
+      gen_atoms::MIN_BIN_VHEAP_SIZE => self.min_bin_vheap_size = val,
+      _ => return fail::create::badarg(),
     }
+    Ok(())
   }
 }
@@ -0,0 +1,138 @@
+//! Counters for `erlang:statistics/1`. Each scheduler counts its own work in
+//! relaxed atomics once per time slice, the readers sum the counters of all
+//! schedulers, so counting costs next to nothing while nobody reads them.
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::{
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+/// Counters of one scheduler, written by its thread and read by any thread.
+#[derive(Default)]
+pub struct SchedulerStats {
+  /// How many times a process was scheduled in
+  pub context_switches: AtomicU64,
+  pub reductions: AtomicU64,
+  /// Time spent running processes, in nanoseconds
+  pub active_ns: AtomicU64,
+  pub gc_count: AtomicU64,
+  pub gc_words_reclaimed: AtomicU64,
+}
+
+impl SchedulerStats {
+  #[inline]
+  fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+  }
+
+  #[inline]
+  pub fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+  }
+
+  /// A process has run for `elapsed` time.
+  pub fn count_timeslice(&self, elapsed: Duration) {
+    Self::add(&self.context_switches, 1);
+    Self::add(&self.active_ns, elapsed.as_nanos() as u64);
+  }
+
+  pub fn count_reductions(&self, reductions: usize) {
+    Self::add(&self.reductions, reductions as u64);
+  }
+
+  pub fn count_gc(&self, words_reclaimed: usize) {
+    Self::add(&self.gc_count, 1);
+    Self::add(&self.gc_words_reclaimed, words_reclaimed as u64);
+  }
+}
+
+/// Values returned by the previous call, to report the change since then.
+#[derive(Default, Clone, Copy)]
+struct LastRead {
+  reductions: u64,
+  runtime_ms: u64,
+  wall_clock_ms: u64,
+}
+
+/// Which "since the last call" value to read.
+#[derive(Clone, Copy)]
+pub enum SinceLast {
+  Reductions,
+  Runtime,
+  WallClock,
+}
+
+/// VM wide part of the statistics.
+pub struct VmStats {
+  started: Instant,
+  last: Mutex<LastRead>,
+  /// When `scheduler_wall_time` was enabled, and the active time of each
+  /// scheduler at that moment. `None` while disabled.
+  wall_time: Mutex<Option<(Instant, Vec<u64>)>>,
+}
+
+impl VmStats {
+  pub fn new() -> Self {
+    Self {
+      started: Instant::now(),
+      last: Mutex::new(LastRead::default()),
+      wall_time: Mutex::new(None),
+    }
+  }
+
+  /// Milliseconds since the VM has started.
+  pub fn get_wall_clock_ms(&self) -> u64 {
+    self.started.elapsed().as_millis() as u64
+  }
+
+  /// The value `key` had when `set_last` was called the last time.
+  pub fn get_last(&self, key: SinceLast) -> u64 {
+    let last = self.last.lock().unwrap();
+    match key {
+      SinceLast::Reductions => last.reductions,
+      SinceLast::Runtime => last.runtime_ms,
+      SinceLast::WallClock => last.wall_clock_ms,
+    }
+  }
+
+  /// Remember the value which was returned, after the result was built.
+  pub fn set_last(&self, key: SinceLast, value: u64) {
+    let mut last = self.last.lock().unwrap();
+    match key {
+      SinceLast::Reductions => last.reductions = value,
+      SinceLast::Runtime => last.runtime_ms = value,
+      SinceLast::WallClock => last.wall_clock_ms = value,
+    }
+  }
+
+  pub fn is_wall_time_enabled(&self) -> bool {
+    self.wall_time.lock().unwrap().is_some()
+  }
+
+  /// Enable or disable `scheduler_wall_time`, `active_ns` is the current
+  /// active time of each scheduler. Returns the previous state.
+  pub fn set_wall_time(&self, enable: bool, active_ns: Vec<u64>) -> bool {
+    let mut wall_time = self.wall_time.lock().unwrap();
+    let was_enabled = wall_time.is_some();
+    if !enable {
+      *wall_time = None;
+    } else if !was_enabled {
+      *wall_time = Some((Instant::now(), active_ns));
+    }
+    was_enabled
+  }
+
+  /// Active and total time of each scheduler in nanoseconds since
+  /// `scheduler_wall_time` was enabled, `None` while disabled.
+  pub fn get_wall_time(&self, active_ns: &[u64]) -> Option<Vec<(u64, u64)>> {
+    let wall_time = self.wall_time.lock().unwrap();
+    let (since, start_ns) = wall_time.as_ref()?;
+    let total = since.elapsed().as_nanos() as u64;
+    let times = active_ns
+      .iter()
+      .zip(start_ns.iter())
+      .map(|(now, start)| (now - start, total))
+      .collect();
+    Some(times)
+  }
+}
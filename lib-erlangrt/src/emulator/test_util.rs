@@ -0,0 +1,115 @@
+//! Helpers for the unit tests which run processes. There is no Erlang compiler
+//! here, so the processes run the code of a small hand-assembled module.
+use crate::{
+  beam::gen_op,
+  command_line_args::ErlStartArgs,
+  defs::Word,
+  emulator::{
+    atom,
+    code::opcode,
+    funarity::FunArity,
+    heap::heap_trait::THeap,
+    mfa::ModFunArgs,
+    module::{Module, VersionedModuleName},
+    process::Process,
+    spawn_options::SpawnOptions,
+    vm::VM,
+  },
+  term::{
+    term_builder::{list_builder::ListBuilder, tuple_builder::tuple2},
+    value::Term,
+  },
+};
+
+/// A VM with one scheduler. Run its processes with `run_until_idle`.
+pub fn new_test_vm() -> VM {
+  let mut args = ErlStartArgs::new(&Vec::new());
+  args.schedulers = 1;
+  args.dirty_cpu_schedulers = 1;
+  args.dirty_io_schedulers = 1;
+  VM::new(&mut args)
+}
+
+/// Load the test module with the name `m` (use a different name in each test,
+/// the literal areas are registered globally). Returns the module name atom.
+/// Functions of the module:
+/// `done/0` and `done/1` return right away, the process exits with reason
+/// `normal`;
+/// `wait/0` waits for messages forever, they stay in the mailbox;
+/// `loop/0` calls itself forever, it spends reductions and never waits.
+pub fn load_test_module(vm: &VM, m: &str) -> Term {
+  let name = atom::from_str(m);
+  let mut module = Module::new(&VersionedModuleName::new(name, 1));
+  let op = |raw| opcode::to_memory_word(raw);
+  let arity_0 = Term::small_0().raw();
+  module.code = vec![
+    op(gen_op::OPCODE_RETURN),
+    op(gen_op::OPCODE_WAIT),
+    0,
+    op(gen_op::OPCODE_CALL_ONLY),
+    arity_0,
+    0,
+  ];
+  // The wait and the call loop back to themselves
+  let code_p = module.code.as_ptr();
+  module.code[2] = Term::make_cp(unsafe { code_p.add(1) }).raw();
+  module.code[5] = Term::make_cp(unsafe { code_p.add(3) }).raw();
+  let funs = &mut module.funs;
+  funs.insert(FunArity::new(atom::from_str("done"), 0), 0);
+  funs.insert(FunArity::new(atom::from_str("done"), 1), 0);
+  funs.insert(FunArity::new(atom::from_str("wait"), 0), 1);
+  funs.insert(FunArity::new(atom::from_str("loop"), 0), 3);
+  vm.code_server
+    .write()
+    .unwrap()
+    .module_loaded(Box::new(module))
+    .unwrap();
+  name
+}
+
+/// Load two empty versions of the module `m`, the old version has the literal
+/// `{hello, hello}`. Returns the literal and the memory ranges of the literal
+/// area, to check that nothing points there after the purge.
+pub fn load_old_literal(vm: &VM, m: &str) -> (Term, Vec<(*const Word, *const Word)>) {
+  let name = atom::from_str(m);
+  let mut old_mod = Module::new(&VersionedModuleName::new(name, 1));
+  let hello = atom::from_str("hello");
+  let literal = tuple2(&mut old_mod.lit_heap, hello, hello).unwrap();
+  let literal_areas = old_mod.lit_heap.get_memory_ranges();
+  let new_mod = Module::new(&VersionedModuleName::new(name, 2));
+  let mut code_server = vm.code_server.write().unwrap();
+  code_server.module_loaded(Box::new(old_mod)).unwrap();
+  code_server.module_loaded(Box::new(new_mod)).unwrap();
+  (literal, literal_areas)
+}
+
+/// Spawn a process running the function `f` of the test module `m`.
+pub fn spawn(vm: &VM, m: Term, f: &str, spawn_opts: &SpawnOptions) -> Term {
+  let mfargs = ModFunArgs::with_args_list(m, atom::from_str(f), Term::nil());
+  vm.create_process(Term::nil(), &mfargs, spawn_opts).unwrap()
+}
+
+/// Access a process which is not running, to call the native functions on it
+/// or to check its state. Do not use the process after it has exited.
+pub fn get_process<'a>(vm: &VM, pid: Term) -> &'a mut Process {
+  let p = vm.processes.unsafe_lookup_pid_mut(pid);
+  assert!(!p.is_null(), "Process {} does not exist", pid);
+  unsafe { &mut (*p) }
+}
+
+/// Run the processes until all have exited or are waiting.
+pub fn run_until_idle(vm: &VM) {
+  while vm.tick().unwrap() {}
+}
+
+/// Build a list of `items` on the heap.
+pub fn make_list(hp: &mut THeap, items: &[Term]) -> Term {
+  if items.is_empty() {
+    return Term::nil();
+  }
+  let mut lb = ListBuilder::new().unwrap();
+  for item in items.iter() {
+    unsafe { lb.append(*item, hp).unwrap() };
+  }
+  unsafe { lb.make_term_with_tail(Term::nil()) }
+}
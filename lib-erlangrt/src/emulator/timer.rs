@@ -0,0 +1,308 @@
+//! Timers shared by the schedulers, ordered by their deadline on the monotonic
+//! clock. Cancelled timers are removed from the index and skipped lazily when
+//! they reach the top of the heap. Any scheduler thread can fire the expired
+//! timers, the result is delivered to the target process as a signal.
+use crate::{
+  defs::Word,
+  emulator::{heap::Heap, process::Process, process_flags},
+  term::value::Term,
+};
+use core::cmp::Reverse;
+use std::{
+  collections::{BinaryHeap, HashMap},
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+/// Unique id of a timer, never reused.
+pub type TimerId = u64;
+
+/// A message sent by `erlang:send_after` or `erlang:start_timer` when the
+/// timer fires.
+pub struct TimerMessage {
+  /// Id of the reference which was returned to the caller.
+  pub ref_id: Word,
+  /// A pid or a registered name.
+  pub dest: Term,
+  pub message: Term,
+  /// The message data is copied here while the timer is running.
+  pub fragment: Option<Heap>,
+}
+
+/// What happens when the timer fires.
+pub enum TimerAction {
+  /// The `receive ... after` of a process has timed out.
+  ReceiveTimeout(Term),
+  /// Send a message to a process.
+  SendMessage(Box<TimerMessage>),
+}
+
+impl TimerAction {
+  /// The process which owns the timer, the timer is cancelled when the
+  /// owner exits. Timers targeting a registered name have no owner.
+  fn get_owner(&self) -> Option<Term> {
+    match self {
+      TimerAction::ReceiveTimeout(pid) => Some(*pid),
+      TimerAction::SendMessage(msg) if msg.dest.is_pid() => Some(msg.dest),
+      TimerAction::SendMessage(_) => None,
+    }
+  }
+
+  fn get_ref_id(&self) -> Option<Word> {
+    match self {
+      TimerAction::SendMessage(msg) => Some(msg.ref_id),
+      TimerAction::ReceiveTimeout(_) => None,
+    }
+  }
+}
+
+struct Timer {
+  deadline: Instant,
+  action: TimerAction,
+}
+
+pub struct TimerHeap {
+  /// Deadlines and timer ids, the earliest on top.
+  queue: BinaryHeap<Reverse<(Instant, TimerId)>>,
+  /// Active timers, a cancelled timer is only removed from here.
+  timers: HashMap<TimerId, Timer>,
+  /// Timers which were given a reference, by the reference id.
+  by_ref: HashMap<Word, TimerId>,
+  next_id: TimerId,
+}
+
+impl TimerHeap {
+  pub fn new() -> Self {
+    Self {
+      queue: BinaryHeap::new(),
+      timers: HashMap::new(),
+      by_ref: HashMap::new(),
+      next_id: 1,
+    }
+  }
+
+  /// Add a timer which fires at `deadline`.
+  pub fn start(&mut self, deadline: Instant, action: TimerAction) -> TimerId {
+    let id = self.next_id;
+    self.next_id += 1;
+    self.queue.push(Reverse((deadline, id)));
+    if let Some(ref_id) = action.get_ref_id() {
+      self.by_ref.insert(ref_id, id);
+    }
+    self.timers.insert(id, Timer { deadline, action });
+    id
+  }
+
+  /// Cancel a timer, returns its deadline if it was still active.
+  pub fn cancel(&mut self, id: TimerId) -> Option<Instant> {
+    self.remove(id).map(|t| t.deadline)
+  }
+
+  /// Find an active timer by the id of its reference.
+  pub fn find_by_ref(&self, ref_id: Word) -> Option<TimerId> {
+    self.by_ref.get(&ref_id).cloned()
+  }
+
+  /// Remove an active timer regardless of its deadline, and return it.
+  pub fn take(&mut self, id: TimerId) -> Option<TimerAction> {
+    self.remove(id).map(|t| t.action)
+  }
+
+  /// Deadline of an active timer.
+  pub fn get_deadline(&self, id: TimerId) -> Option<Instant> {
+    self.timers.get(&id).map(|t| t.deadline)
+  }
+
+  /// Cancel all timers owned by the process `pid`.
+  pub fn cancel_owned_by(&mut self, pid: Term) {
+    let owned: Vec<TimerId> = self
+      .timers
+      .iter()
+      .filter(|(_, t)| t.action.get_owner() == Some(pid))
+      .map(|(id, _)| *id)
+      .collect();
+    for id in owned {
+      self.remove(id);
+    }
+  }
+
+  /// Deadline of the earliest active timer.
+  pub fn next_deadline(&mut self) -> Option<Instant> {
+    self.skip_cancelled();
+    self.queue.peek().map(|Reverse((deadline, _))| *deadline)
+  }
+
+  /// Remove and return the next timer which has expired at `now`.
+  pub fn pop_expired(&mut self, now: Instant) -> Option<(TimerId, TimerAction)> {
+    self.skip_cancelled();
+    match self.queue.peek() {
+      Some(Reverse((deadline, id))) if *deadline <= now => {
+        let id = *id;
+        self.queue.pop();
+        self.remove(id).map(|t| (id, t.action))
+      }
+      _ => None,
+    }
+  }
+
+  fn remove(&mut self, id: TimerId) -> Option<Timer> {
+    let timer = self.timers.remove(&id)?;
+    if let Some(ref_id) = timer.action.get_ref_id() {
+      self.by_ref.remove(&ref_id);
+    }
+    Some(timer)
+  }
+
+  fn skip_cancelled(&mut self) {
+    while let Some(Reverse((_, id))) = self.queue.peek() {
+      if self.timers.contains_key(id) {
+        break;
+      }
+      self.queue.pop();
+    }
+  }
+}
+
+/// The timer heap shared by the scheduler threads.
+pub struct Timers {
+  heap: Mutex<TimerHeap>,
+}
+
+impl Timers {
+  pub fn new() -> Self {
+    Self {
+      heap: Mutex::new(TimerHeap::new()),
+    }
+  }
+
+  /// Remove and return the next timer which has expired at `now`.
+  pub fn pop_expired(&self, now: Instant) -> Option<(TimerId, TimerAction)> {
+    self.heap.lock().unwrap().pop_expired(now)
+  }
+
+  /// Deadline of the earliest active timer.
+  pub fn next_deadline(&self) -> Option<Instant> {
+    self.heap.lock().unwrap().next_deadline()
+  }
+
+  /// Remove and return an active timer before its deadline.
+  pub fn take(&self, id: TimerId) -> Option<(TimerId, TimerAction)> {
+    let action = self.heap.lock().unwrap().take(id)?;
+    Some((id, action))
+  }
+
+  /// Start the timer for `receive ... after` of the process.
+  pub fn start_receive_timeout(&self, proc: &mut Process, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let action = TimerAction::ReceiveTimeout(proc.pid);
+    proc.receive_timer = Some(self.heap.lock().unwrap().start(deadline, action));
+  }
+
+  /// A message was received before the timeout, stop the timer.
+  pub fn cancel_receive_timeout(&self, proc: &mut Process) {
+    if let Some(timer) = proc.receive_timer.take() {
+      self.heap.lock().unwrap().cancel(timer);
+    }
+    proc
+      .process_flags
+      .set_value(process_flags::TIMED_OUT, false);
+  }
+
+  /// Start a timer which sends a message after `timeout`.
+  pub fn start_message_timer(&self, timeout: Duration, msg: TimerMessage) {
+    let deadline = Instant::now() + timeout;
+    let action = TimerAction::SendMessage(Box::new(msg));
+    self.heap.lock().unwrap().start(deadline, action);
+  }
+
+  /// Time left until a message timer fires, `None` if the timer does not
+  /// exist or has already fired.
+  pub fn read_message_timer(&self, ref_id: Word) -> Option<Duration> {
+    let heap = self.heap.lock().unwrap();
+    let deadline = heap.get_deadline(heap.find_by_ref(ref_id)?)?;
+    Some(deadline.saturating_duration_since(Instant::now()))
+  }
+
+  /// Stop a message timer, returns the time which was left.
+  pub fn cancel_message_timer(&self, ref_id: Word) -> Option<Duration> {
+    let mut heap = self.heap.lock().unwrap();
+    let id = heap.find_by_ref(ref_id)?;
+    let deadline = heap.cancel(id)?;
+    Some(deadline.saturating_duration_since(Instant::now()))
+  }
+
+  /// Cancel all timers owned by the process `pid`, it has exited.
+  pub fn cancel_owned_by(&self, pid: Term) {
+    self.heap.lock().unwrap().cancel_owned_by(pid)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulator::gen_atoms;
+
+  fn pop_timeout(timers: &mut TimerHeap, now: Instant) -> Option<Term> {
+    match timers.pop_expired(now) {
+      Some((_, TimerAction::ReceiveTimeout(pid))) => Some(pid),
+      Some(_) => panic!("A receive timeout was expected"),
+      None => None,
+    }
+  }
+
+  fn message_timer(ref_id: Word, dest: Term) -> TimerAction {
+    TimerAction::SendMessage(Box::new(TimerMessage {
+      ref_id,
+      dest,
+      message: Term::nil(),
+      fragment: None,
+    }))
+  }
+
+  #[test]
+  fn test_timers_fire_in_deadline_order() {
+    let mut timers = TimerHeap::new();
+    let now = Instant::now();
+    let ms = Duration::from_millis;
+    let pid = Term::make_local_pid;
+    timers.start(now + ms(30), TimerAction::ReceiveTimeout(pid(3)));
+    let first = timers.start(now + ms(10), TimerAction::ReceiveTimeout(pid(1)));
+    timers.start(now + ms(20), TimerAction::ReceiveTimeout(pid(2)));
+    assert_eq!(timers.next_deadline(), Some(now + ms(10)));
+    assert_eq!(pop_timeout(&mut timers, now), None);
+
+    // A cancelled timer is skipped
+    assert_eq!(timers.cancel(first), Some(now + ms(10)));
+    assert_eq!(timers.cancel(first), None);
+    assert_eq!(timers.next_deadline(), Some(now + ms(20)));
+
+    let later = now + ms(25);
+    assert_eq!(pop_timeout(&mut timers, later), Some(pid(2)));
+    assert_eq!(pop_timeout(&mut timers, later), None);
+    assert_eq!(pop_timeout(&mut timers, now + ms(30)), Some(pid(3)));
+    assert_eq!(timers.next_deadline(), None);
+  }
+
+  #[test]
+  fn test_timers_by_ref_and_owner() {
+    let mut timers = TimerHeap::new();
+    let now = Instant::now();
+    let ms = Duration::from_millis;
+    let pid = Term::make_local_pid(5);
+    let to_pid = timers.start(now + ms(10), message_timer(1, pid));
+    let to_name = timers.start(now + ms(20), message_timer(2, gen_atoms::INIT));
+    timers.start(now + ms(30), TimerAction::ReceiveTimeout(pid));
+    assert_eq!(timers.find_by_ref(1), Some(to_pid));
+    assert_eq!(timers.get_deadline(to_pid), Some(now + ms(10)));
+
+    // The timers sending to a registered name have no owner
+    timers.cancel_owned_by(pid);
+    assert_eq!(timers.find_by_ref(1), None);
+    assert_eq!(timers.find_by_ref(2), Some(to_name));
+    assert_eq!(timers.next_deadline(), Some(now + ms(20)));
+
+    assert!(timers.take(to_name).is_some());
+    assert_eq!(timers.find_by_ref(2), None);
+    assert!(timers.pop_expired(now + ms(100)).is_none());
+  }
+}
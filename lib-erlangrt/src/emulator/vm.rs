@@ -5,78 +5,216 @@ use crate::{
   command_line_args::ErlStartArgs,
   defs::Word,
   emulator::{
+    atom,
     code_srv::CodeServer,
+    deterministic::Deterministic,
+    dirty_scheduler::{DirtyKind, DirtySchedulers},
     mfa::ModFunArgs,
     process::Process,
     process_registry::ProcessRegistry,
-    scheduler::{Scheduler},
+    runtime_ctx::call_native_fun,
+    scheduler::Scheduler,
+    signal::Signal,
     spawn_options::SpawnOptions,
+    statistics::{SchedulerStats, VmStats},
+    timer::Timers,
   },
   fail::RtResult,
   term::value::*,
 };
 use crate::emulator::process_flags;
-use crate::emulator::heap::{Heap, Designation};
+use crate::emulator::heap::{gc::GcSettings, literal_area};
+use std::{
+  panic,
+  sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex, RwLock,
+  },
+  thread,
+  time::Duration,
+};
+
+/// How long an idle dirty thread waits for work before it checks whether the
+/// VM is still running.
+const DIRTY_THREAD_WAIT: Duration = Duration::from_millis(10);
+
+/// How the VM has stopped, returned by `VM::run`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HaltStatus {
+  /// All processes have exited
+  Normal,
+  /// `erlang:halt` was called with the status code
+  Halt(i32),
+}
+
+impl HaltStatus {
+  /// Exit code for the OS process.
+  pub fn get_exit_code(self) -> i32 {
+    match self {
+      HaltStatus::Normal => 0,
+      HaltStatus::Halt(code) => code,
+    }
+  }
+}
 
 /// VM environment, heaps, tables, processes all goes here.
 /// Atoms are a global API in `atom.rs`.
 /// Code server is a global API in `code_srv.rs`.
+/// The VM is shared by the scheduler threads, so every field which can change
+/// is protected by a lock or is atomic.
 pub struct VM {
   /// Pid counter increments every time a new process is spawned
-  pid_counter: Word,
+  pid_counter: AtomicUsize,
+  /// Reference counter increments every time a new reference is created
+  ref_counter: AtomicUsize,
 
   /// Contains all loaded modules and manages versions
-  pub code_server: CodeServer,
+  pub code_server: RwLock<CodeServer>,
+
+  /// Name of this node as an atom
+  pub node_name: Term,
 
-  pub scheduler: Scheduler,
+  /// One scheduler per scheduler thread
+  pub schedulers: Vec<Scheduler>,
+  /// Thread pools for the dirty native functions
+  pub dirty_schedulers: DirtySchedulers,
   pub processes: ProcessRegistry,
-  pub binary_heap: Heap,
+  /// Receive timeouts and message timers of all processes
+  pub timers: Timers,
+
+  /// Global default GC settings, can be overridden per process in
+  /// `SpawnOptions`.
+  pub gc_settings: RwLock<GcSettings>,
+  /// Seeded scheduling and the record/replay log, if switched on
+  pub deterministic: Deterministic,
+
+  /// Set when the VM halts or when a thread has failed, the others stop too
+  stop: Arc<AtomicBool>,
+  /// Set by `erlang:halt`, the first call wins
+  halt_status: Mutex<Option<HaltStatus>>,
+  /// VM wide part of `erlang:statistics`, the counters are in the schedulers
+  pub stats: VmStats,
+}
+
+/// Pointer to the VM given to the scheduler threads, the VM outlives them
+/// because `VM::run` joins the threads before returning.
+struct VmPtr(*const VM);
+
+unsafe impl Send for VmPtr {}
+
+/// Stops the other threads when a scheduler or a dirty thread panics.
+struct StopOnPanic(Arc<AtomicBool>);
+
+impl Drop for StopOnPanic {
+  fn drop(&mut self) {
+    if thread::panicking() {
+      self.0.store(true, Ordering::SeqCst);
+    }
+  }
 }
 
 impl VM {
   /// Create a VM, multiple VMs can be created but atom table and code server
   /// will be shared (global).
   pub fn new(args: &mut ErlStartArgs) -> VM {
+    let deterministic = Deterministic::new(&args.deterministic).unwrap_or_else(|e| {
+      println!("vm: Failed to start the deterministic mode: {}", e);
+      Deterministic::disabled()
+    });
+    // Deterministic mode runs everything on one thread
+    let n_schedulers = if deterministic.is_enabled() {
+      1
+    } else {
+      args.schedulers.max(1)
+    };
+    let schedulers = (0..n_schedulers).map(Scheduler::new).collect();
     VM {
-      code_server: CodeServer::new(args),
-      pid_counter: 0,
-      scheduler: Scheduler::new(),
+      code_server: RwLock::new(CodeServer::new(args)),
+      pid_counter: AtomicUsize::new(0),
+      ref_counter: AtomicUsize::new(0),
+      node_name: atom::from_str(args.get_node_name()),
+      schedulers,
+      dirty_schedulers: DirtySchedulers::new(
+        args.dirty_cpu_schedulers.max(1),
+        args.dirty_io_schedulers.max(1),
+      ),
       processes: ProcessRegistry::new(),
-      binary_heap: Heap::new(Designation::BinaryHeap),
+      timers: Timers::new(),
+      gc_settings: RwLock::new(GcSettings::default()),
+      deterministic,
+      stop: Arc::new(AtomicBool::new(false)),
+      halt_status: Mutex::new(None),
+      stats: VmStats::new(),
+    }
+  }
+
+  /// Stop all threads of the VM with the status `code`, the processes which
+  /// are still alive do not run any more.
+  pub fn halt(&self, code: i32) {
+    {
+      let mut status = self.halt_status.lock().unwrap();
+      if status.is_none() {
+        *status = Some(HaltStatus::Halt(code));
+      }
+    }
+    self.stop.store(true, Ordering::SeqCst);
+    self.wake_up_schedulers();
+  }
+
+  #[inline]
+  pub fn is_stopping(&self) -> bool {
+    self.stop.load(Ordering::Relaxed)
+  }
+
+  /// Let the idle schedulers check the processes count and the stop flag.
+  pub fn wake_up_schedulers(&self) {
+    for sched in self.schedulers.iter() {
+      sched.wake_up();
     }
   }
 
-  /// Dirty trick to not have to dynamically borrow scheduler via
-  /// `RefCell<Box<>>` because schedulers live just as long as the VM itself.
-  #[allow(dead_code)]
-  pub fn get_scheduler_p(&self) -> *mut Scheduler {
-    let p = &self.scheduler as *const Scheduler;
-    p as *mut Scheduler
+  /// Sum a statistics counter over all schedulers.
+  pub fn sum_scheduler_stats(&self, counter: fn(&SchedulerStats) -> &AtomicU64) -> u64 {
+    let counters = self.schedulers.iter().map(|sched| counter(&sched.stats));
+    counters.map(SchedulerStats::get).sum()
   }
 
-  /// Dirty trick to not have to dynamically borrow code server via
-  /// `RefCell<Box<>>` because code server lives just as long as the VM itself.
-  pub fn get_code_server_p(&self) -> *mut CodeServer {
-    let p = &self.code_server as *const CodeServer;
-    p as *mut CodeServer
+  /// Time each scheduler has spent running processes, in nanoseconds.
+  pub fn get_scheduler_active_ns(&self) -> Vec<u64> {
+    let counters = self.schedulers.iter().map(|sched| &sched.stats.active_ns);
+    counters.map(SchedulerStats::get).collect()
+  }
+
+  /// Take a new id for a local reference, unique in this VM.
+  pub fn next_ref_id(&self) -> Word {
+    self.ref_counter.fetch_add(1, Ordering::Relaxed)
   }
 
   /// Spawn a new process, create a new pid, register the process and jump to
   /// the MFA specified. Arguments are copies into the new process heap and
   /// stored into the registers.
   pub fn create_process(
-    &mut self,
+    &self,
     parent: Term,
     mfargs: &ModFunArgs,
     spawn_opts: &SpawnOptions,
   ) -> RtResult<Term> {
-    let pid_c = self.pid_counter;
-    self.pid_counter += 1;
+    let pid_c = self.pid_counter.fetch_add(1, Ordering::Relaxed);
 
     let pid = Term::make_local_pid(pid_c);
     let mfarity = mfargs.get_mfarity()?;
-    let cs = self.get_code_server_p();
-    let mut p0 = Process::new(pid, parent, &mfarity, spawn_opts, unsafe { &mut (*cs) })?;
+    let gc_settings = spawn_opts.get_gc_settings(&self.gc_settings.read().unwrap());
+    let mut p0 = {
+      let mut code_server = self.code_server.write().unwrap();
+      Process::new(
+        pid,
+        parent,
+        &mfarity,
+        spawn_opts,
+        gc_settings,
+        &mut code_server,
+      )?
+    };
 
     // Error may happen here due to arg term copy error
     p0.set_spawn_args(&mfargs)?;
@@ -86,29 +224,266 @@ impl VM {
   }
 
   pub fn spawn_system_process(
-    &mut self,
+    &self,
     parent: Term,
     mfargs: &ModFunArgs,
     mut spawn_opts: SpawnOptions,
   ) -> RtResult<Term> {
     let mfarity = mfargs.get_mfarity()?;
     // Fail if MFA not found, otherwise continue
-    let _ = self.code_server.lookup_mfa(&mfarity, false)?;
+    let _ = self
+      .code_server
+      .write()
+      .unwrap()
+      .lookup_mfa(&mfarity, false)?;
     spawn_opts.process_flags.set(process_flags::SYSTEM_PROCESS);
     self.create_process(parent, mfargs, &spawn_opts)
   }
 
-  pub fn register_new_process(&mut self, pid: Term, mut proc: Process) {
-    proc.owned_by_scheduler = (&mut self.scheduler) as *mut Scheduler;
+  /// Remove the old version of module `m`, processes which still refer to its
+  /// literals get their own copies of them first. The copying is done by
+  /// each process when it handles the signal, the signals share the module
+  /// and it is freed when the last process has handled its signal.
+  /// Messages sent meanwhile by the processes which have not copied yet get
+  /// their own copies of the literals, same as the timer messages always do.
+  /// Returns false if there was no old version.
+  pub fn purge_module(&self, m: Term) -> bool {
+    let old_mod = match self.code_server.write().unwrap().take_old_module(m) {
+      Some(old_mod) => {
+        literal_area::mark_purged(&old_mod.versioned_name);
+        Arc::from(old_mod)
+      }
+      None => return false,
+    };
+    self
+      .processes
+      .for_each(|p| p.send_signal(Signal::PurgeLiterals(Arc::clone(&old_mod))));
+    true
+  }
+
+  /// Register the process and queue it on the least loaded scheduler.
+  pub fn register_new_process(&self, pid: Term, proc: Process) {
+    let sched = self
+      .schedulers
+      .iter()
+      .min_by_key(|s| s.get_queued_count())
+      .unwrap();
+    proc.set_owner(sched);
     self.processes.insert(pid, proc);
-    self.scheduler.enqueue(&mut self.processes, pid);
+    self.processes.with_process(pid, |p| sched.enqueue(p));
   }
 
-  /// Run the VM loop (one time slice), call this repeatedly to run forever.
+  /// Run the VM loop (one time slice) on the first scheduler, call this
+  /// repeatedly to run forever. Dirty calls are run on the calling thread.
   /// Time slice ends when a current process yields or when reduction count
   /// reaches zero.
   #[inline]
-  pub fn tick(&mut self) -> RtResult<bool> {
-    self.dispatch()
+  pub fn tick(&self) -> RtResult<bool> {
+    let result = self.dispatch(0);
+    while self.run_dirty_call(DirtyKind::Cpu, Duration::from_millis(0)) {}
+    while self.run_dirty_call(DirtyKind::Io, Duration::from_millis(0)) {}
+    result
+  }
+
+  /// Run the processes on the scheduler threads, one thread per scheduler,
+  /// and on the dirty threads until all processes have exited or until
+  /// `erlang:halt` is called. Idle threads sleep until there is work or the
+  /// next timer deadline. An error on any thread stops the VM, the first
+  /// error is returned.
+  pub fn run(&self) -> RtResult<HaltStatus> {
+    let result = if self.deterministic.is_enabled() {
+      self.run_deterministic()
+    } else {
+      self.run_threads()
+    };
+    result?;
+    let status = self.halt_status.lock().unwrap().take();
+    let status = status.unwrap_or(HaltStatus::Normal);
+    if status == HaltStatus::Normal {
+      println!("vm: All processes have exited, this is the end.");
+    }
+    Ok(status)
+  }
+
+  fn run_threads(&self) -> RtResult<()> {
+    let mut threads = Vec::new();
+    let dirty_cpu = self.dirty_schedulers.cpu_threads;
+    let dirty_io = self.dirty_schedulers.io_threads;
+    let all_threads = (0..self.schedulers.len())
+      .map(|index| (format!("scheduler{}", index), None))
+      .chain((0..dirty_cpu).map(|i| (format!("dirty_cpu{}", i), Some(DirtyKind::Cpu))))
+      .chain((0..dirty_io).map(|i| (format!("dirty_io{}", i), Some(DirtyKind::Io))));
+    for (index, (name, dirty)) in all_threads.enumerate() {
+      let vm_p = VmPtr(self as *const VM);
+      let spawn_result = thread::Builder::new().name(name).spawn(move || {
+        let vm = unsafe { &*vm_p.0 };
+        match dirty {
+          None => vm.scheduler_thread(index),
+          Some(kind) => vm.dirty_thread(kind),
+        }
+      });
+      match spawn_result {
+        Ok(t) => threads.push(t),
+        Err(e) => {
+          self.stop.store(true, Ordering::SeqCst);
+          println!("vm: Failed to start a VM thread: {}", e);
+          break;
+        }
+      }
+    }
+
+    let mut result = Ok(());
+    let mut panic = None;
+    for t in threads {
+      match t.join() {
+        Ok(Err(e)) if result.is_ok() => result = Err(e),
+        Ok(_) => {}
+        Err(p) => panic = panic.or(Some(p)),
+      }
+    }
+    if let Some(p) = panic {
+      panic::resume_unwind(p);
+    }
+    result
+  }
+
+  /// Run the processes and the dirty calls on the calling thread in the
+  /// deterministic mode, so that the order of events only depends on the seed
+  /// or on the replayed log.
+  fn run_deterministic(&self) -> RtResult<()> {
+    let result = loop {
+      if self.processes.count() == 0 || self.is_stopping() {
+        break Ok(());
+      }
+      match self.tick() {
+        Ok(true) => {}
+        Ok(false) => self.schedulers[0].wait_for_work(self),
+        Err(e) => break Err(e),
+      }
+    };
+    self.deterministic.flush();
+    result
+  }
+
+  /// Loop of one scheduler thread.
+  fn scheduler_thread(&self, index: usize) -> RtResult<()> {
+    let _stop_on_panic = StopOnPanic(Arc::clone(&self.stop));
+    while self.processes.count() > 0 && !self.stop.load(Ordering::SeqCst) {
+      match self.dispatch(index) {
+        Ok(true) => {}
+        // Nothing to run here, sleep until there is
+        Ok(false) => self.schedulers[index].wait_for_work(self),
+        Err(e) => {
+          self.stop.store(true, Ordering::SeqCst);
+          return Err(e);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Loop of one dirty thread, runs the dirty calls of `kind`.
+  fn dirty_thread(&self, kind: DirtyKind) -> RtResult<()> {
+    let _stop_on_panic = StopOnPanic(Arc::clone(&self.stop));
+    while self.processes.count() > 0 && !self.stop.load(Ordering::SeqCst) {
+      self.run_dirty_call(kind, DIRTY_THREAD_WAIT);
+    }
+    Ok(())
+  }
+
+  /// Take a process waiting for a dirty thread of `kind`, run its call and
+  /// queue it back on its scheduler. Returns false if there was nothing to
+  /// run within `timeout`.
+  fn run_dirty_call(&self, kind: DirtyKind, timeout: Duration) -> bool {
+    let pid = match self.dirty_schedulers.take(kind, timeout) {
+      Some(pid) => pid,
+      None => return false,
+    };
+    let proc_p = self.processes.unsafe_lookup_pid_mut(pid);
+    if proc_p.is_null() {
+      return true;
+    }
+    let proc = unsafe { &mut (*proc_p) };
+    call_native_fun::run_dirty_call(self, proc);
+    Scheduler::end_dirty_call(proc);
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulator::{
+    heap::{Designation, Heap},
+    test_util,
+  };
+  use std::time::Instant;
+
+  #[test]
+  fn test_run_ends_when_all_exited() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_vm_run_ends");
+    // `done/1` returns its arg
+    let mut hp = Heap::new(Designation::TransientDestructible);
+    let args = test_util::make_list(&mut hp, &[Term::nil()]);
+    let mfargs = ModFunArgs::with_args_list(m, atom::from_str("done"), args);
+    for _ in 0..4 {
+      vm.create_process(Term::nil(), &mfargs, &SpawnOptions::default())
+        .unwrap();
+    }
+    assert_eq!(vm.run().unwrap(), HaltStatus::Normal);
+    assert_eq!(vm.processes.count(), 0);
+  }
+
+  #[test]
+  fn test_run_waits_until_halt() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_vm_run_halt");
+    test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+
+    // The waiting process keeps the VM running until it is halted
+    let started = Instant::now();
+    let vm_p = VmPtr(&vm as *const VM);
+    let status = thread::scope(|s| {
+      s.spawn(move || {
+        let vm = unsafe { &*vm_p.0 };
+        thread::sleep(Duration::from_millis(50));
+        vm.halt(3);
+      });
+      vm.run().unwrap()
+    });
+    assert_eq!(status, HaltStatus::Halt(3));
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(vm.processes.count(), 1);
+    // The scheduler has slept, the waiting process has not been run again
+    let switches = &vm.schedulers[0].stats.context_switches;
+    assert_eq!(SchedulerStats::get(switches), 1);
+  }
+
+  #[test]
+  fn test_purge_copies_literals_sent_meanwhile() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_vm_purge_dest");
+    let sender = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let receiver = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let sender_p = test_util::get_process(&vm, sender);
+    let receiver_p = test_util::get_process(&vm, receiver);
+
+    let name = "test_vm_purge";
+    let (literal, literal_areas) = test_util::load_old_literal(&vm, name);
+    assert!(vm.purge_module(atom::from_str(name)));
+    // The receiver has copied its literals, the sender has not and still can
+    // send one. The module is freed when the sender has copied too.
+    receiver_p.handle_signals(&vm);
+    receiver_p.deliver_message(literal).unwrap();
+    receiver_p.handle_signals(&vm);
+    sender_p.handle_signals(&vm);
+
+    let message = receiver_p.mailbox.get_messages()[0];
+    let message_p = message.get_box_ptr::<Word>();
+    assert!(!literal_areas
+      .iter()
+      .any(|(begin, end)| message_p >= *begin && message_p < *end));
+    assert_eq!(format!("{}", message), "{hello, hello}");
   }
 }
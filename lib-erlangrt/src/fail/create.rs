@@ -50,3 +50,7 @@ pub fn badfun_val<T>(val: Term, hp: &mut THeap) -> RtResult<T> {
 pub fn system_limit<T>() -> RtResult<T> {
   generic_fail(gen_atoms::SYSTEM_LIMIT)
 }
+
+pub fn timeout_value<T>() -> RtResult<T> {
+  generic_fail(gen_atoms::TIMEOUT_VALUE)
+}
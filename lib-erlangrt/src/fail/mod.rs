@@ -33,6 +33,14 @@ pub enum RtErr {
   /// Attempt to index outside of the current stack.
   StackIndexRange(usize),
 
+  //--- Scheduling ---
+  /// A native function has used up the reductions of the time slice, it is
+  /// called again with these args when the process runs next time.
+  Trap([Term; 4]),
+  /// A process with a dirty call was scheduled before the dirty thread has
+  /// run the call.
+  DirtyCallNotReturned,
+
   //--- VM Checks --
   Exception(ExceptionType, Term), // type, value
   TermIsNotABoxed,
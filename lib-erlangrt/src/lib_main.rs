@@ -1,23 +1,24 @@
 use crate::{
   command_line_args::ErlStartArgs,
   emulator::{
-    atom, mfa::ModFunArgs, spawn_options::SpawnOptions, vm::VM,
+    atom,
+    mfa::ModFunArgs,
+    spawn_options::SpawnOptions,
+    vm::{HaltStatus, VM},
   },
   term::value::*,
 };
-use std::{
-  io::{stdout, Write},
-  thread, time,
-};
+use std::io::{stdout, Write};
 
 /// Entry point for the command-line interface. Pre-parse command line args
 /// by calling StartArgs methods, or just use default constructed StartArgs.
-pub fn start_emulator(args: &mut ErlStartArgs) {
+/// Returns when the VM has stopped, with the status to exit with.
+pub fn start_emulator(args: &mut ErlStartArgs) -> HaltStatus {
   if cfg!(feature = "r20") { println!("Erlang Runtime (compat OTP 20)"); }
   if cfg!(feature = "r21") { println!("Erlang Runtime (compat OTP 21)"); }
   if cfg!(feature = "r22") { println!("Erlang Runtime (compat OTP 22)"); }
 
-  let mut beam_vm = VM::new(args);
+  let beam_vm = VM::new(args);
 
   let mfargs = ModFunArgs::with_args_list(
     atom::from_str("test2"),
@@ -34,8 +35,13 @@ pub fn start_emulator(args: &mut ErlStartArgs) {
     .unwrap();
 
   println!("Process created. Entering main loop...");
-  while beam_vm.tick().unwrap() {
-    thread::sleep(time::Duration::from_millis(0));
-  }
+  let status = match beam_vm.run() {
+    Ok(status) => status,
+    Err(e) => {
+      println!("VM has stopped on error: {:?}", e);
+      HaltStatus::Halt(1)
+    }
+  };
   stdout().flush().unwrap();
+  status
 }
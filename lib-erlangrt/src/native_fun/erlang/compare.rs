@@ -11,7 +11,7 @@ fn module() -> &'static str {
 
 /// Compare 2 terms with '=='
 pub fn nativefun_equalequal_2(
-  _vm: &mut VM,
+  _vm: &VM,
   _cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
@@ -22,7 +22,7 @@ pub fn nativefun_equalequal_2(
 /// Compare 2 terms with '/='
 /// Expressed as NOT EQUAL
 pub fn nativefun_notequal_2(
-  _vm: &mut VM,
+  _vm: &VM,
   _cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
@@ -32,7 +32,7 @@ pub fn nativefun_notequal_2(
 
 /// Compare 2 terms with '=:='
 pub fn nativefun_equal_exact_2(
-  _vm: &mut VM,
+  _vm: &VM,
   _cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
@@ -44,7 +44,7 @@ pub fn nativefun_equal_exact_2(
 /// Expressed as NOT EQUAL (EXACT)
 /// Sssssnek...
 pub fn nativefun_notequal_exact_2(
-  _vm: &mut VM,
+  _vm: &VM,
   _cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
@@ -54,7 +54,7 @@ pub fn nativefun_notequal_exact_2(
 
 /// Compare 2 terms with '<' (s less-than)
 pub fn nativefun_lessthan_2(
-  _vm: &mut VM,
+  _vm: &VM,
   _cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
@@ -64,7 +64,7 @@ pub fn nativefun_lessthan_2(
 
 /// Compare 2 terms with '=<' (s greater-than)
 pub fn nativefun_greaterthan_2(
-  _vm: &mut VM,
+  _vm: &VM,
   _cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
@@ -75,7 +75,7 @@ pub fn nativefun_greaterthan_2(
 /// Compare 2 terms with '=<' (s less-equal)
 /// Expressed as NOT GREATER
 pub fn nativefun_lessequal_2(
-  _vm: &mut VM,
+  _vm: &VM,
   _cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
@@ -86,7 +86,7 @@ pub fn nativefun_lessequal_2(
 /// Compare 2 terms with '>=' (s greater-equal)
 /// Expressed as NOT LESS
 pub fn nativefun_greaterequal_2(
-  _vm: &mut VM,
+  _vm: &VM,
   _cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
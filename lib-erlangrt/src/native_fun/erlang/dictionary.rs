@@ -0,0 +1,185 @@
+use crate::{
+  emulator::{gen_atoms, heap::heap_trait::THeap, process::Process},
+  fail::RtResult,
+  term::{
+    term_builder::{list_builder::ListBuilder, tuple_builder::tuple2},
+    value::Term,
+  },
+};
+
+#[allow(dead_code)]
+fn module() -> &'static str {
+  "native funs module for erlang[dictionary]: "
+}
+
+// Store a value in the process dictionary, returns the previous value or
+// `undefined`.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:put/2", struct_name: NfErlangPut2, arity: 2,
+  invoke: {
+    let old = proc.dictionary.put(key, value);
+    Ok(old.unwrap_or(gen_atoms::UNDEFINED))
+  },
+  args: term(key), term(value),
+);
+
+// Returns the whole dictionary as a list of `{Key, Value}`.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:get/0", struct_name: NfErlangGet0, arity: 0,
+  invoke: { get_0(proc) },
+  args:
+);
+
+pub fn get_0(proc: &mut Process) -> RtResult<Term> {
+  let pairs: Vec<(Term, Term)> = proc.dictionary.iter().collect();
+  make_pairs_list(proc.get_heap_mut(), &pairs)
+}
+
+// Returns the value stored under `key` or `undefined`.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:get/1", struct_name: NfErlangGet1, arity: 1,
+  invoke: {
+    let value = proc.dictionary.get(key);
+    Ok(value.unwrap_or(gen_atoms::UNDEFINED))
+  },
+  args: term(key),
+);
+
+// Returns all keys of the dictionary.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:get_keys/0", struct_name: NfErlangGetKeys0, arity: 0,
+  invoke: {
+    let keys: Vec<Term> = proc.dictionary.iter().map(|(k, _)| k).collect();
+    make_list(proc.get_heap_mut(), &keys)
+  },
+  args:
+);
+
+// Returns the keys which have the value `value`.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:get_keys/1", struct_name: NfErlangGetKeys1, arity: 1,
+  invoke: {
+    let keys = proc.dictionary.get_keys_with_value(value);
+    make_list(proc.get_heap_mut(), &keys)
+  },
+  args: term(value),
+);
+
+// Empty the dictionary, returns its old contents as a list of `{Key, Value}`.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:erase/0", struct_name: NfErlangErase0, arity: 0,
+  invoke: {
+    let result = get_0(proc)?;
+    proc.dictionary.clear();
+    Ok(result)
+  },
+  args:
+);
+
+// Remove `key` from the dictionary, returns its value or `undefined`.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:erase/1", struct_name: NfErlangErase1, arity: 1,
+  invoke: {
+    let old = proc.dictionary.erase(key);
+    Ok(old.unwrap_or(gen_atoms::UNDEFINED))
+  },
+  args: term(key),
+);
+
+/// Build a list of `{Key, Value}` tuples on the heap `hp`.
+pub fn make_pairs_list(hp: &mut THeap, pairs: &[(Term, Term)]) -> RtResult<Term> {
+  let mut items = Vec::with_capacity(pairs.len());
+  for (k, v) in pairs.iter() {
+    items.push(tuple2(hp, *k, *v)?);
+  }
+  make_list(hp, &items)
+}
+
+fn make_list(hp: &mut THeap, items: &[Term]) -> RtResult<Term> {
+  if items.is_empty() {
+    return Ok(Term::nil());
+  }
+  let mut lb = ListBuilder::new()?;
+  for item in items.iter() {
+    unsafe { lb.append(*item, hp)? };
+  }
+  Ok(unsafe { lb.make_term_with_tail(Term::nil()) })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    defs::WordSize,
+    emulator::{atom, spawn_options::SpawnOptions, test_util},
+  };
+
+  /// A new `{Name, N}` key on the process heap, equal keys are separate terms.
+  fn make_key(proc: &mut Process, name: &str, n: isize) -> Term {
+    let hp = proc.get_heap_mut();
+    tuple2(hp, atom::from_str(name), Term::make_small_signed(n)).unwrap()
+  }
+
+  #[test]
+  fn test_put_get_erase() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_dictionary");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+    let put = |proc: &mut Process, key, value| NfErlangPut2::_f(&vm, proc, &[key, value]);
+    let get = |proc: &mut Process, name, n| {
+      let key = make_key(proc, name, n);
+      NfErlangGet1::_f(&vm, proc, &[key]).unwrap()
+    };
+
+    let value = atom::from_str("value");
+    for n in 0..3 {
+      let key = make_key(proc, "key", n);
+      assert_eq!(put(proc, key, value).unwrap(), gen_atoms::UNDEFINED);
+    }
+    let key = make_key(proc, "key", 1);
+    let other = atom::from_str("other");
+    assert_eq!(put(proc, key, other).unwrap(), value);
+    assert_eq!(get(proc, "key", 1), other);
+    assert_eq!(get(proc, "missing", 1), gen_atoms::UNDEFINED);
+
+    let keys = NfErlangGetKeys1::_f(&vm, proc, &[value]).unwrap();
+    assert_eq!(format!("{}", keys), "[{key, 0}, {key, 2}]");
+
+    // The last pair moves to the place of the erased one
+    let key = make_key(proc, "key", 0);
+    assert_eq!(NfErlangErase1::_f(&vm, proc, &[key]).unwrap(), value);
+    assert_eq!(get(proc, "key", 0), gen_atoms::UNDEFINED);
+    assert_eq!(get(proc, "key", 2), value);
+    assert_eq!(get(proc, "key", 1), other);
+
+    let all = NfErlangErase0::_f(&vm, proc, &[]).unwrap();
+    assert_eq!(format!("{}", all), "[{{key, 2}, value}, {{key, 1}, other}]");
+    assert_eq!(NfErlangGet0::_f(&vm, proc, &[]).unwrap(), Term::nil());
+  }
+
+  #[test]
+  fn test_survives_gc() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_dictionary_gc");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+
+    for n in 0..10 {
+      let key = make_key(proc, "key", n);
+      let value = make_key(proc, "value", n);
+      NfErlangPut2::_f(&vm, proc, &[key, value]).unwrap();
+    }
+    let key = make_key(proc, "key", 5);
+    let before = NfErlangGet1::_f(&vm, proc, &[key]).unwrap();
+    proc.garbage_collect(WordSize::new(0), 0, &mut []).unwrap();
+
+    // The terms have moved, the lookup by value still finds them
+    let key = make_key(proc, "key", 5);
+    let after = NfErlangGet1::_f(&vm, proc, &[key]).unwrap();
+    assert_ne!(after.raw(), before.raw());
+    assert_eq!(format!("{}", after), "{value, 5}");
+    let keys = NfErlangGetKeys0::_f(&vm, proc, &[]).unwrap();
+    assert_eq!(format!("{}", keys).matches("key").count(), 10);
+  }
+}
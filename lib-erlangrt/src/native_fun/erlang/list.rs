@@ -1,4 +1,6 @@
-use crate::{emulator::process::Process, fail::RtResult, term::value::*};
+use crate::{
+  defs::Reductions, emulator::process::Process, fail::RtResult, term::value::*,
+};
 
 #[allow(dead_code)]
 fn module() -> &'static str {
@@ -6,10 +8,11 @@ fn module() -> &'static str {
 }
 
 // Calculate length of a list by traversing it.
-define_nativefun!(_vm, _proc, args,
+define_nativefun!(_vm, proc, args,
   name: "erlang:length/1", struct_name: NfErlangLength1, arity: 1,
   invoke: {
     let result = cons::list_length(list)?;
+    proc.consume_reductions(Reductions::for_elements(result));
     Ok(Term::make_small_unsigned(result))
   },
   args: list(list),
@@ -2,8 +2,8 @@ use crate::{
   emulator::gen_atoms,
   native_fun::{
     erlang::{
-      arithmetic::*, compare::*, list::*, predicate::*, process::*, sys::*, tuple::*,
-      type_conversions::*, binary::*,
+      arithmetic::*, compare::*, dictionary::*, list::*, predicate::*, process::*, sys::*,
+      timer::*, tuple::*, type_conversions::*, binary::*,
     },
     fn_entry::NativeFnEntry,
     module::NativeModule,
@@ -12,10 +12,12 @@ use crate::{
 
 pub mod arithmetic;
 pub mod compare;
+pub mod dictionary;
 pub mod list;
 pub mod predicate;
 pub mod process;
 pub mod sys;
+pub mod timer;
 pub mod tuple;
 pub mod type_conversions;
 pub mod binary;
@@ -36,28 +38,70 @@ pub fn new() -> NativeModule {
     NativeFnEntry::with_str(">", 2, nativefun_greaterthan_2),
     NativeFnEntry::with_str(">=", 2, nativefun_greaterequal_2),
     NativeFnEntry::with_str("atom_to_list", 1, NfErlangA2List2::_f),
+    NativeFnEntry::with_str("bump_reductions", 1, NfErlangBumpReductions1::_f),
+    NativeFnEntry::with_str("cancel_timer", 1, NfErlangCancelTimer1::_f),
+    NativeFnEntry::with_str("cancel_timer", 2, NfErlangCancelTimer2::_f),
+    NativeFnEntry::with_str("demonitor", 1, NfErlangDemonitor1::_f),
+    NativeFnEntry::with_str("demonitor", 2, NfErlangDemonitor2::_f),
+    NativeFnEntry::with_str("erase", 0, NfErlangErase0::_f),
+    NativeFnEntry::with_str("erase", 1, NfErlangErase1::_f),
     NativeFnEntry::with_str("error", 1, NfErlangError1::_f),
     NativeFnEntry::with_str("error", 2, NfErlangError2::_f),
+    NativeFnEntry::with_str("exit", 1, NfErlangExit1::_f),
+    NativeFnEntry::with_str("exit", 2, NfErlangExit2::_f),
+    NativeFnEntry::with_str("get", 0, NfErlangGet0::_f),
+    NativeFnEntry::with_str("get", 1, NfErlangGet1::_f),
+    NativeFnEntry::with_str("get_keys", 0, NfErlangGetKeys0::_f),
+    NativeFnEntry::with_str("get_keys", 1, NfErlangGetKeys1::_f),
+    NativeFnEntry::with_str("halt", 0, NfErlangHalt0::_f),
+    NativeFnEntry::with_str("halt", 1, NfErlangHalt1::_f),
     NativeFnEntry::with_str("hd", 1, NfErlangHd1::_f),
     NativeFnEntry::with_str("integer_to_list", 1, NfErlangInt2List2::_f),
     NativeFnEntry::with_str("is_boolean", 1, nativefun_is_boolean_1),
     NativeFnEntry::with_str("is_process_alive", 1, NfErlangIsPAlive1::_f),
+    NativeFnEntry::with_str("is_reference", 1, nativefun_is_reference_1),
     NativeFnEntry::with_str("length", 1, NfErlangLength1::_f),
     NativeFnEntry::with_str("list_to_binary", 1, NfErlangL2b1::_f),
+    NativeFnEntry::with_str("link", 1, NfErlangLink1::_f),
     NativeFnEntry::with_str("load_nif", 2, NfErlangLoadNif2::_f),
     NativeFnEntry::with_str("make_fun", 3, nativefun_make_fun_3),
+    NativeFnEntry::with_str("make_ref", 0, NfErlangMakeRef0::_f),
+    NativeFnEntry::with_str("monitor", 2, NfErlangMonitor2::_f),
     NativeFnEntry::with_str("nif_error", 1, NfErlangNifError1::_f),
     NativeFnEntry::with_str("nif_error", 2, NfErlangNifError2::_f),
     NativeFnEntry::with_str("process_flag", 2, NfErlangProcFlag2::_f),
     NativeFnEntry::with_str("process_flag", 3, NfErlangProcFlag3::_f),
+    NativeFnEntry::with_str("process_info", 2, NfErlangProcessInfo2::_f),
+    NativeFnEntry::with_str("purge_module", 1, NfErlangPurgeModule1::_f),
+    NativeFnEntry::with_str("put", 2, NfErlangPut2::_f),
     NativeFnEntry::with_str("register", 2, NfErlangRegister2::_f),
+    NativeFnEntry::with_str("read_timer", 1, NfErlangReadTimer1::_f),
+    NativeFnEntry::with_str("read_timer", 2, NfErlangReadTimer2::_f),
+    NativeFnEntry::with_str("ref_to_list", 1, NfErlangRef2List1::_f),
     NativeFnEntry::with_str("registered", 0, NfErlangRegistered0::_f),
+    NativeFnEntry::with_str("resume_process", 1, NfErlangResumeProcess1::_f),
     NativeFnEntry::with_str("self", 0, NfErlangSelf0::_f),
+    NativeFnEntry::with_str("send_after", 3, NfErlangSendAfter3::_f),
+    NativeFnEntry::with_str("send_after", 4, NfErlangSendAfter4::_f),
     NativeFnEntry::with_str("size", 1, NfErlangSize1::_f),
     NativeFnEntry::with_str("bit_size", 1, NfErlangBitSize1::_f),
     NativeFnEntry::with_str("byte_size", 1, NfErlangByteSize1::_f),
     NativeFnEntry::with_str("spawn", 3, NfErlangSpawn3::_f),
+    NativeFnEntry::with_str("spawn_link", 1, NfErlangSpawnLink1::_f),
+    NativeFnEntry::with_str("spawn_link", 2, NfErlangSpawnLink2::_f),
+    NativeFnEntry::with_str("spawn_link", 3, NfErlangSpawnLink3::_f),
+    NativeFnEntry::with_str("spawn_link", 4, NfErlangSpawnLink4::_f),
+    NativeFnEntry::with_str("spawn_monitor", 1, NfErlangSpawnMonitor1::_f),
+    NativeFnEntry::with_str("spawn_monitor", 3, NfErlangSpawnMonitor3::_f),
+    NativeFnEntry::with_str("spawn_opt", 4, NfErlangSpawnOpt4::_f),
+    NativeFnEntry::with_str("start_timer", 3, NfErlangStartTimer3::_f),
+    NativeFnEntry::with_str("start_timer", 4, NfErlangStartTimer4::_f),
+    NativeFnEntry::with_str("statistics", 1, NfErlangStatistics1::_f),
+    NativeFnEntry::with_str("suspend_process", 1, NfErlangSuspendProcess1::_f),
+    NativeFnEntry::with_str("suspend_process", 2, NfErlangSuspendProcess2::_f),
+    NativeFnEntry::with_str("system_flag", 2, NfErlangSystemFlag2::_f),
     NativeFnEntry::with_str("tl", 1, NfErlangTl1::_f),
+    NativeFnEntry::with_str("unlink", 1, NfErlangUnlink1::_f),
   ];
   m.init_with(fn_entries.iter());
   m
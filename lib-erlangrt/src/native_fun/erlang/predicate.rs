@@ -12,10 +12,20 @@ fn module() -> &'static str {
 
 /// Return `true` if the value is a boolean (atom `true` or atom `false`)
 pub fn nativefun_is_boolean_1(
-  _vm: &mut VM,
+  _vm: &VM,
   _curr_p: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
   assert_arity("erlang:is_boolean", 1, args);
   Ok(Term::make_bool(args[0].is_bool()))
 }
+
+/// Return `true` if the value is a local or an external reference
+pub fn nativefun_is_reference_1(
+  _vm: &VM,
+  _curr_p: &mut Process,
+  args: &[Term],
+) -> RtResult<Term> {
+  assert_arity("erlang:is_reference", 1, args);
+  Ok(Term::make_bool(args[0].is_ref()))
+}
@@ -1,16 +1,23 @@
 use crate::{
-  defs::exc_type::ExceptionType,
+  defs::{exc_type::ExceptionType, Word},
   emulator::{
     gen_atoms,
+    heap::{copy_term, gc::MaxHeapSize, heap_trait::THeap, Designation, Heap},
     mfa::{ModFunArity, ModFunArgs},
-    process::Process,
-    process_flags,
-    spawn_options::SpawnOptions,
+    process::{ExitAction, Process},
+    process_flags::{self, FlagSetting},
+    scheduler::{Prio, Scheduler},
+    signal::Signal,
+    spawn_options::{MessageQueueLocation, SpawnOptions},
     vm::VM,
   },
   fail::{self, RtErr, RtResult},
-  native_fun::assert_arity,
-  term::{boxed, value::*},
+  native_fun::{assert_arity, erlang::dictionary},
+  term::{
+    boxed,
+    term_builder::tuple_builder::{tuple2, TupleBuilder},
+    value::*,
+  },
 };
 
 #[allow(dead_code)]
@@ -26,7 +33,7 @@ define_nativefun!(_vm, proc, _args,
 
 /// Create a function pointer from atom(), atom(), smallint()
 pub fn nativefun_make_fun_3(
-  _vm: &mut VM,
+  _vm: &VM,
   cur_proc: &mut Process,
   args: &[Term],
 ) -> RtResult<Term> {
@@ -56,6 +63,364 @@ define_nativefun!(vm, _proc, _args,
   args: atom(m), atom(f), list(args),
 );
 
+// Same as `spawn/3` but takes a list of spawn options. Returns `{Pid, Ref}`
+// with the `monitor` option.
+// Spec: erlang:spawn_opt(mod, fun, args:list, options:list)
+define_nativefun!(vm, proc, _args,
+  name: "erlang:spawn_opt/4", struct_name: NfErlangSpawnOpt4, arity: 4,
+  invoke: {
+    let mfargs = ModFunArgs::with_args_list(m, f, args);
+    let spawn_opts = SpawnOptions::from_list(opts, &proc.process_flags)?;
+    if spawn_opts.monitor_requested {
+      return spawn_monitor(vm, proc, &mfargs, spawn_opts);
+    }
+    spawn_with_options(vm, proc, &mfargs, &spawn_opts)
+  },
+  args: atom(m), atom(f), list(args), list(opts),
+);
+
+/// Spawn a process, with the `link` option the new process is linked to the
+/// caller before it runs.
+fn spawn_with_options(
+  vm: &VM,
+  proc: &mut Process,
+  mfargs: &ModFunArgs,
+  spawn_opts: &SpawnOptions,
+) -> RtResult<Term> {
+  if !spawn_opts.link && spawn_opts.monitor.is_none() {
+    return vm.create_process(Term::nil(), mfargs, spawn_opts);
+  }
+  let pid = vm.create_process(proc.pid, mfargs, spawn_opts)?;
+  if spawn_opts.link {
+    proc.links.insert(pid);
+  }
+  if let Some(ref_id) = spawn_opts.monitor {
+    proc.monitors.insert(ref_id, pid);
+  }
+  Ok(pid)
+}
+
+fn spawn_link(vm: &VM, proc: &mut Process, mfargs: &ModFunArgs) -> RtResult<Term> {
+  let mut spawn_opts = SpawnOptions::default();
+  spawn_opts.link = true;
+  spawn_with_options(vm, proc, mfargs, &spawn_opts)
+}
+
+/// Spawn a process which runs `Fun()` with `erlang:apply(Fun, [])`.
+fn spawn_link_fun(vm: &VM, proc: &mut Process, fun: Term) -> RtResult<Term> {
+  let apply_args = get_apply_args(fun)?;
+  let mfargs =
+    ModFunArgs::with_args_slice(gen_atoms::ERLANG, gen_atoms::APPLY, &apply_args);
+  spawn_link(vm, proc, &mfargs)
+}
+
+/// Args for `erlang:apply(Fun, [])`, which runs a spawned fun.
+fn get_apply_args(fun: Term) -> RtResult<[Term; 2]> {
+  if !fun.is_fun_of_arity(0) {
+    return fail::create::badarg();
+  }
+  Ok([fun, Term::nil()])
+}
+
+/// Only the local node can be given to spawn.
+// TODO: Spawn on other nodes when there is distribution
+fn check_local_node(vm: &VM, node: Term) -> RtResult<()> {
+  if node != vm.node_name {
+    return fail::create::badarg();
+  }
+  Ok(())
+}
+
+// Same as `spawn/3` and links the new process to the caller.
+// Spec: erlang:spawn_link(mod, fun, args:list)
+define_nativefun!(vm, proc, _args,
+  name: "erlang:spawn_link/3", struct_name: NfErlangSpawnLink3, arity: 3,
+  invoke: {
+    let mfargs = ModFunArgs::with_args_list(m, f, args);
+    spawn_link(vm, proc, &mfargs)
+  },
+  args: atom(m), atom(f), list(args),
+);
+
+// Spec: erlang:spawn_link(node, mod, fun, args:list)
+define_nativefun!(vm, proc, _args,
+  name: "erlang:spawn_link/4", struct_name: NfErlangSpawnLink4, arity: 4,
+  invoke: {
+    check_local_node(vm, node)?;
+    let mfargs = ModFunArgs::with_args_list(m, f, args);
+    spawn_link(vm, proc, &mfargs)
+  },
+  args: atom(node), atom(m), atom(f), list(args),
+);
+
+// Spec: erlang:spawn_link(fun)
+define_nativefun!(vm, proc, _args,
+  name: "erlang:spawn_link/1", struct_name: NfErlangSpawnLink1, arity: 1,
+  invoke: { spawn_link_fun(vm, proc, fun) },
+  args: term(fun),
+);
+
+// Spec: erlang:spawn_link(node, fun)
+define_nativefun!(vm, proc, _args,
+  name: "erlang:spawn_link/2", struct_name: NfErlangSpawnLink2, arity: 2,
+  invoke: {
+    check_local_node(vm, node)?;
+    spawn_link_fun(vm, proc, fun)
+  },
+  args: atom(node), term(fun),
+);
+
+/// Spawn a process monitored by the caller, returns `{Pid, Ref}`.
+fn spawn_monitor(
+  vm: &VM,
+  proc: &mut Process,
+  mfargs: &ModFunArgs,
+  mut spawn_opts: SpawnOptions,
+) -> RtResult<Term> {
+  // The process is created before the result is built
+  proc.reserve_heap(boxed::LocalRef::storage_size() + boxed::Tuple::storage_size(2))?;
+  let ref_id = vm.next_ref_id();
+  let hp = proc.get_heap_mut();
+  let mref = boxed::LocalRef::create_into(hp, ref_id)?;
+  let tb = TupleBuilder::with_arity(2, hp)?;
+
+  spawn_opts.monitor = Some(ref_id);
+  let pid = spawn_with_options(vm, proc, mfargs, &spawn_opts)?;
+  unsafe {
+    tb.set_element(0, pid);
+    tb.set_element(1, mref);
+  }
+  Ok(tb.make_term())
+}
+
+// Same as `spawn/3` and monitors the new process, returns `{Pid, Ref}`.
+// Spec: erlang:spawn_monitor(mod, fun, args:list)
+define_nativefun!(vm, proc, _args,
+  name: "erlang:spawn_monitor/3", struct_name: NfErlangSpawnMonitor3, arity: 3,
+  invoke: {
+    let mfargs = ModFunArgs::with_args_list(m, f, args);
+    spawn_monitor(vm, proc, &mfargs, SpawnOptions::default())
+  },
+  args: atom(m), atom(f), list(args),
+);
+
+// Spec: erlang:spawn_monitor(fun)
+define_nativefun!(vm, proc, _args,
+  name: "erlang:spawn_monitor/1", struct_name: NfErlangSpawnMonitor1, arity: 1,
+  invoke: {
+    let apply_args = get_apply_args(fun)?;
+    let mfargs =
+      ModFunArgs::with_args_slice(gen_atoms::ERLANG, gen_atoms::APPLY, &apply_args);
+    spawn_monitor(vm, proc, &mfargs, SpawnOptions::default())
+  },
+  args: term(fun),
+);
+
+// Start monitoring a process given by pid or registered name, a
+// `{'DOWN', Ref, process, Object, Reason}` message arrives when it terminates.
+// Spec: erlang:monitor(process, pid | name)
+define_nativefun!(vm, proc, args,
+  name: "erlang:monitor/2", struct_name: NfErlangMonitor2, arity: 2,
+  invoke: { monitor_2(vm, proc, kind, target) },
+  args: atom(kind), term(target),
+);
+
+pub fn monitor_2(
+  vm: &VM,
+  proc: &mut Process,
+  kind: Term,
+  target: Term,
+) -> RtResult<Term> {
+  if kind != gen_atoms::PROCESS {
+    return fail::create::badarg();
+  }
+  // A name is resolved once, the monitor stays with the process
+  let pid = if target.is_local_pid() {
+    Some(target)
+  } else if target.is_atom() {
+    let found = vm.processes.find_registered(target);
+    found.filter(|p| p.is_local_pid())
+  } else {
+    return fail::create::badarg();
+  };
+  proc.reserve_heap(boxed::LocalRef::storage_size())?;
+  let ref_id = vm.next_ref_id();
+  let mref = boxed::LocalRef::create_into(proc.get_heap_mut(), ref_id)?;
+
+  let watcher = proc.pid;
+  let found = match pid {
+    Some(pid) if pid == watcher => {
+      proc.monitored_by.insert(ref_id, (watcher, target));
+      true
+    }
+    Some(pid) => {
+      let signal = Signal::Monitor {
+        watcher,
+        ref_id,
+        target,
+      };
+      vm.processes
+        .with_process(pid, |p| p.send_signal(signal))
+        .is_some()
+    }
+    None => false,
+  };
+  proc.monitors.insert(ref_id, pid.unwrap_or(target));
+  if !found {
+    // The process does not exist, the monitor fires right away
+    proc.deliver_down(ref_id, target, vm.node_name, gen_atoms::NOPROC)?;
+  }
+  Ok(mref)
+}
+
+define_nativefun!(vm, proc, args,
+  name: "erlang:demonitor/1", struct_name: NfErlangDemonitor1, arity: 1,
+  invoke: { demonitor_2(vm, proc, mref, Term::nil()) },
+  args: term(mref),
+);
+
+// Remove a monitor. Option `flush` removes its `'DOWN'` message from the
+// mailbox, option `info` returns whether the monitor was still active.
+define_nativefun!(vm, proc, args,
+  name: "erlang:demonitor/2", struct_name: NfErlangDemonitor2, arity: 2,
+  invoke: { demonitor_2(vm, proc, mref, opts) },
+  args: term(mref), list(opts),
+);
+
+pub fn demonitor_2(
+  vm: &VM,
+  proc: &mut Process,
+  mref: Term,
+  opts: Term,
+) -> RtResult<Term> {
+  let ref_id = match boxed::LocalRef::get_id(mref) {
+    Some(ref_id) => ref_id,
+    None => return fail::create::badarg(),
+  };
+  let mut flush = false;
+  let mut info = false;
+  cons::for_each(opts, |opt| {
+    match opt {
+      gen_atoms::FLUSH => flush = true,
+      gen_atoms::INFO => info = true,
+      _ => return fail::create::badarg(),
+    }
+    Ok(())
+  })?;
+
+  let removed = match proc.monitors.remove(&ref_id) {
+    Some(pid) if pid == proc.pid => {
+      proc.monitored_by.remove(&ref_id);
+      true
+    }
+    Some(pid) => {
+      // A monitor of a name which was not registered has no process
+      if pid.is_pid() {
+        vm.processes
+          .with_process(pid, |p| p.send_signal(Signal::Demonitor(ref_id)));
+      }
+      true
+    }
+    None => false,
+  };
+  if flush {
+    let mailbox = &mut proc.mailbox;
+    mailbox.remove_matching(|msg| is_down_message(msg, ref_id));
+  }
+  if info {
+    Ok(Term::make_bool(removed))
+  } else {
+    Ok(gen_atoms::TRUE)
+  }
+}
+
+/// Whether the message is `{'DOWN', Ref, ...}` of the monitor `ref_id`.
+fn is_down_message(msg: Term, ref_id: Word) -> bool {
+  if !msg.is_tuple() {
+    return false;
+  }
+  let tuple = msg.get_tuple_ptr();
+  unsafe {
+    (*tuple).get_arity() == 5
+      && (*tuple).get_element(0) == gen_atoms::SYM_DOWN
+      && boxed::LocalRef::get_id((*tuple).get_element(1)) == Some(ref_id)
+  }
+}
+
+// Link the caller to another process, when either of them terminates the
+// other gets an exit signal.
+define_nativefun!(vm, proc, args,
+  name: "erlang:link/1", struct_name: NfErlangLink1, arity: 1,
+  invoke: { link_1(vm, proc, pid) },
+  args: pid(pid),
+);
+
+pub fn link_1(vm: &VM, proc: &mut Process, pid: Term) -> RtResult<Term> {
+  if pid == proc.pid || proc.links.contains(&pid) {
+    return Ok(gen_atoms::TRUE);
+  }
+  let from = proc.pid;
+  match vm
+    .processes
+    .with_process(pid, |p| p.send_signal(Signal::Link(from)))
+  {
+    Some(()) => {
+      proc.links.insert(pid);
+      Ok(gen_atoms::TRUE)
+    }
+    // The process does not exist, a process which traps exits gets a message
+    None if proc.process_flags.get(process_flags::TRAP_EXIT) => {
+      proc.deliver_exit(pid, gen_atoms::NOPROC, false)?;
+      Ok(gen_atoms::TRUE)
+    }
+    None => Err(RtErr::Exception(ExceptionType::Error, gen_atoms::NOPROC)),
+  }
+}
+
+// Remove the link between the caller and another process, if there is one.
+define_nativefun!(vm, proc, args,
+  name: "erlang:unlink/1", struct_name: NfErlangUnlink1, arity: 1,
+  invoke: { unlink_1(vm, proc, pid) },
+  args: pid(pid),
+);
+
+pub fn unlink_1(vm: &VM, proc: &mut Process, pid: Term) -> RtResult<Term> {
+  if proc.links.remove(&pid) {
+    let from = proc.pid;
+    vm.processes
+      .with_process(pid, |p| p.send_signal(Signal::Unlink(from)));
+  }
+  Ok(gen_atoms::TRUE)
+}
+
+// Send an exit signal to a process. Reason `kill` kills the process even if
+// it traps exits.
+define_nativefun!(vm, proc, args,
+  name: "erlang:exit/2", struct_name: NfErlangExit2, arity: 2,
+  invoke: { exit_2(vm, proc, pid, reason) },
+  args: pid(pid), term(reason),
+);
+
+pub fn exit_2(vm: &VM, proc: &mut Process, pid: Term, reason: Term) -> RtResult<Term> {
+  if pid == proc.pid {
+    // The caller dies right away without running its catch handlers
+    if let ExitAction::Die(reason) = proc.get_exit_action(pid, reason, false) {
+      proc.killed = true;
+      return Err(RtErr::Exception(ExceptionType::Exit, reason));
+    }
+    proc.deliver_exit(pid, reason, false)?;
+    return Ok(gen_atoms::TRUE);
+  }
+  let from = proc.pid;
+  let result = vm
+    .processes
+    .with_process(pid, |p| p.deliver_exit(from, reason, false));
+  if let Some(result) = result {
+    result?;
+  }
+  Ok(gen_atoms::TRUE)
+}
+
 define_nativefun!(vm, _proc, args,
   name: "erlang:is_process_alive/1", struct_name: NfErlangIsPAlive1, arity: 1,
   invoke: { Ok(Term::make_bool(vm.processes.lookup_pid(pid).is_some())) },
@@ -71,7 +436,7 @@ define_nativefun!(vm, _proc, _args,
   args: atom(name), pid_port(pid_or_port),
 );
 
-pub fn register_2(vm: &mut VM, name: Term, pid_or_port: Term) -> RtResult<Term> {
+pub fn register_2(vm: &VM, name: Term, pid_or_port: Term) -> RtResult<Term> {
   // The define_nativefun! macro will check that the arguments are atom and pid/port
   // but here we additionally check if the name is not `undefined` and does not exist
   if name == gen_atoms::UNDEFINED || vm.processes.find_registered(name).is_some() {
@@ -87,40 +452,750 @@ define_nativefun!(_vm, _proc, _args,
   args:
 );
 
+// Charge `n` reductions to the calling process.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:bump_reductions/1", struct_name: NfErlangBumpReductions1, arity: 1,
+  invoke: {
+    proc.consume_reductions(n as isize);
+    Ok(gen_atoms::TRUE)
+  },
+  args: usize(n),
+);
+
+// Returns `{Item, Value}` for an information item about a process, or
+// `undefined` if the process does not exist.
+// TODO: A list of items
+define_nativefun!(vm, proc, args,
+  name: "erlang:process_info/2", struct_name: NfErlangProcessInfo2, arity: 2,
+  invoke: { process_info_2(vm, proc, pid, item) },
+  args: pid(pid), atom(item),
+);
+
+pub fn process_info_2(
+  vm: &VM,
+  proc: &mut Process,
+  pid: Term,
+  item: Term,
+) -> RtResult<Term> {
+  if item == gen_atoms::DICTIONARY {
+    return process_info_dictionary(vm, proc, pid);
+  }
+  let value = if pid == proc.pid {
+    get_own_process_info(proc, item)?
+  } else {
+    // The process might be running on another scheduler thread, the value is
+    // copied while the process can not exit
+    let result = vm.processes.with_process(pid, |p| {
+      let value = get_process_info(p, item)?;
+      copy_term::copy_to(value, proc.get_heap_mut())
+    });
+    match result {
+      Some(value) => value?,
+      None => return Ok(gen_atoms::UNDEFINED),
+    }
+  };
+  tuple2(proc.get_heap_mut(), item, value)
+}
+
+/// The dictionary is built on the heap of the caller. Another process is
+/// only read while it does not run, its heap and dictionary could change
+/// meanwhile. While it runs, the call is repeated in the next time slice.
+fn process_info_dictionary(vm: &VM, proc: &mut Process, pid: Term) -> RtResult<Term> {
+  let value = if pid == proc.pid {
+    let pairs: Vec<(Term, Term)> = proc.dictionary.iter().collect();
+    dictionary::make_pairs_list(proc.get_heap_mut(), &pairs)?
+  } else {
+    let hp = proc.get_heap_mut();
+    let result = vm.processes.with_process(pid, |p| {
+      Scheduler::with_stopped_process(p, |p| {
+        let pairs: Vec<(Term, Term)> = p.dictionary.iter().collect();
+        let mut tmp_heap = Heap::new(Designation::TransientDestructible);
+        let list = dictionary::make_pairs_list(&mut tmp_heap, &pairs)?;
+        copy_term::copy_to(list, hp)
+      })
+    });
+    match result {
+      Some(Some(value)) => value?,
+      Some(None) => {
+        let dictionary = gen_atoms::DICTIONARY;
+        return Err(RtErr::Trap([pid, dictionary, Term::nil(), Term::nil()]));
+      }
+      None => return Ok(gen_atoms::UNDEFINED),
+    }
+  };
+  tuple2(proc.get_heap_mut(), gen_atoms::DICTIONARY, value)
+}
+
+/// Value of a `process_info` item of the calling process, the reductions
+/// include the current time slice.
+fn get_own_process_info(proc: &Process, item: Term) -> RtResult<Term> {
+  match item {
+    gen_atoms::REDUCTIONS => Ok(Term::make_small_unsigned(proc.get_reductions())),
+    _ => get_process_info(proc, item),
+  }
+}
+
+/// Value of a `process_info` item, located on the heap of the process `p`.
+/// Only the fields which are safe to read from another thread are used.
+fn get_process_info(p: &Process, item: Term) -> RtResult<Term> {
+  match item {
+    gen_atoms::REDUCTIONS => Ok(Term::make_small_unsigned(p.get_finished_reductions())),
+    gen_atoms::PRIORITY => Ok(p.lock_sched_state().prio.to_atom()),
+    _ => fail::create::badarg(),
+  }
+}
+
+define_nativefun!(vm, proc, args,
+  name: "erlang:suspend_process/1", struct_name: NfErlangSuspendProcess1, arity: 1,
+  invoke: { suspend_process_2(vm, proc, pid, Term::nil()) },
+  args: pid(pid),
+);
+
+// Suspend a process, the options are `unless_suspending`, `asynchronous` and
+// `{asynchronous, ReplyTag}`. The process stops running but keeps receiving
+// messages.
+define_nativefun!(vm, proc, args,
+  name: "erlang:suspend_process/2", struct_name: NfErlangSuspendProcess2, arity: 2,
+  invoke: { suspend_process_2(vm, proc, pid, opts) },
+  args: pid(pid), list(opts),
+);
+
+/// The suspend is always done before returning, so `asynchronous` changes
+/// nothing. With `{asynchronous, ReplyTag}` the caller also gets the message
+/// `{ReplyTag, suspended | not_suspended}`.
+pub fn suspend_process_2(
+  vm: &VM,
+  proc: &mut Process,
+  pid: Term,
+  opts: Term,
+) -> RtResult<Term> {
+  let mut unless_suspending = false;
+  let mut reply_tag = None;
+  cons::for_each(opts, |opt| {
+    if opt == gen_atoms::UNLESS_SUSPENDING {
+      unless_suspending = true;
+    } else if opt == gen_atoms::ASYNCHRONOUS {
+      // Done before returning anyway
+    } else if let Some(tag) = get_async_reply_tag(opt) {
+      reply_tag = Some(tag);
+    } else {
+      return fail::create::badarg();
+    }
+    Ok(())
+  })?;
+  if pid == proc.pid {
+    return fail::create::badarg();
+  }
+  if reply_tag.is_some() {
+    proc.reserve_heap(boxed::Tuple::storage_size(2))?;
+  }
+
+  let suspender = proc.pid;
+  let result = vm.processes.with_process(pid, |p| {
+    Scheduler::suspend_process(p, suspender, unless_suspending)
+  });
+  let done = match result {
+    Some(done) => done,
+    None => return fail::create::badarg(),
+  };
+  if done {
+    proc.suspending.push(pid);
+  }
+  if let Some(tag) = reply_tag {
+    let state = if done {
+      gen_atoms::SUSPENDED
+    } else {
+      gen_atoms::NOT_SUSPENDED
+    };
+    let reply = tuple2(proc.get_heap_mut(), tag, state)?;
+    proc.deliver_message(reply)?;
+  }
+  Ok(Term::make_bool(done))
+}
+
+/// The tag of the option `{asynchronous, ReplyTag}`.
+fn get_async_reply_tag(opt: Term) -> Option<Term> {
+  if !opt.is_tuple() {
+    return None;
+  }
+  let tuple = opt.get_tuple_ptr();
+  unsafe {
+    if (*tuple).get_arity() == 2 && (*tuple).get_element(0) == gen_atoms::ASYNCHRONOUS {
+      return Some((*tuple).get_element(1));
+    }
+  }
+  None
+}
+
+// Undo one suspend of a process made by the calling process.
+define_nativefun!(vm, proc, args,
+  name: "erlang:resume_process/1", struct_name: NfErlangResumeProcess1, arity: 1,
+  invoke: { resume_process_1(vm, proc, pid) },
+  args: pid(pid),
+);
+
+pub fn resume_process_1(vm: &VM, proc: &mut Process, pid: Term) -> RtResult<Term> {
+  let suspender = proc.pid;
+  match vm
+    .processes
+    .with_process(pid, |p| Scheduler::resume_process(p, suspender))
+  {
+    Some(true) => {
+      if let Some(index) = proc.suspending.iter().position(|p| *p == pid) {
+        proc.suspending.swap_remove(index);
+      }
+      Ok(gen_atoms::TRUE)
+    }
+    _ => fail::create::badarg(),
+  }
+}
+
 define_nativefun!(_vm, proc, args,
   name: "erlang:process_flag/2", struct_name: NfErlangProcFlag2, arity: 2,
-  invoke: { do_erlang_process_flag(proc, flag, value) },
-  args: atom(flag), bool(value),
+  invoke: { process_flag_2(proc, flag, value) },
+  args: atom(flag), term(value),
 );
 
-// Set a supported process flag for some other process.
-define_nativefun!(vm, _proc, args,
+pub fn process_flag_2(proc: &mut Process, flag: Term, value: Term) -> RtResult<Term> {
+  let setting = parse_process_flag(proc.get_heap_mut(), flag, value)?;
+  if let FlagSetting::Priority(prio) = setting {
+    process_flags::check_priority_allowed(&proc.process_flags, prio)?;
+  }
+  let old = proc.change_flag(setting);
+  old.value_to_term(proc.get_heap_mut())
+}
+
+// Set a supported process flag for some other process. The process might be
+// running on another scheduler thread, so the change is sent to it as a
+// signal, and `true` is returned instead of the old value.
+define_nativefun!(vm, proc, args,
   name: "erlang:process_flag/3", struct_name: NfErlangProcFlag3, arity: 3,
-  invoke: { process_flag_3(vm, pid, flag, value) },
-  args: pid(pid), atom(flag), bool(value),
+  invoke: { process_flag_3(vm, proc, pid, flag, value) },
+  args: pid(pid), atom(flag), term(value),
 );
 
 pub fn process_flag_3(
-  vm: &mut VM,
+  vm: &VM,
+  proc: &mut Process,
   pid: Term,
   flag: Term,
-  value: bool,
+  value: Term,
 ) -> RtResult<Term> {
-  let proc_p = vm.processes.unsafe_lookup_pid_mut(pid);
-  if proc_p.is_null() {
+  let setting = parse_process_flag(proc.get_heap_mut(), flag, value)?;
+  // Can not be checked here whether the other process is a system process
+  if let FlagSetting::Priority(Prio::Max) = setting {
+    return fail::create::badarg();
+  }
+  if pid == proc.pid {
+    proc.change_flag(setting);
+  } else if vm
+    .processes
+    .with_process(pid, |p| p.send_signal(Signal::ProcessFlag(setting)))
+    .is_none()
+  {
     return fail::create::badarg();
   }
-  let p = unsafe { &mut (*proc_p) };
-  do_erlang_process_flag(p, flag, value)
+  Ok(gen_atoms::TRUE)
 }
 
-#[inline]
-fn do_erlang_process_flag(p: &mut Process, flag: Term, value: bool) -> RtResult<Term> {
+fn parse_process_flag(hp: &mut THeap, flag: Term, value: Term) -> RtResult<FlagSetting> {
   match flag {
-    gen_atoms::TRAP_EXIT => Ok(Term::make_bool(
-      p.process_flags
-        .read_and_set(process_flags::TRAP_EXIT, value),
+    gen_atoms::TRAP_EXIT if value.is_bool() => {
+      Ok(FlagSetting::TrapExit(value == gen_atoms::TRUE))
+    }
+    gen_atoms::MESSAGE_QUEUE_DATA => Ok(FlagSetting::MessageQueueData(
+      MessageQueueLocation::from_atom(value)?,
     )),
-    _ => fail::create::badarg_val(flag, p.get_heap_mut()),
+    gen_atoms::PRIORITY => Ok(FlagSetting::Priority(Prio::from_atom(value)?)),
+    gen_atoms::MAX_HEAP_SIZE => {
+      Ok(FlagSetting::MaxHeapSize(MaxHeapSize::from_term(value)?))
+    }
+    _ => fail::create::badarg_val(flag, hp),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    defs::{exc_type::ExceptionType, WordSize},
+    emulator::{atom, scheduler::Queue, test_util},
+  };
+
+  /// Load the test module `name` and spawn `n` processes which wait, the
+  /// first one calls the native functions in the tests. Returns when they all
+  /// wait, with the VM, the module and the pids.
+  fn spawn_waiting(name: &str, n: usize) -> (VM, Term, Vec<Term>) {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, name);
+    let opts = SpawnOptions::default();
+    let pids = (0..n)
+      .map(|_| test_util::spawn(&vm, m, "wait", &opts))
+      .collect();
+    test_util::run_until_idle(&vm);
+    (vm, m, pids)
+  }
+
+  #[test]
+  fn test_spawn_opt_options() {
+    let (vm, m, pids) = spawn_waiting("test_spawn_opt", 1);
+    let parent_pid = pids[0];
+    let parent = test_util::get_process(&vm, parent_pid);
+
+    let hp = parent.get_heap_mut();
+    let small = Term::make_small_unsigned;
+    let opts = [
+      tuple2(hp, gen_atoms::FULLSWEEP_AFTER, small(3)).unwrap(),
+      tuple2(hp, gen_atoms::MIN_HEAP_SIZE, small(1000)).unwrap(),
+      gen_atoms::LINK,
+    ];
+    let opts = test_util::make_list(hp, &opts);
+    let done = atom::from_str("done");
+    let args = [m, done, Term::nil(), opts];
+    let pid = NfErlangSpawnOpt4::_f(&vm, parent, &args).unwrap();
+
+    let child = test_util::get_process(&vm, pid);
+    assert_eq!(child.get_gc_settings_mut().fullsweep_after, 3);
+    assert_eq!(child.get_gc_settings_mut().min_heap_size, 1000);
+    assert!(child.links.contains(&parent_pid));
+    assert!(parent.links.contains(&pid));
+
+    // Max priority is only for the system processes
+    let hp = parent.get_heap_mut();
+    let opt = tuple2(hp, gen_atoms::PRIORITY, gen_atoms::MAX).unwrap();
+    let args = [m, done, Term::nil(), test_util::make_list(hp, &[opt])];
+    assert!(NfErlangSpawnOpt4::_f(&vm, parent, &args).is_err());
+    parent.process_flags.set(process_flags::SYSTEM_PROCESS);
+    let pid = NfErlangSpawnOpt4::_f(&vm, parent, &args).unwrap();
+    let child = test_util::get_process(&vm, pid);
+    assert_eq!(child.lock_sched_state().prio, Prio::Max);
+  }
+
+  #[test]
+  fn test_max_heap_size_kills() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_max_heap_size");
+    let mut opts = SpawnOptions::default();
+    opts.max_heap_size = Some(MaxHeapSize {
+      size: 10,
+      kill: true,
+      error_logger: true,
+    });
+    let pid = test_util::spawn(&vm, m, "wait", &opts);
+    let proc = test_util::get_process(&vm, pid);
+
+    let result = proc.garbage_collect(WordSize::new(0), 0, &mut []);
+    match result {
+      Err(RtErr::Exception(ExceptionType::Exit, reason)) => {
+        assert_eq!(reason, gen_atoms::KILLED)
+      }
+      _ => panic!("The process must be killed"),
+    }
+    assert!(proc.killed);
+  }
+
+  #[test]
+  fn test_process_flag_3_is_a_signal() {
+    let (vm, _, pids) = spawn_waiting("test_process_flag_3", 2);
+    let pid = pids[1];
+    let caller = test_util::get_process(&vm, pids[0]);
+
+    let args = [pid, gen_atoms::TRAP_EXIT, gen_atoms::TRUE];
+    let result = NfErlangProcFlag3::_f(&vm, caller, &args).unwrap();
+    assert_eq!(result, gen_atoms::TRUE);
+    let args = [pid, gen_atoms::PRIORITY, gen_atoms::HIGH];
+    NfErlangProcFlag3::_f(&vm, caller, &args).unwrap();
+    // Only applied when the process handles its signals
+    let proc = test_util::get_process(&vm, pid);
+    assert!(!proc.process_flags.get(process_flags::TRAP_EXIT));
+
+    proc.handle_signals(&vm);
+    assert!(proc.process_flags.get(process_flags::TRAP_EXIT));
+    assert_eq!(proc.lock_sched_state().prio, Prio::High);
+
+    // Max priority can not be given to another process
+    let args = [pid, gen_atoms::PRIORITY, gen_atoms::MAX];
+    assert!(NfErlangProcFlag3::_f(&vm, caller, &args).is_err());
+  }
+
+  #[test]
+  fn test_process_info_reductions() {
+    let (vm, _, pids) = spawn_waiting("test_process_info_reductions", 2);
+    let (caller_pid, pid) = (pids[0], pids[1]);
+    let caller = test_util::get_process(&vm, caller_pid);
+    let other = test_util::get_process(&vm, pid);
+
+    // Another process reports the finished time slices only
+    let reductions_of = |caller: &mut Process, pid| {
+      let info = process_info_2(&vm, caller, pid, gen_atoms::REDUCTIONS).unwrap();
+      let value = unsafe { (*info.get_tuple_ptr()).get_element(1) };
+      value.get_small_unsigned()
+    };
+    let other_before = reductions_of(caller, pid);
+    assert_eq!(other_before, other.get_finished_reductions());
+    other.consume_reductions(100);
+    assert_eq!(reductions_of(caller, pid), other_before);
+
+    // The calling process sees its current time slice too
+    let own_before = reductions_of(caller, caller_pid);
+    NfErlangBumpReductions1::_f(&vm, caller, &[Term::make_small_unsigned(100)]).unwrap();
+    assert_eq!(reductions_of(caller, caller_pid), own_before + 100);
+  }
+
+  #[test]
+  fn test_process_info_dictionary() {
+    let (vm, _, pids) = spawn_waiting("test_process_info_dictionary", 2);
+    let (caller_pid, pid) = (pids[0], pids[1]);
+    let caller = test_util::get_process(&vm, caller_pid);
+    let other = test_util::get_process(&vm, pid);
+
+    let key = atom::from_str("key");
+    let key = tuple2(other.get_heap_mut(), key, Term::small_0()).unwrap();
+    let value = atom::from_str("value");
+    dictionary::NfErlangPut2::_f(&vm, other, &[key, value]).unwrap();
+    let info = process_info_2(&vm, caller, pid, gen_atoms::DICTIONARY).unwrap();
+    assert_eq!(format!("{}", info), "{dictionary, [{{key, 0}, value}]}");
+    let pairs = unsafe { (*info.get_tuple_ptr()).get_element(1) };
+    let pairs_p = pairs.get_cons_ptr() as *const Word;
+    assert!(caller.get_heap().belongs_to_heap(pairs_p));
+
+    // A running process is read when it has stopped, the call is repeated
+    other.lock_sched_state().current_queue = Queue::Dirty;
+    match process_info_2(&vm, caller, pid, gen_atoms::DICTIONARY) {
+      Err(RtErr::Trap(_)) => {}
+      _ => panic!("The call must be repeated"),
+    }
+    other.lock_sched_state().current_queue = Queue::InfiniteWait;
+  }
+
+  #[test]
+  fn test_suspend_resume_counts() {
+    let (vm, _, pids) = spawn_waiting("test_suspend_resume", 2);
+    let (caller_pid, pid) = (pids[0], pids[1]);
+    let caller = test_util::get_process(&vm, caller_pid);
+    let proc = test_util::get_process(&vm, pid);
+
+    let suspend = |caller: &mut Process| NfErlangSuspendProcess1::_f(&vm, caller, &[pid]);
+    let resume = |caller: &mut Process| NfErlangResumeProcess1::_f(&vm, caller, &[pid]);
+    assert_eq!(suspend(caller).unwrap(), gen_atoms::TRUE);
+    assert_eq!(suspend(caller).unwrap(), gen_atoms::TRUE);
+    assert_eq!(proc.lock_sched_state().current_queue, Queue::Suspended);
+    assert_eq!(caller.suspending, vec![pid, pid]);
+
+    // Messages are received but the process does not run
+    proc.deliver_message(gen_atoms::OK).unwrap();
+    assert!(!vm.tick().unwrap());
+    assert_eq!(proc.lock_sched_state().current_queue, Queue::Suspended);
+
+    // Runs again when every suspend is undone
+    assert_eq!(resume(caller).unwrap(), gen_atoms::TRUE);
+    assert_eq!(proc.lock_sched_state().current_queue, Queue::Suspended);
+    assert_eq!(resume(caller).unwrap(), gen_atoms::TRUE);
+    assert_eq!(proc.lock_sched_state().current_queue, Queue::None);
+    assert!(caller.suspending.is_empty());
+    assert!(resume(caller).is_err());
+    // A process can not suspend itself
+    assert!(NfErlangSuspendProcess1::_f(&vm, caller, &[caller_pid]).is_err());
+  }
+
+  #[test]
+  fn test_suspend_options() {
+    let (vm, _, pids) = spawn_waiting("test_suspend_options", 2);
+    let (caller_pid, pid) = (pids[0], pids[1]);
+    let caller = test_util::get_process(&vm, caller_pid);
+
+    let tag = atom::from_str("suspend_tag");
+    let hp = caller.get_heap_mut();
+    let reply_opt = tuple2(hp, gen_atoms::ASYNCHRONOUS, tag).unwrap();
+    let opts = test_util::make_list(hp, &[reply_opt, gen_atoms::UNLESS_SUSPENDING]);
+    let suspend = |caller: &mut Process| {
+      NfErlangSuspendProcess2::_f(&vm, caller, &[pid, opts]).unwrap()
+    };
+    assert_eq!(suspend(caller), gen_atoms::TRUE);
+    assert_eq!(suspend(caller), gen_atoms::FALSE);
+    assert_eq!(caller.suspending, vec![pid]);
+
+    // One reply for each call
+    caller.handle_signals(&vm);
+    let replies: Vec<Term> = caller
+      .mailbox
+      .get_messages()
+      .iter()
+      .map(|msg| unsafe { (*msg.get_tuple_ptr()).get_element(1) })
+      .collect();
+    let expected = vec![gen_atoms::SUSPENDED, gen_atoms::NOT_SUSPENDED];
+    assert_eq!(replies, expected);
+
+    let hp = caller.get_heap_mut();
+    let bad_opts = test_util::make_list(hp, &[gen_atoms::OK]);
+    assert!(NfErlangSuspendProcess2::_f(&vm, caller, &[pid, bad_opts]).is_err());
+  }
+
+  #[test]
+  fn test_suspender_exit_resumes() {
+    let (vm, _, pids) = spawn_waiting("test_suspender_exit", 2);
+    let (caller_pid, pid) = (pids[0], pids[1]);
+    let caller = test_util::get_process(&vm, caller_pid);
+    let proc = test_util::get_process(&vm, pid);
+
+    NfErlangSuspendProcess1::_f(&vm, caller, &[pid]).unwrap();
+    NfErlangSuspendProcess1::_f(&vm, caller, &[pid]).unwrap();
+    caller.deliver_exit(pid, gen_atoms::KILL, false).unwrap();
+    test_util::run_until_idle(&vm);
+
+    assert!(vm.processes.lookup_pid(caller_pid).is_none());
+    let state = proc.lock_sched_state();
+    assert!(state.suspended_by.is_empty());
+    assert_eq!(state.current_queue, Queue::InfiniteWait);
+  }
+
+  /// Run the scheduler until the process has exited, it is removed when the
+  /// next time slice is scheduled.
+  fn run_until_exited(vm: &VM, pid: Term) {
+    for _ in 0..10 {
+      if vm.processes.lookup_pid(pid).is_none() {
+        return;
+      }
+      vm.tick().unwrap();
+    }
+    panic!("The process must have exited");
+  }
+
+  /// The `{'EXIT', From, Reason}` messages in the mailbox of a process.
+  fn get_exit_messages(proc: &mut Process) -> Vec<(Term, Term)> {
+    proc
+      .mailbox
+      .get_messages()
+      .iter()
+      .filter_map(|msg| {
+        let t = unsafe { &*msg.get_tuple_ptr() };
+        if unsafe { t.get_element(0) } != gen_atoms::SYM_EXIT {
+          return None;
+        }
+        unsafe { Some((t.get_element(1), t.get_element(2))) }
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_link_unlink() {
+    let (vm, _, pids) = spawn_waiting("test_link_unlink", 2);
+    let (caller_pid, pid) = (pids[0], pids[1]);
+    let caller = test_util::get_process(&vm, caller_pid);
+    let proc = test_util::get_process(&vm, pid);
+
+    // The other side of the link is set when it handles the signal
+    assert_eq!(link_1(&vm, caller, pid).unwrap(), gen_atoms::TRUE);
+    assert!(caller.links.contains(&pid));
+    proc.handle_signals(&vm);
+    assert!(proc.links.contains(&caller_pid));
+
+    assert_eq!(unlink_1(&vm, caller, pid).unwrap(), gen_atoms::TRUE);
+    assert!(!caller.links.contains(&pid));
+    proc.handle_signals(&vm);
+    assert!(proc.links.is_empty());
+    // Unlinking again does nothing
+    assert_eq!(unlink_1(&vm, caller, pid).unwrap(), gen_atoms::TRUE);
+
+    // A dead process can only be linked by a process which traps exits
+    NfErlangExit2::_f(&vm, caller, &[pid, gen_atoms::KILL]).unwrap();
+    test_util::run_until_idle(&vm);
+    assert!(vm.processes.lookup_pid(pid).is_none());
+    assert!(link_1(&vm, caller, pid).is_err());
+    process_flag_2(caller, gen_atoms::TRAP_EXIT, gen_atoms::TRUE).unwrap();
+    assert_eq!(link_1(&vm, caller, pid).unwrap(), gen_atoms::TRUE);
+    caller.handle_signals(&vm);
+    assert_eq!(get_exit_messages(caller), vec![(pid, gen_atoms::NOPROC)]);
+  }
+
+  #[test]
+  fn test_spawn_link_exit_propagates() {
+    let (vm, m, pids) = spawn_waiting("test_spawn_link_exit", 2);
+    let (caller_pid, killer_pid) = (pids[0], pids[1]);
+    let caller = test_util::get_process(&vm, caller_pid);
+    let killer = test_util::get_process(&vm, killer_pid);
+
+    // A normal exit does not kill the linked process
+    let hp = caller.get_heap_mut();
+    let done_args = test_util::make_list(hp, &[Term::nil()]);
+    let args = [m, atom::from_str("done"), done_args];
+    let pid = NfErlangSpawnLink3::_f(&vm, caller, &args).unwrap();
+    assert!(caller.links.contains(&pid));
+    test_util::run_until_idle(&vm);
+    assert!(vm.processes.lookup_pid(pid).is_none());
+    assert!(vm.processes.lookup_pid(caller_pid).is_some());
+    assert!(caller.links.is_empty());
+
+    // Any other reason does, `kill` is received as `killed`
+    let args = [m, atom::from_str("wait"), Term::nil()];
+    let pid = NfErlangSpawnLink3::_f(&vm, caller, &args).unwrap();
+    test_util::run_until_idle(&vm);
+    NfErlangExit2::_f(&vm, killer, &[pid, gen_atoms::KILL]).unwrap();
+    test_util::run_until_idle(&vm);
+    assert!(vm.processes.lookup_pid(pid).is_none());
+    assert!(vm.processes.lookup_pid(caller_pid).is_none());
+  }
+
+  #[test]
+  fn test_trap_exit_message() {
+    let (vm, _, pids) = spawn_waiting("test_trap_exit", 2);
+    let (caller_pid, pid) = (pids[0], pids[1]);
+    let caller = test_util::get_process(&vm, caller_pid);
+
+    process_flag_2(caller, gen_atoms::TRAP_EXIT, gen_atoms::TRUE).unwrap();
+    link_1(&vm, caller, pid).unwrap();
+    // Trapping does not protect from `kill` sent directly. The caller only
+    // handles its signals here, `wait/0` would spin on the unread messages.
+    NfErlangExit2::_f(&vm, caller, &[pid, gen_atoms::KILL]).unwrap();
+    run_until_exited(&vm, pid);
+    caller.handle_signals(&vm);
+    assert_eq!(get_exit_messages(caller), vec![(pid, gen_atoms::KILLED)]);
+
+    // An exit signal to itself is a message too
+    let reason = atom::from_str("shutdown");
+    NfErlangExit2::_f(&vm, caller, &[caller_pid, reason]).unwrap();
+    assert!(!caller.killed);
+    caller.handle_signals(&vm);
+    let messages = get_exit_messages(caller);
+    assert_eq!(messages[1], (caller_pid, reason));
+  }
+
+  #[test]
+  fn test_exit_kills_suspended() {
+    let (vm, _, pids) = spawn_waiting("test_exit_kills_suspended", 3);
+    let (caller_pid, pid, trapping_pid) = (pids[0], pids[1], pids[2]);
+    let caller = test_util::get_process(&vm, caller_pid);
+    let trapping = test_util::get_process(&vm, trapping_pid);
+
+    // A process which traps exits receives the message and stays suspended
+    process_flag_2(trapping, gen_atoms::TRAP_EXIT, gen_atoms::TRUE).unwrap();
+    let reason = atom::from_str("shutdown");
+    for p in &[pid, trapping_pid] {
+      NfErlangSuspendProcess1::_f(&vm, caller, &[*p]).unwrap();
+      NfErlangExit2::_f(&vm, caller, &[*p, reason]).unwrap();
+    }
+    test_util::run_until_idle(&vm);
+    assert!(vm.processes.lookup_pid(pid).is_none());
+    assert_eq!(get_exit_messages(trapping), vec![(caller_pid, reason)]);
+    let state = trapping.lock_sched_state();
+    assert_eq!(state.current_queue, Queue::Suspended);
+  }
+
+  /// The `{'DOWN', Ref, process, Object, Reason}` messages in the mailbox of
+  /// a process, as the monitor id, the object and the reason.
+  fn get_down_messages(proc: &mut Process) -> Vec<(Word, Term, Term)> {
+    proc
+      .mailbox
+      .get_messages()
+      .iter()
+      .filter_map(|msg| {
+        let t = unsafe { &*msg.get_tuple_ptr() };
+        if unsafe { t.get_element(0) } != gen_atoms::SYM_DOWN {
+          return None;
+        }
+        let ref_id = boxed::LocalRef::get_id(unsafe { t.get_element(1) })?;
+        unsafe { Some((ref_id, t.get_element(3), t.get_element(4))) }
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_monitor_down() {
+    let (vm, m, pids) = spawn_waiting("test_monitor_down", 1);
+    let caller_pid = pids[0];
+    let caller = test_util::get_process(&vm, caller_pid);
+
+    // A normal exit is reported too
+    let hp = caller.get_heap_mut();
+    let done_args = test_util::make_list(hp, &[Term::nil()]);
+    let args = [m, atom::from_str("done"), done_args];
+    let result = NfErlangSpawnMonitor3::_f(&vm, caller, &args).unwrap();
+    let (pid, mref) = unsafe {
+      let t = &*result.get_tuple_ptr();
+      (t.get_element(0), t.get_element(1))
+    };
+    let ref_id = boxed::LocalRef::get_id(mref).unwrap();
+    run_until_exited(&vm, pid);
+    caller.handle_signals(&vm);
+    let expected = vec![(ref_id, pid, gen_atoms::NORMAL)];
+    assert_eq!(get_down_messages(caller), expected);
+    assert!(caller.monitors.is_empty());
+
+    // A dead process is reported right away
+    let mref = monitor_2(&vm, caller, gen_atoms::PROCESS, pid).unwrap();
+    let noproc_id = boxed::LocalRef::get_id(mref).unwrap();
+    caller.handle_signals(&vm);
+    let down = get_down_messages(caller);
+    assert_eq!(down[1], (noproc_id, pid, gen_atoms::NOPROC));
+  }
+
+  #[test]
+  fn test_spawn_opt_monitor() {
+    let (vm, m, pids) = spawn_waiting("test_spawn_opt_monitor", 1);
+    let caller_pid = pids[0];
+    let caller = test_util::get_process(&vm, caller_pid);
+
+    let hp = caller.get_heap_mut();
+    let done_args = test_util::make_list(hp, &[Term::nil()]);
+    let spawn_opts = test_util::make_list(hp, &[gen_atoms::MONITOR]);
+    let args = [m, atom::from_str("done"), done_args, spawn_opts];
+    let result = NfErlangSpawnOpt4::_f(&vm, caller, &args).unwrap();
+    let (pid, mref) = unsafe {
+      let t = &*result.get_tuple_ptr();
+      (t.get_element(0), t.get_element(1))
+    };
+    let ref_id = boxed::LocalRef::get_id(mref).unwrap();
+    assert_eq!(caller.monitors.get(&ref_id), Some(&pid));
+    run_until_exited(&vm, pid);
+    caller.handle_signals(&vm);
+    let expected = vec![(ref_id, pid, gen_atoms::NORMAL)];
+    assert_eq!(get_down_messages(caller), expected);
+  }
+
+  #[test]
+  fn test_demonitor() {
+    let (vm, _, pids) = spawn_waiting("test_demonitor", 4);
+    let caller = test_util::get_process(&vm, pids[0]);
+    let pids = &pids[1..];
+    let monitor = |caller: &mut Process, pid| {
+      let mref = monitor_2(&vm, caller, gen_atoms::PROCESS, pid).unwrap();
+      let hp = caller.get_heap_mut();
+      let opts = test_util::make_list(hp, &[gen_atoms::FLUSH, gen_atoms::INFO]);
+      (mref, opts)
+    };
+    let kill = |caller: &mut Process, pid| {
+      NfErlangExit2::_f(&vm, caller, &[pid, gen_atoms::KILL]).unwrap();
+      run_until_exited(&vm, pid);
+    };
+
+    // Removed before the process exits, no message arrives
+    let (mref, _) = monitor(caller, pids[0]);
+    let result = NfErlangDemonitor1::_f(&vm, caller, &[mref]).unwrap();
+    assert_eq!(result, gen_atoms::TRUE);
+    kill(caller, pids[0]);
+    caller.handle_signals(&vm);
+    assert!(get_down_messages(caller).is_empty());
+
+    // Removed when the signal is already on its way, it is dropped
+    let (mref, opts) = monitor(caller, pids[1]);
+    let ref_id = boxed::LocalRef::get_id(mref).unwrap();
+    let node = vm.node_name;
+    caller
+      .deliver_down(ref_id, pids[1], node, gen_atoms::KILLED)
+      .unwrap();
+    let result = NfErlangDemonitor2::_f(&vm, caller, &[mref, opts]).unwrap();
+    assert_eq!(result, gen_atoms::TRUE);
+    caller.handle_signals(&vm);
+    assert!(get_down_messages(caller).is_empty());
+
+    // Flushed from the mailbox, `info` tells the monitor was gone
+    let (mref, opts) = monitor(caller, pids[2]);
+    kill(caller, pids[2]);
+    caller.handle_signals(&vm);
+    assert_eq!(get_down_messages(caller).len(), 1);
+    let result = NfErlangDemonitor2::_f(&vm, caller, &[mref, opts]).unwrap();
+    assert_eq!(result, gen_atoms::FALSE);
+    assert!(get_down_messages(caller).is_empty());
+    assert!(caller.monitors.is_empty());
   }
 }
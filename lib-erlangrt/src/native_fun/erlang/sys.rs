@@ -1,8 +1,23 @@
 use crate::{
   defs::exc_type::ExceptionType,
-  emulator::{gen_atoms, process::Process},
-  fail::{RtErr, RtResult},
-  term::{builders::make_badfun_n, term_builder::tuple_builder::tuple2, value::Term},
+  emulator::{
+    dirty_scheduler::DirtyKind,
+    error_report, gen_atoms,
+    heap::{self, gc::MaxHeapSize, heap_trait::THeap, verify},
+    process::Process,
+    statistics::SinceLast,
+    vm::VM,
+  },
+  fail::{self, RtErr, RtResult},
+  term::{
+    boxed,
+    builders::make_badfun_n,
+    term_builder::{
+      list_builder::ListBuilder,
+      tuple_builder::{tuple2, tuple3},
+    },
+    value::{cons, Term},
+  },
 };
 
 #[allow(dead_code)]
@@ -10,6 +25,40 @@ fn module() -> &'static str {
   "native funs module for erlang[sys]: "
 }
 
+// Stop the VM with status 0.
+define_nativefun!(vm, _proc, args,
+  name: "erlang:halt/0", struct_name: NfErlangHalt0, arity: 0,
+  invoke: {
+    vm.halt(0);
+    Ok(gen_atoms::TRUE)
+  },
+  args:
+);
+
+// Stop the VM with an integer status, or with a crash slogan string and
+// status 1.
+define_nativefun!(vm, _proc, args,
+  name: "erlang:halt/1", struct_name: NfErlangHalt1, arity: 1,
+  invoke: { halt_1(vm, status) },
+  args: term(status),
+);
+
+pub fn halt_1(vm: &VM, status: Term) -> RtResult<Term> {
+  if status.is_small() {
+    let code = status.get_small_signed();
+    if code < 0 || code > i32::MAX as isize {
+      return fail::create::badarg();
+    }
+    vm.halt(code as i32);
+  } else if status.is_list() {
+    error_report::report(format_args!("Halt: {}", status));
+    vm.halt(1);
+  } else {
+    return fail::create::badarg();
+  }
+  Ok(gen_atoms::TRUE)
+}
+
 // Create an error for a NIF not loaded/not implemented.
 define_nativefun!(_vm, proc, args,
   name: "erlang:nif_error/1", struct_name: NfErlangNifError1, arity: 1,
@@ -44,6 +93,13 @@ pub fn error_2(proc: &mut Process, reason: Term, err_args: Term) -> RtResult<Ter
   Err(RtErr::Exception(ExceptionType::Error, tuple_val))
 }
 
+// Create an exception of type `exit`.
+define_nativefun!(_vm, _proc, args,
+  name: "erlang:exit/1", struct_name: NfErlangExit1, arity: 1,
+  invoke: { Err(RtErr::Exception(ExceptionType::Exit, reason)) },
+  args: term(reason),
+);
+
 // Create an exception of type `error`.
 define_nativefun!(_vm, _proc, args,
   name: "erlang:error/1", struct_name: NfErlangError1, arity: 1,
@@ -61,3 +117,343 @@ define_nativefun!(_vm, _proc, args,
   },
   args: list(path), term(load_info),
 );
+
+// Create a new local reference, unique in this VM.
+define_nativefun!(vm, proc, args,
+  name: "erlang:make_ref/0", struct_name: NfErlangMakeRef0, arity: 0,
+  invoke: { boxed::LocalRef::create_into(proc.get_heap_mut(), vm.next_ref_id()) },
+  args:
+);
+
+// Remove the old code of a module, badarg if there is no old code.
+define_nativefun!(vm, _proc, args,
+  name: "erlang:purge_module/1", struct_name: NfErlangPurgeModule1, arity: 1,
+  invoke: {
+    if vm.purge_module(m) {
+      Ok(gen_atoms::TRUE)
+    } else {
+      fail::create::badarg()
+    }
+  },
+  args: atom(m),
+);
+
+// Set a VM global flag, returns the old value.
+define_nativefun!(vm, proc, args,
+  name: "erlang:system_flag/2", struct_name: NfErlangSystemFlag2, arity: 2,
+  invoke: { system_flag_2(vm, proc, flag, value) },
+  args: atom(flag), term(value),
+);
+
+pub fn system_flag_2(
+  vm: &VM,
+  proc: &mut Process,
+  flag: Term,
+  value: Term,
+) -> RtResult<Term> {
+  if flag == gen_atoms::MAX_HEAP_SIZE {
+    // Affects the processes spawned later
+    let limit = MaxHeapSize::from_term(value)?;
+    let mut settings = vm.gc_settings.write().unwrap();
+    let result = settings.max_heap_size.to_term(proc.get_heap_mut())?;
+    settings.max_heap_size = limit;
+    return Ok(result);
+  }
+  if flag == gen_atoms::VERIFY_HEAP {
+    return unsafe { verify_heap_flag(proc, value) };
+  }
+  if flag == gen_atoms::SCHEDULER_WALL_TIME {
+    let enable = match value {
+      gen_atoms::TRUE => true,
+      gen_atoms::FALSE => false,
+      _ => return fail::create::badarg(),
+    };
+    let active_ns = vm.get_scheduler_active_ns();
+    return Ok(Term::make_bool(vm.stats.set_wall_time(enable, active_ns)));
+  }
+  if !value.is_small() || value.get_small_signed() < 0 {
+    return fail::create::badarg();
+  }
+  let mut settings = vm.gc_settings.write().unwrap();
+  let setting = match flag {
+    gen_atoms::FULLSWEEP_AFTER => &mut settings.fullsweep_after,
+    gen_atoms::MIN_HEAP_SIZE => &mut settings.min_heap_size,
+    gen_atoms::MIN_BIN_VHEAP_SIZE => &mut settings.min_bin_vheap_size,
+    _ => return fail::create::badarg(),
+  };
+  let old = *setting;
+  *setting = value.get_small_unsigned();
+  Ok(Term::make_small_unsigned(old))
+}
+
+/// Names of the heap verification points for `system_flag(verify_heap, _)`.
+const VERIFY_POINT_NAMES: [(usize, Term); 3] = [
+  (verify::VERIFY_AFTER_TIMESLICE, gen_atoms::TIMESLICE),
+  (verify::VERIFY_AFTER_GC, gen_atoms::GC),
+  (verify::VERIFY_AFTER_MESSAGE, gen_atoms::MESSAGE),
+];
+
+/// Select when the heap verifier runs with a list of point names, returns
+/// the list of previously selected points.
+unsafe fn verify_heap_flag(proc: &mut Process, value: Term) -> RtResult<Term> {
+  if !value.is_list() {
+    return fail::create::badarg();
+  }
+  let mut points = 0;
+  cons::for_each(value, |point| {
+    match VERIFY_POINT_NAMES.iter().find(|(_, name)| *name == point) {
+      Some((bit, _)) => {
+        points |= bit;
+        Ok(())
+      }
+      None => fail::create::badarg(),
+    }
+  })?;
+
+  let old_points = verify::get_verify_points();
+  let hp = proc.get_heap_mut();
+  let mut result = Term::nil();
+  for (bit, name) in VERIFY_POINT_NAMES.iter().rev() {
+    if old_points & bit != 0 {
+      let cell = heap::allocate_cons(hp)?;
+      (*cell).set_hd(*name);
+      (*cell).set_tl(result);
+      result = Term::make_cons(cell);
+    }
+  }
+  verify::set_verify_points(points);
+  Ok(result)
+}
+
+// Read a counter of the VM, see `emulator::statistics`. The `io` item is not
+// supported, there are no ports to count the bytes of.
+// TODO: Add `io` when there are ports
+define_nativefun!(vm, proc, args,
+  name: "erlang:statistics/1", struct_name: NfErlangStatistics1, arity: 1,
+  invoke: { statistics_1(vm, proc, item) },
+  args: atom(item),
+);
+
+pub fn statistics_1(vm: &VM, proc: &mut Process, item: Term) -> RtResult<Term> {
+  let hp = proc.get_heap_mut();
+  let dirty_queued = vm.dirty_schedulers.get_queued_count(DirtyKind::Cpu);
+  match item {
+    gen_atoms::RUN_QUEUE => {
+      let queued: usize = vm.schedulers.iter().map(|s| s.get_queued_count()).sum();
+      Ok(Term::make_small_unsigned(queued + dirty_queued))
+    }
+    gen_atoms::RUN_QUEUE_LENGTHS => {
+      // One length per scheduler, followed by the dirty CPU queue
+      let lengths = vm.schedulers.iter().map(|s| s.get_queued_count());
+      let lengths = lengths.chain(Some(dirty_queued));
+      make_list(hp, lengths.map(|n| Ok(Term::make_small_unsigned(n))))
+    }
+    gen_atoms::REDUCTIONS => {
+      let total = vm.sum_scheduler_stats(|s| &s.reductions);
+      make_since_last(vm, hp, SinceLast::Reductions, total)
+    }
+    gen_atoms::CONTEXT_SWITCHES => {
+      let total = vm.sum_scheduler_stats(|s| &s.context_switches);
+      tuple2(hp, make_count(total), Term::small_0())
+    }
+    gen_atoms::RUNTIME => {
+      let total = vm.sum_scheduler_stats(|s| &s.active_ns) / 1_000_000;
+      make_since_last(vm, hp, SinceLast::Runtime, total)
+    }
+    gen_atoms::WALL_CLOCK => {
+      let total = vm.stats.get_wall_clock_ms();
+      make_since_last(vm, hp, SinceLast::WallClock, total)
+    }
+    gen_atoms::GARBAGE_COLLECTION => {
+      let count = vm.sum_scheduler_stats(|s| &s.gc_count);
+      let words = vm.sum_scheduler_stats(|s| &s.gc_words_reclaimed);
+      tuple3(hp, make_count(count), make_count(words), Term::small_0())
+    }
+    gen_atoms::SCHEDULER_WALL_TIME => {
+      let active_ns = vm.get_scheduler_active_ns();
+      match vm.stats.get_wall_time(&active_ns) {
+        None => Ok(gen_atoms::UNDEFINED),
+        Some(times) => {
+          // Scheduler ids start from 1
+          let mut items = Vec::with_capacity(times.len());
+          for (index, (active, total)) in times.into_iter().enumerate() {
+            let id = Term::make_small_unsigned(index + 1);
+            items.push(tuple3(hp, id, make_count(active), make_count(total))?);
+          }
+          make_list(hp, items.into_iter().map(Ok))
+        }
+      }
+    }
+    _ => fail::create::badarg(),
+  }
+}
+
+#[inline]
+fn make_count(n: u64) -> Term {
+  Term::make_small_unsigned(n as usize)
+}
+
+/// Build `{Total, SinceLastCall}` and remember `total` for the next call.
+fn make_since_last(
+  vm: &VM,
+  hp: &mut THeap,
+  key: SinceLast,
+  total: u64,
+) -> RtResult<Term> {
+  let since_last = total.saturating_sub(vm.stats.get_last(key));
+  let result = tuple2(hp, make_count(total), make_count(since_last))?;
+  vm.stats.set_last(key, total);
+  Ok(result)
+}
+
+fn make_list(
+  hp: &mut THeap,
+  items: impl Iterator<Item = RtResult<Term>>,
+) -> RtResult<Term> {
+  let mut lb = ListBuilder::new()?;
+  for item in items {
+    unsafe { lb.append(item?, hp)? };
+  }
+  if lb.head_p.is_null() {
+    return Ok(Term::nil());
+  }
+  Ok(unsafe { lb.make_term_with_tail(Term::nil()) })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    defs::WordSize,
+    emulator::{atom, heap::gc, spawn_options::SpawnOptions, test_util, vm::HaltStatus},
+  };
+
+  /// Elements of a tuple as small integers.
+  fn get_counts(tuple: Term) -> Vec<usize> {
+    let p = tuple.get_tuple_ptr();
+    let arity = unsafe { (*p).get_arity() };
+    (0..arity)
+      .map(|i| unsafe { (*p).get_element(i) }.get_small_unsigned())
+      .collect()
+  }
+
+  fn get_list(list: Term) -> Vec<Term> {
+    let mut items = Vec::new();
+    cons::for_each(list, |item| {
+      items.push(item);
+      Ok(())
+    })
+    .unwrap();
+    items
+  }
+
+  #[test]
+  fn test_system_flag_gc_settings() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_system_flag");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+
+    let seven = Term::make_small_unsigned(7);
+    let old = system_flag_2(&vm, proc, gen_atoms::FULLSWEEP_AFTER, seven).unwrap();
+    assert_eq!(old, Term::make_small_unsigned(gc::DEFAULT_FULLSWEEP_AFTER));
+    let old = system_flag_2(&vm, proc, gen_atoms::FULLSWEEP_AFTER, seven).unwrap();
+    assert_eq!(old, seven);
+    let bad = Term::make_small_signed(-1);
+    assert!(system_flag_2(&vm, proc, gen_atoms::MIN_HEAP_SIZE, bad).is_err());
+
+    // The processes spawned later use the new setting
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+    assert_eq!(proc.get_gc_settings_mut().fullsweep_after, 7);
+  }
+
+  #[test]
+  fn test_halt_status_range() {
+    let vm = test_util::new_test_vm();
+    let too_big = Term::make_small_signed(i64::from(i32::MAX) as isize + 1);
+    assert!(halt_1(&vm, too_big).is_err());
+    assert!(halt_1(&vm, Term::make_small_signed(-1)).is_err());
+    // A status which fits is accepted and returned by the VM
+    halt_1(&vm, Term::make_small_signed(5)).unwrap();
+    assert!(vm.run().unwrap() == HaltStatus::Halt(5));
+  }
+
+  #[test]
+  fn test_statistics_counters() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_statistics_counters");
+    let opts = SpawnOptions::default();
+    let pid = test_util::spawn(&vm, m, "wait", &opts);
+    test_util::spawn(&vm, m, "loop", &opts);
+    test_util::spawn(&vm, m, "wait", &opts);
+    // The busy process runs in the three last slices
+    for _ in 0..4 {
+      assert!(vm.tick().unwrap());
+    }
+    let proc = test_util::get_process(&vm, pid);
+    let mut stat = |item| statistics_1(&vm, proc, item).unwrap();
+
+    let switches = get_counts(stat(gen_atoms::CONTEXT_SWITCHES));
+    assert_eq!(switches, vec![4, 0]);
+    // The second value is the change since the last call
+    let reductions = get_counts(stat(gen_atoms::REDUCTIONS));
+    assert!(reductions[0] > 0);
+    assert_eq!(reductions[1], reductions[0]);
+    let reductions_again = get_counts(stat(gen_atoms::REDUCTIONS));
+    assert_eq!(reductions_again, vec![reductions[0], 0]);
+    let wall_clock = get_counts(stat(gen_atoms::WALL_CLOCK));
+    assert_eq!(wall_clock[1], wall_clock[0]);
+    let runtime = get_counts(stat(gen_atoms::RUNTIME));
+    assert!(runtime[0] <= wall_clock[0]);
+
+    // The busy process is still the current one, nothing is queued until new
+    // processes are spawned
+    assert_eq!(stat(gen_atoms::RUN_QUEUE), Term::small_0());
+    test_util::spawn(&vm, m, "wait", &opts);
+    test_util::spawn(&vm, m, "wait", &opts);
+    let proc = test_util::get_process(&vm, pid);
+    let mut stat = |item| statistics_1(&vm, proc, item).unwrap();
+    assert_eq!(stat(gen_atoms::RUN_QUEUE), Term::make_small_unsigned(2));
+    // The scheduler and the dirty CPU queue
+    let lengths = get_list(stat(gen_atoms::RUN_QUEUE_LENGTHS));
+    let expected = vec![Term::make_small_unsigned(2), Term::small_0()];
+    assert_eq!(lengths, expected);
+
+    let gc_before = get_counts(stat(gen_atoms::GARBAGE_COLLECTION));
+    proc.garbage_collect(WordSize::new(0), 0, &mut []).unwrap();
+    let proc = test_util::get_process(&vm, pid);
+    let gc_after = statistics_1(&vm, proc, gen_atoms::GARBAGE_COLLECTION).unwrap();
+    let gc_after = get_counts(gc_after);
+    assert_eq!(gc_after[0], gc_before[0] + 1);
+    assert!(statistics_1(&vm, proc, gen_atoms::OK).is_err());
+    assert!(statistics_1(&vm, proc, atom::from_str("io")).is_err());
+  }
+
+  #[test]
+  fn test_statistics_scheduler_wall_time() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_statistics_wall_time");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+    let item = gen_atoms::SCHEDULER_WALL_TIME;
+
+    // Disabled by default
+    assert_eq!(statistics_1(&vm, proc, item).unwrap(), gen_atoms::UNDEFINED);
+    let old = system_flag_2(&vm, proc, item, gen_atoms::TRUE).unwrap();
+    assert_eq!(old, gen_atoms::FALSE);
+
+    test_util::run_until_idle(&vm);
+    let proc = test_util::get_process(&vm, pid);
+    let times = get_list(statistics_1(&vm, proc, item).unwrap());
+    assert_eq!(times.len(), 1);
+    // {SchedulerId, ActiveTime, TotalTime}
+    let counts = get_counts(times[0]);
+    assert_eq!(counts[0], 1);
+    assert!(counts[1] > 0 && counts[1] <= counts[2]);
+
+    let old = system_flag_2(&vm, proc, item, gen_atoms::FALSE).unwrap();
+    assert_eq!(old, gen_atoms::TRUE);
+    assert_eq!(statistics_1(&vm, proc, item).unwrap(), gen_atoms::UNDEFINED);
+  }
+}
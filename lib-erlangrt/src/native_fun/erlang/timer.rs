@@ -0,0 +1,316 @@
+use crate::{
+  emulator::{
+    gen_atoms,
+    heap::{copy_term, Designation, Heap},
+    process::Process,
+    timer::TimerMessage,
+    vm::VM,
+  },
+  fail::{self, RtResult},
+  term::{
+    boxed,
+    term_builder::tuple_builder::tuple3,
+    value::{cons, Term},
+  },
+};
+use std::time::Duration;
+
+#[allow(dead_code)]
+fn module() -> &'static str {
+  "native funs module for erlang[timer]: "
+}
+
+// Send `msg` to `dest` after `time` milliseconds, returns the timer reference.
+define_nativefun!(vm, proc, args,
+  name: "erlang:send_after/3", struct_name: NfErlangSendAfter3, arity: 3,
+  invoke: { start_timer(vm, proc, time, dest, msg, Term::nil(), false) },
+  args: term(time), term(dest), term(msg),
+);
+
+define_nativefun!(vm, proc, args,
+  name: "erlang:send_after/4", struct_name: NfErlangSendAfter4, arity: 4,
+  invoke: { start_timer(vm, proc, time, dest, msg, opts, false) },
+  args: term(time), term(dest), term(msg), list(opts),
+);
+
+// Send `{timeout, TRef, msg}` to `dest` after `time` milliseconds, returns
+// the timer reference `TRef`.
+define_nativefun!(vm, proc, args,
+  name: "erlang:start_timer/3", struct_name: NfErlangStartTimer3, arity: 3,
+  invoke: { start_timer(vm, proc, time, dest, msg, Term::nil(), true) },
+  args: term(time), term(dest), term(msg),
+);
+
+define_nativefun!(vm, proc, args,
+  name: "erlang:start_timer/4", struct_name: NfErlangStartTimer4, arity: 4,
+  invoke: { start_timer(vm, proc, time, dest, msg, opts, true) },
+  args: term(time), term(dest), term(msg), list(opts),
+);
+
+// Stop a timer, returns milliseconds which were left or `false`.
+define_nativefun!(vm, proc, args,
+  name: "erlang:cancel_timer/1", struct_name: NfErlangCancelTimer1, arity: 1,
+  invoke: { cancel_timer(vm, proc, tref, Term::nil()) },
+  args: term(tref),
+);
+
+define_nativefun!(vm, proc, args,
+  name: "erlang:cancel_timer/2", struct_name: NfErlangCancelTimer2, arity: 2,
+  invoke: { cancel_timer(vm, proc, tref, opts) },
+  args: term(tref), list(opts),
+);
+
+// Returns milliseconds left until a timer fires or `false`.
+define_nativefun!(vm, proc, args,
+  name: "erlang:read_timer/1", struct_name: NfErlangReadTimer1, arity: 1,
+  invoke: { read_timer(vm, proc, tref, Term::nil()) },
+  args: term(tref),
+);
+
+define_nativefun!(vm, proc, args,
+  name: "erlang:read_timer/2", struct_name: NfErlangReadTimer2, arity: 2,
+  invoke: { read_timer(vm, proc, tref, opts) },
+  args: term(tref), list(opts),
+);
+
+/// Walk the timer options list, every element must be a `{atom, boolean}`
+/// pair. Calls `f` with the key and the value.
+fn for_each_option<F>(opts: Term, mut f: F) -> RtResult<()>
+where
+  F: FnMut(Term, bool) -> RtResult<()>,
+{
+  cons::for_each(opts, |opt| {
+    if !opt.is_tuple() {
+      return fail::create::badarg();
+    }
+    let tuple_p = opt.get_tuple_ptr();
+    let (key, value) = unsafe {
+      if (*tuple_p).get_arity() != 2 {
+        return fail::create::badarg();
+      }
+      ((*tuple_p).get_element(0), (*tuple_p).get_element(1))
+    };
+    if !value.is_bool() {
+      return fail::create::badarg();
+    }
+    f(key, value.is_true())
+  })?;
+  Ok(())
+}
+
+/// Milliseconds left on a timer or `false`.
+fn time_left_to_term(left: Option<Duration>) -> Term {
+  match left {
+    Some(d) => Term::make_small_unsigned(d.as_millis() as usize),
+    None => gen_atoms::FALSE,
+  }
+}
+
+fn get_timer_ref_id(tref: Term) -> RtResult<usize> {
+  match boxed::LocalRef::get_id(tref) {
+    Some(id) => Ok(id),
+    None => fail::create::badarg(),
+  }
+}
+
+pub fn start_timer(
+  vm: &VM,
+  proc: &mut Process,
+  time: Term,
+  dest: Term,
+  msg: Term,
+  opts: Term,
+  wrap_timeout: bool,
+) -> RtResult<Term> {
+  if !time.is_small() || time.get_small_signed() < 0 {
+    return fail::create::badarg();
+  }
+  if !dest.is_pid() && !dest.is_atom() {
+    return fail::create::badarg();
+  }
+  for_each_option(opts, |key, value| match key {
+    // TODO: Absolute time needs erlang:monotonic_time
+    gen_atoms::ABS if !value => Ok(()),
+    _ => fail::create::badarg(),
+  })?;
+
+  let mut need = boxed::LocalRef::storage_size();
+  if wrap_timeout {
+    need = need + boxed::Tuple::storage_size(3);
+  }
+  proc.reserve_heap(need)?;
+  let ref_id = vm.next_ref_id();
+  let hp = proc.get_heap_mut();
+  let tref = boxed::LocalRef::create_into(hp, ref_id)?;
+  let message = if wrap_timeout {
+    tuple3(hp, gen_atoms::TIMEOUT, tref, msg)?
+  } else {
+    msg
+  };
+
+  // The message outlives the call and maybe the module version, so it is
+  // copied to the timer with the literals
+  let mut timer_msg = TimerMessage {
+    ref_id,
+    dest,
+    message,
+    fragment: None,
+  };
+  if message.is_cons() || message.is_boxed() {
+    let mut fragment = Heap::new(Designation::HeapFragment);
+    timer_msg.message = copy_term::copy_all_to(message, &mut fragment)?;
+    timer_msg.fragment = Some(fragment);
+  }
+
+  let timeout = Duration::from_millis(time.get_small_unsigned() as u64);
+  vm.timers.start_message_timer(timeout, timer_msg);
+  Ok(tref)
+}
+
+pub fn cancel_timer(
+  vm: &VM,
+  proc: &mut Process,
+  tref: Term,
+  opts: Term,
+) -> RtResult<Term> {
+  let ref_id = get_timer_ref_id(tref)?;
+  let mut is_async = false;
+  let mut info = true;
+  for_each_option(opts, |key, value| {
+    match key {
+      gen_atoms::ASYNC => is_async = value,
+      gen_atoms::INFO => info = value,
+      _ => return fail::create::badarg(),
+    }
+    Ok(())
+  })?;
+
+  if !info {
+    vm.timers.cancel_message_timer(ref_id);
+    return Ok(gen_atoms::OK);
+  }
+  if !is_async {
+    let left = vm.timers.cancel_message_timer(ref_id);
+    return Ok(time_left_to_term(left));
+  }
+
+  proc.reserve_heap(boxed::Tuple::storage_size(3))?;
+  let left = time_left_to_term(vm.timers.cancel_message_timer(ref_id));
+  let reply = tuple3(proc.get_heap_mut(), gen_atoms::CANCEL_TIMER, tref, left)?;
+  proc.deliver_message(reply)?;
+  Ok(gen_atoms::OK)
+}
+
+pub fn read_timer(vm: &VM, proc: &mut Process, tref: Term, opts: Term) -> RtResult<Term> {
+  let ref_id = get_timer_ref_id(tref)?;
+  let mut is_async = false;
+  for_each_option(opts, |key, value| match key {
+    gen_atoms::ASYNC => {
+      is_async = value;
+      Ok(())
+    }
+    _ => fail::create::badarg(),
+  })?;
+
+  if !is_async {
+    return Ok(time_left_to_term(vm.timers.read_message_timer(ref_id)));
+  }
+  proc.reserve_heap(boxed::Tuple::storage_size(3))?;
+  let left = time_left_to_term(vm.timers.read_message_timer(ref_id));
+  let reply = tuple3(proc.get_heap_mut(), gen_atoms::READ_TIMER, tref, left)?;
+  proc.deliver_message(reply)?;
+  Ok(gen_atoms::OK)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    defs::Word,
+    emulator::{atom, spawn_options::SpawnOptions, test_util},
+    term::term_builder::tuple_builder::tuple2,
+  };
+
+  #[test]
+  fn test_start_timer_sends_timeout() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_start_timer");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+
+    let hello = atom::from_str("hello");
+    let zero = Term::make_small_unsigned(0);
+    let tref = start_timer(&vm, proc, zero, pid, hello, Term::nil(), true).unwrap();
+    // The process keeps waking up while there is an unread message, so the
+    // VM is not idle after the delivery
+    while proc.mailbox.get_messages().is_empty() {
+      assert!(vm.tick().unwrap());
+    }
+
+    let expected = tuple3(proc.get_heap_mut(), gen_atoms::TIMEOUT, tref, hello).unwrap();
+    let messages = proc.mailbox.get_messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(format!("{}", messages[0]), format!("{}", expected));
+  }
+
+  #[test]
+  fn test_read_and_cancel_timer() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_cancel_timer");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+
+    let minute = Term::make_small_unsigned(60000);
+    let nil = Term::nil();
+    let tref = start_timer(&vm, proc, minute, pid, nil, nil, false).unwrap();
+    let left = read_timer(&vm, proc, tref, nil).unwrap();
+    assert!(left.is_small() && left.get_small_unsigned() <= 60000);
+
+    // The asynchronous cancel replies with a message
+    let hp = proc.get_heap_mut();
+    let opt = tuple2(hp, gen_atoms::ASYNC, gen_atoms::TRUE).unwrap();
+    let opts = test_util::make_list(hp, &[opt]);
+    let result = cancel_timer(&vm, proc, tref, opts).unwrap();
+    assert_eq!(result, gen_atoms::OK);
+    proc.handle_signals(&vm);
+    let messages = proc.mailbox.get_messages();
+    assert_eq!(messages.len(), 1);
+    let reply = messages[0].get_tuple_ptr();
+    unsafe {
+      assert_eq!((*reply).get_element(0), gen_atoms::CANCEL_TIMER);
+      assert!((*reply).get_element(2).is_small());
+    }
+
+    // The timer is gone
+    let left = read_timer(&vm, proc, tref, nil).unwrap();
+    assert_eq!(left, gen_atoms::FALSE);
+    let left = cancel_timer(&vm, proc, tref, nil).unwrap();
+    assert_eq!(left, gen_atoms::FALSE);
+    assert!(cancel_timer(&vm, proc, nil, nil).is_err());
+  }
+
+  #[test]
+  fn test_timer_message_outlives_literals() {
+    let vm = test_util::new_test_vm();
+    let m = test_util::load_test_module(&vm, "test_timer_literal_dest");
+    let pid = test_util::spawn(&vm, m, "wait", &SpawnOptions::default());
+    let proc = test_util::get_process(&vm, pid);
+
+    let name = "test_timer_literal";
+    let (literal, literal_areas) = test_util::load_old_literal(&vm, name);
+    let zero = Term::make_small_unsigned(0);
+    start_timer(&vm, proc, zero, pid, literal, Term::nil(), false).unwrap();
+    assert!(vm.purge_module(atom::from_str(name)));
+    proc.handle_signals(&vm);
+    while proc.mailbox.get_messages().is_empty() {
+      assert!(vm.tick().unwrap());
+    }
+
+    let message = proc.mailbox.get_messages()[0];
+    let message_p = message.get_box_ptr::<Word>();
+    assert!(!literal_areas
+      .iter()
+      .any(|(begin, end)| message_p >= *begin && message_p < *end));
+    assert_eq!(format!("{}", message), "{hello, hello}");
+  }
+}
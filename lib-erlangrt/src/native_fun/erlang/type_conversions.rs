@@ -1,4 +1,5 @@
 use crate::{
+  defs::Reductions,
   emulator::{atom, process::Process},
   fail::{self, RtResult},
   term::{
@@ -41,6 +42,13 @@ pub fn integer_to_list_1(curr_p: &mut Process, val: Term) -> RtResult<Term> {
   unsafe { cons::integer_to_list(val, curr_p.get_heap_mut()) }
 }
 
+// Converts a reference to Erlang string `"#Ref<...>"`.
+define_nativefun!(_vm, proc, args,
+  name: "erlang:ref_to_list/1", struct_name: NfErlangRef2List1, arity: 1,
+  invoke: { unsafe { cons::rust_str_to_list(&format!("{}", r), proc.get_heap_mut()) } },
+  args: reference(r),
+);
+
 // Returns list `list` reversed with `tail` appended (any term).
 define_nativefun!(_vm, proc, args,
   name: "erlang:list_to_binary/1", struct_name: NfErlangL2b1, arity: 1,
@@ -51,6 +59,7 @@ define_nativefun!(_vm, proc, args,
 #[inline]
 unsafe fn list_to_binary_1(proc: &mut Process, list: Term) -> RtResult<Term> {
   let size = cons::get_iolist_size(list);
+  proc.consume_reductions(Reductions::for_bytes(size.bytes()));
   if size.bytes() == 0 {
     Ok(Term::empty_binary())
   } else {
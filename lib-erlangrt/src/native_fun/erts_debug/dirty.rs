@@ -0,0 +1,32 @@
+//! Functions for testing the dirty threads. They do nothing useful, but they
+//! are registered as dirty and run like the long running native functions.
+use crate::{
+  emulator::gen_atoms,
+  fail::{self, RtResult},
+  term::value::Term,
+};
+use std::{thread, time::Duration};
+
+// Usage: erts_debug:dirty_cpu(wait, Milliseconds) sleeps on a dirty CPU thread
+// and returns `ok`.
+define_nativefun!(_vm, _proc, args,
+  name: "erts_debug:dirty_cpu/2", struct_name: NfErtsdDirtyCpu2, arity: 2,
+  invoke: { dirty_op(op, arg) },
+  args: atom(op), term(arg),
+);
+
+// Usage: erts_debug:dirty_io(wait, Milliseconds) sleeps on a dirty IO thread
+// and returns `ok`.
+define_nativefun!(_vm, _proc, args,
+  name: "erts_debug:dirty_io/2", struct_name: NfErtsdDirtyIo2, arity: 2,
+  invoke: { dirty_op(op, arg) },
+  args: atom(op), term(arg),
+);
+
+fn dirty_op(op: Term, arg: Term) -> RtResult<Term> {
+  if op != gen_atoms::WAIT || !arg.is_small() || arg.get_small_signed() < 0 {
+    return fail::create::badarg();
+  }
+  thread::sleep(Duration::from_millis(arg.get_small_unsigned() as u64));
+  Ok(gen_atoms::OK)
+}
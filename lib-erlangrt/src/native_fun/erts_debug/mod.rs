@@ -0,0 +1,16 @@
+pub mod dirty;
+
+use crate::{
+  emulator::{atom, dirty_scheduler::DirtyKind},
+  native_fun::{erts_debug::dirty::*, fn_entry::NativeFnEntry, module::NativeModule},
+};
+
+pub fn new() -> NativeModule {
+  let mut m = NativeModule::new(atom::from_str("erts_debug"));
+  let fn_entries: Vec<NativeFnEntry> = vec![
+    NativeFnEntry::with_str_dirty("dirty_cpu", 2, NfErtsdDirtyCpu2::_f, DirtyKind::Cpu),
+    NativeFnEntry::with_str_dirty("dirty_io", 2, NfErtsdDirtyIo2::_f, DirtyKind::Io),
+  ];
+  m.init_with(fn_entries.iter());
+  m
+}
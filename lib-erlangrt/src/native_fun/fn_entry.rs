@@ -1,18 +1,40 @@
 use crate::{
   defs::Arity,
-  emulator::{atom, funarity::FunArity},
+  emulator::{atom, dirty_scheduler::DirtyKind, funarity::FunArity},
   native_fun::NativeFn,
 };
 
 /// A nativefn entry for lookup tables and preloaded module tables.
+#[derive(Clone)]
 pub struct NativeFnEntry {
   pub fa: FunArity,
   pub func: super::NativeFn,
+  /// Dirty functions run on a dirty thread, `None` runs on the scheduler
+  pub dirty: Option<DirtyKind>,
 }
 
 impl NativeFnEntry {
   pub fn with_str(fun: &str, arity: Arity, func: NativeFn) -> Self {
     let fa = FunArity::new(atom::from_str(fun), arity);
-    Self { fa, func }
+    Self {
+      fa,
+      func,
+      dirty: None,
+    }
+  }
+
+  /// Register a function which runs for a long time, on a dirty thread.
+  pub fn with_str_dirty(
+    fun: &str,
+    arity: Arity,
+    func: NativeFn,
+    kind: DirtyKind,
+  ) -> Self {
+    let fa = FunArity::new(atom::from_str(fun), arity);
+    Self {
+      fa,
+      func,
+      dirty: Some(kind),
+    }
   }
 }
@@ -1,40 +1,47 @@
 //! Implements Keyfind/Keysearch/... tuple-operations on a list.
 use crate::{
-  emulator::gen_atoms,
-  fail::RtResult,
+  defs::Reductions,
+  emulator::{gen_atoms, process::Process},
+  fail::{RtErr, RtResult},
   term::{
     compare,
     value::{cons, Term},
   },
 };
-use std::cmp::Ordering;
 
-define_nativefun!(_vm, _proc, args,
+define_nativefun!(_vm, proc, args,
   name: "lists:keyfind/3", struct_name: NfListsKeyfind3, arity: 3,
-  invoke: { keyfind_3(key, pos, list) },
+  invoke: { keyfind_3(proc, key, pos, list) },
   args: term(key), usize(pos), list(list),
 );
 
+/// Visits as many elements as the reductions left in the time slice allow,
+/// and traps with the rest of the list if it has not ended.
 #[inline]
-fn keyfind_3(sample: Term, pos: usize, list: Term) -> RtResult<Term> {
-  if let Some(first) = cons::find_first(list, |elem| {
+fn keyfind_3(proc: &mut Process, sample: Term, pos: usize, list: Term) -> RtResult<Term> {
+  let limit = Reductions::elements_budget(proc.get_reductions_left());
+  let mut visited = 0;
+  let (found, rest) = cons::find_first_within(list, limit, |elem| {
+    visited += 1;
     if !elem.is_tuple() {
       return false;
     }
 
-    // TODO: Count reductions to pay for the complexity
-
     let tuple_p = elem.get_tuple_ptr();
     if unsafe { (*tuple_p).get_arity() } <= pos {
       return false;
     }
     let tuple_element = unsafe { (*tuple_p).get_element(pos) };
-    if let Ok(cmp_result) = compare::cmp_terms(sample, tuple_element, true) {
-      cmp_result == Ordering::Equal
-    } else {
-      return false;
-    }
-  }) {
+    compare::eq_terms_exact(sample, tuple_element)
+  });
+  // Pay for the complexity
+  proc.consume_reductions(Reductions::for_elements(visited));
+  if found.is_none() && rest.is_cons() {
+    let pos = Term::make_small_unsigned(pos);
+    return Err(RtErr::Trap([sample, pos, rest, Term::nil()]));
+  }
+
+  if let Some(first) = found {
     Ok(Term::make_boxed(first))
   } else {
     Ok(gen_atoms::FALSE)
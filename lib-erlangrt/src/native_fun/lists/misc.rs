@@ -1,30 +1,32 @@
 //! Implements misc and general purpose list operations.
 use crate::{
+  defs::Reductions,
   emulator::process::Process,
-  fail::RtResult,
+  fail::{RtErr, RtResult},
   term::{compare, term_builder::ListBuilder, value::*},
 };
-use core::cmp::Ordering;
 
-define_nativefun!(_vm, _proc, args,
+define_nativefun!(_vm, proc, args,
   name: "lists:member/2", struct_name: NfListsMember2, arity: 2,
-  invoke: { member_2(sample, list) },
+  invoke: { member_2(proc, sample, list) },
   args: term(sample), list(list),
 );
 
+/// Visits as many elements as the reductions left in the time slice allow,
+/// and traps with the rest of the list if it has not ended.
 #[inline]
-fn member_2(sample: Term, list: Term) -> RtResult<Term> {
-  if list == Term::nil() {
-    return Ok(Term::make_bool(false));
-  }
-  let result = cons::any(list, |elem| {
-    if let Ok(cmp_result) = compare::cmp_terms(sample, elem, true) {
-      cmp_result == Ordering::Equal
-    } else {
-      return false;
-    }
+fn member_2(proc: &mut Process, sample: Term, list: Term) -> RtResult<Term> {
+  let limit = Reductions::elements_budget(proc.get_reductions_left());
+  let mut visited = 0;
+  let (found, rest) = cons::find_first_within(list, limit, |elem| {
+    visited += 1;
+    compare::eq_terms_exact(sample, elem)
   });
-  return Ok(Term::make_bool(result));
+  proc.consume_reductions(Reductions::for_elements(visited));
+  if found.is_none() && rest.is_cons() {
+    return Err(RtErr::Trap([sample, rest, Term::nil(), Term::nil()]));
+  }
+  return Ok(Term::make_bool(found.is_some()));
 }
 
 // Returns list `list` reversed with `tail` appended (any term).
@@ -38,9 +40,14 @@ define_nativefun!(_vm, proc, args,
 unsafe fn reverse_2(proc: &mut Process, list: Term, tail: Term) -> RtResult<Term> {
   let mut lb = ListBuilder::new()?;
   let hp = proc.get_heap_mut();
+  let mut length = 0;
 
   // Going forward the list, prepend values to the result
-  cons::for_each(list, |elem| lb.prepend(elem, hp))?;
+  cons::for_each(list, |elem| {
+    length += 1;
+    lb.prepend(elem, hp)
+  })?;
+  proc.consume_reductions(Reductions::for_elements(length));
 
   // Last element's tail in the new list is set to `tail` argument
   Ok(lb.make_term_with_tail(tail))
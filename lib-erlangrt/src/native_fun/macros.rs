@@ -34,7 +34,7 @@ macro_rules! define_nativefun {
     pub struct $struct_name {}
     impl $struct_name {
       pub fn _f(
-        $vmvar: &mut crate::emulator::vm::VM,
+        $vmvar: &crate::emulator::vm::VM,
         $procvar: &mut crate::emulator::process::Process,
         $argsvar: &[Term],
       ) -> crate::fail::RtResult<Term> {
@@ -166,6 +166,14 @@ macro_rules! define_one_arg {
       { return_badarg!($fn_name, $arg_pos, $arg_ident, "pid|port"); }
   };
 
+  // Reference args are verified to be a reference otherwise a badarg is created.
+  ( $fn_name:expr, $vmvar:ident, $procvar:ident, $argsvar:ident, $arg_pos:expr,
+    reference($arg_ident:ident)
+  ) => {
+    let $arg_ident = $argsvar[$arg_pos];
+    if !$arg_ident.is_ref() { return_badarg!($fn_name, $arg_pos, $arg_ident, "reference"); }
+  };
+
   // Atom args are verified to be an atom otherwise a badarg is created.
   ( $fn_name:expr, $vmvar:ident, $procvar:ident, $argsvar:ident, $arg_pos:expr,
     bool($arg_ident:ident)
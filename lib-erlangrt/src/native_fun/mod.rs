@@ -14,6 +14,7 @@ pub mod macros;
 // Native Modules (precompiled and preloaded)
 //
 pub mod erlang;
+pub mod erts_debug;
 pub mod erts_internal;
 pub mod lists;
 
@@ -21,8 +22,7 @@ pub mod lists;
 /// its name and hardcoded in its code), and returns an `Term`.
 /// In case of error the `NON_VALUE` should be returned and the process is
 /// informed about error situation (error reason and type are set etc).
-pub type NativeFn =
-  fn(vm: &mut VM, cur_proc: &mut Process, args: &[Term]) -> RtResult<Term>;
+pub type NativeFn = fn(vm: &VM, cur_proc: &mut Process, args: &[Term]) -> RtResult<Term>;
 
 #[inline]
 pub fn assert_arity(fn_name: &str, have_arity: usize, args: &[Term]) {
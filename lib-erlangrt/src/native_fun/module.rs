@@ -1,14 +1,12 @@
 use crate::{
-  emulator::funarity::FunArity,
-  native_fun::{fn_entry::NativeFnEntry, NativeFn},
-  term::value::Term,
+  emulator::funarity::FunArity, native_fun::fn_entry::NativeFnEntry, term::value::Term,
 };
 use std::collections::hash_map::HashMap;
 
 /// A loaded native module contains a dictionary of functions with arity
 pub struct NativeModule {
   pub name: Term,
-  pub functions: HashMap<FunArity, NativeFn>,
+  pub functions: HashMap<FunArity, NativeFnEntry>,
 }
 
 impl NativeModule {
@@ -24,7 +22,7 @@ impl NativeModule {
     T: Iterator<Item = &'x NativeFnEntry>,
   {
     for entry in iter {
-      self.functions.insert(entry.fa.clone(), entry.func);
+      self.functions.insert(entry.fa.clone(), entry.clone());
     }
   }
 }
@@ -1,6 +1,9 @@
 use crate::{
   emulator::{atom, mfa::ModFunArity},
-  native_fun::{erlang, erts_internal, lists, module::NativeModule, NativeFn},
+  native_fun::{
+    erlang, erts_debug, erts_internal, fn_entry::NativeFnEntry, lists,
+    module::NativeModule, NativeFn,
+  },
   term::value::Term,
 };
 use std::collections::HashMap;
@@ -25,6 +28,9 @@ impl NativeFunRegistry {
     let a_erlang = atom::from_str("erlang");
     self.modules.insert(a_erlang, erlang::new());
 
+    let a_ertsd = atom::from_str("erts_debug");
+    self.modules.insert(a_ertsd, erts_debug::new());
+
     let a_ertsi = atom::from_str("erts_internal");
     self.modules.insert(a_ertsi, erts_internal::new());
 
@@ -43,10 +49,13 @@ impl NativeFunRegistry {
   }
 
   pub fn find_mfa(&self, mfa: &ModFunArity) -> Option<NativeFn> {
+    self.find_entry(mfa).map(|entry| entry.func)
+  }
+
+  /// Find the registration of a native function, with its dirty kind.
+  pub fn find_entry(&self, mfa: &ModFunArity) -> Option<&NativeFnEntry> {
     if let Some(module_def) = self.modules.get(&mfa.m) {
-      if let Some(fn_ptr) = module_def.functions.get(&mfa.get_funarity()) {
-        return Some(*fn_ptr);
-      }
+      return module_def.functions.get(&mfa.get_funarity());
     }
     None
   }
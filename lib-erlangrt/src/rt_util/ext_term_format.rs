@@ -2,7 +2,7 @@ use super::bin_reader::BinaryReader;
 use crate::{
   defs::{SWord, Word},
   emulator::{atom, heap::heap_trait::THeap},
-  fail::{RtErr, RtResult},
+  fail::{self, RtErr, RtResult},
   term::{
     boxed::{self, bignum::sign::Sign},
     term_builder::{ListBuilder, TupleBuilder},
@@ -17,6 +17,7 @@ enum Tag {
   NewFloat = 70,
   BitBinary = 77,
   AtomCacheRef_ = 82,
+  NewerReference = 90,
   SmallInteger = 97,
   Integer = 98,
   Float = 99,
@@ -51,27 +52,46 @@ fn fail<TermType: Copy>(msg: String) -> RtResult<TermType> {
 }
 
 /// Given a binary reader `r` parse term and return it, `heap` is used to
-/// allocate space for larger boxed terms.
-pub fn decode(r: &mut BinaryReader, hp: &mut THeap) -> RtResult<Term> {
+/// allocate space for larger boxed terms. References are only accepted from
+/// `node`, which is the name of this node, and not at all if it is `None`.
+pub fn decode(
+  r: &mut BinaryReader,
+  hp: &mut THeap,
+  node: Option<Term>,
+) -> RtResult<Term> {
   let etf_tag = r.read_u8();
   if etf_tag != Tag::ETF as u8 {
     let msg = format!("{}Expected ETF tag byte 131, got {}", module(), etf_tag);
     return fail(msg);
   }
-  decode_naked(r, hp)
+  decode_naked(r, hp, node)
 }
 
 /// Given an encoded term without ETF tag (131u8), read the term from `r` and
 /// place boxed term parts on heap `heap`.
-pub fn decode_naked(r: &mut BinaryReader, hp: &mut THeap) -> RtResult<Term> {
+pub fn decode_naked(
+  r: &mut BinaryReader,
+  hp: &mut THeap,
+  node: Option<Term>,
+) -> RtResult<Term> {
   let term_tag = r.read_u8();
   match term_tag {
-    x if x == Tag::List as u8 => decode_list(r, hp),
+    x if x == Tag::List as u8 => decode_list(r, hp, node),
 
     x if x == Tag::String as u8 => decode_string(r, hp),
 
     x if x == Tag::AtomDeprecated as u8 => decode_atom_latin1(r, hp),
 
+    x if x == Tag::AtomUtf8 as u8 => {
+      let size = r.read_u16be() as Word;
+      decode_atom_utf8(r, size)
+    }
+
+    x if x == Tag::SmallAtomUtf8 as u8 => {
+      let size = r.read_u8() as Word;
+      decode_atom_utf8(r, size)
+    }
+
     x if x == Tag::SmallInteger as u8 => decode_u8(r, hp),
 
     x if x == Tag::Integer as u8 => decode_s32(r, hp),
@@ -80,12 +100,12 @@ pub fn decode_naked(r: &mut BinaryReader, hp: &mut THeap) -> RtResult<Term> {
 
     x if x == Tag::LargeTuple as u8 => {
       let size = r.read_u32be() as Word;
-      decode_tuple(r, size, hp)
+      decode_tuple(r, size, hp, node)
     }
 
     x if x == Tag::SmallTuple as u8 => {
       let size = r.read_u8() as Word;
-      decode_tuple(r, size, hp)
+      decode_tuple(r, size, hp, node)
     }
 
     x if x == Tag::LargeBig as u8 => {
@@ -102,9 +122,11 @@ pub fn decode_naked(r: &mut BinaryReader, hp: &mut THeap) -> RtResult<Term> {
 
     x if x == Tag::Map as u8 => {
       let size = r.read_u32be() as Word;
-      decode_map(r, size, hp)
+      decode_map(r, size, hp, node)
     }
 
+    x if x == Tag::NewerReference as u8 => decode_newer_reference(r, hp, node),
+
     _ => {
       let msg = format!(
         "Don't know how to decode ETF value tag 0x{:x} ({})",
@@ -141,21 +163,31 @@ fn decode_binary(r: &mut BinaryReader, hp: &mut THeap) -> RtResult<Term> {
 }
 
 /// Given arity, allocate a tuple and read its elements sequentially.
-fn decode_tuple(r: &mut BinaryReader, size: usize, hp: &mut THeap) -> RtResult<Term> {
+fn decode_tuple(
+  r: &mut BinaryReader,
+  size: usize,
+  hp: &mut THeap,
+  node: Option<Term>,
+) -> RtResult<Term> {
   let tb = TupleBuilder::with_arity(size, hp)?;
   for i in 0..size {
-    let elem = decode_naked(r, hp)?;
+    let elem = decode_naked(r, hp, node)?;
     unsafe { tb.set_element(i, elem) }
   }
   Ok(tb.make_term())
 }
 
 /// Given size, create a map of given size and read `size` pairs.
-fn decode_map(r: &mut BinaryReader, size: usize, hp: &mut THeap) -> RtResult<Term> {
+fn decode_map(
+  r: &mut BinaryReader,
+  size: usize,
+  hp: &mut THeap,
+  node: Option<Term>,
+) -> RtResult<Term> {
   let map_ptr = boxed::Map::create_into(hp, size)?;
   for _i in 0..size {
-    let key = decode_naked(r, hp)?;
-    let val = decode_naked(r, hp)?;
+    let key = decode_naked(r, hp, node)?;
+    let val = decode_naked(r, hp, node)?;
     unsafe { boxed::Map::add(map_ptr, key, val)? }
   }
   Ok(Term::make_boxed(map_ptr))
@@ -177,7 +209,43 @@ fn decode_atom_latin1(r: &mut BinaryReader, _hp: &mut THeap) -> RtResult<Term> {
   Ok(atom::from_str(&val))
 }
 
-fn decode_list(r: &mut BinaryReader, hp: &mut THeap) -> RtResult<Term> {
+fn decode_atom_utf8(r: &mut BinaryReader, size: Word) -> RtResult<Term> {
+  let val = r.read_str_utf8(size)?;
+  Ok(atom::from_str(&val))
+}
+
+/// A reference with node, 32-bit creation and a number of 32-bit id words.
+/// Only the local references are supported, as encoded by `encode_local_ref`.
+fn decode_newer_reference(
+  r: &mut BinaryReader,
+  hp: &mut THeap,
+  node: Option<Term>,
+) -> RtResult<Term> {
+  let n_words = r.read_u16be();
+  let ref_node = decode_naked(r, hp, node)?;
+  let creation = r.read_u32be();
+  if node != Some(ref_node) || creation != 0 {
+    let msg = format!(
+      "{}Reference from another node {} (creation {})",
+      module(),
+      ref_node,
+      creation
+    );
+    return fail(msg);
+  }
+  if n_words != 3 {
+    let msg = format!("{}Expected 3 reference words, got {}", module(), n_words);
+    return fail(msg);
+  }
+  let words = [r.read_u32be(), r.read_u32be(), r.read_u32be()];
+  boxed::LocalRef::create_into(hp, boxed::LocalRef::words_to_id(words))
+}
+
+fn decode_list(
+  r: &mut BinaryReader,
+  hp: &mut THeap,
+  node: Option<Term>,
+) -> RtResult<Term> {
   let n_elem = r.read_u32be();
   if n_elem == 0 {
     return Ok(Term::nil());
@@ -185,14 +253,14 @@ fn decode_list(r: &mut BinaryReader, hp: &mut THeap) -> RtResult<Term> {
 
   let mut lb = ListBuilder::new()?;
   for _i in 0..n_elem {
-    let another = decode_naked(r, hp)?;
+    let another = decode_naked(r, hp, node)?;
     unsafe {
       lb.append(another, hp)?;
     }
   }
 
   // Decode tail, possibly a nil
-  let tl = decode_naked(r, hp)?;
+  let tl = decode_naked(r, hp, node)?;
   unsafe { Ok(lb.make_term_with_tail(tl)) }
 }
 
@@ -216,3 +284,63 @@ fn decode_string(r: &mut BinaryReader, hp: &mut THeap) -> RtResult<Term> {
 
   Ok(lb.make_term())
 }
+
+/// Encode a local reference `r` as a NEWER_REFERENCE (tag 90) without the
+/// ETF tag byte, `node` is the atom name of this node.
+#[allow(dead_code)]
+pub fn encode_local_ref(r: Term, node: Term, out: &mut Vec<u8>) -> RtResult<()> {
+  let words = match boxed::LocalRef::get_words(r) {
+    Some(w) => w,
+    None => return fail::create::badarg(),
+  };
+  out.push(Tag::NewerReference as u8);
+  out.extend_from_slice(&(words.len() as u16).to_be_bytes());
+  encode_atom(node, out)?;
+  // Creation is always 0 while the VM is not distributed
+  out.extend_from_slice(&0u32.to_be_bytes());
+  for w in words.iter() {
+    out.extend_from_slice(&w.to_be_bytes());
+  }
+  Ok(())
+}
+
+/// Encode an atom as SMALL_ATOM_UTF8 or as ATOM_UTF8 if it is longer.
+fn encode_atom(a: Term, out: &mut Vec<u8>) -> RtResult<()> {
+  let name = atom::to_str(a)?;
+  if name.len() < 256 {
+    out.push(Tag::SmallAtomUtf8 as u8);
+    out.push(name.len() as u8);
+  } else {
+    out.push(Tag::AtomUtf8 as u8);
+    out.extend_from_slice(&(name.len() as u16).to_be_bytes());
+  }
+  out.extend_from_slice(name.as_bytes());
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulator::heap::{Designation, Heap};
+
+  #[test]
+  fn test_encode_decode_local_ref() {
+    let mut hp = Heap::new(Designation::TransientDestructible);
+    let node = atom::from_str("test@localhost");
+    let mref = boxed::LocalRef::create_into(&mut hp, (5 << 50) | (6 << 18) | 7).unwrap();
+    let mut out = vec![Tag::ETF as u8];
+    encode_local_ref(mref, node, &mut out).unwrap();
+
+    let mut r = BinaryReader::from_bytes(out.clone());
+    let decoded = decode(&mut r, &mut hp, Some(node)).unwrap();
+    let id = boxed::LocalRef::get_id(decoded);
+    assert_eq!(id, boxed::LocalRef::get_id(mref));
+
+    // A reference of another node, or in the module literals, is rejected
+    let mut r = BinaryReader::from_bytes(out.clone());
+    let other_node = Some(atom::from_str("other@localhost"));
+    assert!(decode(&mut r, &mut hp, other_node).is_err());
+    let mut r = BinaryReader::from_bytes(out);
+    assert!(decode(&mut r, &mut hp, None).is_err());
+  }
+}
@@ -48,9 +48,11 @@ impl TBoxed for Bignum {
 }
 
 impl Bignum {
-  const fn storage_size() -> WordSize {
-    // This impl stores bignum in dynamic heap with the num library
-    ByteSize::new(size_of::<Bignum>()).get_words_rounded_up()
+  /// Size of a bignum in memory with the header word and all limbs.
+  fn storage_size(n_limbs: usize) -> WordSize {
+    // Minus one because `digits` in the struct already consumes one limb
+    let self_size = ByteSize::new(size_of::<Bignum>()).get_words_rounded_up();
+    WordSize::new(self_size.words + n_limbs.max(1) - 1)
   }
 
   /// Create bignum for one isize
@@ -84,7 +86,7 @@ impl Bignum {
     sign: Sign,
     limbs: &[Digit],
   ) -> RtResult<*mut Self> {
-    let n_words = Self::storage_size();
+    let n_words = Self::storage_size(limbs.len());
     let this = hp.alloc(n_words, false)? as *mut Self;
 
     ptr::write(
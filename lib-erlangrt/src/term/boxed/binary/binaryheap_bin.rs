@@ -1,5 +1,5 @@
 use crate::{
-  defs::{BitReader, BitSize, ByteReader, ByteSize, WordSize},
+  defs::{BitReader, BitSize, ByteReader, ByteSize, Word, WordSize, WORD_BYTES},
   fail::{RtErr, RtResult},
   term::{
     boxed::{
@@ -9,13 +9,23 @@ use crate::{
     value::Term,
   },
 };
-use core::ptr;
+use core::{
+  ptr,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+use std::alloc;
 
-/// Defines operations with a binary on the binary heap
+/// Defines operations with a binary on the binary heap. This is a large
+/// binary allocated once outside of the process heaps and shared by the
+/// processes through `ReferenceToBinary` handles. Freed when the last handle
+/// is released.
 /// Pointer to this can be directly casted from pointer to boxed::Binary
+#[repr(C)]
 pub struct BinaryHeapBinary {
   pub bin_header: Binary,
   pub size: BitSize,
+  /// How many `ReferenceToBinary` handles point here
+  refc: AtomicUsize,
   pub data: usize, // first 8 (or 4) bytes of data begin here
 }
 
@@ -25,6 +35,44 @@ impl BinaryHeapBinary {
     // The size is `BinaryHeapBinary` in words rounded up + storage bytes rounded up
     header_size.get_words_rounded_up() + size.get_words_rounded_up()
   }
+
+  fn get_layout(size: BitSize) -> alloc::Layout {
+    let n_bytes = Self::storage_size(size).words * WORD_BYTES;
+    alloc::Layout::from_size_align(n_bytes, WORD_BYTES).unwrap()
+  }
+
+  /// Allocate a binary outside of any heap, with the refcount of 1.
+  pub unsafe fn create(size: BitSize) -> *mut BinaryHeapBinary {
+    let this = alloc::alloc(Self::get_layout(size)) as *mut Self;
+    if this.is_null() {
+      alloc::handle_alloc_error(Self::get_layout(size));
+    }
+    let new_self = Self {
+      bin_header: Binary::new(BinaryType::BinaryHeap, Self::storage_size(size)),
+      size,
+      refc: AtomicUsize::new(1),
+      data: 0,
+    };
+    ptr::write(this, new_self);
+    this
+  }
+
+  /// Another handle now points to this binary.
+  pub unsafe fn retain(this: *mut BinaryHeapBinary) {
+    (*this).refc.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// A handle pointing to this binary is gone, free the memory if it was the
+  /// last one.
+  pub unsafe fn release(this: *mut BinaryHeapBinary) {
+    if (*this).refc.fetch_sub(1, Ordering::AcqRel) == 1 {
+      alloc::dealloc(this as *mut u8, Self::get_layout((*this).size));
+    }
+  }
+
+  pub fn get_refcount(&self) -> Word {
+    self.refc.load(Ordering::Relaxed)
+  }
 }
 
 impl TBinary for BinaryHeapBinary {
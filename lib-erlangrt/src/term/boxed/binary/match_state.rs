@@ -7,7 +7,8 @@ use crate::{
       self,
       binary::trait_interface::TBinary,
       boxtype::{self, BoxType},
-      trait_interface::TBoxed,
+      trait_interface::{InplaceMapFn, TBoxed},
+      Binary,
     },
     classify,
   },
@@ -16,7 +17,7 @@ use core::ptr;
 
 /// Binary match buffer is a part of `BinaryMatchState`
 struct MatchBuffer {
-  // TODO: Make sure this is detected when garbage collected
+  /// Updated by the GC in `BinaryMatchState::inplace_map`
   pub orig: *const TBinary,
   /// The window begins at bit offset 0 always, and `start_at` will advance
   /// forward as we are reading from the binary.
@@ -51,6 +52,17 @@ impl TBoxed for BinaryMatchState {
   fn get_type(&self) -> BoxType {
     boxtype::BOXTYPETAG_BINARY_MATCH_STATE
   }
+
+  fn inplace_map(&mut self, mapfn: &mut InplaceMapFn) {
+    let this_p = self as *mut Self as *mut boxed::BoxHeader;
+    unsafe {
+      let orig_term = (*self.match_buffer.orig).make_term();
+      let new_term = mapfn(this_p, orig_term);
+      if new_term != orig_term {
+        self.match_buffer.orig = Binary::get_trait_from_term(new_term);
+      }
+    }
+  }
 }
 
 impl BinaryMatchState {
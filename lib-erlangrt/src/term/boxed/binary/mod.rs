@@ -2,7 +2,7 @@ use core::fmt;
 
 use crate::{
   defs::{self, data_reader::TDataReader, BitSize, ByteSize, WordSize},
-  emulator::{heap::heap_trait::THeap, vm::VM},
+  emulator::{heap::heap_trait::THeap, process::Process},
   fail::{RtErr, RtResult},
   term::{
     boxed::{
@@ -11,7 +11,7 @@ use crate::{
         refc_bin::ReferenceToBinary, slice::BinarySlice, trait_interface::TBinary,
       },
       boxtype::{self, BoxType},
-      trait_interface::TBoxed,
+      trait_interface::{InplaceMapFn, TBoxed},
       BoxHeader,
     },
     classify,
@@ -65,30 +65,38 @@ impl TBoxed for Binary {
   fn get_type(&self) -> BoxType {
     boxtype::BOXTYPETAG_BINARY
   }
+
+  fn inplace_map(&mut self, mapfn: &mut InplaceMapFn) {
+    // Only slices refer to other heap objects, the rest contain no terms
+    if let BinaryType::Slice = self.bin_type {
+      let slice_p = self as *mut Binary as *mut BinarySlice;
+      unsafe { (*slice_p).inplace_map_orig(mapfn) }
+    }
+  }
 }
 
 impl Binary {
   /// For binary of given size ensure that the heap has enough space on it,
-  /// and if a large binary is to be created, also check the binary heap capacity.
+  /// a large binary only needs space for a reference to the binary heap.
+  /// The process heap may be garbage collected using `live` registers.
   pub fn ensure_memory_for_binary(
-    vm: &mut VM,
-    hp: &mut THeap,
+    proc: &mut Process,
     size: BitSize,
     extra_memory: WordSize,
+    live: usize,
   ) -> RtResult<()> {
-    if size.get_byte_size_rounded_up().bytes() <= ProcessHeapBinary::ONHEAP_THRESHOLD {
-      return hp.allocate_intent_no_gc(ProcessHeapBinary::storage_size(size) + extra_memory);
-    }
-    vm.binary_heap
-      .allocate_intent_no_gc(BinaryHeapBinary::storage_size(size))?;
-    return hp.allocate_intent_no_gc(ReferenceToBinary::storage_size() + extra_memory);
+    let need = match Self::get_binary_type_for_creation(size) {
+      BinaryType::ProcessHeap => ProcessHeapBinary::storage_size(size),
+      _ => ReferenceToBinary::storage_size(),
+    };
+    proc.ensure_heap(need + extra_memory, live, &mut [])
   }
 
   fn get_binary_type_for_creation(size: BitSize) -> BinaryType {
     if size.get_byte_size_rounded_up().bytes() <= ProcessHeapBinary::ONHEAP_THRESHOLD {
       return BinaryType::ProcessHeap;
     }
-    BinaryType::BinaryHeap
+    BinaryType::RefToBinaryHeap
   }
 
   fn new(bin_type: BinaryType, storage_size: WordSize) -> Binary {
@@ -112,8 +120,15 @@ impl Binary {
     let b_type = Self::get_binary_type_for_creation(size);
     match b_type {
       BinaryType::ProcessHeap => ProcessHeapBinary::create_into(size, hp),
-      BinaryType::BinaryHeap => unimplemented!("create binary on the binary heap"),
-      BinaryType::RefToBinaryHeap => unimplemented!("create ref to binary heap"),
+      BinaryType::RefToBinaryHeap => {
+        let bin_p = BinaryHeapBinary::create(size);
+        let ref_p = ReferenceToBinary::create_into(bin_p, hp).map_err(|e| {
+          BinaryHeapBinary::release(bin_p);
+          e
+        })?;
+        Ok(ref_p as *mut TBinary)
+      }
+      BinaryType::BinaryHeap => panic!("Binary heap binaries are never on a heap"),
       BinaryType::Slice => panic!("Can't create slice here"),
     }
   }
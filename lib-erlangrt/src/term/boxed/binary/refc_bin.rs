@@ -1,5 +1,6 @@
 use crate::{
-  defs::{BitReader, BitSize, ByteReader, ByteSize, Word, WordSize},
+  defs::{BitReader, BitSize, ByteReader, ByteSize, WordSize},
+  emulator::heap::heap_trait::THeap,
   fail::{RtErr, RtResult},
   term::{
     boxed::{
@@ -9,13 +10,17 @@ use crate::{
     value::Term,
   },
 };
+use core::ptr;
 
 /// Defines operations with reference to binary.
+/// A handle on the process heap which points to a refcounted binary on the
+/// binary heap. The heap keeps a list of its handles, and releases the ones
+/// which did not survive the GC or when the heap is dropped.
 /// Pointer to this can be directly casted from pointer to boxed::Binary
+#[repr(C)]
 pub struct ReferenceToBinary {
   pub bin_header: Binary,
   pub size: BitSize,
-  refc: Word,
   pub pointer: *mut BinaryHeapBinary,
 }
 
@@ -25,12 +30,35 @@ impl ReferenceToBinary {
     header_size.get_words_rounded_up()
   }
 
-  #[allow(dead_code)]
+  /// Create a new handle on heap `hp` pointing to the binary `pointer`, the
+  /// ownership of one refcount is passed to the new handle.
+  pub unsafe fn create_into(
+    pointer: *mut BinaryHeapBinary,
+    hp: &mut THeap,
+  ) -> RtResult<*mut ReferenceToBinary> {
+    let storage_sz = Self::storage_size();
+    let this = hp.alloc(storage_sz, false)? as *mut Self;
+
+    let new_self = Self {
+      bin_header: Binary::new(BinaryType::RefToBinaryHeap, storage_sz),
+      size: (*pointer).size,
+      pointer,
+    };
+    ptr::write(this, new_self);
+    hp.add_off_heap_binary(this);
+    Ok(this)
+  }
+
+  /// The handle was copied word by word to heap `hp` (when the binary is
+  /// sent to another process), register it there and increment the refcount.
+  pub unsafe fn on_copied(this: *mut ReferenceToBinary, hp: &mut THeap) {
+    BinaryHeapBinary::retain((*this).pointer);
+    hp.add_off_heap_binary(this);
+  }
+
+  /// The handle is dead, decrement the binary refcount.
   pub unsafe fn on_destroy(this: *mut ReferenceToBinary) {
-    if (*this).refc > 0 {
-      (*this).refc -= 1;
-      return;
-    }
+    BinaryHeapBinary::release((*this).pointer);
   }
 }
 
@@ -48,24 +76,27 @@ impl TBinary for ReferenceToBinary {
   }
 
   fn get_byte_reader(&self) -> Option<ByteReader> {
-    unimplemented!()
+    unsafe { (*self.pointer).get_byte_reader() }
   }
 
   unsafe fn get_data_mut(&mut self) -> &mut [u8] {
-    unimplemented!()
+    (*self.pointer).get_data_mut()
   }
 
   unsafe fn get_data(&self) -> &[u8] {
-    unimplemented!()
+    (*self.pointer).get_data()
   }
 
   fn get_bit_reader(&self) -> BitReader {
-    unimplemented!()
+    unsafe { (*self.pointer).get_bit_reader() }
   }
 
-  fn store(&mut self, _data: &[u8]) -> RtResult<()> {
-    // TODO: Maybe should be possible? Assist with resolution into BinaryHeapBinary
-    return Err(RtErr::CannotCopyIntoRefbin);
+  fn store(&mut self, data: &[u8]) -> RtResult<()> {
+    // Only a freshly created binary with a single owner can be written to
+    if unsafe { (*self.pointer).get_refcount() } != 1 {
+      return Err(RtErr::CannotCopyIntoRefbin);
+    }
+    unsafe { (*self.pointer).store(data) }
   }
 
   fn make_term(&self) -> Term {
@@ -83,3 +114,36 @@ impl TBinary for ReferenceToBinary {
     (*p).put_integer(val, size, offset, flags)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    defs::Word,
+    emulator::heap::{copy_term, gc::GcSettings, Heap},
+  };
+
+  #[test]
+  fn test_refcount_follows_the_handles() {
+    let mut a = Heap::new_process_heap(GcSettings::default());
+    let mut b = Heap::new_process_heap(GcSettings::default());
+    unsafe {
+      // Large binaries are stored on the binary heap
+      let bin = Binary::create_with_data(&[7u8; 1000], &mut a).unwrap();
+      let handle = copy_term::copy_to((*bin).make_term(), &mut b).unwrap();
+      let refbin = handle.get_box_ptr::<ReferenceToBinary>();
+      let bin_p = (*refbin).pointer;
+      assert_eq!((*bin_p).get_refcount(), 2);
+
+      // The handle in `b` survives the GC, the one in `a` does not
+      let mut roots = [handle];
+      b.garbage_collect(WordSize::new(0), &mut [&mut roots], Vec::new());
+      assert_eq!((*bin_p).get_refcount(), 2);
+      a.garbage_collect(WordSize::new(0), &mut [], Vec::new());
+      assert_eq!((*bin_p).get_refcount(), 1);
+      let moved = roots[0].get_box_ptr::<ReferenceToBinary>();
+      assert!(b.belongs_to_heap(moved as *const Word));
+      assert_eq!((*moved).pointer, bin_p);
+    }
+  }
+}
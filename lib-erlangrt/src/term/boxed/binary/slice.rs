@@ -5,7 +5,8 @@ use crate::{
   term::{
     boxed::{
       binary::{trait_interface::TBinary, BinaryType},
-      Binary,
+      trait_interface::InplaceMapFn,
+      Binary, BoxHeader,
     },
     value::Term,
   },
@@ -17,7 +18,7 @@ pub struct BinarySlice {
   pub bin_header: Binary,
   pub offset: BitSize,
   pub size: BitSize,
-  // TODO: Make sure this is recognized as a term during the GC
+  /// Updated by the GC via `inplace_map_orig` when the original binary moves
   pub orig: *const TBinary,
 }
 
@@ -58,6 +59,17 @@ impl BinarySlice {
 
     Ok(this as *mut TBinary)
   }
+
+  /// Present the original binary to the `mapfn` as a term, and if it was moved
+  /// rebuild the `orig` pointer from the new location.
+  pub unsafe fn inplace_map_orig(&mut self, mapfn: &mut InplaceMapFn) {
+    let this_p = self as *mut Self as *mut BoxHeader;
+    let orig_term = (*self.orig).make_term();
+    let new_term = mapfn(this_p, orig_term);
+    if new_term != orig_term {
+      self.orig = Binary::get_trait_from_term(new_term);
+    }
+  }
 }
 
 impl TBinary for BinarySlice {
@@ -11,7 +11,7 @@ use crate::{
   term::{
     boxed::{
       boxtype::{self, BoxType},
-      trait_interface::TBoxed,
+      trait_interface::{InplaceMapFn, TBoxed},
       BoxHeader, BOXTYPETAG_CLOSURE,
     },
     classify,
@@ -45,13 +45,13 @@ impl TBoxed for Closure {
     boxtype::BOXTYPETAG_CLOSURE
   }
 
-  //  fn inplace_map(&mut self, mapfn: &InplaceMapFn) {
-  //    let this_p = self as *mut Closure;
-  //    let frozen = unsafe { (*this_p).get_frozen_mut() };
-  //    for i in 0..frozen.len() {
-  //      frozen[i] = mapfn(this_p as *mut BoxHeader, frozen[i]);
-  //    }
-  //  }
+  fn inplace_map(&mut self, mapfn: &mut InplaceMapFn) {
+    let this_p = self as *mut Closure;
+    let frozen = unsafe { (*this_p).get_frozen_mut() };
+    for i in 0..frozen.len() {
+      frozen[i] = mapfn(this_p as *mut BoxHeader, frozen[i]);
+    }
+  }
 }
 
 impl Closure {
@@ -63,9 +63,8 @@ impl Closure {
   }
 
   fn new(mfa: ModFunArity, nfrozen: usize) -> Self {
-    let storage_size = Self::storage_size(nfrozen) - WordSize::one();
     Self {
-      header: BoxHeader::new::<Self>(storage_size),
+      header: BoxHeader::new::<Self>(Self::storage_size(nfrozen)),
       mfa,
       dst: None,
       nfrozen: nfrozen as Arity,
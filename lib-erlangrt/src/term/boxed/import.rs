@@ -1,11 +1,13 @@
 use crate::{
   defs::{ByteSize, WordSize},
   emulator::{
-    code::pointer::CodePtr, code_srv::CodeServer, heap::heap_trait::THeap,
+    code::pointer::CodePtr,
+    code_srv::{self, CodeServer},
+    heap::heap_trait::THeap,
     mfa::ModFunArity,
   },
   fail::{RtErr, RtResult},
-  native_fun::NativeFn,
+  native_fun::fn_entry::NativeFnEntry,
   term::{
     boxed::{
       boxtype::{self, BoxType},
@@ -17,6 +19,7 @@ use crate::{
   },
 };
 use core::{mem::size_of, ptr};
+use std::sync::RwLock;
 
 #[allow(dead_code)]
 pub struct Import {
@@ -87,13 +90,16 @@ impl Import {
 
   /// Lookup a function, referred by this object and possibly attempt code
   /// loading if the module was missing. Return a code pointer.
-  pub fn resolve(&self, code_server: &mut CodeServer) -> RtResult<CodePtr> {
-    code_server.lookup_beam_code_and_load(&self.mfarity)
+  pub fn resolve(&self, code_server: &RwLock<CodeServer>) -> RtResult<CodePtr> {
+    code_srv::lookup_and_load(code_server, &self.mfarity)
   }
 
   /// Assuming that this object refers to a native function, look it up and
-  /// return the function pointer.
-  pub fn get_native_fn_ptr(&self, code_srv: &CodeServer) -> Option<NativeFn> {
-    code_srv.native_functions.find_mfa(&self.mfarity)
+  /// return the function pointer with its dirty kind.
+  pub fn get_native_fn_entry<'a>(
+    &self,
+    code_srv: &'a CodeServer,
+  ) -> Option<&'a NativeFnEntry> {
+    code_srv.native_functions.find_entry(&self.mfarity)
   }
 }
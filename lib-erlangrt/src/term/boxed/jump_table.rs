@@ -6,7 +6,7 @@ use crate::{
     boxed::{
       self,
       boxtype::{self, BoxType},
-      trait_interface::TBoxed,
+      trait_interface::{InplaceMapFn, TBoxed},
       BoxHeader,
     },
     classify,
@@ -31,20 +31,20 @@ impl TBoxed for JumpTable {
     boxtype::BOXTYPETAG_JUMP_TABLE
   }
 
-  //  fn inplace_map(&mut self, mapfn: &InplaceMapFn) {
-  //    let this_p = self as *mut JumpTable;
-  //    unsafe {
-  //      let count = (*this_p).get_count();
-  //      for i in 0..count {
-  //        let (val, loc) = (*this_p).get_pair(i);
-  //        (*this_p).set_pair(
-  //          i,
-  //          mapfn(this_p as *mut BoxHeader, val),
-  //          mapfn(this_p as *mut BoxHeader, loc),
-  //        );
-  //      }
-  //    }
-  //  }
+  fn inplace_map(&mut self, mapfn: &mut InplaceMapFn) {
+    let this_p = self as *mut JumpTable;
+    unsafe {
+      let count = (*this_p).get_count();
+      for i in 0..count {
+        let (val, loc) = (*this_p).get_pair(i);
+        (*this_p).set_pair(
+          i,
+          mapfn(this_p as *mut BoxHeader, val),
+          mapfn(this_p as *mut BoxHeader, loc),
+        );
+      }
+    }
+  }
 }
 
 impl JumpTable {
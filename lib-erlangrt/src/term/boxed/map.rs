@@ -1,13 +1,13 @@
 use core::cmp::Ordering;
 
 use crate::{
-  defs::{Word, WordSize},
+  defs::{ByteSize, Word, WordSize},
   emulator::heap::heap_trait::THeap,
   fail::RtResult,
   term::{
     boxed::{
       boxtype::{self, BoxType},
-      trait_interface::TBoxed,
+      trait_interface::{InplaceMapFn, TBoxed},
       BoxHeader,
     },
     classify,
@@ -15,7 +15,7 @@ use crate::{
     value::Term,
   },
 };
-use core::ptr;
+use core::{mem::size_of, ptr};
 
 enum MapType {
   FlatMap,
@@ -47,17 +47,28 @@ impl TBoxed for Map {
   fn get_type(&self) -> BoxType {
     boxtype::BOXTYPETAG_MAP
   }
+
+  fn inplace_map(&mut self, mapfn: &mut InplaceMapFn) {
+    let this_p = self as *mut Map;
+    unsafe {
+      // Only `count` pairs are initialized, the remaining capacity is garbage
+      let p = this_p.add(1) as *mut Term;
+      for i in 0..(2 * (*this_p).count) {
+        let val = ptr::read(p.add(i));
+        ptr::write(p.add(i), mapfn(this_p as *mut BoxHeader, val));
+      }
+    }
+  }
 }
 
 impl Map {
-  /// Size of a tuple in memory with the header word (used for allocations)
+  /// Size of a map in memory with the header word (used for allocations)
   #[inline]
   pub fn storage_size(num_pairs: Word) -> WordSize {
-    WordSize::new(2 * num_pairs) + BoxHeader::storage_size()
+    let self_size = ByteSize::new(size_of::<Self>()).get_words_rounded_up();
+    WordSize::new(2 * num_pairs) + self_size
   }
 
-  /// Capacity is how many extra words been allocated
-  /// TODO: capacity is not words count on heap, but the count of k/v pairs
   fn new(num_pairs: usize) -> Self {
     let storage_size = Self::storage_size(num_pairs);
     Self {
@@ -67,9 +78,10 @@ impl Map {
     }
   }
 
-  /// Returns allocated size used by this map on heap
+  /// Returns how many k/v pairs can be stored in the allocated memory
   pub fn get_capacity(&self) -> usize {
-    self.header.get_storage_size()
+    let self_size = ByteSize::new(size_of::<Self>()).get_words_rounded_up();
+    (self.header.get_storage_size() - self_size.words) / 2
   }
 
   /// Returns actual element count, less or equal to the capacity
@@ -87,6 +99,12 @@ impl Map {
     Ok(p)
   }
 
+  /// Keys and values one after another, in the order of the keys.
+  pub unsafe fn get_pairs(this: *const Map) -> &'static [Term] {
+    let p = this.add(1) as *const Term;
+    core::slice::from_raw_parts(p, 2 * (*this).count)
+  }
+
   /// Add a key/value pair to map (unsorted).
   /// Note: the flatmap must be sorted for use
   pub unsafe fn add(this: *mut Map, key: Term, value: Term) -> RtResult<()> {
@@ -148,39 +166,26 @@ impl Map {
       return Ok(MapGetResult::ClosestLarger(0));
     }
 
+    // Search in the range `a..b`
     let mut a = 0usize;
-    let mut b = count - 1;
-    loop {
+    let mut b = count;
+    while a < b {
       let median = a + (b - a) / 2;
       let median_value = ptr::read(p.add(median * 2));
-      // The key is less than median, step left
       println!("map:get a={} b={} median={}", a, b, median);
       match cmp_terms(median_value, key, true)? {
-        Ordering::Less => {
-          if a == b {
-            // search cannot shrink when the range is already length 0
-            // Suggest to the caller that we've found where the closest larger
-            // element is located for possible insertion.
-            return Ok(MapGetResult::ClosestLarger(a + 1));
-          }
-          b = median;
-          continue;
-        }
-        Ordering::Greater => {
-          if a == b {
-            // search cannot shrink when the range is already length 0
-            // Suggest to the caller that we've found where the closest smaller
-            // element is located for possible insertion.
-            return Ok(MapGetResult::ClosestLarger(a));
-          }
-          a = median;
-          continue;
-        }
+        // The median is less than the key, step right
+        Ordering::Less => a = median + 1,
+        // The median is greater than the key, step left
+        Ordering::Greater => b = median,
         Ordering::Equal => {
           // Found it!
           return Ok(MapGetResult::FoundAt(median));
         }
       }
     }
+    // The range has shrunk to zero length. Suggest to the caller that we've
+    // found where the closest larger element is located for possible insertion.
+    Ok(MapGetResult::ClosestLarger(a))
   }
 }
@@ -5,7 +5,7 @@ use crate::{
   term::{
     boxed::{
       boxtype::{self, BoxType},
-      trait_interface::TBoxed,
+      trait_interface::{InplaceMapFn, TBoxed},
       BoxHeader,
     },
     classify,
@@ -29,6 +29,11 @@ impl TBoxed for ExternalPid {
   fn get_type(&self) -> BoxType {
     boxtype::BOXTYPETAG_EXTERNALPID
   }
+
+  fn inplace_map(&mut self, mapfn: &mut InplaceMapFn) {
+    let this_p = self as *mut ExternalPid;
+    self.node = mapfn(this_p as *mut BoxHeader, self.node);
+  }
 }
 
 impl ExternalPid {
@@ -37,9 +42,8 @@ impl ExternalPid {
   }
 
   fn new(node: Term, id: Word) -> ExternalPid {
-    let storage_size = ExternalPid::storage_size() - WordSize::one();
     ExternalPid {
-      header: BoxHeader::new::<ExternalPid>(storage_size),
+      header: BoxHeader::new::<ExternalPid>(ExternalPid::storage_size()),
       node,
       id,
     }
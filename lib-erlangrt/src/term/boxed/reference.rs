@@ -0,0 +1,120 @@
+use crate::{
+  defs::{ByteSize, Word, WordSize},
+  emulator::heap::heap_trait::THeap,
+  fail::RtResult,
+  term::{
+    boxed::{
+      boxtype::{self, BoxType},
+      trait_interface::TBoxed,
+      BoxHeader,
+    },
+    classify,
+    value::Term,
+  },
+};
+use core::{cmp::Ordering, fmt, mem::size_of, ptr};
+
+/// How many bits of the id are stored in the first word, same as Erlang/OTP.
+const FIRST_WORD_BITS: u32 = 18;
+
+/// Represents a local reference box on heap. The id is unique in the VM and
+/// is stored as 3 words of 32 bits, the first word has only 18 bits used.
+pub struct LocalRef {
+  #[allow(dead_code)]
+  header: BoxHeader,
+  pub id: [u32; 3],
+}
+
+impl TBoxed for LocalRef {
+  fn get_class(&self) -> classify::TermClass {
+    classify::CLASS_REF
+  }
+
+  fn get_type(&self) -> BoxType {
+    boxtype::BOXTYPETAG_LOCALREF
+  }
+}
+
+impl LocalRef {
+  pub const fn storage_size() -> WordSize {
+    ByteSize::new(size_of::<LocalRef>()).get_words_rounded_up()
+  }
+
+  fn new(id: Word) -> LocalRef {
+    LocalRef {
+      header: BoxHeader::new::<LocalRef>(LocalRef::storage_size()),
+      id: Self::id_to_words(id),
+    }
+  }
+
+  /// Split a VM-unique counter value into 3 id words.
+  pub fn id_to_words(id: Word) -> [u32; 3] {
+    let id = id as u64;
+    [
+      (id & ((1 << FIRST_WORD_BITS) - 1)) as u32,
+      (id >> FIRST_WORD_BITS) as u32,
+      (id >> (FIRST_WORD_BITS + 32)) as u32,
+    ]
+  }
+
+  /// Join 3 id words back into the counter value.
+  pub fn words_to_id(words: [u32; 3]) -> Word {
+    let id = (words[0] as u64)
+      | ((words[1] as u64) << FIRST_WORD_BITS)
+      | ((words[2] as u64) << (FIRST_WORD_BITS + 32));
+    id as Word
+  }
+
+  /// Allocate a reference with the given id and return it as a term.
+  pub fn create_into(hp: &mut THeap, id: Word) -> RtResult<Term> {
+    let p = hp.alloc(LocalRef::storage_size(), false)? as *mut Self;
+    unsafe { ptr::write(p, LocalRef::new(id)) }
+    Ok(Term::make_boxed(p))
+  }
+
+  /// Read the id of a local reference, `None` if the term is not one.
+  pub fn get_id(t: Term) -> Option<Word> {
+    if !t.is_local_ref() {
+      return None;
+    }
+    Self::get_words(t).map(Self::words_to_id)
+  }
+
+  /// Read the 3 id words of a local reference, `None` if the term is not one.
+  pub fn get_words(t: Term) -> Option<[u32; 3]> {
+    if !t.is_local_ref() {
+      return None;
+    }
+    let p = t.get_box_ptr::<LocalRef>();
+    Some(unsafe { (*p).id })
+  }
+
+  /// Order of two references, the last word is the most significant.
+  pub fn cmp_id(&self, other: &LocalRef) -> Ordering {
+    self.id.iter().rev().cmp(other.id.iter().rev())
+  }
+}
+
+impl fmt::Display for LocalRef {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "#Ref<0.{}.{}.{}>", self.id[2], self.id[1], self.id[0])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_id_words() {
+    let id = (7 << 50) | (12345 << 18) | 99;
+    let words = LocalRef::id_to_words(id);
+    assert_eq!(words, [99, 12345, 7]);
+    assert_eq!(LocalRef::words_to_id(words), id);
+
+    let a = LocalRef::new((1 << 18) - 1);
+    let b = LocalRef::new(1 << 18);
+    assert_eq!(a.cmp_id(&b), Ordering::Less);
+    assert_eq!(format!("{}", b), "#Ref<0.0.1.0>");
+  }
+}
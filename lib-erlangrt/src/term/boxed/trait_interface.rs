@@ -1,11 +1,18 @@
-use crate::term::{boxed::boxtype::BoxType, classify::TermClass};
+use crate::term::{
+  boxed::{boxtype::BoxType, BoxHeader},
+  classify::TermClass,
+  value::Term,
+};
 
-// pub type InplaceMapFn = FnMut(*mut boxed::BoxHeader, Term) -> Term;
+/// A function which is given the box owning a term and the term, and returns
+/// the term to be written back in its place (used by the GC to move data).
+pub type InplaceMapFn<'a> = FnMut(*mut BoxHeader, Term) -> Term + 'a;
 
 pub trait TBoxed {
   fn get_class(&self) -> TermClass;
   fn get_type(&self) -> BoxType;
 
-  //  /// For all terms contained in this boxed, run a function and update the data.
-  //  fn inplace_map(&mut self, mapfn: &InplaceMapFn);
+  /// For all terms contained in this boxed, run a function and update the data.
+  /// Boxes which do not contain any terms can use the default (does nothing).
+  fn inplace_map(&mut self, _mapfn: &mut InplaceMapFn) {}
 }
@@ -1,17 +1,24 @@
-use core::cmp::Ordering;
+use core::{cmp::Ordering, hash::Hasher};
+use std::collections::hash_map::DefaultHasher;
 
 use crate::{
   defs::TDataReader,
   emulator::atom,
   fail::RtResult,
   term::{
-    boxed::{self, binary::trait_interface::TBinary},
+    boxed::{self, binary::trait_interface::TBinary, boxtype, BoxHeader},
     classify,
     compare::EqResult::Concluded,
     value::*,
   },
 };
 
+/// How deep into nested terms `hash_term_exact` looks.
+const HASH_DEPTH: usize = 3;
+/// How many elements of a tuple, list or map, or bytes of a binary
+/// `hash_term_exact` visits.
+const HASH_ELEMENTS: usize = 8;
+
 /// When comparing nested terms they might turn out to be equal. `CompareOp`
 /// is stored in `stack` in `eq_terms()` function and tells where to resume
 /// comparing the previous term.
@@ -47,6 +54,203 @@ pub fn cmp_terms(a: Term, b: Term, exact: bool) -> RtResult<Ordering> {
   cmp_terms_1(a, b, exact)
 }
 
+/// Exact equality as `=:=`. Unlike `cmp_terms` it is defined for all terms,
+/// so it is safe to use for lookups by any key. Nested terms are compared
+/// with a stack of pairs which remain to be checked.
+pub fn eq_terms_exact(a: Term, b: Term) -> bool {
+  let mut stack = vec![(a, b)];
+  while let Some((a, b)) = stack.pop() {
+    if a.raw() == b.raw() {
+      continue;
+    }
+    if !unsafe { eq_terms_exact_shallow(a, b, &mut stack) } {
+      return false;
+    }
+  }
+  true
+}
+
+/// Compare the non-term contents of `a` and `b` and push their nested terms
+/// on `stack`. The terms are known to differ in their raw values.
+unsafe fn eq_terms_exact_shallow(
+  a: Term,
+  b: Term,
+  stack: &mut Vec<(Term, Term)>,
+) -> bool {
+  if a.is_cons() {
+    if !b.is_cons() {
+      return false;
+    }
+    let a_ptr = a.get_cons_ptr();
+    let b_ptr = b.get_cons_ptr();
+    stack.push(((*a_ptr).tl(), (*b_ptr).tl()));
+    stack.push(((*a_ptr).hd(), (*b_ptr).hd()));
+    return true;
+  }
+  // Different immediate values, or an immediate and a box
+  if !a.is_boxed() || !b.is_boxed() {
+    return false;
+  }
+  let a_trait = (*a.get_box_ptr::<BoxHeader>()).get_trait_ptr();
+  let b_trait = (*b.get_box_ptr::<BoxHeader>()).get_trait_ptr();
+  let box_type = (*a_trait).get_type();
+  if box_type != (*b_trait).get_type() {
+    return false;
+  }
+
+  match box_type {
+    boxtype::BOXTYPETAG_TUPLE => {
+      let a_tuple = &*(a_trait as *const boxed::Tuple);
+      let b_tuple = &*(b_trait as *const boxed::Tuple);
+      let arity = a_tuple.get_arity();
+      if arity != b_tuple.get_arity() {
+        return false;
+      }
+      for i in 0..arity {
+        stack.push((a_tuple.get_element(i), b_tuple.get_element(i)));
+      }
+      true
+    }
+    boxtype::BOXTYPETAG_MAP => {
+      // Flat maps keep their keys sorted, so equal maps have equal pairs
+      let a_pairs = boxed::Map::get_pairs(a_trait as *const boxed::Map);
+      let b_pairs = boxed::Map::get_pairs(b_trait as *const boxed::Map);
+      if a_pairs.len() != b_pairs.len() {
+        return false;
+      }
+      stack.extend(a_pairs.iter().cloned().zip(b_pairs.iter().cloned()));
+      true
+    }
+    boxtype::BOXTYPETAG_FLOAT => {
+      let a_float = &*(a_trait as *const boxed::Float);
+      let b_float = &*(b_trait as *const boxed::Float);
+      a_float.value == b_float.value
+    }
+    boxtype::BOXTYPETAG_BIGINTEGER => {
+      let a_big = &*(a_trait as *const boxed::Bignum);
+      let b_big = &*(b_trait as *const boxed::Bignum);
+      a_big.is_negative() == b_big.is_negative()
+        && a_big.get_digits() == b_big.get_digits()
+    }
+    boxtype::BOXTYPETAG_LOCALREF => cmp_local_refs(a, b) == Ordering::Equal,
+    boxtype::BOXTYPETAG_BINARY => {
+      let a_bin = boxed::Binary::get_trait_from_term(a);
+      let b_bin = boxed::Binary::get_trait_from_term(b);
+      (*a_bin).get_bit_size() == (*b_bin).get_bit_size()
+        && cmp_binary_data(a, b, a_bin, b_bin).ok() == Some(Ordering::Equal)
+    }
+    boxtype::BOXTYPETAG_EXPORT => {
+      let a_mfa = (*(a_trait as *const boxed::Export)).exp.mfa;
+      let b_mfa = (*(b_trait as *const boxed::Export)).exp.mfa;
+      a_mfa.m == b_mfa.m && a_mfa.f == b_mfa.f && a_mfa.arity == b_mfa.arity
+    }
+    boxtype::BOXTYPETAG_CLOSURE => {
+      let a_fun = &*(a_trait as *const boxed::Closure);
+      let b_fun = &*(b_trait as *const boxed::Closure);
+      if a_fun.mfa.m != b_fun.mfa.m
+        || a_fun.mfa.f != b_fun.mfa.f
+        || a_fun.mfa.arity != b_fun.mfa.arity
+        || a_fun.nfrozen != b_fun.nfrozen
+      {
+        return false;
+      }
+      let frozen = a_fun.get_frozen().iter().cloned();
+      stack.extend(frozen.zip(b_fun.get_frozen().iter().cloned()));
+      true
+    }
+    // External pids, ports and refs do not exist without distribution, and
+    // the other box types are not terms which a program can compare
+    _ => false,
+  }
+}
+
+/// Hash of a term which agrees with `eq_terms_exact`: exactly equal terms
+/// have equal hashes. Does not depend on where the term is on the heap, so it
+/// stays valid after a garbage collection. Only the first few levels and
+/// elements are visited to limit the cost for large terms.
+pub fn hash_term_exact(t: Term) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  unsafe { hash_term_exact_1(t, HASH_DEPTH, &mut hasher) };
+  hasher.finish()
+}
+
+unsafe fn hash_term_exact_1(t: Term, depth: usize, hasher: &mut DefaultHasher) {
+  if t.is_cons() {
+    hasher.write_u8(b'[');
+    if depth == 0 {
+      return;
+    }
+    let mut rest = t;
+    for _ in 0..HASH_ELEMENTS {
+      if !rest.is_cons() {
+        hash_term_exact_1(rest, depth - 1, hasher);
+        return;
+      }
+      hash_term_exact_1((*rest.get_cons_ptr()).hd(), depth - 1, hasher);
+      rest = (*rest.get_cons_ptr()).tl();
+    }
+    return;
+  }
+  if !t.is_boxed() {
+    hasher.write_usize(t.raw());
+    return;
+  }
+  let trait_ptr = (*t.get_box_ptr::<BoxHeader>()).get_trait_ptr();
+  let box_type = (*trait_ptr).get_type();
+  hasher.write_usize(box_type.get());
+  if depth == 0 {
+    return;
+  }
+
+  match box_type {
+    boxtype::BOXTYPETAG_TUPLE => {
+      let tuple = &*(trait_ptr as *const boxed::Tuple);
+      let arity = tuple.get_arity();
+      hasher.write_usize(arity);
+      for i in 0..arity.min(HASH_ELEMENTS) {
+        hash_term_exact_1(tuple.get_element(i), depth - 1, hasher);
+      }
+    }
+    boxtype::BOXTYPETAG_MAP => {
+      let pairs = boxed::Map::get_pairs(trait_ptr as *const boxed::Map);
+      hasher.write_usize(pairs.len());
+      for kv in pairs.iter().take(2 * HASH_ELEMENTS) {
+        hash_term_exact_1(*kv, depth - 1, hasher);
+      }
+    }
+    boxtype::BOXTYPETAG_LOCALREF => {
+      let id = boxed::LocalRef::get_id(t).unwrap_or(0);
+      hasher.write_usize(id);
+    }
+    boxtype::BOXTYPETAG_BIGINTEGER => {
+      let big = &*(trait_ptr as *const boxed::Bignum);
+      hasher.write_u8(big.is_negative() as u8);
+      for d in big.get_digits().iter() {
+        hasher.write_usize(*d);
+      }
+    }
+    boxtype::BOXTYPETAG_BINARY => {
+      let bin = boxed::Binary::get_trait_from_term(t);
+      hasher.write_usize((*bin).get_bit_size().bits);
+      match (*bin).get_byte_reader() {
+        Some(reader) => hash_binary_data(reader, hasher),
+        None => hash_binary_data((*bin).get_bit_reader(), hasher),
+      }
+    }
+    // Floats `0.0` and `-0.0` are equal, and funs are rare keys: the box type
+    // is enough for these
+    _ => {}
+  }
+}
+
+/// Hash the first bytes of a binary, the same for a byte and a bit reader.
+fn hash_binary_data<Reader: TDataReader>(reader: Reader, hasher: &mut DefaultHasher) {
+  let n_bytes = reader.get_bit_size().get_byte_size_rounded_up().bytes();
+  for i in 0..n_bytes.min(HASH_ELEMENTS) {
+    hasher.write_u8(reader.read(i));
+  }
+}
+
 #[inline]
 fn cmp_terms_1(a: Term, b: Term, exact: bool) -> RtResult<Ordering> {
   // Comparison might want to recurse.
@@ -342,6 +546,22 @@ fn cmp_terms_immed_box(a: Term, b: Term) -> RtResult<Ordering> {
     // cmp atoms a.fn and b.fn
     // cmp arity
     unimplemented!("compare 2 exports")
+  } else if a.is_local_ref() {
+    if b.is_local_ref() {
+      return Ok(unsafe { cmp_local_refs(a, b) });
+    } else if b.is_external_ref() {
+      unimplemented!("compare local vs ext ref")
+    } else {
+      return cmp_mixed_types(a, b);
+    }
+  } else if a.is_external_ref() {
+    if b.is_local_ref() {
+      unimplemented!("compare ext vs local ref")
+    } else if b.is_external_ref() {
+      unimplemented!("compare ext vs ext ref")
+    } else {
+      return cmp_mixed_types(a, b);
+    }
   } else if a.is_boxed() {
     if a.is_binary() {
       if b.is_binary() {
@@ -373,22 +593,6 @@ fn cmp_terms_immed_box(a: Term, b: Term) -> RtResult<Ordering> {
     } else {
       return cmp_mixed_types(a, b);
     }
-  } else if a.is_local_ref() {
-    if b.is_local_ref() {
-      unimplemented!("compare local vs local ref")
-    } else if b.is_external_ref() {
-      unimplemented!("compare local vs ext ref")
-    } else {
-      return cmp_mixed_types(a, b);
-    }
-  } else if a.is_external_ref() {
-    if b.is_local_ref() {
-      unimplemented!("compare ext vs local ref")
-    } else if b.is_external_ref() {
-      unimplemented!("compare ext vs ext ref")
-    } else {
-      return cmp_mixed_types(a, b);
-    }
   } else {
     // must be a binary
     assert!(a.is_binary());
@@ -400,6 +604,13 @@ fn cmp_terms_immed_box(a: Term, b: Term) -> RtResult<Ordering> {
   unimplemented!("eq_terms_immed_box {} {}", a, b)
 }
 
+#[inline]
+unsafe fn cmp_local_refs(a: Term, b: Term) -> Ordering {
+  let a_ptr = a.get_box_ptr::<boxed::LocalRef>();
+  let b_ptr = b.get_box_ptr::<boxed::LocalRef>();
+  (*a_ptr).cmp_id(&*b_ptr)
+}
+
 #[inline]
 unsafe fn cmp_binary(a: Term, b: Term) -> RtResult<Ordering> {
   let a_trait = boxed::Binary::get_trait_from_term(a);
@@ -419,6 +630,16 @@ unsafe fn cmp_binary(a: Term, b: Term) -> RtResult<Ordering> {
     (*b_trait).get_byte_size()
   );
 
+  cmp_binary_data(a, b, a_trait, b_trait)
+}
+
+/// Compare the contents of two binaries of the same bit size.
+unsafe fn cmp_binary_data(
+  a: Term,
+  b: Term,
+  a_trait: *const TBinary,
+  b_trait: *const TBinary,
+) -> RtResult<Ordering> {
   // Try figure out a compatible byte- or bit-reader combination for A arg and
   // B arg and then call a branch function which will do the same for B.
   match (*a_trait).get_byte_reader() {
@@ -553,3 +774,53 @@ unsafe fn cmp_cons(a: Term, b: Term) -> EqResult {
 //  // TODO: see if cmp_terms_immed_box can be useful
 //  unimplemented!("eq_terms_box")
 //}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    emulator::heap::{Designation, Heap},
+    term::term_builder::{ListBuilder, TupleBuilder},
+  };
+
+  fn make_float(hp: &mut Heap, value: f64) -> Term {
+    Term::make_boxed(unsafe { boxed::Float::create_into(hp, value).unwrap() })
+  }
+
+  /// Build `{Name, [1, 2], #{1 => 2.5}}` for an atom `name`.
+  fn make_nested(hp: &mut Heap, name: &str) -> Term {
+    let small = Term::make_small_signed;
+    let mut lb = ListBuilder::new().unwrap();
+    unsafe {
+      lb.append(small(1), hp).unwrap();
+      lb.append(small(2), hp).unwrap();
+    }
+    let map = boxed::Map::create_into(hp, 1).unwrap();
+    let value = make_float(hp, 2.5);
+    unsafe { boxed::Map::add(map, small(1), value).unwrap() };
+    let tb = TupleBuilder::with_arity(3, hp).unwrap();
+    unsafe {
+      tb.set_element(0, atom::from_str(name));
+      tb.set_element(1, lb.make_term());
+      tb.set_element(2, Term::make_boxed(map));
+    }
+    tb.make_term()
+  }
+
+  #[test]
+  fn test_eq_terms_exact() {
+    let mut hp = Heap::new(Designation::TransientDestructible);
+    let a = make_nested(&mut hp, "key");
+    let b = make_nested(&mut hp, "key");
+    let c = make_nested(&mut hp, "other");
+    assert_ne!(a.raw(), b.raw());
+    assert!(eq_terms_exact(a, b));
+    assert_eq!(hash_term_exact(a), hash_term_exact(b));
+    assert!(!eq_terms_exact(a, c));
+
+    // Exact: an integer is not equal to a float
+    let one = Term::make_small_signed(1);
+    assert!(!eq_terms_exact(one, make_float(&mut hp, 1.0)));
+    assert!(!eq_terms_exact(a, one));
+  }
+}
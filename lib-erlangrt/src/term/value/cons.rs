@@ -85,63 +85,30 @@ where
   }
 }
 
-/// Finds if any element of lst satisfies `predicate` function.
-/// For each list element run the predicate until it returns true, then the
-/// result becomes true. If predicate did not return true for any element,
-/// the result becomes false.
-pub fn any<T>(lst: Term, mut predicate: T) -> bool
+/// Finds the first element of lst which satisfies `predicate`, visiting at
+/// most `limit` elements.
+/// Returns: the found cell, and the part of the list which was not visited
+/// (NIL, or an improper tail, if the whole list was visited).
+pub fn find_first_within<T>(
+  lst: Term,
+  limit: usize,
+  mut predicate: T,
+) -> (Option<*const boxed::Cons>, Term)
 where
   T: FnMut(Term) -> bool,
 {
-  // Not found
-  if lst == Term::nil() {
-    return false;
-  }
-  let mut p = lst.get_cons_ptr();
-  loop {
-    let hd_el = unsafe { (*p).hd() };
-    // Found at *p
-    if predicate(hd_el) {
-      return true;
-    }
-
-    let tl_el = unsafe { (*p).tl() };
-    if tl_el.is_cons() {
-      // for a list tail element step forward
-      p = tl_el.get_cons_ptr();
-    } else {
-      // for a non list end here
-      return false;
-    }
-  }
-}
-
-/// Finds and returns first element of lst which satisfies `predicate`.
-pub fn find_first<T>(lst: Term, mut predicate: T) -> Option<*const boxed::Cons>
-where
-  T: FnMut(Term) -> bool,
-{
-  // Not found
-  if lst == Term::nil() {
-    return None;
-  }
-  let mut p = lst.get_cons_ptr();
-  loop {
-    let hd_el = unsafe { (*p).hd() };
-    // Found at *p
-    if predicate(hd_el) {
-      return Some(p);
+  let mut rest = lst;
+  for _ in 0..limit {
+    if !rest.is_cons() {
+      break;
     }
-
-    let tl_el = unsafe { (*p).tl() };
-    if tl_el.is_cons() {
-      // for a list tail element step forward
-      p = tl_el.get_cons_ptr();
-    } else {
-      // for a non list end here
-      return None;
+    let p = rest.get_cons_ptr();
+    if predicate(unsafe { (*p).hd() }) {
+      return (Some(p), unsafe { (*p).tl() });
     }
+    rest = unsafe { (*p).tl() };
   }
+  (None, rest)
 }
 
 /// Given Rust `String`, create list of characters on heap
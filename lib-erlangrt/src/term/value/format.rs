@@ -108,6 +108,10 @@ unsafe fn format_box_contents(
     boxtype::BOXTYPETAG_EXTERNALPID => write!(f, "ExtPid<>"),
     boxtype::BOXTYPETAG_EXTERNALPORT => write!(f, "ExtPort<>"),
     boxtype::BOXTYPETAG_EXTERNALREF => write!(f, "ExtRef<>"),
+    boxtype::BOXTYPETAG_LOCALREF => {
+      let rptr = trait_ptr as *const boxed::LocalRef;
+      write!(f, "{}", *rptr)
+    }
     boxtype::BOXTYPETAG_IMPORT => {
       let iptr = trait_ptr as *const boxed::Import;
       write!(f, "#Import<{}>", (*iptr).mfarity)